@@ -0,0 +1,35 @@
+#!/bin/sh
+# build this app in an ArceOS checkout and boot it on qemu, a keyboard and a mouse sit behind qemu-xhci.
+#
+# usage: ./run.sh <arceos checkout>
+# ARCH defaults to aarch64. AXUSB_XHCI_BASE is the physical address of BAR0 of the controller,
+# AXUSB_XHCI_IRQ its interrupt line, the app polls when it's left unset.
+set -e
+
+ARCEOS=${1:?usage: $0 <arceos checkout>}
+ARCH=${ARCH:-aarch64}
+APP=$(cd "$(dirname "$0")" && pwd)
+
+# axdriver assigns BARs while probing the PCI bus, this one is the first in the MMIO window of qemu virt
+export AXUSB_XHCI_BASE=${AXUSB_XHCI_BASE:-0x10000000}
+
+make -C "$ARCEOS" A="$APP" ARCH="$ARCH" BUS=pci LOG=${LOG:-info} build
+
+case "$ARCH" in
+aarch64)
+    MACHINE="-machine virt -cpu cortex-a72"
+    ;;
+riscv64)
+    MACHINE="-machine virt -bios default"
+    ;;
+*)
+    echo "no qemu machine for $ARCH here, boot $APP/*.bin by hand" >&2
+    exit 1
+    ;;
+esac
+
+qemu-system-"$ARCH" $MACHINE -m 128M -smp 1 -nographic \
+    -kernel "$APP"/arceos-xhci_"$ARCH"-qemu-virt.bin \
+    -device qemu-xhci,id=xhci \
+    -device usb-kbd,bus=xhci.0 \
+    -device usb-mouse,bus=xhci.0
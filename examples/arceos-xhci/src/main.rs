@@ -0,0 +1,168 @@
+//! minimal ArceOS unikernel bringing up axusb_host on one xhci controller.
+//!
+//! shows the whole bring-up: platform abstractions, interrupt wiring, then the staged start.
+//! built through ArceOS, see `run.sh`. controller comes from `AXUSB_XHCI_BASE` and
+//! `AXUSB_XHCI_IRQ` at build time, it's polled if no irq is given
+
+#![no_std]
+#![no_main]
+#![feature(allocator_api)]
+
+#[macro_use]
+extern crate axstd as std;
+extern crate alloc;
+
+use alloc::{alloc::Global, boxed::Box, sync::Arc, vec::Vec};
+use core::time::Duration;
+
+use async_lock::Semaphore;
+use axhal::mem::{phys_to_virt, virt_to_phys};
+use axusb_host::{
+    abstractions::{PlatformAbstractions, SystemWordWide, USBSystemConfig, WakeMethod},
+    USBSystem,
+};
+use lazy_static::lazy_static;
+use log::info;
+
+const RING_BUFFER_SIZE: usize = 512;
+///spec says 100ms
+const PORT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+struct VirtAddr(usize);
+#[derive(Clone, Copy)]
+struct PhysAddr(usize);
+
+impl From<usize> for VirtAddr {
+    fn from(addr: usize) -> Self {
+        Self(addr)
+    }
+}
+
+impl From<VirtAddr> for usize {
+    fn from(addr: VirtAddr) -> Self {
+        addr.0
+    }
+}
+
+impl From<PhysAddr> for VirtAddr {
+    fn from(addr: PhysAddr) -> Self {
+        Self(phys_to_virt(addr.0.into()).as_usize())
+    }
+}
+
+impl From<usize> for PhysAddr {
+    fn from(addr: usize) -> Self {
+        Self(addr)
+    }
+}
+
+impl From<PhysAddr> for usize {
+    fn from(addr: PhysAddr) -> Self {
+        addr.0
+    }
+}
+
+impl From<VirtAddr> for PhysAddr {
+    fn from(addr: VirtAddr) -> Self {
+        Self(virt_to_phys(addr.0.into()).as_usize())
+    }
+}
+
+///heap of ArceOS lives in its linear mapping, so it doubles as DMA memory.
+///caches are coherent on qemu, defaults of the cache hooks are fine
+#[derive(Clone)]
+struct ArceOS;
+
+impl PlatformAbstractions for ArceOS {
+    type VirtAddr = VirtAddr;
+    type PhysAddr = PhysAddr;
+    type DMA = Global;
+    const PAGE_SIZE: usize = axhal::mem::PAGE_SIZE_4K;
+    const RING_BUFFER_SIZE: usize = RING_BUFFER_SIZE;
+    const WORD: SystemWordWide = SystemWordWide::X64;
+
+    fn dma_alloc(&self) -> Self::DMA {
+        Global
+    }
+
+    fn now(&self) -> Option<Duration> {
+        Some(axhal::time::monotonic_time())
+    }
+
+    ///the heap is DMA memory already, so caller buffers are never bounced
+    fn dma_capable(&self, _addr: VirtAddr, _len: usize) -> bool {
+        true
+    }
+}
+
+lazy_static! {
+    ///one permit per interrupt, see [`WakeMethod::Timer`]
+    static ref XHCI_EVENTS: Arc<Semaphore> = Arc::new(Semaphore::new(0));
+}
+
+///ArceOS takes plain fn pointers as handlers, so the irq only hands out a permit
+fn on_xhci_irq() {
+    XHCI_EVENTS.add_permits(1);
+}
+
+fn parse_addr(value: &str) -> usize {
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .expect("malformed number in AXUSB_XHCI_*")
+}
+
+///MSI or edge triggered lines only, a level triggered INTx keeps firing
+///until controller is acked, which this handler does not do
+fn wake_method() -> WakeMethod {
+    match option_env!("AXUSB_XHCI_IRQ").map(parse_addr) {
+        Some(irq) if axhal::irq::register_irq_handler(irq, on_xhci_irq) => {
+            info!("xhci events signalled by irq {}", irq);
+            WakeMethod::Timer(XHCI_EVENTS.clone())
+        }
+        Some(irq) => panic!("irq {} could not be registered", irq),
+        None => {
+            info!("no irq given, polling xhci");
+            WakeMethod::Yield
+        }
+    }
+}
+
+#[no_mangle]
+fn main() {
+    let base = parse_addr(option_env!("AXUSB_XHCI_BASE").expect("AXUSB_XHCI_BASE is not set"));
+    let config = USBSystemConfig {
+        base_addr: PhysAddr(base).into(),
+        wake_method: wake_method(),
+        extra_controllers: Vec::new(),
+        os: ArceOS,
+        lpm_policy: Default::default(),
+        power_policy: Default::default(),
+        spec_policy: Default::default(),
+        device_filter: Default::default(),
+        quirks: Default::default(),
+        port_debounce: PORT_DEBOUNCE,
+        timeouts: Default::default(),
+        watchdog: None,
+        ring_growth: None,
+        autosuspend: None,
+        bounded_memory: None,
+        device_node_hook: None,
+    };
+
+    //controller tasks borrow the system for as long as they run
+    let system: &'static USBSystem<'static, ArceOS, RING_BUFFER_SIZE> =
+        Box::leak(Box::new(USBSystem::new(config)));
+
+    println!("starting xhci at {:#x}", base);
+    system
+        .stage_1_start_controller()
+        .stage_2_initialize_usb_layer();
+
+    //packed mouse driver logs what qemu mouse reports, keyboard only gets enumerated
+    embassy_futures::block_on(system.async_run());
+    println!("usb system stopped");
+}
@@ -0,0 +1,135 @@
+//! DMA memory out of one region handed over at init, for builds that must not allocate at runtime.
+//!
+//! until [`BoundedDma::seal`] blocks are cut from the region one after another. freed blocks are
+//! kept by size and alignment, once sealed only those kept blocks are handed out again and
+//! everything else fails. set [`super::USBSystemConfig::bounded_memory`] so controllers
+//! reserve what devices will need, the system seals once every controller is up.
+
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::NonNull,
+};
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+
+use super::spin::SpinCell;
+
+///what controllers reserve before allocations are sealed
+#[derive(Clone, Debug)]
+pub struct BoundedMemory {
+    ///devices attached at once, further ones are refused
+    pub max_devices: usize,
+    ///transfer buffers alive at once, across descriptors reads and drivers
+    pub transfer_buffers: usize,
+    ///size of each transfer buffer, larger requests fail once sealed
+    pub transfer_buffer_len: usize,
+}
+
+///[`Allocator`] over a fixed region, use it as [`super::PlatformAbstractions::DMA`].
+///
+///region must be reachable by controller, addresses are translated like any other DMA memory
+#[derive(Clone)]
+pub struct BoundedDma {
+    ///freed from Drop, so no async lock here
+    inner: Arc<SpinCell<RegionState>>,
+}
+
+struct RegionState {
+    base: usize,
+    len: usize,
+    ///offset of first byte never handed out
+    cut: usize,
+    sealed: bool,
+    ///free blocks by (size, align) they were cut with
+    free: BTreeMap<(usize, usize), Vec<usize>>,
+    ///(size, align) of every block handed out, by address
+    live: BTreeMap<usize, (usize, usize)>,
+}
+
+impl BoundedDma {
+    pub fn new(region: &'static mut [u8]) -> Self {
+        Self {
+            inner: Arc::new(SpinCell::new(RegionState {
+                base: region.as_mut_ptr() as usize,
+                len: region.len(),
+                cut: 0,
+                sealed: false,
+                free: BTreeMap::new(),
+                live: BTreeMap::new(),
+            })),
+        }
+    }
+
+    ///from now on only blocks freed before are handed out again
+    pub fn seal(&self) {
+        self.with(|state| state.sealed = true)
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.with(|state| state.sealed)
+    }
+
+    ///bytes of region not cut into blocks yet, unusable once sealed
+    pub fn uncut(&self) -> usize {
+        self.with(|state| state.len - state.cut)
+    }
+
+    ///blocks ready to be handed out
+    pub fn free_blocks(&self) -> usize {
+        self.with(|state| state.free.values().map(Vec::len).sum())
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut RegionState) -> R) -> R {
+        self.inner.with(f)
+    }
+}
+
+impl RegionState {
+    fn cut(&mut self, size: usize, align: usize) -> Option<usize> {
+        let start = (self.base + self.cut).next_multiple_of(align);
+        let end = start.checked_add(size)?;
+        if end > self.base + self.len {
+            return None;
+        }
+        self.cut = end - self.base;
+        Some(start)
+    }
+
+    ///smallest kept block fitting both size and alignment
+    fn reuse(&mut self, size: usize, align: usize) -> Option<(usize, (usize, usize))> {
+        let (class, blocks) = self
+            .free
+            .range_mut((size, 0)..)
+            .find(|((_, class_align), blocks)| *class_align >= align && !blocks.is_empty())?;
+        Some((blocks.pop()?, *class))
+    }
+}
+
+unsafe impl Allocator for BoundedDma {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let size = layout.size().max(1);
+        let align = layout.align();
+        self.with(|state| {
+            let (addr, class) = match state.reuse(size, align) {
+                Some(block) => block,
+                None if !state.sealed => (state.cut(size, align).ok_or(AllocError)?, (size, align)),
+                None => return Err(AllocError),
+            };
+            state.live.insert(addr, class);
+            let ptr = NonNull::new(addr as *mut u8).ok_or(AllocError)?;
+            //a reused block could be larger, DMA takes its length from the slice
+            Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+        })
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        let addr = ptr.addr().get();
+        self.with(|state| {
+            let class = state
+                .live
+                .remove(&addr)
+                .expect("freed a block bounded DMA never handed out");
+            state.free.entry(class).or_default().push(addr);
+        })
+    }
+}
@@ -0,0 +1,390 @@
+//! single threaded executor on virtual time, so async paths run the same way on every run.
+//!
+//! tasks are polled in the order they were woken, timers fire in deadline order and
+//! time only moves when nothing is runnable. controller loops yield instead of sleeping,
+//! so they never stall, time moves by a fixed tick then. meant for tests and CI, not for real hardware.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use async_lock::{Mutex, Semaphore};
+
+use super::WakeMethod;
+
+///time that only moves when told to, shared by everything of one test.
+///
+///hand [`VirtualClock::now`] out through [`super::PlatformAbstractions::now`]
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    inner: Arc<ClockInner>,
+}
+
+#[derive(Default)]
+struct ClockInner {
+    nanos: AtomicU64,
+    ///keeps timers with equal deadline in registration order
+    next_timer: AtomicU64,
+    timers: Mutex<BTreeMap<(Duration, u64), Waker>>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.inner.nanos.load(Ordering::Acquire))
+    }
+
+    ///earliest deadline some [`Sleep`] waits for
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.timers()
+            .first_key_value()
+            .map(|((deadline, _), _)| *deadline)
+    }
+
+    ///move to `to`, waking every timer due until then in deadline order. never goes back
+    pub fn advance_to(&self, to: Duration) {
+        if to > self.now() {
+            self.inner
+                .nanos
+                .store(to.as_nanos() as u64, Ordering::Release);
+        }
+        let now = self.now();
+        let mut timers = self.timers();
+        while let Some(entry) = timers.first_entry()
+            && entry.key().0 <= now
+        {
+            entry.remove().wake();
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.advance_to(self.now() + by)
+    }
+
+    ///resolves once virtual time reached `deadline`
+    pub fn sleep_until(&self, deadline: Duration) -> Sleep {
+        Sleep {
+            clock: self.clone(),
+            deadline,
+            key: None,
+        }
+    }
+
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    ///[`WakeMethod::Timer`] ticking every `period` of virtual time, run the future beside the system
+    pub fn timer_wake(&self, period: Duration) -> (WakeMethod, impl Future<Output = ()> + 'static) {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let clock = self.clone();
+        let ticks = semaphore.clone();
+        let ticker = async move {
+            loop {
+                clock.sleep(period).await;
+                ticks.add_permits(1);
+            }
+        };
+        (WakeMethod::Timer(semaphore), ticker)
+    }
+
+    fn timers(&self) -> async_lock::MutexGuard<'_, BTreeMap<(Duration, u64), Waker>> {
+        //single threaded by design, a held lock here is a bug in the test
+        self.inner
+            .timers
+            .try_lock()
+            .expect("virtual clock used from several threads")
+    }
+}
+
+///future of [`VirtualClock::sleep_until`]
+pub struct Sleep {
+    clock: VirtualClock,
+    deadline: Duration,
+    key: Option<(Duration, u64)>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if this.clock.now() >= this.deadline {
+            if let Some(key) = this.key.take() {
+                this.clock.timers().remove(&key);
+            }
+            return Poll::Ready(());
+        }
+        let (clock, deadline) = (&this.clock, this.deadline);
+        let key = *this.key.get_or_insert_with(|| {
+            (
+                deadline,
+                clock.inner.next_timer.fetch_add(1, Ordering::Relaxed),
+            )
+        });
+        this.clock.timers().insert(key, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.clock.timers().remove(&key);
+        }
+    }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+///wake order stamp of one task, 0 while not woken
+struct TaskWaker {
+    stamp: AtomicU64,
+    sequence: Arc<AtomicU64>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let stamp = self.sequence.fetch_add(1, Ordering::Relaxed);
+        //first wake decides position, later ones before the poll change nothing
+        let _ = self
+            .stamp
+            .compare_exchange(0, stamp, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+///rounds in a row with some task woken, after that tasks count as busy polling
+const BUSY_ROUNDS: usize = 64;
+
+///polls spawned tasks in wake order, moving [`VirtualClock`] only when all of them wait
+pub struct DeterministicExecutor {
+    clock: VirtualClock,
+    tasks: Vec<Option<(Task, Arc<TaskWaker>)>>,
+    ///stamps start at 1, 0 means not woken
+    sequence: Arc<AtomicU64>,
+    ///time moved per step while tasks are busy polling
+    tick: Duration,
+}
+
+impl DeterministicExecutor {
+    pub fn new(clock: VirtualClock) -> Self {
+        Self {
+            clock,
+            tasks: Vec::new(),
+            sequence: Arc::new(AtomicU64::new(1)),
+            tick: Duration::from_millis(1),
+        }
+    }
+
+    ///time moved per step while some task keeps waking itself, 1ms by default
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    ///task is first polled on next run, after tasks spawned before it
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        let waker = Arc::new(TaskWaker {
+            stamp: AtomicU64::new(0),
+            sequence: self.sequence.clone(),
+        });
+        waker.wake_by_ref();
+        self.tasks.push(Some((Box::pin(future), waker)));
+    }
+
+    ///tasks not finished yet
+    pub fn pending(&self) -> usize {
+        self.tasks.iter().flatten().count()
+    }
+
+    ///poll every task woken before this round once, in wake order. returns polls made
+    pub fn run_round(&mut self) -> usize {
+        let round_start = self.sequence.load(Ordering::Acquire);
+        let mut polls = 0;
+        while let Some(idx) = self.next_woken(round_start) {
+            let Some((task, waker)) = &mut self.tasks[idx] else {
+                continue;
+            };
+            waker.stamp.store(0, Ordering::Release);
+            let task_waker = Waker::from(waker.clone());
+            polls += 1;
+            if task
+                .as_mut()
+                .poll(&mut Context::from_waker(&task_waker))
+                .is_ready()
+            {
+                self.tasks[idx] = None;
+            }
+        }
+        polls
+    }
+
+    ///run rounds until nothing is woken, time stays where it is.
+    ///false if tasks were still busy polling after [`BUSY_ROUNDS`]
+    pub fn run_until_stalled(&mut self) -> bool {
+        (0..BUSY_ROUNDS).any(|_| self.run_round() == 0)
+    }
+
+    ///run for `duration` of virtual time, jumping from one timer deadline to the next
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.clock.now() + duration;
+        while self.step(end) {}
+        //everything waits with no timer, nothing to run until then
+        self.clock.advance_to(end);
+    }
+
+    ///run until `future` resolves, None if it did not within `limit` of virtual time
+    pub fn block_on<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+        limit: Duration,
+    ) -> Option<T> {
+        let output = Arc::new(Mutex::new(None));
+        let slot = output.clone();
+        self.spawn(async move {
+            let value = future.await;
+            *slot.lock().await = Some(value);
+        });
+        let end = self.clock.now() + limit;
+        loop {
+            let more = self.step(end);
+            if let Some(value) = output.try_lock().and_then(|mut value| value.take()) {
+                return Some(value);
+            }
+            if !more {
+                return None;
+            }
+        }
+    }
+
+    ///run, then move time to next deadline, or by a tick if tasks are busy polling.
+    ///false once `end` is reached, or everything waits with no timer left
+    fn step(&mut self, end: Duration) -> bool {
+        let stalled = self.run_until_stalled();
+        let now = self.clock.now();
+        if now >= end {
+            return false;
+        }
+        let next = match (stalled, self.clock.next_deadline()) {
+            (true, Some(deadline)) => deadline,
+            (true, None) => return false,
+            (false, Some(deadline)) => deadline.min(now + self.tick),
+            (false, None) => now + self.tick,
+        };
+        self.clock.advance_to(next.min(end));
+        true
+    }
+
+    ///earliest woken task, among those woken before `before`
+    fn next_woken(&self, before: u64) -> Option<usize> {
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, task)| {
+                let stamp = task.as_ref()?.1.stamp.load(Ordering::Acquire);
+                (stamp != 0 && stamp < before).then_some((stamp, idx))
+            })
+            .min()
+            .map(|(_, idx)| idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{rc::Rc, vec, vec::Vec};
+    use core::{cell::RefCell, time::Duration};
+
+    use super::{DeterministicExecutor, VirtualClock};
+
+    const MS: Duration = Duration::from_millis(1);
+
+    ///three tasks sleeping in turns, each logs (task, virtual time) after every sleep
+    fn replay() -> (Vec<(u8, Duration)>, Duration) {
+        let clock = VirtualClock::new();
+        let mut executor = DeterministicExecutor::new(clock.clone());
+        let log = Rc::new(RefCell::new(Vec::new()));
+        for (task, period) in [(0, 3 * MS), (1, 2 * MS), (2, 3 * MS)] {
+            let (clock, log) = (clock.clone(), log.clone());
+            executor.spawn(async move {
+                for _ in 0..3 {
+                    clock.sleep(period).await;
+                    log.borrow_mut().push((task, clock.now()));
+                }
+            });
+        }
+        executor.run_for(20 * MS);
+        assert_eq!(executor.pending(), 0);
+        let log = log.borrow().clone();
+        (log, clock.now())
+    }
+
+    #[test]
+    fn replays_fixed_schedule() {
+        //equal deadlines fire in the order their timers were registered
+        let expected = vec![
+            (1, 2 * MS),
+            (0, 3 * MS),
+            (2, 3 * MS),
+            (1, 4 * MS),
+            (0, 6 * MS),
+            (2, 6 * MS),
+            (1, 6 * MS),
+            (0, 9 * MS),
+            (2, 9 * MS),
+        ];
+        assert_eq!(replay(), (expected.clone(), 20 * MS));
+        assert_eq!(replay(), (expected, 20 * MS));
+    }
+
+    #[test]
+    fn busy_polling_moves_time_by_tick() {
+        let clock = VirtualClock::new();
+        let mut executor = DeterministicExecutor::new(clock.clone()).with_tick(MS);
+        let spins = Rc::new(RefCell::new(0));
+        let counted = spins.clone();
+        let deadline = clock.clone();
+        let woken = executor.block_on(
+            async move {
+                while deadline.now() < 5 * MS {
+                    *counted.borrow_mut() += 1;
+                    yield_once().await;
+                }
+                deadline.now()
+            },
+            10 * MS,
+        );
+        assert_eq!(woken, Some(5 * MS));
+        assert!(*spins.borrow() > 0);
+    }
+
+    ///pending once, waking itself, like controller loops do
+    async fn yield_once() {
+        let mut yielded = false;
+        core::future::poll_fn(|cx| {
+            if yielded {
+                core::task::Poll::Ready(())
+            } else {
+                yielded = true;
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            }
+        })
+        .await
+    }
+}
@@ -1,11 +1,43 @@
 use core::{
-    alloc::{Allocator, Layout},
+    alloc::{AllocError, Allocator, Layout},
     marker::PhantomData,
     ops::{Deref, DerefMut},
     ptr::{slice_from_raw_parts, slice_from_raw_parts_mut, NonNull},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use super::PlatformAbstractions;
+use alloc::sync::Arc;
+
+#[cfg(feature = "usb-layer")]
+use crate::usb::operations::Direction;
+
+use super::{
+    dma_tracker::{self, DmaKind},
+    PlatformAbstractions,
+};
+
+///hand CPU writes over a physical range to controller, noop on coherent platforms
+pub fn sync_for_device<O: PlatformAbstractions>(os: &O, phys: usize, len: usize) {
+    if !os.dma_coherent() && len > 0 {
+        os.dma_clean(O::VirtAddr::from(O::PhysAddr::from(phys)), len);
+    }
+}
+
+///make controller writes over a physical range visible to CPU, noop on coherent platforms
+pub fn sync_for_cpu<O: PlatformAbstractions>(os: &O, phys: usize, len: usize) {
+    if !os.dma_coherent() && len > 0 {
+        os.dma_invalidate(O::VirtAddr::from(O::PhysAddr::from(phys)), len);
+    }
+}
+
+///CPU side of a transfer buffer controller is done with, given by physical address
+///
+///# Safety
+///range must be mapped and not written by anyone while the slice lives
+pub unsafe fn cpu_view<'s, O: PlatformAbstractions>(phys: usize, len: usize) -> &'s [u8] {
+    let virt: usize = O::VirtAddr::from(O::PhysAddr::from(phys)).into();
+    &*slice_from_raw_parts(virt as *const u8, len)
+}
 
 pub struct DMA<T, O>
 where
@@ -44,22 +76,28 @@ where
     ///
     /// * `align` 必须是2的幂次方。
     pub fn new(value: T, align: usize, allocator: O::DMA) -> Self {
+        Self::try_new(value, align, allocator).unwrap()
+    }
+
+    ///like [`Self::new`], but hands allocator failure back
+    pub fn try_new(value: T, align: usize, allocator: O::DMA) -> Result<Self, AllocError> {
         //计算所需内存大小
         let buff_size = size_of::<T>();
         // 根据元素数量和对其要求创建内存布局
         let layout = Layout::from_size_align(buff_size, align).unwrap();
         // 使用分配器分配内存
-        let data = allocator.allocate(layout).unwrap();
+        let data = allocator.allocate(layout)?;
         let ptr = data.cast();
         unsafe {
             ptr.write(value);
         };
-        Self {
+        dma_tracker::track(data.addr().get(), buff_size);
+        Ok(Self {
             layout,
             data,
             allocator,
             __marker: PhantomData,
-        }
+        })
     }
 
     pub fn fill_zero(mut self) -> Self {
@@ -82,9 +120,29 @@ where
         self.layout.size()
     }
 
+    ///whole allocation, see [`sync_for_device`]
+    pub fn sync_for_device(&self, os: &O) {
+        if !os.dma_coherent() {
+            os.dma_clean(self.addr(), self.length_for_bytes());
+        }
+    }
+
+    ///whole allocation, see [`sync_for_cpu`]
+    pub fn sync_for_cpu(&self, os: &O) {
+        if !os.dma_coherent() {
+            os.dma_invalidate(self.addr(), self.length_for_bytes());
+        }
+    }
+
     pub fn phys_addr_len_tuple(&self) -> AddrLenTuple<O> {
         AddrLenTuple(O::PhysAddr::from(self.addr()), self.length_for_bytes())
     }
+
+    ///label allocation for leak reports, `owner` is slot id of device it belongs to
+    pub fn tagged(self, kind: DmaKind, owner: Option<u8>) -> Self {
+        dma_tracker::tag(self.addr().into(), kind, owner);
+        self
+    }
 }
 
 pub struct AddrLenTuple<O: PlatformAbstractions>(O::PhysAddr, usize);
@@ -104,36 +162,52 @@ where
     O: PlatformAbstractions,
 {
     pub fn zeroed(count: usize, align: usize, allocator: O::DMA) -> Self {
+        Self::try_zeroed(count, align, allocator).unwrap()
+    }
+
+    ///like [`Self::zeroed`], but hands allocator failure back, e.g. for alignments above page size
+    pub fn try_zeroed(count: usize, align: usize, allocator: O::DMA) -> Result<Self, AllocError> {
         let t_size = size_of::<T>();
         let size = count * t_size;
 
         // 根据元素数量和对其要求创建内存布局
         let layout = Layout::from_size_align(size, align).unwrap();
         // 使用分配器分配内存
-        let mut data = allocator.allocate(layout).unwrap();
+        let mut data = allocator.allocate(layout)?;
 
         unsafe {
             for one in data.as_mut() {
                 *one = 0;
             }
         }
+        dma_tracker::track(data.addr().get(), size);
 
-        Self {
+        Ok(Self {
             layout,
             data,
             allocator,
             __marker: PhantomData,
-        }
+        })
     }
 
     pub fn new_vec(init: T, count: usize, align: usize, allocator: O::DMA) -> Self {
+        Self::try_new_vec(init, count, align, allocator).unwrap()
+    }
+
+    ///like [`Self::new_vec`], but hands allocator failure back
+    pub fn try_new_vec(
+        init: T,
+        count: usize,
+        align: usize,
+        allocator: O::DMA,
+    ) -> Result<Self, AllocError> {
         let t_size = size_of::<T>();
         let size = count * t_size;
 
         // 根据元素数量和对其要求创建内存布局
         let layout = Layout::from_size_align(size, align).unwrap();
         // 使用分配器分配内存
-        let mut data = allocator.allocate(layout).unwrap();
+        let mut data = allocator.allocate(layout)?;
         // debug!("allocated data:{:?}", data);
 
         unsafe {
@@ -144,13 +218,14 @@ where
                 data.copy_from_slice(t_data);
             }
         }
+        dma_tracker::track(data.addr().get(), size);
 
-        Self {
+        Ok(Self {
             layout,
             data,
             allocator,
             __marker: PhantomData,
-        }
+        })
     }
 }
 
@@ -213,9 +288,274 @@ where
     O: PlatformAbstractions,
 {
     fn drop(&mut self) {
+        dma_tracker::untrack(self.data.addr().get());
         unsafe {
             let ptr = self.data.cast::<u8>();
             self.allocator.deallocate(ptr, self.layout);
         }
     }
 }
+
+///bounce buffers start on a cache line of common platforms, so invalidating them spares neighbours
+#[cfg(feature = "usb-layer")]
+const BOUNCE_ALIGN: usize = 64;
+
+///caller memory lent to controller for one transfer, see [`PlatformAbstractions::dma_capable`]
+#[cfg(feature = "usb-layer")]
+pub enum CallerBuffer<'b, O>
+where
+    O: PlatformAbstractions,
+{
+    ///controller works on caller memory directly
+    Direct(&'b mut [u8]),
+    ///controller works on a copy, which goes back to caller on [`Self::finish`] for IN
+    Bounce {
+        caller: &'b mut [u8],
+        bounce: DMA<[u8], O>,
+    },
+}
+
+#[cfg(feature = "usb-layer")]
+impl<'b, O> CallerBuffer<'b, O>
+where
+    O: PlatformAbstractions,
+{
+    ///OUT data is copied into the bounce buffer right away, if there is one.
+    ///
+    ///a directly lent `buffer` must stay with controller until the transfer completes, even if
+    ///whoever waits on it gives up, so only map memory nothing else gets back before that
+    pub fn map(os: &O, buffer: &'b mut [u8], direction: Direction) -> Result<Self, AllocError> {
+        if os.dma_capable(O::VirtAddr::from(buffer.as_ptr() as usize), buffer.len()) {
+            return Ok(Self::Direct(buffer));
+        }
+        Self::staged(os, buffer, direction)
+    }
+
+    ///always through a bounce buffer, which can outlive the borrow of `buffer` if the transfer
+    ///gets abandoned
+    pub fn staged(os: &O, buffer: &'b mut [u8], direction: Direction) -> Result<Self, AllocError> {
+        if buffer.is_empty() {
+            return Ok(Self::Direct(buffer));
+        }
+        let mut bounce = DMA::try_zeroed(buffer.len(), BOUNCE_ALIGN, os.dma_alloc())?
+            .tagged(DmaKind::TransferBuffer, None);
+        if direction == Direction::Out {
+            bounce.copy_from_slice(buffer);
+        }
+        Ok(Self::Bounce {
+            caller: buffer,
+            bounce,
+        })
+    }
+
+    pub fn is_direct(&self) -> bool {
+        matches!(self, Self::Direct(_))
+    }
+
+    ///physical address and length to put into the transfer
+    pub fn phys_addr_len(&self) -> (usize, usize) {
+        match self {
+            Self::Direct(buffer) => (
+                O::PhysAddr::from(O::VirtAddr::from(buffer.as_ptr() as usize)).into(),
+                buffer.len(),
+            ),
+            Self::Bounce { bounce, .. } => bounce.phys_addr_len_tuple().into(),
+        }
+    }
+
+    ///transfer completed with `length` bytes, caller gets its memory back
+    pub fn finish(self, direction: Direction, length: usize) {
+        if let Self::Bounce { caller, bounce } = self
+            && direction == Direction::In
+        {
+            let length = length.min(caller.len());
+            caller[..length].copy_from_slice(&bounce[..length]);
+        }
+    }
+}
+
+///slot size of [`SmallBufferPool`], enough for setup-sized payloads like device/BOS headers
+pub const SMALL_BUFFER_SLOT: usize = 64;
+
+///one pinned DMA page split into fixed slots, so tiny control transfers don't take a page each
+pub struct SmallBufferPool<O>
+where
+    O: PlatformAbstractions,
+{
+    page: DMA<[u8], O>,
+    slots: usize,
+    used: AtomicU64,
+    allocator: O::DMA,
+}
+
+impl<O> SmallBufferPool<O>
+where
+    O: PlatformAbstractions,
+{
+    pub fn new(allocator: O::DMA) -> Arc<Self> {
+        let slots = (O::PAGE_SIZE / SMALL_BUFFER_SLOT).min(u64::BITS as usize);
+        Arc::new(Self {
+            page: DMA::zeroed(slots * SMALL_BUFFER_SLOT, O::PAGE_SIZE, allocator.clone())
+                .tagged(DmaKind::TransferBuffer, None),
+            slots,
+            used: AtomicU64::new(0),
+            allocator,
+        })
+    }
+
+    ///None if `len` doesn't fit a slot or pool is exhausted
+    pub fn alloc(self: &Arc<Self>, len: usize) -> Option<SmallBuffer<O>> {
+        if len > SMALL_BUFFER_SLOT {
+            return None;
+        }
+
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let slot = (!used).trailing_zeros() as usize;
+            if slot >= self.slots {
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used | (1 << slot),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let mut buffer = SmallBuffer {
+                        pool: self.clone(),
+                        slot,
+                        len,
+                    };
+                    buffer.fill(0);
+                    return Some(buffer);
+                }
+                Err(now) => used = now,
+            }
+        }
+    }
+
+    ///small slot if possible, otherwise a dedicated DMA buffer
+    pub fn alloc_or_dma(self: &Arc<Self>, len: usize, align: usize) -> TransferBuffer<O> {
+        self.try_alloc_or_dma(len, align).unwrap()
+    }
+
+    ///like [`Self::alloc_or_dma`], but hands allocator failure back
+    pub fn try_alloc_or_dma(
+        self: &Arc<Self>,
+        len: usize,
+        align: usize,
+    ) -> Result<TransferBuffer<O>, AllocError> {
+        Ok(match self.alloc(len) {
+            Some(small) => TransferBuffer::Small(small),
+            None => TransferBuffer::Dedicated(
+                DMA::try_zeroed(len, align, self.allocator.clone())?
+                    .tagged(DmaKind::TransferBuffer, None),
+            ),
+        })
+    }
+}
+
+pub struct SmallBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    pool: Arc<SmallBufferPool<O>>,
+    slot: usize,
+    len: usize,
+}
+
+impl<O> SmallBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    fn ptr(&self) -> *mut u8 {
+        let base: usize = self.pool.page.addr().into();
+        (base + self.slot * SMALL_BUFFER_SLOT) as *mut u8
+    }
+
+    pub fn phys_addr_len_tuple(&self) -> AddrLenTuple<O> {
+        AddrLenTuple(
+            O::PhysAddr::from(O::VirtAddr::from(self.ptr() as usize)),
+            self.len,
+        )
+    }
+}
+
+impl<O> Deref for SmallBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        //safety: slot is exclusively owned until drop
+        unsafe { &*slice_from_raw_parts(self.ptr(), self.len) }
+    }
+}
+
+impl<O> DerefMut for SmallBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *slice_from_raw_parts_mut(self.ptr(), self.len) }
+    }
+}
+
+impl<O> Drop for SmallBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    fn drop(&mut self) {
+        self.pool
+            .used
+            .fetch_and(!(1 << self.slot), Ordering::Release);
+    }
+}
+
+pub enum TransferBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    Small(SmallBuffer<O>),
+    Dedicated(DMA<[u8], O>),
+}
+
+impl<O> TransferBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    pub fn phys_addr_len_tuple(&self) -> AddrLenTuple<O> {
+        match self {
+            TransferBuffer::Small(small) => small.phys_addr_len_tuple(),
+            TransferBuffer::Dedicated(dma) => dma.phys_addr_len_tuple(),
+        }
+    }
+}
+
+impl<O> Deref for TransferBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            TransferBuffer::Small(small) => small,
+            TransferBuffer::Dedicated(dma) => dma,
+        }
+    }
+}
+
+impl<O> DerefMut for TransferBuffer<O>
+where
+    O: PlatformAbstractions,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            TransferBuffer::Small(small) => small,
+            TransferBuffer::Dedicated(dma) => dma,
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! bookkeeping of every live [`super::dma::DMA`] object in debug builds, for leak reports.
+//!
+//! release builds keep the same api, but record nothing and always report clean.
+
+use alloc::vec::Vec;
+use log::warn;
+
+///what a DMA object is used for, only shown in leak reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaKind {
+    Untagged,
+    DeviceContext,
+    InputContext,
+    TransferRing,
+    CommandRing,
+    EventRing,
+    StreamArray,
+    Scratchpad,
+    ///queue heads, qTDs and frame list of ehci
+    Schedule,
+    TransferBuffer,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRecord {
+    pub addr: usize,
+    pub size: usize,
+    pub kind: DmaKind,
+    ///slot id (or device address on ehci) of device it belongs to
+    pub owner: Option<u8>,
+}
+
+#[cfg(debug_assertions)]
+mod imp {
+    use alloc::collections::btree_map::BTreeMap;
+
+    use super::DmaRecord;
+    use crate::abstractions::spin::SpinCell;
+
+    ///called from Drop, so no async lock here
+    pub static LIVE: SpinCell<BTreeMap<usize, DmaRecord>> = SpinCell::new(BTreeMap::new());
+}
+
+pub fn track(addr: usize, size: usize) {
+    #[cfg(debug_assertions)]
+    imp::LIVE.with(|records| {
+        records.insert(
+            addr,
+            DmaRecord {
+                addr,
+                size,
+                kind: DmaKind::Untagged,
+                owner: None,
+            },
+        )
+    });
+    #[cfg(not(debug_assertions))]
+    let _ = (addr, size);
+}
+
+pub fn untrack(addr: usize) {
+    #[cfg(debug_assertions)]
+    imp::LIVE.with(|records| records.remove(&addr));
+    #[cfg(not(debug_assertions))]
+    let _ = addr;
+}
+
+///`addr` is virtual address of the object, as returned by `DMA::addr`
+pub fn tag(addr: usize, kind: DmaKind, owner: Option<u8>) {
+    #[cfg(debug_assertions)]
+    imp::LIVE.with(|records| {
+        if let Some(record) = records.get_mut(&addr) {
+            record.kind = kind;
+            record.owner = owner;
+        }
+    });
+    #[cfg(not(debug_assertions))]
+    let _ = (addr, kind, owner);
+}
+
+pub fn live() -> Vec<DmaRecord> {
+    #[cfg(debug_assertions)]
+    return imp::LIVE.with(|records| records.values().copied().collect());
+    #[cfg(not(debug_assertions))]
+    Vec::new()
+}
+
+///objects still owned by a device that was just removed, returns how many leaked
+pub fn report_owner(owner: u8) -> usize {
+    let leaked: Vec<_> = live()
+        .into_iter()
+        .filter(|record| record.owner == Some(owner))
+        .collect();
+    for record in &leaked {
+        warn!(
+            "[DMA] leaked after removal of device {}: {:x?}",
+            owner, record
+        );
+    }
+    leaked.len()
+}
+
+///everything still alive, meant to be called after the whole system got dropped
+pub fn report() -> usize {
+    let leaked = live();
+    for record in &leaked {
+        warn!("[DMA] leaked at shutdown: {:x?}", record);
+    }
+    leaked.len()
+}
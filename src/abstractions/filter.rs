@@ -0,0 +1,99 @@
+use alloc::vec::Vec;
+use async_lock::RwLock;
+
+/// identity of a device, known right after the first device descriptor read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub class: u8,
+}
+
+impl DeviceIdentity {
+    pub fn from_device_desc(desc: &[u8]) -> Self {
+        Self {
+            vendor_id: u16::from_le_bytes([desc[8], desc[9]]),
+            product_id: u16::from_le_bytes([desc[10], desc[11]]),
+            class: desc[4],
+        }
+    }
+}
+
+/// a rule matches if every field that is set matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub class: Option<u8>,
+}
+
+impl DeviceMatch {
+    pub const fn vid_pid(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            class: None,
+        }
+    }
+
+    pub const fn vendor(vendor_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            product_id: None,
+            class: None,
+        }
+    }
+
+    pub const fn class(class: u8) -> Self {
+        Self {
+            vendor_id: None,
+            product_id: None,
+            class: Some(class),
+        }
+    }
+
+    pub fn matches(&self, identity: &DeviceIdentity) -> bool {
+        self.vendor_id.map_or(true, |v| v == identity.vendor_id)
+            && self.product_id.map_or(true, |p| p == identity.product_id)
+            && self.class.map_or(true, |c| c == identity.class)
+    }
+}
+
+/// runtime allow/deny list, checked before a device is enumerated any further.
+///
+/// deny rules always win, an empty allow list allows everything.
+#[derive(Default)]
+pub struct DeviceFilter {
+    allow: RwLock<Vec<DeviceMatch>>,
+    deny: RwLock<Vec<DeviceMatch>>,
+}
+
+impl DeviceFilter {
+    pub async fn allow(&self, rule: DeviceMatch) {
+        self.allow.write().await.push(rule);
+    }
+
+    pub async fn deny(&self, rule: DeviceMatch) {
+        self.deny.write().await.push(rule);
+    }
+
+    pub async fn clear(&self) {
+        self.allow.write().await.clear();
+        self.deny.write().await.clear();
+    }
+
+    pub async fn is_allowed(&self, identity: &DeviceIdentity) -> bool {
+        if self
+            .deny
+            .read()
+            .await
+            .iter()
+            .any(|rule| rule.matches(identity))
+        {
+            return false;
+        }
+
+        let allow = self.allow.read().await;
+        allow.is_empty() || allow.iter().any(|rule| rule.matches(identity))
+    }
+}
@@ -1,9 +1,32 @@
-use core::{alloc::Allocator, task::Waker};
+#[cfg(feature = "usb-layer")]
+use core::fmt::Arguments;
+use core::{alloc::Allocator, task::Waker, time::Duration};
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use async_lock::Semaphore;
+use bounded::BoundedMemory;
+use filter::DeviceFilter;
+use quirks::QuirkTable;
 
+#[cfg(feature = "usb-layer")]
+use log::{error, warn};
+
+#[cfg(feature = "usb-layer")]
+use crate::usb::operations::UsbError;
+
+#[cfg(feature = "drivers")]
+use crate::driver::device_node::DeviceNodeHook;
+
+pub mod bounded;
+#[cfg(feature = "deterministic")]
+pub mod deterministic;
 pub mod dma;
+pub mod dma_tracker;
+pub mod filter;
+pub mod quirks;
+pub mod spin;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub trait PlatformAbstractions: Clone + Send + Sync + Sized {
     type VirtAddr: From<Self::PhysAddr> + From<usize> + Into<usize> + Clone + Send + Sync;
@@ -13,28 +36,297 @@ pub trait PlatformAbstractions: Clone + Send + Sync + Sized {
     const RING_BUFFER_SIZE: usize;
     const WORD: SystemWordWide;
     fn dma_alloc(&self) -> Self::DMA;
+    ///monotonic time since some fixed point, platforms without a clock source could leave it None.
+    ///
+    ///waits and [`USBSystemConfig::port_debounce`] then fall back to poll counts
+    fn now(&self) -> Option<Duration> {
+        None
+    }
+    ///called once controllers reserved [`USBSystemConfig::bounded_memory`],
+    ///platforms using [`bounded::BoundedDma`] seal it here
+    fn seal_dma(&self) {}
+    ///false where host controller doesn't snoop CPU caches, as on many ARM SoCs.
+    ///xhci then calls [`Self::dma_clean`] and [`Self::dma_invalidate`] around every DMA access,
+    ///ehci refuses to init
+    fn dma_coherent(&self) -> bool {
+        true
+    }
+    ///write dirty cache lines over the range back to memory, before controller reads it
+    fn dma_clean(&self, _addr: Self::VirtAddr, _len: usize) {}
+    ///discard cache lines over the range, before CPU reads what controller wrote
+    fn dma_invalidate(&self, _addr: Self::VirtAddr, _len: usize) {}
+    ///true if controller could work on caller memory over the range as is: physically contiguous,
+    ///reachable by its DMA and, on non-coherent platforms, cache line aligned at both ends.
+    ///otherwise [`dma::CallerBuffer`] bounces it through memory of [`Self::dma_alloc`]
+    fn dma_capable(&self, _addr: Self::VirtAddr, _len: usize) -> bool {
+        false
+    }
 }
 
 pub type InterruptRegister = dyn Fn(&dyn Fn()) + Send + Sync;
+///attach handler as ISR of given MSI/MSI-X vector
+pub type VectorRegister = dyn Fn(u16, &dyn Fn()) + Send + Sync;
+
+///sees every xhci event TRB before it's interpreted: raw bytes, cycle bit, and [`PlatformAbstractions::now`].
+///runs on event handling path, keep it short
+#[cfg(feature = "observe_raw_event_trb")]
+pub type EventTrbObserver = dyn Fn(&[u8; 16], bool, Option<Duration>) + Send + Sync;
 
 #[derive(Clone)]
 pub struct USBSystemConfig<O, const RING_BUFFER_SIZE: usize>
 where
     O: PlatformAbstractions,
 {
+    ///controller driven by [`ControllerKind::compiled_default`]
     pub base_addr: O::VirtAddr,
     pub wake_method: WakeMethod,
+    ///controllers besides the one at `base_addr`, devices on them are told apart by [`crate::usb::standards::TopologyRoute::controller`]
+    pub extra_controllers: Vec<ControllerDesc<O>>,
     pub os: O,
+    pub lpm_policy: LpmPolicy,
+    pub power_policy: PowerPolicy,
+    pub spec_policy: SpecPolicy,
+    pub device_filter: Arc<DeviceFilter>,
+    ///looked up by device identity whenever endpoints get set up
+    pub quirks: Arc<QuirkTable>,
+    ///connect status must stay stable this long before enumeration or teardown, spec says 100ms.
+    ///
+    ///measured with [`PlatformAbstractions::now`], without a clock source the window is counted
+    ///in debounce polls instead, one per millisecond, so it only roughly holds
+    pub port_debounce: Duration,
+    pub timeouts: TimeoutPolicy,
+    ///off when None
+    pub watchdog: Option<WatchdogPolicy>,
+    ///segments a full transfer ring may grow to, each as long as the first one. xhci only.
+    ///None keeps one segment and submitters wait for room, a TD longer than the whole ring
+    ///grows it anyway
+    pub ring_growth: Option<usize>,
+    ///devices on root ports without any bound driver are suspended once they stayed so this long,
+    ///off when None. platforms without clock source never autosuspend
+    pub autosuspend: Option<Duration>,
+    ///reserve all DMA memory at init and none afterwards, see [`bounded`]. xhci only
+    pub bounded_memory: Option<BoundedMemory>,
+    ///notified on every driver bind and unbind, with a stable name derived from topology
+    #[cfg(feature = "drivers")]
+    pub device_node_hook: Option<Arc<dyn DeviceNodeHook>>,
+    ///bring-up aid, ignored by controllers other than xhci
+    #[cfg(feature = "observe_raw_event_trb")]
+    pub event_observer: Option<Arc<EventTrbObserver>>,
+    ///TRBs kept per slot, submitted ones and events alike, off when None. xhci only.
+    ///read them back with [`crate::USBSystem::trb_trace`]
+    #[cfg(feature = "trace_trb_ring")]
+    pub trb_trace: Option<usize>,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> USBSystemConfig<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///every controller, the one at `base_addr` first. position is controller index
+    pub fn controller_descs(&self) -> Vec<ControllerDesc<O>> {
+        let mut descs = Vec::with_capacity(1 + self.extra_controllers.len());
+        descs.push(ControllerDesc {
+            kind: ControllerKind::compiled_default(),
+            base_addr: self.base_addr.clone(),
+            wake_method: self.wake_method.clone(),
+        });
+        descs.extend(self.extra_controllers.iter().cloned());
+        descs
+    }
+}
+
+///backend of a host controller, each one needs its feature enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControllerKind {
+    Xhci,
+    Ehci,
+}
+
+impl ControllerKind {
+    ///xhci if it's compiled in, otherwise ehci
+    pub const fn compiled_default() -> Self {
+        if cfg!(feature = "backend-xhci") {
+            Self::Xhci
+        } else {
+            Self::Ehci
+        }
+    }
+}
+
+///one more host controller on the board
+#[derive(Clone)]
+pub struct ControllerDesc<O>
+where
+    O: PlatformAbstractions,
+{
+    pub kind: ControllerKind,
+    pub base_addr: O::VirtAddr,
+    ///each controller got its own interrupt line
+    pub wake_method: WakeMethod,
+}
+
+///link power management policy, all off by default
+#[derive(Clone, Debug, Default)]
+pub struct LpmPolicy {
+    pub usb2_hardware_lpm: bool,
+    ///in 1us unit, 0 to disable, 0xff to accept but never initiate
+    pub u1_timeout: u8,
+    ///in 256us unit, 0 to disable, 0xff to accept but never initiate
+    pub u2_timeout: u8,
+}
+
+impl LpmPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.usb2_hardware_lpm || self.u1_timeout != 0 || self.u2_timeout != 0
+    }
+}
+
+///how long controller waits on hardware before giving up, None waits forever.
+///
+///measured with [`PlatformAbstractions::now`], platforms without a clock never time out.
+///a late transfer gets its endpoint stopped on xhci, its QH unlinked on ehci, and everything
+///queued on that endpoint resolves as [`crate::usb::operations::UsbError::Timeout`].
+///`command` is xhci only, a late command is aborted
+#[derive(Clone, Debug)]
+pub struct TimeoutPolicy {
+    pub command: Option<Duration>,
+    pub control: Option<Duration>,
+    ///bulk IN could legitimately wait long for data, so it's off by default
+    pub bulk: Option<Duration>,
+}
+
+impl Default for TimeoutPolicy {
+    fn default() -> Self {
+        Self {
+            command: Some(Duration::from_secs(5)),
+            control: Some(Duration::from_secs(5)),
+            bulk: None,
+        }
+    }
+}
+
+///flags TDs submitted long ago without any event, a diagnostic for lost doorbells
+///on buggy controllers or non-coherent DMA. xhci only.
+///
+///unlike [`TimeoutPolicy`] it covers every non-periodic TD, and failing is opt-in
+#[derive(Clone, Debug)]
+pub struct WatchdogPolicy {
+    ///silence after submission before a TD counts as lost
+    pub after: Duration,
+    pub action: LostTdAction,
+}
+
+///what the watchdog does with a lost TD, besides dumping ring and context state
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LostTdAction {
+    ///keep waiting on it
+    #[default]
+    Report,
+    ///ring doorbell of its endpoint again, and report only if that doesn't help either
+    RingAgain,
+    ///stop endpoint, TD resolves as [`crate::usb::operations::UsbError::Timeout`]
+    Fail,
+}
+
+///bus power budgeting, over-budget configurations are only warned by default
+#[derive(Clone, Debug, Default)]
+pub struct PowerPolicy {
+    ///refuse to bind drivers on devices that exceed the budget
+    pub enforce: bool,
+    ///override of per root port budget in mA, default is decided by port speed
+    pub root_port_budget_ma: Option<u16>,
+}
+
+///what to do about spec violations found in descriptors, while enumerating or setting up endpoints
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpecPolicy {
+    ///refuse the device or endpoint, catches issues loudly in development builds
+    Strict,
+    ///warn, clamp into spec and carry on. real devices violate it constantly
+    #[default]
+    Permissive,
+}
+
+#[cfg(feature = "usb-layer")]
+impl SpecPolicy {
+    ///report `violation`, Ok if caller should carry on with a value clamped into spec
+    pub fn check(&self, violation: Arguments) -> Result<(), UsbError> {
+        match self {
+            SpecPolicy::Strict => {
+                error!("spec violation, refused: {}", violation);
+                Err(UsbError::SpecViolation)
+            }
+            SpecPolicy::Permissive => {
+                warn!("spec violation, tolerated: {}", violation);
+                Ok(())
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub enum WakeMethod {
     Interrupt(Arc<InterruptRegister>),
+    ///one vector per interrupter, controllers with a single interrupter only use vector 0
+    Vectored(VectoredInterrupts),
     ///remember: increase permit on event. consumer side would drop every permit but not return it!
     Timer(Arc<Semaphore>),
     Yield,
 }
 
+impl WakeMethod {
+    ///events are signalled by interrupts, nothing has to poll for them
+    pub fn is_interrupt(&self) -> bool {
+        matches!(self, WakeMethod::Interrupt(_) | WakeMethod::Vectored(_))
+    }
+
+    ///attach `handler` to the only, or the first, interrupt line. false if not interrupt driven
+    pub fn register_primary(&self, handler: &dyn Fn()) -> bool {
+        match self {
+            WakeMethod::Interrupt(register) => register(handler),
+            WakeMethod::Vectored(vectored) => (vectored.register)(0, handler),
+            WakeMethod::Timer(_) | WakeMethod::Yield => return false,
+        }
+        true
+    }
+}
+
+///MSI/MSI-X with several vectors, xhci gives each its own interrupter and event ring
+#[derive(Clone)]
+pub struct VectoredInterrupts {
+    pub register: Arc<VectorRegister>,
+    ///vectors platform could provide, controller uses at most as many as it got interrupters
+    pub vectors: u16,
+    pub routing: InterrupterRouting,
+}
+
+///which interrupter gets transfer events, command and port events always go to the first one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterrupterRouting {
+    #[default]
+    Primary,
+    ///every slot sticks to one of the secondary interrupters
+    PerSlot,
+    ///every endpoint sticks to one of the secondary interrupters, spreads a busy device further
+    PerEndpoint,
+}
+
+impl InterrupterRouting {
+    ///interrupter of endpoint `dci` on `slot`, given `interrupters` in use including the primary one
+    pub fn interrupter(&self, interrupters: u16, slot: u8, dci: u8) -> u16 {
+        let secondary = interrupters.saturating_sub(1) as usize;
+        if secondary == 0 {
+            return 0;
+        }
+        let key = match self {
+            InterrupterRouting::Primary => return 0,
+            InterrupterRouting::PerSlot => slot as usize,
+            InterrupterRouting::PerEndpoint => slot as usize * 32 + dci as usize,
+        };
+        1 + (key % secondary) as u16
+    }
+}
+
 #[derive(Clone)]
 pub enum SystemWordWide {
     X64,
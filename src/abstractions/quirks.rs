@@ -0,0 +1,48 @@
+use alloc::vec::Vec;
+use async_lock::RwLock;
+
+use super::filter::{DeviceIdentity, DeviceMatch};
+
+/// workarounds for devices not doing what their descriptors say
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// full speed endpoints get at most this wMaxPacketSize programmed,
+    /// for devices claiming more than they honor and babbling
+    pub fs_max_packet_clamp: Option<u16>,
+}
+
+impl Quirks {
+    /// fields set in `self` win
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            fs_max_packet_clamp: self.fs_max_packet_clamp.or(other.fs_max_packet_clamp),
+        }
+    }
+}
+
+/// runtime quirk list, looked up whenever endpoints of a device are set up.
+///
+/// earlier entries win where several match the same device
+#[derive(Default)]
+pub struct QuirkTable {
+    entries: RwLock<Vec<(DeviceMatch, Quirks)>>,
+}
+
+impl QuirkTable {
+    pub async fn add(&self, rule: DeviceMatch, quirks: Quirks) {
+        self.entries.write().await.push((rule, quirks));
+    }
+
+    pub async fn clear(&self) {
+        self.entries.write().await.clear();
+    }
+
+    pub async fn lookup(&self, identity: &DeviceIdentity) -> Quirks {
+        self.entries
+            .read()
+            .await
+            .iter()
+            .filter(|(rule, _)| rule.matches(identity))
+            .fold(Quirks::default(), |merged, (_, quirks)| merged.or(*quirks))
+    }
+}
@@ -0,0 +1,57 @@
+//! spin lock for state touched from sync code, on whichever core happens to run it.
+//!
+//! access only goes through a closure, so the value can't be held across an await or handed
+//! out as a reference that outlives the lock.
+
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub struct SpinCell<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+//safety: value is only reached with the lock held
+unsafe impl<T: Send> Sync for SpinCell<T> {}
+
+impl<T> SpinCell<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    ///`f` must not reach this cell again, it would spin forever
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop()
+        }
+        let result = f(unsafe { self.value.get().as_mut_unchecked() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for SpinCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for SpinCell<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
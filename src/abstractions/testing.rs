@@ -0,0 +1,51 @@
+//! platform unit tests run on: heap memory stands in for DMA, addresses are used as they are
+
+use alloc::{alloc::Global, sync::Arc, vec::Vec};
+use core::time::Duration;
+
+use super::{PlatformAbstractions, SystemWordWide, USBSystemConfig, WakeMethod};
+
+pub(crate) const TEST_RING_BUFFER_SIZE: usize = 16;
+
+#[derive(Clone, Default)]
+pub(crate) struct TestPlatform;
+
+impl PlatformAbstractions for TestPlatform {
+    type VirtAddr = usize;
+    type PhysAddr = usize;
+    type DMA = Global;
+    const PAGE_SIZE: usize = 4096;
+    const RING_BUFFER_SIZE: usize = TEST_RING_BUFFER_SIZE;
+    const WORD: SystemWordWide = SystemWordWide::X64;
+
+    fn dma_alloc(&self) -> Self::DMA {
+        Global
+    }
+}
+
+///defaults everywhere, no controller behind `base_addr`
+pub(crate) fn test_config() -> Arc<USBSystemConfig<TestPlatform, TEST_RING_BUFFER_SIZE>> {
+    Arc::new(USBSystemConfig {
+        base_addr: 0,
+        wake_method: WakeMethod::Yield,
+        extra_controllers: Vec::new(),
+        os: TestPlatform,
+        lpm_policy: Default::default(),
+        power_policy: Default::default(),
+        spec_policy: Default::default(),
+        device_filter: Default::default(),
+        quirks: Default::default(),
+        port_debounce: Duration::ZERO,
+        timeouts: Default::default(),
+        watchdog: None,
+        ring_growth: None,
+        autosuspend: None,
+        bounded_memory: None,
+        #[cfg(feature = "drivers")]
+        device_node_hook: None,
+        #[cfg(feature = "observe_raw_event_trb")]
+        event_observer: None,
+        #[cfg(feature = "trace_trb_ring")]
+        trb_trace: None,
+    })
+}
@@ -0,0 +1,204 @@
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+use futures::{Stream, StreamExt};
+use log::{trace, warn};
+use usb_descriptor_decoder::descriptors::desc_endpoint::{Endpoint, EndpointType};
+
+use crate::{
+    abstractions::{dma::DMA, PlatformAbstractions},
+    host::device::USBDevice,
+    usb::operations::{
+        bulk::BulkTransfer, IocPolicy, PendingRequest, RequestResult, RequestedOperation, UsbError,
+    },
+};
+
+///sustained bulk OUT with several transfers in flight, e.g. storage writes or network TX.
+///
+///data is copied into one of `depth` DMA buffers and submitted right away,
+///only when every buffer is in flight the oldest transfer is waited for and its buffer reused.
+///transfers on one endpoint complete in order, so bytes leave in the order they were written.
+pub struct BulkOutPipe<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    endpoint_id: usize,
+    buffers: Vec<DMA<[u8], O>>,
+    free: VecDeque<usize>,
+    in_flight: VecDeque<(usize, PendingRequest)>,
+    ///bytes whose transfer completed successfully
+    completed: usize,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> BulkOutPipe<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///endpoint must be a bulk OUT of an already enabled function.
+    ///`depth` buffers of `buffer_len` bytes are allocated, two is enough for double buffering
+    pub fn new(
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        endpoint: &Endpoint,
+        depth: usize,
+        buffer_len: usize,
+    ) -> Result<Self, UsbError> {
+        let endpoint_id = endpoint.doorbell_value_aka_dci() as usize;
+        if endpoint.endpoint_type() != EndpointType::BulkOut {
+            warn!(
+                "endpoint {} of {:?} is no bulk OUT endpoint",
+                endpoint_id,
+                endpoint.endpoint_type()
+            );
+            return Err(UsbError::BadDescriptor);
+        }
+        if depth == 0 || buffer_len == 0 {
+            warn!(
+                "bulk out pipe on endpoint {} with {}x{} bytes refused",
+                endpoint_id, depth, buffer_len
+            );
+            return Err(UsbError::InvalidArgument);
+        }
+        let buffers = (0..depth)
+            .map(|_| DMA::try_new_vec(0u8, buffer_len, 64, device.config.os.dma_alloc()))
+            .collect::<Result<_, _>>()
+            .map_err(|_| UsbError::OutOfMemory)?;
+        trace!(
+            "bulk out pipe on endpoint {} with {}x{} bytes",
+            endpoint_id,
+            depth,
+            buffer_len
+        );
+
+        Ok(Self {
+            device,
+            endpoint_id,
+            buffers,
+            free: (0..depth).collect(),
+            in_flight: VecDeque::new(),
+            completed: 0,
+        })
+    }
+
+    pub fn endpoint_id(&self) -> usize {
+        self.endpoint_id
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    ///bytes confirmed by controller so far
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    ///endpoint could take another transfer without it waiting on controller,
+    ///see [`crate::usb::operations::flow::EndpointFlow`]
+    pub async fn writable(&self) {
+        self.device.endpoint_flow(self.endpoint_id).writable().await
+    }
+
+    ///queue `data`, returns once all of it is submitted, not once it is transferred.
+    ///
+    ///on error the other transfers in flight are waited for, and pipe could be used again
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), UsbError> {
+        let buffer_len = self.buffers[0].len();
+        for chunk in data.chunks(buffer_len) {
+            let idx = match self.free.pop_front() {
+                Some(idx) => idx,
+                None => self.retire_oldest().await?,
+            };
+            self.writable().await;
+            self.buffers[idx][..chunk.len()].copy_from_slice(chunk);
+            let (addr, _): (usize, usize) = self.buffers[idx].phys_addr_len_tuple().into();
+            let pending = self
+                .device
+                .submit_with_length(RequestedOperation::Bulk(BulkTransfer {
+                    endpoint_id: self.endpoint_id,
+                    buffer_addr_len: (addr, chunk.len()),
+                    scatter: Vec::new(),
+                    ioc_policy: IocPolicy::default(),
+                    stream_id: None,
+                    refill: None,
+                }))
+                .await;
+            self.in_flight.push_back((idx, pending));
+        }
+        Ok(())
+    }
+
+    ///write every chunk of `stream`, then wait for all of it, returns bytes transferred
+    pub async fn write_all<S, B>(&mut self, stream: S) -> Result<usize, UsbError>
+    where
+        S: Stream<Item = B>,
+        B: AsRef<[u8]>,
+    {
+        let start = self.completed;
+        let mut stream = core::pin::pin!(stream);
+        while let Some(chunk) = stream.next().await {
+            self.write(chunk.as_ref()).await?;
+        }
+        self.flush().await?;
+        Ok(self.completed - start)
+    }
+
+    ///wait until nothing is in flight
+    pub async fn flush(&mut self) -> Result<(), UsbError> {
+        while !self.in_flight.is_empty() {
+            let idx = self.retire_oldest().await?;
+            self.free.push_back(idx);
+        }
+        Ok(())
+    }
+
+    ///wait for oldest transfer and hand back its buffer
+    async fn retire_oldest(&mut self) -> Result<usize, UsbError> {
+        let (idx, pending) = self
+            .in_flight
+            .pop_front()
+            .expect("retire on a pipe with nothing in flight");
+        match Self::checked(pending.wait().await) {
+            Ok(length) => {
+                self.completed += length;
+                Ok(idx)
+            }
+            Err(error) => {
+                warn!(
+                    "bulk out on endpoint {} failed: {}",
+                    self.endpoint_id, error
+                );
+                self.free.push_back(idx);
+                //buffers must not be reused while controller may still read them
+                while let Some((idx, pending)) = self.in_flight.pop_front() {
+                    let _ = pending.wait().await;
+                    self.free.push_back(idx);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn checked(result: Result<(RequestResult, usize), u8>) -> Result<usize, UsbError> {
+        match result {
+            Ok((RequestResult::SlotNotEnabledError, _)) => Err(UsbError::DeviceGone),
+            Ok((result, length)) => UsbError::check(Ok(result)).map(|_| length),
+            Err(code) => Err(UsbError::UnknownCode(code)),
+        }
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> Drop for BulkOutPipe<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    fn drop(&mut self) {
+        if !self.in_flight.is_empty() {
+            //controller may still read them, leaking is the safe option
+            warn!(
+                "bulk out pipe on endpoint {} dropped with {} transfers in flight",
+                self.endpoint_id,
+                self.in_flight.len()
+            );
+            core::mem::forget(core::mem::take(&mut self.buffers));
+        }
+    }
+}
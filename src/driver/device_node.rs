@@ -0,0 +1,28 @@
+use alloc::string::String;
+
+use crate::usb::standards::TopologyRoute;
+
+///one bound driver instance, as OS would see it in a devfs like layer
+#[derive(Debug, Clone)]
+pub struct DeviceNode {
+    ///"usb1-2.3" for whole device, "usb1-2.3:1.0" for a function, see [`TopologyRoute::device_name`]
+    pub name: String,
+    ///name of bound driver module
+    pub driver: String,
+    pub route: TopologyRoute,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    ///configuration value device runs in
+    pub config: u8,
+    pub interface: Option<u8>,
+}
+
+///told about every bind and unbind, so OS could create and remove its nodes.
+///
+///called from binding path, queue the real work instead of blocking here
+pub trait DeviceNodeHook: Send + Sync {
+    fn bound(&self, node: &DeviceNode);
+
+    ///instance already got `pre_drop`, node should go away
+    fn unbound(&self, node: &DeviceNode);
+}
@@ -1,31 +1,198 @@
+use core::any::Any;
 use core::future::Future;
 use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use alloc::boxed::Box;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::sync::Weak;
+use alloc::vec::Vec;
 use alloc::{string::String, sync::Arc};
-use async_lock::RwLock;
-use async_trait::async_trait;
+use async_lock::{Mutex, RwLock, Semaphore};
 use embassy_futures::select;
 use futures::task::FutureObj;
+use usb_descriptor_decoder::descriptors::desc_interface::{TopologyUSBFunction, USBInterface};
 
 use crate::{
-    abstractions::{PlatformAbstractions, USBSystemConfig},
+    abstractions::{filter::DeviceIdentity, PlatformAbstractions, USBSystemConfig},
     host::device::USBDevice,
+    usb::{
+        capabilities::{ApiVersion, Capabilities, API_VERSION},
+        class_descriptors::InterfaceClassDescriptors,
+    },
 };
 
 pub trait USBSystemDriverModule<'a, O, const RING_BUFFER_SIZE: usize>: Send + Sync
 where
     O: PlatformAbstractions,
 {
+    ///by default first interface of current configuration matching one of [`Self::match_rules`]
+    ///is handed to [`Self::bind_matched`], override for what rules can't express
     fn should_active(
         &self,
         device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
         config: &Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
-    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>>;
+    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>> {
+        let matched = MatchedInterface::find(&device, self.match_rules())?;
+        self.bind_matched(device, config, matched)
+    }
+
+    ///what default [`Self::should_active`] looks for, matches nothing by default
+    fn match_rules(&self) -> &[DriverMatchRule] {
+        &[]
+    }
+
+    ///build instance for an interface found by [`Self::match_rules`], None still refuses device
+    fn bind_matched(
+        &self,
+        _device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        _config: &Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        _matched: MatchedInterface,
+    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>> {
+        None
+    }
 
     fn preload_module(&self);
 
     fn name(&self) -> &'a str;
+
+    ///modules are offered a device in descending priority,
+    ///equal priorities keep the order they were plugged in
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    ///api version module was written against, refused on mismatch
+    fn api_version(&self) -> ApiVersion {
+        API_VERSION
+    }
+
+    ///module is refused on plug if host stack lacks any of these
+    fn required_capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+}
+
+///a rule matches an interface if every field that is set matches,
+///ids are checked against device, class, subclass and protocol against interface
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriverMatchRule {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub protocol: Option<u8>,
+}
+
+impl DriverMatchRule {
+    pub const fn vid_pid(vendor_id: u16, product_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            class: None,
+            subclass: None,
+            protocol: None,
+        }
+    }
+
+    pub const fn class(class: u8) -> Self {
+        Self {
+            vendor_id: None,
+            product_id: None,
+            class: Some(class),
+            subclass: None,
+            protocol: None,
+        }
+    }
+
+    pub const fn with_subclass(mut self, subclass: u8) -> Self {
+        self.subclass = Some(subclass);
+        self
+    }
+
+    pub const fn with_protocol(mut self, protocol: u8) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    ///rules with ids never match while device descriptor is unknown
+    pub fn matches(&self, identity: Option<&DeviceIdentity>, interface: &USBInterface) -> bool {
+        let ids_match = match identity {
+            Some(identity) => {
+                self.vendor_id.map_or(true, |v| v == identity.vendor_id)
+                    && self.product_id.map_or(true, |p| p == identity.product_id)
+            }
+            None => self.vendor_id.is_none() && self.product_id.is_none(),
+        };
+        ids_match
+            && self
+                .class
+                .map_or(true, |c| c == interface.interface.interface_class)
+            && self
+                .subclass
+                .map_or(true, |s| s == interface.interface.interface_subclass)
+            && self
+                .protocol
+                .map_or(true, |p| p == interface.interface.interface_protocol)
+    }
+}
+
+///interface default [`USBSystemDriverModule::should_active`] found
+pub struct MatchedInterface {
+    ///rule that matched it
+    pub rule: DriverMatchRule,
+    ///alternate setting that matched
+    pub interface: Arc<USBInterface>,
+    ///every alternate setting of same interface, matched one included
+    pub alternates: Vec<Arc<USBInterface>>,
+    ///class and vendor specific descriptors of matched alternate setting
+    pub class_descriptors: InterfaceClassDescriptors,
+}
+
+impl MatchedInterface {
+    ///first interface of current configuration, in descriptor order, any of `rules` matches
+    pub fn find<O, const RING_BUFFER_SIZE: usize>(
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+        rules: &[DriverMatchRule],
+    ) -> Option<Self>
+    where
+        O: PlatformAbstractions,
+    {
+        if rules.is_empty() {
+            return None;
+        }
+        let identity = device
+            .device_desc_raw
+            .get()
+            .map(|desc| DeviceIdentity::from_device_desc(desc));
+        device
+            .descriptor
+            .get()?
+            .configs
+            .iter()
+            .find(|config| config.desc.config_val() == device.current_config())?
+            .functions
+            .iter()
+            .find_map(|function| match function.as_ref() {
+                TopologyUSBFunction::Interface(interfaces) => {
+                    interfaces.iter().find_map(|interface| {
+                        let rule = rules
+                            .iter()
+                            .find(|rule| rule.matches(identity.as_ref(), interface))?;
+                        Some(Self {
+                            rule: *rule,
+                            interface: interface.clone(),
+                            alternates: interfaces.clone(),
+                            class_descriptors: device.class_descriptors(
+                                interface.interface.interface_number,
+                                interface.interface.alternate_setting,
+                            ),
+                        })
+                    })
+                }
+                _ => None,
+            })
+    }
 }
 
 pub trait USBSystemDriverModuleInstanceFunctionalInterface<'a, O>: Send + Sync
@@ -34,4 +201,101 @@ where
 {
     fn run(&'a mut self) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
     fn pre_drop(&'a self);
+
+    ///new subscription to what this instance produces, a boxed [`OutputStream`] of driver defined event type.
+    ///
+    ///None if instance produces nothing for the OS
+    fn subscribe_output(&self) -> Option<Box<dyn Any + Send>> {
+        None
+    }
+
+    ///interface this instance drives, names its device node, see [`crate::driver::device_node`].
+    ///
+    ///None if it drives device as a whole
+    fn interface_number(&self) -> Option<u8> {
+        None
+    }
+
+    ///alternate setting of [`Self::interface_number`] in use, recorded with its claim
+    fn alternate_setting(&self) -> u8 {
+        0
+    }
+}
+
+///events a driver instance hands to the OS, every subscriber gets its own queue.
+///
+///a full queue drops its oldest event, slow consumer never stalls the driver
+pub struct DriverOutput<T> {
+    subscribers: Mutex<Vec<Weak<OutputQueue<T>>>>,
+    capacity: usize,
+}
+
+struct OutputQueue<T> {
+    events: Mutex<VecDeque<T>>,
+    ready: Semaphore,
+    missed: AtomicUsize,
+}
+
+///subscription made by [`DriverOutput::subscribe`], dropping it unsubscribes
+pub struct OutputStream<T> {
+    queue: Arc<OutputQueue<T>>,
+}
+
+impl<T: Clone + Send + 'static> DriverOutput<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn subscribe(&self) -> OutputStream<T> {
+        let queue = Arc::new(OutputQueue {
+            events: Mutex::new(VecDeque::with_capacity(self.capacity)),
+            ready: Semaphore::new(0),
+            missed: AtomicUsize::new(0),
+        });
+        embassy_futures::block_on(self.subscribers.lock()).push(Arc::downgrade(&queue));
+        OutputStream { queue }
+    }
+
+    ///boxed subscription, what [`USBSystemDriverModuleInstanceFunctionalInterface::subscribe_output`] returns
+    pub fn subscribe_any(&self) -> Option<Box<dyn Any + Send>> {
+        Some(Box::new(self.subscribe()))
+    }
+
+    pub async fn publish(&self, event: T) {
+        let mut subscribers = self.subscribers.lock().await;
+        subscribers.retain(|queue| queue.strong_count() > 0);
+        for queue in subscribers.iter().filter_map(Weak::upgrade) {
+            let mut events = queue.events.lock().await;
+            if events.len() == self.capacity {
+                //permit of dropped event is taken over by the new one
+                events.pop_front();
+                events.push_back(event.clone());
+                queue.missed.fetch_add(1, Ordering::Relaxed);
+            } else {
+                events.push_back(event.clone());
+                queue.ready.add_permits(1);
+            }
+        }
+    }
+}
+
+impl<T> OutputStream<T> {
+    pub async fn next(&self) -> T {
+        //permit is consumed, not returned
+        core::mem::forget(self.queue.ready.acquire().await);
+        self.queue
+            .events
+            .lock()
+            .await
+            .pop_front()
+            .expect("ready count and queue out of sync")
+    }
+
+    ///events dropped because queue was full
+    pub fn missed(&self) -> usize {
+        self.queue.missed.load(Ordering::Relaxed)
+    }
 }
@@ -1,11 +1,11 @@
 use core::{
     future::{Future, IntoFuture},
     pin::Pin,
+    time::Duration,
 };
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 use async_lock::{OnceCell, RwLock};
-use embassy_futures::yield_now;
 use futures::{task::AtomicWaker, FutureExt};
 use log::{info, trace, warn};
 use num_traits::Zero;
@@ -13,85 +13,67 @@ use squeak::Response;
 use usb_descriptor_decoder::descriptors::{
     desc_device::StandardUSBDeviceClassCode,
     desc_endpoint::{Endpoint, EndpointType},
-    desc_hid::{
-        HIDDescriptorTypes, Hid, USBHIDProtocolDescriptorType, USBHIDSubclassDescriptorType,
-    },
-    desc_interface::{TopologyUSBFunction, USBInterface},
+    desc_hid::{Hid, USBHIDProtocolDescriptorType, USBHIDSubclassDescriptorType},
+    desc_interface::USBInterface,
 };
 
 use crate::{
     abstractions::{dma::DMA, PlatformAbstractions},
-    driver::driverapi::{USBSystemDriverModule, USBSystemDriverModuleInstanceFunctionalInterface},
+    driver::{
+        driverapi::{
+            DriverMatchRule, DriverOutput, MatchedInterface, USBSystemDriverModule,
+            USBSystemDriverModuleInstanceFunctionalInterface,
+        },
+        timing::sleep,
+    },
     host::device::USBDevice,
     usb::operations::{
-        control::{
-            bRequest, bRequestStandard, bmRequestType, construct_control_transfer_type,
-            ControlTransfer, DataTransferType, Recipient,
-        },
-        interrupt::InterruptTransfer,
-        Direction, RequestResult, RequestedOperation,
+        control::{ControlRequestBuilder, DescType, HidReportType},
+        RequestResult, RequestedOperation, UsbError,
     },
 };
 
+///reports kept for a subscriber that is not keeping up
+const REPORT_QUEUE_LEN: usize = 32;
+///report polling rate over EP0 for devices without interrupt IN that report only on change
+const DEFAULT_CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const MOUSE_RULE: DriverMatchRule = DriverMatchRule::class(StandardUSBDeviceClassCode::HID as u8)
+    .with_protocol(USBHIDProtocolDescriptorType::Mouse as u8);
+
 pub struct HIDMouseModule;
 
+///input report as sent by the mouse, layout follows its report descriptor
+#[derive(Debug, Clone)]
+pub struct HidReport {
+    pub data: Vec<u8>,
+}
+
 impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>
     for HIDMouseModule
 where
     'a: 'static,
     O: PlatformAbstractions + 'a,
 {
-    fn should_active(
-        &self,
-        device: alloc::sync::Arc<crate::host::device::USBDevice<O, RING_BUFFER_SIZE>>,
-        config: &alloc::sync::Arc<crate::abstractions::USBSystemConfig<O, RING_BUFFER_SIZE>>,
-    ) -> Option<
-        alloc::sync::Arc<
-            async_lock::RwLock<
-                dyn crate::driver::driverapi::USBSystemDriverModuleInstanceFunctionalInterface<
-                    'a,
-                    O,
-                >,
-            >,
-        >,
-    > {
-        trace!("testing device if it's hid mouse...");
-        let usbdevice = device.clone();
+    fn match_rules(&self) -> &[DriverMatchRule] {
+        &[MOUSE_RULE]
+    }
 
-        device
-            .descriptor
-            .get()
-            .unwrap()
-            .configs
-            .iter()
-            .find(|config| config.desc.config_val() == device.current_config)
-            .unwrap()
-            .functions
-            .iter()
-            .find_map(|function| match function.as_ref() {
-                TopologyUSBFunction::Interface(usbinterfaces) => usbinterfaces
-                    .iter()
-                    .find(|intf| {
-                        intf.interface.interface_class == StandardUSBDeviceClassCode::HID as u8
-                            && intf.interface.interface_protocol
-                                == USBHIDProtocolDescriptorType::Mouse as u8
-                    })
-                    .and_then(move |a| Some((a.clone(), usbinterfaces.clone()))),
-                _ => None,
-            })
-            .map(
-                move |(intf, alts)| -> Arc<
-                    RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>,
-                > {
-                    trace!("yes it is!");
-                    Arc::new(RwLock::new(HIDMouseModuleInstance {
-                        device_ref: usbdevice,
-                        interface_refs: alts,
-                        selected_alt: intf,
-                        hid_report_decoder: OnceCell::new(),
-                    }))
-                },
-            )
+    fn bind_matched(
+        &self,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        _config: &Arc<crate::abstractions::USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        matched: MatchedInterface,
+    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>> {
+        trace!("found hid mouse");
+        Some(Arc::new(RwLock::new(HIDMouseModuleInstance {
+            device_ref: device,
+            interface_refs: matched.alternates,
+            selected_alt: matched.interface,
+            hid_report_decoder: OnceCell::new(),
+            report_buffer: None,
+            output: DriverOutput::new(REPORT_QUEUE_LEN),
+        })))
     }
 
     //
@@ -126,12 +108,15 @@ where
     interface_refs: Vec<Arc<USBInterface>>,
     selected_alt: Arc<USBInterface>,
     hid_report_decoder: OnceCell<axhid::report_handler::ReportHandler>,
+    ///interrupt IN is filled into it, a halted endpoint still points at it once polling stopped
+    report_buffer: Option<DMA<[u8], O>>,
+    output: DriverOutput<HidReport>,
 }
 
 impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModuleInstanceFunctionalInterface<'a, O>
     for HIDMouseModuleInstance<O, RING_BUFFER_SIZE>
 where
-    O: PlatformAbstractions,
+    O: PlatformAbstractions + 'static,
     'a: 'static,
 {
     fn run(&'a mut self) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
@@ -139,158 +124,210 @@ where
     }
 
     fn pre_drop(&'a self) {
-        todo!()
+        info!(
+            "hid mouse at {} unplugged, driver stopping",
+            self.device_ref.topology_path
+        );
+    }
+
+    fn subscribe_output(&self) -> Option<Box<dyn core::any::Any + Send>> {
+        self.output.subscribe_any()
+    }
+
+    fn interface_number(&self) -> Option<u8> {
+        Some(self.selected_alt.interface.interface_number)
+    }
+
+    fn alternate_setting(&self) -> u8 {
+        self.selected_alt.interface.alternate_setting
     }
 }
 impl<'a, O, const RING_BUFFER_SIZE: usize> HIDMouseModuleInstance<O, RING_BUFFER_SIZE>
 where
-    O: PlatformAbstractions,
+    O: PlatformAbstractions + 'static,
 {
     pub async fn work_fut(&mut self) {
         trace!("hid mouse driver instance running...");
+        if let Err(err) = self.work().await {
+            warn!(
+                "mouse at {} stopped: {}",
+                self.device_ref.topology_path, err
+            );
+        }
+    }
 
-        self.device_ref
+    async fn work(&mut self) -> Result<(), UsbError> {
+        //may be a lighter alternate setting than the one picked
+        self.selected_alt = self
+            .device_ref
             .enable_function(self.selected_alt.clone())
-            .await;
+            .await?;
 
-        self.device_ref
-            .request_once(crate::usb::operations::RequestedOperation::Control(
-                ControlTransfer {
-                    request_type: bmRequestType::new(
-                        Direction::Out,
-                        DataTransferType::Standard,
-                        Recipient::Device,
-                    ),
-                    request: bRequest::Standard(bRequestStandard::SetConfiguration),
-                    index: self.selected_alt.interface.interface_number as _,
-                    value: self.device_ref.current_config as _,
-                    data: None,
-                    response: true,
-                },
-            ))
-            .await
-            .unwrap();
-
-        self.device_ref
-            .request_once(crate::usb::operations::RequestedOperation::Control(
-                ControlTransfer {
-                    request_type: bmRequestType::new(
-                        Direction::Out,
-                        DataTransferType::Standard,
-                        Recipient::Interface,
-                    ),
-                    request: bRequest::Standard(bRequestStandard::SetInterface),
-                    index: self.selected_alt.interface.alternate_setting as u16,
-                    value: self.selected_alt.interface.interface_number as u16,
-                    data: None,
-                    response: true,
-                },
-            ))
-            .await
-            .unwrap();
+        self.control(ControlRequestBuilder::set_configuration(
+            self.device_ref.current_config(),
+        ))
+        .await?;
+        self.control(ControlRequestBuilder::set_interface(
+            self.selected_alt.interface.interface_number,
+            self.selected_alt.interface.alternate_setting,
+        ))
+        .await?;
         trace!("request success!, now we could request actual data!");
-        {
-            let hid_report: DMA<[u8], O> = DMA::new_vec(
-                0u8,
-                O::PAGE_SIZE,
-                O::PAGE_SIZE,
-                self.device_ref.config.os.dma_alloc(),
-            );
-
-            self.device_ref
-                .request_once(crate::usb::operations::RequestedOperation::Control(
-                    ControlTransfer {
-                        request_type: bmRequestType::new(
-                            Direction::In,
-                            DataTransferType::Standard,
-                            Recipient::Interface,
-                        ),
-                        request: bRequest::Standard(bRequestStandard::GetDescriptor),
-                        index: self.selected_alt.interface.alternate_setting as u16,
-                        value: construct_control_transfer_type(
-                            HIDDescriptorTypes::HIDReport as u8,
-                            0,
-                        )
-                        .bits(),
-                        data: Some(hid_report.phys_addr_len_tuple().into()),
-                        response: true,
-                    },
-                ))
-                .await
-                .unwrap();
-
-            let (last_offset, _) = hid_report
-                .iter()
-                .enumerate()
-                .filter(|(i, b)| **b != 0)
-                .last()
-                .unwrap();
-
-            let report_handler =
-                axhid::report_handler::ReportHandler::new(&hid_report[0..=last_offset]).unwrap();
-            drop(hid_report);
 
-            let _ = self.hid_report_decoder.set(report_handler).await;
-        }
+        let report_len = self.read_report_descriptor().await?;
+        let aligned_size = report_len.next_power_of_two();
+        let mut hid_response = DMA::<[u8], O>::try_new_vec(
+            0u8,
+            aligned_size,
+            aligned_size,
+            self.device_ref.config.os.dma_alloc(),
+        )
+        .map_err(|_| UsbError::OutOfMemory)?;
+        trace!("prepare complete!");
 
-        let ep_id = self
+        //some devices only ever answer on EP0
+        let interrupt_in = self
             .selected_alt
             .endpoints
             .iter()
             .find(|ep| ep.endpoint_type() == EndpointType::InterruptIn)
-            .unwrap()
-            .doorbell_value_aka_dci() as _;
-        let aligned_size = self
-            .hid_report_decoder
-            .get()
-            .map(|handler| handler.total_byte_length)
-            .unwrap()
-            .next_power_of_two();
+            .map(|ep| ep.doorbell_value_aka_dci() as usize);
+        match interrupt_in {
+            Some(ep_id) => {
+                let buffer = hid_response.phys_addr_len_tuple().into();
+                self.report_buffer = Some(hid_response);
+                self.poll_interrupt(ep_id, buffer).await
+            }
+            None => self.poll_control(&mut hid_response, report_len).await,
+        }
+        Ok(())
+    }
 
-        let mut hid_response: DMA<[u8], O> = DMA::new_vec(
-            0u8,
-            aligned_size,
-            aligned_size,
-            self.device_ref.config.os.dma_alloc(),
+    async fn control(&self, request: ControlRequestBuilder) -> Result<(), UsbError> {
+        match self
+            .device_ref
+            .request_once(RequestedOperation::Control(request.build()))
+            .await
+        {
+            Ok(RequestResult::SlotNotEnabledError) => Err(UsbError::DeviceGone),
+            result => UsbError::check(result).map(|_| ()),
+        }
+    }
+
+    ///parses report descriptor into decoder, returns length of input reports
+    async fn read_report_descriptor(&self) -> Result<usize, UsbError> {
+        let mut hid_report = vec![0u8; O::PAGE_SIZE];
+        let length = self
+            .device_ref
+            .control_in_place(
+                ControlRequestBuilder::get_descriptor(DescType::HidReport, 0)
+                    .interface(self.selected_alt.interface.interface_number),
+                &mut hid_report,
+            )
+            .await?;
+        let report_handler = axhid::report_handler::ReportHandler::new(&hid_report[..length])
+            .map_err(|_| UsbError::BadDescriptor)?;
+        let report_len = report_handler.total_byte_length;
+        let _ = self.hid_report_decoder.set(report_handler).await;
+        Ok(report_len)
+    }
+
+    ///endpoint is kept filling over `buffer`, every report is taken from the stream in order
+    async fn poll_interrupt(&mut self, ep_id: usize, buffer: (usize, usize)) {
+        let stream = self
+            .device_ref
+            .keep_interrupt_reports(ep_id, buffer, REPORT_QUEUE_LEN)
+            .await;
+        while let Some(report) = stream.next_report().await {
+            match report.result {
+                Ok(RequestResult::Success | RequestResult::ShortPacket) => {
+                    self.publish_report(&report.data).await
+                }
+                Ok(RequestResult::StallError) => {
+                    if let Err(err) = self.device_ref.clear_stall(ep_id).await {
+                        warn!(
+                            "mouse at {} stalled and could not be cleared: {}",
+                            self.device_ref.topology_path, err
+                        );
+                        stream.cancel();
+                        break;
+                    }
+                }
+                other => trace!("interrupt fill failed: {:?}", other),
+            }
+        }
+        if stream.lost_reports() > 0 {
+            trace!("mouse reports lost: {}", stream.lost_reports());
+        }
+    }
+
+    ///GET_REPORT over EP0 once per idle period the device declares
+    async fn poll_control(&mut self, hid_response: &mut DMA<[u8], O>, report_len: usize) {
+        let interface = self.selected_alt.interface.interface_number;
+        let interval = self.control_poll_interval().await;
+        info!(
+            "hid mouse at {} has no interrupt IN endpoint, polling reports every {:?}",
+            self.device_ref.topology_path, interval
         );
-        trace!("prepare complete!");
+        let report_len = report_len.min(hid_response.len());
         loop {
+            let (addr, _): (usize, usize) = hid_response.phys_addr_len_tuple().into();
             let request_result = self
                 .device_ref
-                .request_once(RequestedOperation::Interrupt(InterruptTransfer {
-                    endpoint_id: ep_id,
-                    buffer_addr_len: hid_response.phys_addr_len_tuple().into(),
-                    short_packet_ok: true,
-                }))
+                .request_with_length(RequestedOperation::Control(
+                    ControlRequestBuilder::hid_get_report(interface, HidReportType::Input, 0)
+                        .data((addr, report_len))
+                        .build(),
+                ))
                 .await;
-
-            if let Some(handler) = self.hid_report_decoder.get_mut() {
-                let _ = handler
-                    .handle(&hid_response)
-                    .inspect(|ok| info!("response! {:#?}", ok));
+            match request_result {
+                Ok((RequestResult::SlotNotEnabledError, _)) => break,
+                Ok((RequestResult::Success | RequestResult::ShortPacket, length)) => {
+                    self.publish_report(&hid_response[..length.min(hid_response.len())])
+                        .await
+                }
+                other => trace!("GET_REPORT failed: {:?}", other),
             }
+            sleep(&self.device_ref.config.os, interval).await;
         }
+    }
 
-        // self.device_ref
-        //     .keep_no_response(
-        //         crate::usb::operations::RequestedOperation::Interrupt(InterruptTransfer {
-        //             endpoint_id: ep_id,
-        //             buffer_addr_len: hid_response.phys_addr_len_tuple().into(),
-        //             short_packet_ok: true,
-        //         }),
-        //         ep_id as _,
-        //     )
-        //     .await;
+    ///idle rate from GET_IDLE, devices reporting only on change get polled at default rate
+    async fn control_poll_interval(&self) -> Duration {
+        let Ok(idle) =
+            DMA::<[u8], O>::try_new_vec(0u8, 1, 1, self.device_ref.config.os.dma_alloc())
+        else {
+            return DEFAULT_CONTROL_POLL_INTERVAL;
+        };
+        let result = self
+            .device_ref
+            .request_with_length(RequestedOperation::Control(
+                ControlRequestBuilder::hid_get_idle(
+                    self.selected_alt.interface.interface_number,
+                    0,
+                )
+                .data(idle.phys_addr_len_tuple().into())
+                .build(),
+            ))
+            .await;
+        match result {
+            Ok((RequestResult::Success, 1)) if idle[0] != 0 => {
+                Duration::from_millis(idle[0] as u64 * 4)
+            }
+            _ => DEFAULT_CONTROL_POLL_INTERVAL,
+        }
+    }
 
-        // loop {
-        //     if hid_response.iter().any(|a| !a.is_zero())
-        //         && let Some(handler) = self.hid_report_decoder.get_mut()
-        //     {
-        //         let _ = handler
-        //             .handle(&hid_response)
-        //             .inspect(|ok| info!("response! {:#?}", ok));
-        //     }
-        //     yield_now().await
-        // }
+    async fn publish_report(&mut self, report: &[u8]) {
+        if let Some(handler) = self.hid_report_decoder.get_mut() {
+            let _ = handler
+                .handle(report)
+                .inspect(|ok| trace!("response! {:#?}", ok));
+        }
+        self.output
+            .publish(HidReport {
+                data: report.to_vec(),
+            })
+            .await;
     }
 }
@@ -0,0 +1,430 @@
+use core::{
+    future::{Future, IntoFuture},
+    pin::Pin,
+    time::Duration,
+};
+
+use alloc::{boxed::Box, collections::btree_set::BTreeSet, sync::Arc, vec::Vec};
+use async_lock::RwLock;
+use log::{debug, info, trace, warn};
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::EndpointType, desc_interface::USBInterface,
+};
+
+use crate::{
+    abstractions::{dma::DMA, PlatformAbstractions},
+    driver::{
+        driverapi::{
+            DriverMatchRule, MatchedInterface, USBSystemDriverModule,
+            USBSystemDriverModuleInstanceFunctionalInterface,
+        },
+        status_endpoint::StatusEndpointListener,
+        timing::sleep,
+    },
+    host::device::USBDevice,
+    usb::{
+        capabilities::Capabilities,
+        operations::{
+            control::{bRequest, bRequestStandard, ControlTransfer, Recipient},
+            hub::{DeviceSpeed, HubConfiguration, HubPortAttach},
+            Direction, RequestResult, RequestedOperation,
+        },
+        standards::{MAX_HUB_DEPTH, MAX_HUB_PORT},
+    },
+};
+
+const HUB_CLASS: u8 = 0x09;
+///interface protocol of multi TT hubs, refer usb2 spec 11.23.1
+const MULTI_TT_PROTOCOL: u8 = 2;
+const HUB_DESC_TYPE: u8 = 0x29;
+const SUPERSPEED_HUB_DESC_TYPE: u8 = 0x2a;
+///refer usb3 spec 10.14.2.9
+const SET_HUB_DEPTH: u8 = 12;
+
+///hub class feature selectors, refer usb2 spec table 11-17
+const PORT_RESET: u16 = 4;
+const PORT_POWER: u16 = 8;
+const C_PORT_CONNECTION: u16 = 16;
+const C_PORT_RESET: u16 = 20;
+
+///wPortStatus and wPortChange bits
+const PORT_STATUS_CONNECTION: u16 = 1 << 0;
+const PORT_STATUS_ENABLE: u16 = 1 << 1;
+const PORT_CHANGE_CONNECTION: u16 = 1 << 0;
+const PORT_CHANGE_RESET: u16 = 1 << 4;
+
+const RESET_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const RESET_POLL_LIMIT: usize = 50;
+///refer usb2 spec 7.1.7.5, TRSTRCY
+const RESET_RECOVERY: Duration = Duration::from_millis(10);
+
+pub struct HubModule;
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>
+    for HubModule
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    fn match_rules(&self) -> &[DriverMatchRule] {
+        &[DriverMatchRule::class(HUB_CLASS)]
+    }
+
+    fn bind_matched(
+        &self,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        _config: &Arc<crate::abstractions::USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        matched: MatchedInterface,
+    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>> {
+        trace!("found hub");
+        //devices behind it could not be routed to, rather leave its ports unpowered
+        if device.topology_path.depth() >= MAX_HUB_DEPTH {
+            warn!(
+                "hub at {} is more than {} hubs deep, not bound",
+                device.topology_path, MAX_HUB_DEPTH
+            );
+            return None;
+        }
+        Some(Arc::new(RwLock::new(HubModuleInstance {
+            device_ref: device,
+            interface: matched.interface,
+            ports: 0,
+            attached: BTreeSet::new(),
+        })))
+    }
+
+    fn preload_module(&self) {
+        info!("loaded usb hub driver!")
+    }
+
+    fn name(&self) -> &'a str {
+        "hub"
+    }
+
+    fn required_capabilities(&self) -> Capabilities {
+        Capabilities::HUBS
+    }
+}
+
+pub struct HubModuleInstance<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    device_ref: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    interface: Arc<USBInterface>,
+    ports: u8,
+    ///downstream ports controller was told about
+    attached: BTreeSet<u8>,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModuleInstanceFunctionalInterface<'a, O>
+    for HubModuleInstance<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    fn run(&'a mut self) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+        Box::pin(self.work_fut().into_future())
+    }
+
+    fn pre_drop(&'a self) {
+        info!(
+            "hub at {} unplugged, {} downstream devices went with it",
+            self.device_ref.topology_path,
+            self.attached.len()
+        );
+    }
+
+    fn interface_number(&self) -> Option<u8> {
+        Some(self.interface.interface.interface_number)
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> HubModuleInstance<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+{
+    pub async fn work_fut(&mut self) {
+        trace!("hub driver instance running...");
+        let superspeed = self.device_ref.is_superspeed();
+
+        if let Err(err) = self
+            .device_ref
+            .enable_function(self.interface.clone())
+            .await
+        {
+            warn!(
+                "hub at {} not enabled: {}",
+                self.device_ref.topology_path, err
+            );
+            return;
+        }
+        self.control(ControlTransfer::new(
+            Direction::Out,
+            Recipient::Device,
+            bRequest::Standard(bRequestStandard::SetConfiguration),
+            self.device_ref.current_config() as _,
+            0,
+            None,
+        ))
+        .await;
+
+        let Some((configuration, power_good)) = self.read_hub_descriptor(superspeed).await else {
+            warn!(
+                "hub at {} gave no hub descriptor, giving up",
+                self.device_ref.topology_path
+            );
+            return;
+        };
+        self.ports = configuration.ports.min(MAX_HUB_PORT);
+        if self.ports < configuration.ports {
+            warn!(
+                "hub at {} got {} ports, only first {} are routable",
+                self.device_ref.topology_path, configuration.ports, self.ports
+            );
+        }
+
+        if superspeed {
+            self.control(ControlTransfer::class(
+                Direction::Out,
+                Recipient::Device,
+                SET_HUB_DEPTH,
+                self.device_ref.topology_path.depth() as _,
+                0,
+                None,
+            ))
+            .await;
+        }
+
+        if self.device_ref.configure_hub(configuration).await != Ok(RequestResult::Success) {
+            warn!(
+                "controller refused hub at {}",
+                self.device_ref.topology_path
+            );
+            return;
+        }
+
+        let Some(status_endpoint) = self
+            .interface
+            .endpoints
+            .iter()
+            .find(|ep| ep.endpoint_type() == EndpointType::InterruptIn)
+            .cloned()
+        else {
+            warn!(
+                "hub at {} has no status endpoint",
+                self.device_ref.topology_path
+            );
+            return;
+        };
+        let listener: StatusEndpointListener<O, Vec<u8>, RING_BUFFER_SIZE> =
+            match StatusEndpointListener::start(self.device_ref.clone(), &status_endpoint).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    warn!(
+                        "hub at {} can't listen on its status endpoint: {}",
+                        self.device_ref.topology_path, err
+                    );
+                    return;
+                }
+            };
+
+        for port in 1..=self.ports {
+            self.set_port_feature(port, PORT_POWER).await;
+        }
+        sleep(&self.device_ref.config.os, power_good).await;
+        info!(
+            "hub at {} powered {} ports",
+            self.device_ref.topology_path, self.ports
+        );
+
+        //devices already plugged in before power on show up as connection changes
+        for port in 1..=self.ports {
+            self.on_port_changed(port, superspeed).await;
+        }
+
+        while let Some(bitmap) = listener.next().await {
+            trace!("hub change bitmap {:x?}", bitmap);
+            //bit 0 is hub itself, nothing to do there for now
+            for port in 1..=self.ports {
+                let (byte, bit) = (port as usize / 8, port % 8);
+                if bitmap.get(byte).is_some_and(|b| b & (1 << bit) != 0) {
+                    self.on_port_changed(port, superspeed).await;
+                }
+            }
+        }
+        debug!(
+            "hub at {} status endpoint stopped",
+            self.device_ref.topology_path
+        );
+    }
+
+    ///returns configuration for controller, and how long ports take to get power good
+    async fn read_hub_descriptor(&self, superspeed: bool) -> Option<(HubConfiguration, Duration)> {
+        let desc_type = if superspeed {
+            SUPERSPEED_HUB_DESC_TYPE
+        } else {
+            HUB_DESC_TYPE
+        };
+        let buffer: DMA<[u8], O> =
+            DMA::try_new_vec(0u8, 64, 64, self.device_ref.config.os.dma_alloc()).ok()?;
+        if !self
+            .control(ControlTransfer::class(
+                Direction::In,
+                Recipient::Device,
+                bRequestStandard::GetDescriptor as u8,
+                (desc_type as u16) << 8,
+                0,
+                Some(buffer.phys_addr_len_tuple().into()),
+            ))
+            .await
+        {
+            return None;
+        }
+
+        let characteristics = u16::from_le_bytes([buffer[3], buffer[4]]);
+        let configuration = HubConfiguration {
+            ports: buffer[2],
+            multi_tt: self.interface.interface.interface_protocol == MULTI_TT_PROTOCOL,
+            tt_think_time: ((characteristics >> 5) & 0x3) as u8,
+        };
+        //2ms units
+        let power_good = Duration::from_millis(buffer[5] as u64 * 2);
+        debug!(
+            "hub at {}: {:?}, power good after {:?}",
+            self.device_ref.topology_path, configuration, power_good
+        );
+        Some((configuration, power_good))
+    }
+
+    async fn on_port_changed(&mut self, port: u8, superspeed: bool) {
+        let seen_at = self.device_ref.config.os.now();
+        let Some((status, change)) = self.port_status(port).await else {
+            return;
+        };
+        trace!("hub port {} status {:x} change {:x}", port, status, change);
+        if change & PORT_CHANGE_CONNECTION != 0 {
+            self.clear_port_feature(port, C_PORT_CONNECTION).await;
+            //replugged between two looks, old device is gone either way
+            if self.attached.remove(&port) {
+                let _ = self.device_ref.detach_child(port).await;
+            }
+        }
+        if change & PORT_CHANGE_RESET != 0 {
+            self.clear_port_feature(port, C_PORT_RESET).await;
+        }
+
+        match (
+            status & PORT_STATUS_CONNECTION != 0,
+            self.attached.contains(&port),
+        ) {
+            (true, false) => {
+                let Some(speed) = self.reset_port(port, superspeed).await else {
+                    warn!(
+                        "port {} of hub {} not enabled after reset",
+                        port, self.device_ref.topology_path
+                    );
+                    return;
+                };
+                let result = self
+                    .device_ref
+                    .attach_child(HubPortAttach {
+                        port,
+                        speed,
+                        connected_at: seen_at,
+                        reset_at: self.device_ref.config.os.now(),
+                    })
+                    .await;
+                if result == Ok(RequestResult::Success) {
+                    self.attached.insert(port);
+                }
+            }
+            (false, true) => {
+                self.attached.remove(&port);
+                let _ = self.device_ref.detach_child(port).await;
+            }
+            _ => {}
+        }
+    }
+
+    ///returns speed of device, once port got enabled
+    async fn reset_port(&self, port: u8, superspeed: bool) -> Option<DeviceSpeed> {
+        self.set_port_feature(port, PORT_RESET).await;
+        for _ in 0..RESET_POLL_LIMIT {
+            sleep(&self.device_ref.config.os, RESET_POLL_INTERVAL).await;
+            let (status, change) = self.port_status(port).await?;
+            if change & PORT_CHANGE_RESET == 0 {
+                continue;
+            }
+            self.clear_port_feature(port, C_PORT_RESET).await;
+            if status & PORT_STATUS_ENABLE == 0 {
+                return None;
+            }
+            sleep(&self.device_ref.config.os, RESET_RECOVERY).await;
+            return Some(DeviceSpeed::from_hub_port_status(status, superspeed));
+        }
+        None
+    }
+
+    ///(wPortStatus, wPortChange)
+    async fn port_status(&self, port: u8) -> Option<(u16, u16)> {
+        let buffer: DMA<[u8], O> =
+            DMA::try_new_vec(0u8, 4, 64, self.device_ref.config.os.dma_alloc()).ok()?;
+        self.control(ControlTransfer::class(
+            Direction::In,
+            Recipient::Other,
+            bRequestStandard::GetStatus as u8,
+            0,
+            port as _,
+            Some(buffer.phys_addr_len_tuple().into()),
+        ))
+        .await
+        .then(|| {
+            (
+                u16::from_le_bytes([buffer[0], buffer[1]]),
+                u16::from_le_bytes([buffer[2], buffer[3]]),
+            )
+        })
+    }
+
+    async fn set_port_feature(&self, port: u8, feature: u16) -> bool {
+        self.control(ControlTransfer::class(
+            Direction::Out,
+            Recipient::Other,
+            bRequestStandard::SetFeature as u8,
+            feature,
+            port as _,
+            None,
+        ))
+        .await
+    }
+
+    async fn clear_port_feature(&self, port: u8, feature: u16) -> bool {
+        self.control(ControlTransfer::class(
+            Direction::Out,
+            Recipient::Other,
+            bRequestStandard::ClearFeature as u8,
+            feature,
+            port as _,
+            None,
+        ))
+        .await
+    }
+
+    async fn control(&self, transfer: ControlTransfer) -> bool {
+        match self
+            .device_ref
+            .request_once(RequestedOperation::Control(transfer))
+            .await
+        {
+            Ok(RequestResult::Success | RequestResult::ShortPacket) => true,
+            other => {
+                debug!(
+                    "hub at {} control request failed: {:?}",
+                    self.device_ref.topology_path, other
+                );
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,987 @@
+use core::{
+    fmt::{self, Display},
+    future::{Future, IntoFuture},
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use async_lock::{Mutex, RwLock};
+use embassy_futures::block_on;
+use log::{debug, info, trace, warn};
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::EndpointType, desc_interface::USBInterface,
+};
+
+use crate::{
+    abstractions::{dma::DMA, spin::SpinCell, PlatformAbstractions},
+    driver::{
+        driverapi::{
+            DriverMatchRule, MatchedInterface, USBSystemDriverModule,
+            USBSystemDriverModuleInstanceFunctionalInterface,
+        },
+        timing::sleep,
+    },
+    host::device::USBDevice,
+    usb::operations::{
+        bulk::BulkTransfer, control::ControlRequestBuilder, Direction, IocPolicy, RequestResult,
+        RequestedOperation, UsbError,
+    },
+};
+
+const MASS_STORAGE_CLASS: u8 = 0x08;
+///SCSI transparent command set
+const SCSI_SUBCLASS: u8 = 0x06;
+///refer usbmassbulk 1.0
+const BULK_ONLY_PROTOCOL: u8 = 0x50;
+///class requests, refer usbmassbulk 3.1 and 3.2
+const BULK_ONLY_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+///LUN field of CBW is 4 bits wide
+const MAX_LUNS: usize = 16;
+const BULK_ONLY_RULE: DriverMatchRule = DriverMatchRule::class(MASS_STORAGE_CLASS)
+    .with_subclass(SCSI_SUBCLASS)
+    .with_protocol(BULK_ONLY_PROTOCOL);
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_LEN: usize = 31;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_LEN: usize = 13;
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+///SCSI opcodes, refer SBC-3
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const START_STOP_UNIT: u8 = 0x1b;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+///START STOP UNIT byte 4, LOEJ set and START clear: stop and eject medium
+const STOP_AND_EJECT: u8 = 1 << 1;
+
+///fixed format sense data, refer SPC-4 4.5.3
+const SENSE_LEN: usize = 18;
+///additional sense code in fixed format sense data
+const SENSE_CODE_OFFSET: usize = 12;
+const SENSE_NOT_READY: u8 = 0x02;
+const SENSE_UNIT_ATTENTION: u8 = 0x06;
+///additional sense code of NOT READY
+const MEDIUM_NOT_PRESENT: u8 = 0x3a;
+
+///bytes moved by one READ/WRITE command at most
+const MAX_TRANSFER: usize = 64 * 1024;
+///units report not ready for a while after power on, disks spinning up take seconds
+const READY_RETRIES: usize = 50;
+const READY_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+///card reader slots could get a card any time, each LUN is asked this often
+const MEDIA_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+///LUNs brought up so far, one handle each, those of unplugged devices are removed
+pub type MassStorageDisks<O, const RING_BUFFER_SIZE: usize> =
+    Arc<RwLock<Vec<Arc<MassStorage<O, RING_BUFFER_SIZE>>>>>;
+
+///bulk only SCSI disks, every LUN of them, e.g. each slot of a card reader.
+///
+///not plugged with packed drivers, caller keeps [`Self::disks`] before plugging it
+pub struct MassStorageModule<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    disks: MassStorageDisks<O, RING_BUFFER_SIZE>,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> MassStorageModule<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    pub fn new() -> Self {
+        Self {
+            disks: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    pub fn disks(&self) -> MassStorageDisks<O, RING_BUFFER_SIZE> {
+        self.disks.clone()
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> Default for MassStorageModule<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>
+    for MassStorageModule<O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    fn match_rules(&self) -> &[DriverMatchRule] {
+        &[BULK_ONLY_RULE]
+    }
+
+    fn bind_matched(
+        &self,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        _config: &Arc<crate::abstractions::USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        matched: MatchedInterface,
+    ) -> Option<Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>> {
+        let interface = matched.interface;
+        let endpoint_of = |ty: EndpointType| {
+            interface
+                .endpoints
+                .iter()
+                .find(|ep| ep.endpoint_type() == ty)
+                .map(|ep| ep.doorbell_value_aka_dci() as usize)
+        };
+        let (bulk_in, bulk_out) = (
+            endpoint_of(EndpointType::BulkIn)?,
+            endpoint_of(EndpointType::BulkOut)?,
+        );
+
+        trace!("found mass storage");
+        Some(Arc::new(RwLock::new(MassStorageModuleInstance {
+            transport: Arc::new(BulkOnly {
+                device,
+                interface,
+                bulk_in,
+                bulk_out,
+                pipe: Mutex::new(()),
+                tag: AtomicU32::new(1),
+                active_luns: AtomicUsize::new(0),
+            }),
+            disks: self.disks.clone(),
+        })))
+    }
+
+    fn preload_module(&self) {
+        info!("loaded usb mass storage driver!")
+    }
+
+    fn name(&self) -> &'a str {
+        "mass_storage"
+    }
+}
+
+pub struct MassStorageModuleInstance<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    transport: Arc<BulkOnly<O, RING_BUFFER_SIZE>>,
+    disks: MassStorageDisks<O, RING_BUFFER_SIZE>,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystemDriverModuleInstanceFunctionalInterface<'a, O>
+    for MassStorageModuleInstance<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    fn run(&'a mut self) -> Pin<Box<dyn Future<Output = ()> + Send + Sync>> {
+        Box::pin(self.work_fut().into_future())
+    }
+
+    fn pre_drop(&'a self) {
+        block_on(self.disks.write()).retain(|disk| {
+            let ours = Arc::ptr_eq(&disk.transport, &self.transport);
+            if ours {
+                disk.unplugged();
+            }
+            !ours
+        });
+    }
+
+    fn interface_number(&self) -> Option<u8> {
+        Some(self.transport.interface.interface.interface_number)
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> MassStorageModuleInstance<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+{
+    pub async fn work_fut(&mut self) {
+        trace!("mass storage driver instance running...");
+        let path = &self.transport.device.topology_path;
+        if let Err(err) = self.transport.bring_up().await {
+            warn!("disk at {} not usable: {}", path, err);
+            return;
+        }
+
+        let max_lun = self.transport.max_lun().await;
+        let mut luns = Vec::new();
+        for lun in 0..=max_lun {
+            let disk = Arc::new(MassStorage::new(self.transport.clone(), lun));
+            match disk.probe().await {
+                Ok(()) => {
+                    match disk.capacity() {
+                        Some((block_size, block_count)) => info!(
+                            "disk at {} LUN {} ready, {} blocks of {} bytes",
+                            path, lun, block_count, block_size
+                        ),
+                        None => info!("disk at {} LUN {} has no medium", path, lun),
+                    }
+                    self.transport.active_luns.fetch_add(1, Ordering::AcqRel);
+                    self.disks.write().await.push(disk.clone());
+                    luns.push(disk);
+                }
+                Err(err) => warn!("disk at {} LUN {} not usable: {}", path, lun, err),
+            }
+        }
+
+        //removable media come and go without the device telling by itself
+        loop {
+            sleep(&self.transport.device.config.os, MEDIA_POLL_INTERVAL).await;
+            for disk in &luns {
+                match disk.poll_medium().await {
+                    Ok(()) => {}
+                    Err(StorageError::Unplugged) => return,
+                    Err(err) => trace!("disk at {} LUN {} poll failed: {}", path, disk.lun, err),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageState {
+    Probing,
+    Ready,
+    ///unit is there but holds no medium, e.g. empty slot of a card reader
+    NoMedium,
+    ///eject in progress, new commands are refused
+    Ejecting,
+    ///flushed and stopped, endpoints released, device could be pulled
+    ReadyForRemoval,
+    Unplugged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    ///still probing, or bring up failed
+    NotReady,
+    NoMedium,
+    Ejected,
+    Unplugged,
+    ///buffer is not a whole number of blocks, or reaches past last block
+    OutOfRange,
+    Transfer(UsbError),
+    ///device answered CHECK CONDITION
+    CommandFailed,
+    ///device lost track of the protocol, pipe was reset
+    PhaseError,
+    ///command passed but moved less than asked, by data stage length or by CSW residue
+    ShortTransfer {
+        expected: usize,
+        moved: usize,
+    },
+}
+
+///what a CSW says about the command it closes, refer usbmassbulk 6.3 and 6.7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommandStatus {
+    Passed,
+    Failed,
+    ///passed, but only `moved` of expected bytes are valid
+    Short {
+        moved: usize,
+    },
+    ///not a valid and meaningful CSW for this command, transport needs reset recovery
+    Invalid,
+}
+
+impl CommandStatus {
+    ///`csw` as received, `moved` is what data stage transferred of `expected`
+    fn of(csw: &[u8], tag: u32, expected: usize, moved: usize) -> Self {
+        if csw.len() != CSW_LEN
+            || u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]) != CSW_SIGNATURE
+            || u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]) != tag
+        {
+            return Self::Invalid;
+        }
+        let residue = u32::from_le_bytes([csw[8], csw[9], csw[10], csw[11]]) as usize;
+        //device can't have left more than it was asked for
+        if residue > expected {
+            return Self::Invalid;
+        }
+        match csw[12] {
+            CSW_PASSED => {
+                let moved = moved.min(expected - residue);
+                if moved < expected {
+                    Self::Short { moved }
+                } else {
+                    Self::Passed
+                }
+            }
+            CSW_FAILED => Self::Failed,
+            _ => Self::Invalid,
+        }
+    }
+}
+
+impl StorageError {
+    fn is_stall(&self) -> bool {
+        *self == StorageError::Transfer(UsbError::Completion(RequestResult::StallError))
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotReady => write!(f, "disk not ready"),
+            StorageError::NoMedium => write!(f, "no medium"),
+            StorageError::Ejected => write!(f, "disk ejected"),
+            StorageError::Unplugged => write!(f, "disk unplugged"),
+            StorageError::OutOfRange => write!(f, "blocks out of range"),
+            StorageError::Transfer(err) => write!(f, "transfer {}", err),
+            StorageError::CommandFailed => write!(f, "command failed"),
+            StorageError::PhaseError => write!(f, "phase error"),
+            StorageError::ShortTransfer { expected, moved } => {
+                write!(f, "short transfer, {} of {} bytes", moved, expected)
+            }
+        }
+    }
+}
+
+///what TEST UNIT READY and sense data say about a unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnitStatus {
+    Ready,
+    NoMedium,
+    ///medium may have changed, or unit got reset
+    Attention,
+    ///e.g. still spinning up
+    Busy,
+}
+
+///bulk only pipe of an interface, shared by all of its LUNs
+struct BulkOnly<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    interface: Arc<USBInterface>,
+    bulk_in: usize,
+    bulk_out: usize,
+    ///one command on the pipe at a time, holding it means none is in flight
+    pipe: Mutex<()>,
+    tag: AtomicU32,
+    ///LUNs handed out and not ejected yet, function is released once the last one is
+    active_luns: AtomicUsize,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> BulkOnly<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    async fn bring_up(&self) -> Result<(), StorageError> {
+        self.device
+            .enable_function(self.interface.clone())
+            .await
+            .map_err(StorageError::Transfer)?;
+        UsbError::check(
+            self.device
+                .request_once(RequestedOperation::Control(
+                    ControlRequestBuilder::set_configuration(self.device.current_config()).build(),
+                ))
+                .await,
+        )
+        .map_err(StorageError::Transfer)
+        .map(|_| ())
+    }
+
+    ///highest LUN of the device, refer usbmassbulk 3.2
+    async fn max_lun(&self) -> u8 {
+        let mut max_lun = [0u8; 1];
+        match self
+            .device
+            .control_in_place(
+                ControlRequestBuilder::class(Direction::In, GET_MAX_LUN)
+                    .interface(self.interface.interface.interface_number),
+                &mut max_lun,
+            )
+            .await
+        {
+            Ok(1) => max_lun[0].min(MAX_LUNS as u8 - 1),
+            //single LUN devices may stall it
+            _ => 0,
+        }
+    }
+
+    ///one CBW, data and CSW round, refer usbmassbulk 5. caller holds `pipe`
+    async fn command(
+        &self,
+        lun: u8,
+        cb: &[u8],
+        data: Option<(Direction, (usize, usize))>,
+    ) -> Result<(), StorageError> {
+        let tag = self.tag.fetch_add(1, Ordering::Relaxed);
+        let (direction, data_len) = data.map_or((Direction::Out, 0), |(dir, (_, len))| (dir, len));
+        let mut moved = 0;
+
+        let mut cbw = transfer_buffer(&self.device.config.os, CBW_LEN)?;
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(data_len as u32).to_le_bytes());
+        cbw[12] = if direction == Direction::In { 0x80 } else { 0 };
+        cbw[13] = lun & 0x0f;
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        self.bulk(self.bulk_out, cbw.phys_addr_len_tuple().into())
+            .await?;
+
+        if let Some((direction, buffer)) = data {
+            let endpoint = match direction {
+                Direction::In => self.bulk_in,
+                Direction::Out => self.bulk_out,
+            };
+            //device refuses rest of data by stalling, status stage still follows
+            let stage = match self.bulk(endpoint, buffer).await {
+                Err(err) if err.is_stall() => self.clear_halt(endpoint).await.map(|_| 0),
+                other => other,
+            };
+            //host and device no longer agree on where the transport is
+            match stage {
+                Ok(length) => moved = length,
+                Err(err) => {
+                    if !matches!(err, StorageError::Unplugged) {
+                        self.reset_recovery().await?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        let csw = transfer_buffer(&self.device.config.os, CSW_LEN)?;
+        let csw_buffer: (usize, usize) = csw.phys_addr_len_tuple().into();
+        let length = match self.bulk(self.bulk_in, csw_buffer).await {
+            //refer usbmassbulk 6.7.2, clear halt and read status once more
+            Err(err) if err.is_stall() => {
+                self.clear_halt(self.bulk_in).await?;
+                self.bulk(self.bulk_in, csw_buffer).await?
+            }
+            other => other?,
+        };
+
+        match CommandStatus::of(&csw[..length.min(CSW_LEN)], tag, data_len, moved) {
+            CommandStatus::Passed => Ok(()),
+            CommandStatus::Failed => Err(StorageError::CommandFailed),
+            CommandStatus::Short { moved } => {
+                debug!(
+                    "disk at {} moved {} of {} bytes",
+                    self.device.topology_path, moved, data_len
+                );
+                Err(StorageError::ShortTransfer {
+                    expected: data_len,
+                    moved,
+                })
+            }
+            CommandStatus::Invalid => {
+                debug!(
+                    "disk at {} sent invalid status {:x?}",
+                    self.device.topology_path,
+                    &csw[..length.min(CSW_LEN)]
+                );
+                self.reset_recovery().await?;
+                Err(StorageError::PhaseError)
+            }
+        }
+    }
+
+    ///TEST UNIT READY, with sense data asked for if it failed. caller holds `pipe`
+    async fn test_unit_ready(&self, lun: u8) -> Result<UnitStatus, StorageError> {
+        match self
+            .command(lun, &[TEST_UNIT_READY, 0, 0, 0, 0, 0], None)
+            .await
+        {
+            Ok(()) => return Ok(UnitStatus::Ready),
+            Err(StorageError::CommandFailed) => {}
+            Err(err) => return Err(err),
+        }
+
+        let sense = transfer_buffer(&self.device.config.os, SENSE_LEN)?;
+        match self
+            .command(
+                lun,
+                &[REQUEST_SENSE, 0, 0, 0, SENSE_LEN as u8, 0],
+                Some((Direction::In, sense.phys_addr_len_tuple().into())),
+            )
+            .await
+        {
+            Ok(()) => {}
+            //additional sense code is all that is read
+            Err(StorageError::ShortTransfer { moved, .. }) if moved > SENSE_CODE_OFFSET => {}
+            Err(err) => return Err(err),
+        }
+        Ok(match (sense[2] & 0x0f, sense[SENSE_CODE_OFFSET]) {
+            (SENSE_NOT_READY, MEDIUM_NOT_PRESENT) => UnitStatus::NoMedium,
+            (SENSE_UNIT_ATTENTION, _) => UnitStatus::Attention,
+            _ => UnitStatus::Busy,
+        })
+    }
+
+    ///block size and block count of medium in `lun`. caller holds `pipe`
+    async fn read_capacity(&self, lun: u8) -> Result<(usize, u64), StorageError> {
+        let capacity = transfer_buffer(&self.device.config.os, 8)?;
+        self.command(
+            lun,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Some((Direction::In, capacity.phys_addr_len_tuple().into())),
+        )
+        .await?;
+        let last_lba = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap()) as usize;
+        if block_size == 0 || block_size > MAX_TRANSFER {
+            return Err(StorageError::NotReady);
+        }
+        Ok((block_size, last_lba as u64 + 1))
+    }
+
+    async fn bulk(
+        &self,
+        endpoint_id: usize,
+        buffer: (usize, usize),
+    ) -> Result<usize, StorageError> {
+        match self
+            .device
+            .request_with_length(RequestedOperation::Bulk(BulkTransfer {
+                endpoint_id,
+                buffer_addr_len: buffer,
+                scatter: Vec::new(),
+                ioc_policy: IocPolicy::default(),
+                stream_id: None,
+                refill: None,
+            }))
+            .await
+        {
+            Ok((RequestResult::SlotNotEnabledError, _)) => Err(StorageError::Unplugged),
+            Ok((result, length)) => UsbError::check(Ok(result))
+                .map(|_| length)
+                .map_err(StorageError::Transfer),
+            Err(code) => Err(StorageError::Transfer(UsbError::UnknownCode(code))),
+        }
+    }
+
+    async fn clear_halt(&self, endpoint_id: usize) -> Result<(), StorageError> {
+        match self.device.clear_stall(endpoint_id).await {
+            Ok(RequestResult::SlotNotEnabledError) => Err(StorageError::Unplugged),
+            result => UsbError::check(result)
+                .map(|_| ())
+                .map_err(StorageError::Transfer),
+        }
+    }
+
+    ///refer usbmassbulk 5.3.4
+    async fn reset_recovery(&self) -> Result<(), StorageError> {
+        warn!("disk at {} reset recovery", self.device.topology_path);
+        UsbError::check(
+            self.device
+                .request_once(RequestedOperation::Control(
+                    ControlRequestBuilder::class(Direction::Out, BULK_ONLY_RESET)
+                        .interface(self.interface.interface.interface_number)
+                        .build(),
+                ))
+                .await,
+        )
+        .map_err(StorageError::Transfer)?;
+        self.clear_halt(self.bulk_in).await?;
+        self.clear_halt(self.bulk_out).await
+    }
+}
+
+///block device handle of one LUN of a bulk only disk.
+///
+///LUNs of one device share its pipe, commands to them are run one after another
+pub struct MassStorage<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    transport: Arc<BulkOnly<O, RING_BUFFER_SIZE>>,
+    lun: u8,
+    state: RwLock<StorageState>,
+    ///block size and block count of medium in it, None without medium
+    capacity: SpinCell<Option<(usize, u64)>>,
+    ///bumped whenever medium got inserted, removed or swapped
+    media_generation: AtomicU64,
+    ///written since last cache flush
+    dirty: AtomicBool,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> MassStorage<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    fn new(transport: Arc<BulkOnly<O, RING_BUFFER_SIZE>>, lun: u8) -> Self {
+        Self {
+            transport,
+            lun,
+            state: RwLock::new(StorageState::Probing),
+            capacity: SpinCell::new(None),
+            media_generation: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn device(&self) -> &Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        &self.transport.device
+    }
+
+    pub fn lun(&self) -> u8 {
+        self.lun
+    }
+
+    pub fn block_size(&self) -> Option<usize> {
+        self.capacity().map(|(size, _)| size)
+    }
+
+    pub fn block_count(&self) -> Option<u64> {
+        self.capacity().map(|(_, count)| count)
+    }
+
+    fn capacity(&self) -> Option<(usize, u64)> {
+        self.capacity.with(|capacity| *capacity)
+    }
+
+    ///changes whenever medium changed, cached blocks of an older generation are stale
+    pub fn media_generation(&self) -> u64 {
+        self.media_generation.load(Ordering::Acquire)
+    }
+
+    pub async fn state(&self) -> StorageState {
+        *self.state.read().await
+    }
+
+    ///writes not yet flushed by [`Self::flush`] or [`Self::eject`]
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    pub async fn read_blocks(&self, lba: u32, buffer: &mut [u8]) -> Result<(), StorageError> {
+        let _pipe = self.transport.pipe.lock().await;
+        let (block_size, chunk) = self.check_io(lba, buffer.len()).await?;
+        let dma = transfer_buffer(&self.device().config.os, chunk)?;
+        let (addr, _): (usize, usize) = dma.phys_addr_len_tuple().into();
+        for (idx, part) in buffer.chunks_mut(chunk).enumerate() {
+            let lba = lba + (idx * chunk / block_size) as u32;
+            let cb = rw_10(READ_10, lba, (part.len() / block_size) as u16);
+            self.command(&cb, Some((Direction::In, (addr, part.len()))))
+                .await?;
+            part.copy_from_slice(&dma[..part.len()]);
+        }
+        Ok(())
+    }
+
+    ///data may sit in device write cache until [`Self::flush`]
+    pub async fn write_blocks(&self, lba: u32, buffer: &[u8]) -> Result<(), StorageError> {
+        let _pipe = self.transport.pipe.lock().await;
+        let (block_size, chunk) = self.check_io(lba, buffer.len()).await?;
+        let mut dma = transfer_buffer(&self.device().config.os, chunk)?;
+        let (addr, _): (usize, usize) = dma.phys_addr_len_tuple().into();
+        for (idx, part) in buffer.chunks(chunk).enumerate() {
+            let lba = lba + (idx * chunk / block_size) as u32;
+            let cb = rw_10(WRITE_10, lba, (part.len() / block_size) as u16);
+            dma[..part.len()].copy_from_slice(part);
+            self.dirty.store(true, Ordering::Release);
+            self.command(&cb, Some((Direction::Out, (addr, part.len()))))
+                .await?;
+        }
+        Ok(())
+    }
+
+    ///SYNCHRONIZE CACHE, written blocks are on the medium once this returns Ok
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        let _pipe = self.transport.pipe.lock().await;
+        self.check_state().await?;
+        self.synchronize_cache().await
+    }
+
+    ///flush, stop unit, and release endpoints once every LUN of the device got ejected.
+    ///device could be pulled once this returned Ok for all of them.
+    ///
+    ///commands queued behind it fail with [`StorageError::Ejected`].
+    ///if flush fails, disk stays usable and nothing else is done.
+    pub async fn eject(&self) -> Result<(), StorageError> {
+        let pipe = self.transport.pipe.lock().await;
+        let had_medium = match self.check_state().await {
+            Ok(()) => true,
+            Err(StorageError::NoMedium) => false,
+            Err(err) => return Err(err),
+        };
+        let previous = core::mem::replace(&mut *self.state.write().await, StorageState::Ejecting);
+
+        if had_medium && let Err(err) = self.synchronize_cache().await {
+            warn!(
+                "disk at {} LUN {} flush failed, not ejected: {}",
+                self.device().topology_path,
+                self.lun,
+                err
+            );
+            self.set_state(previous).await;
+            return Err(err);
+        }
+        //medium is flushed already, unit refusing to stop loses nothing
+        if let Err(err) = self
+            .command(&[START_STOP_UNIT, 0, 0, 0, STOP_AND_EJECT, 0], None)
+            .await
+        {
+            debug!(
+                "disk at {} LUN {} refused to stop: {}",
+                self.device().topology_path,
+                self.lun,
+                err
+            );
+        }
+        if self.transport.active_luns.fetch_sub(1, Ordering::AcqRel) == 1
+            && let Err(err) = self
+                .device()
+                .release_function(self.transport.interface.clone())
+                .await
+        {
+            //endpoints are dropped with the device at the latest
+            debug!(
+                "releasing interface of disk at {} failed: {}",
+                self.device().topology_path,
+                err
+            );
+        }
+        self.set_state(StorageState::ReadyForRemoval).await;
+        drop(pipe);
+
+        info!(
+            "disk at {} LUN {} ready for removal",
+            self.device().topology_path,
+            self.lun
+        );
+        Ok(())
+    }
+
+    ///first look at the unit, a missing medium is not an error
+    async fn probe(&self) -> Result<(), StorageError> {
+        let _pipe = self.transport.pipe.lock().await;
+        //first command after power on usually gets unit attention
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.transport.test_unit_ready(self.lun).await? {
+                UnitStatus::Ready => return self.medium_inserted().await,
+                UnitStatus::NoMedium => {
+                    self.set_state(StorageState::NoMedium).await;
+                    return Ok(());
+                }
+                _ if attempt == READY_RETRIES => return Err(StorageError::NotReady),
+                _ => sleep(&self.device().config.os, READY_RETRY_INTERVAL).await,
+            }
+        }
+    }
+
+    ///one TEST UNIT READY, picks up medium inserted, removed or swapped since last poll
+    async fn poll_medium(&self) -> Result<(), StorageError> {
+        let _pipe = self.transport.pipe.lock().await;
+        let state = self.state().await;
+        if !matches!(state, StorageState::Ready | StorageState::NoMedium) {
+            return Ok(());
+        }
+        match (state, self.transport.test_unit_ready(self.lun).await?) {
+            (StorageState::NoMedium, UnitStatus::Ready) => self.medium_inserted().await,
+            //swapped between two polls, new one is picked up by the next poll
+            (StorageState::Ready, UnitStatus::NoMedium | UnitStatus::Attention) => {
+                self.medium_removed().await;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///caller holds `pipe`
+    async fn medium_inserted(&self) -> Result<(), StorageError> {
+        let capacity = self.transport.read_capacity(self.lun).await?;
+        self.capacity.with(|current| *current = Some(capacity));
+        self.media_generation.fetch_add(1, Ordering::AcqRel);
+        if self.state().await == StorageState::NoMedium {
+            info!(
+                "disk at {} LUN {} medium inserted, {} blocks of {} bytes",
+                self.device().topology_path,
+                self.lun,
+                capacity.1,
+                capacity.0
+            );
+        }
+        self.set_state(StorageState::Ready).await;
+        Ok(())
+    }
+
+    async fn medium_removed(&self) {
+        if self.is_dirty() {
+            warn!(
+                "disk at {} LUN {} medium removed without flush, cached writes may be lost",
+                self.device().topology_path,
+                self.lun
+            );
+            self.dirty.store(false, Ordering::Release);
+        } else {
+            info!(
+                "disk at {} LUN {} medium removed",
+                self.device().topology_path,
+                self.lun
+            );
+        }
+        self.capacity.with(|capacity| *capacity = None);
+        self.media_generation.fetch_add(1, Ordering::AcqRel);
+        self.set_state(StorageState::NoMedium).await;
+    }
+
+    ///called from teardown, warns if cached writes were never flushed
+    fn unplugged(&self) {
+        let mut state = block_on(self.state.write());
+        if *state != StorageState::ReadyForRemoval && self.is_dirty() {
+            warn!(
+                "disk at {} LUN {} unplugged without eject, cached writes may be lost",
+                self.device().topology_path,
+                self.lun
+            );
+        }
+        *state = StorageState::Unplugged;
+    }
+
+    async fn set_state(&self, state: StorageState) {
+        *self.state.write().await = state;
+    }
+
+    async fn check_state(&self) -> Result<(), StorageError> {
+        match *self.state.read().await {
+            StorageState::Ready => Ok(()),
+            StorageState::Probing => Err(StorageError::NotReady),
+            StorageState::NoMedium => Err(StorageError::NoMedium),
+            StorageState::Ejecting | StorageState::ReadyForRemoval => Err(StorageError::Ejected),
+            StorageState::Unplugged => Err(StorageError::Unplugged),
+        }
+    }
+
+    ///returns block size, and bytes moved per command
+    async fn check_io(&self, lba: u32, len: usize) -> Result<(usize, usize), StorageError> {
+        self.check_state().await?;
+        let (block_size, block_count) = self.capacity().ok_or(StorageError::NoMedium)?;
+        if len % block_size != 0 || lba as u64 + (len / block_size) as u64 > block_count {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok((
+            block_size,
+            (MAX_TRANSFER / block_size * block_size).min(len.max(block_size)),
+        ))
+    }
+
+    async fn synchronize_cache(&self) -> Result<(), StorageError> {
+        //zero blocks means whole medium
+        self.command(&[SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], None)
+            .await?;
+        self.dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    ///command to this LUN, caller holds `pipe`
+    async fn command(
+        &self,
+        cb: &[u8],
+        data: Option<(Direction, (usize, usize))>,
+    ) -> Result<(), StorageError> {
+        self.transport.command(self.lun, cb, data).await
+    }
+}
+
+///READ(10)/WRITE(10) command block
+fn rw_10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cb = [0u8; 10];
+    cb[0] = opcode;
+    cb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cb
+}
+
+///zeroed buffer for one transfer, allocator running dry fails the command
+fn transfer_buffer<O: PlatformAbstractions>(
+    os: &O,
+    len: usize,
+) -> Result<DMA<[u8], O>, StorageError> {
+    DMA::try_new_vec(0u8, len, 64, os.dma_alloc())
+        .map_err(|_| StorageError::Transfer(UsbError::OutOfMemory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandStatus, CSW_FAILED, CSW_PASSED, CSW_SIGNATURE};
+
+    const TAG: u32 = 7;
+
+    fn csw(residue: u32, status: u8) -> [u8; 13] {
+        let mut csw = [0u8; 13];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&TAG.to_le_bytes());
+        csw[8..12].copy_from_slice(&residue.to_le_bytes());
+        csw[12] = status;
+        csw
+    }
+
+    #[test]
+    fn whole_data_stage_passes() {
+        assert_eq!(
+            CommandStatus::of(&csw(0, CSW_PASSED), TAG, 512, 512),
+            CommandStatus::Passed
+        );
+        assert_eq!(
+            CommandStatus::of(&csw(0, CSW_PASSED), TAG, 0, 0),
+            CommandStatus::Passed
+        );
+    }
+
+    #[test]
+    fn short_data_stage_is_short() {
+        assert_eq!(
+            CommandStatus::of(&csw(0, CSW_PASSED), TAG, 512, 256),
+            CommandStatus::Short { moved: 256 }
+        );
+    }
+
+    #[test]
+    fn residue_is_short_even_if_data_stage_was_whole() {
+        assert_eq!(
+            CommandStatus::of(&csw(512, CSW_PASSED), TAG, 1024, 1024),
+            CommandStatus::Short { moved: 512 }
+        );
+    }
+
+    #[test]
+    fn failed_command_stays_failed() {
+        assert_eq!(
+            CommandStatus::of(&csw(512, CSW_FAILED), TAG, 512, 0),
+            CommandStatus::Failed
+        );
+    }
+
+    #[test]
+    fn broken_status_is_invalid() {
+        let status = csw(0, CSW_PASSED);
+        assert_eq!(
+            CommandStatus::of(&status[..12], TAG, 0, 0),
+            CommandStatus::Invalid
+        );
+        assert_eq!(
+            CommandStatus::of(&status, TAG + 1, 0, 0),
+            CommandStatus::Invalid
+        );
+        assert_eq!(
+            CommandStatus::of(&csw(1024, CSW_PASSED), TAG, 512, 512),
+            CommandStatus::Invalid
+        );
+        assert_eq!(
+            CommandStatus::of(&csw(0, 2), TAG, 512, 512),
+            CommandStatus::Invalid
+        );
+    }
+}
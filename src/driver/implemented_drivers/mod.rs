@@ -1 +1,6 @@
+#[cfg(feature = "driver-hid")]
 pub mod hid_mouse;
+#[cfg(feature = "driver-hub")]
+pub mod hub;
+#[cfg(feature = "driver-mass-storage")]
+pub mod mass_storage;
@@ -0,0 +1,114 @@
+use core::{
+    fmt::{self, Display},
+    future::Future,
+    pin::Pin,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::{format, sync::Arc};
+use log::{Log, Metadata, Record};
+
+use crate::usb::standards::TopologyRoute;
+
+///context being polled right now, null outside of driver instances
+static CURRENT: AtomicPtr<DriverContext> = AtomicPtr::new(ptr::null_mut());
+
+///label of a driver instance, e.g. `hub@1.3`, given by usb layer when instance is bound
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverContext(Arc<str>);
+
+impl DriverContext {
+    pub fn new(driver: &str, device: &TopologyRoute) -> Self {
+        Self(format!("{}@{}", driver, device).into())
+    }
+
+    pub fn label(&self) -> &str {
+        &self.0
+    }
+
+    ///run `f` with this context current, the outer one is restored afterwards
+    pub fn scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = CURRENT.swap(self as *const _ as *mut _, Ordering::AcqRel);
+        let result = f();
+        CURRENT.store(previous, Ordering::Release);
+        result
+    }
+
+    ///context of driver instance running right now, if any.
+    ///
+    ///assumes driver instances are polled by a single executor, like [`crate::USBSystem::async_run`] does
+    pub fn with_current<R>(f: impl FnOnce(Option<&DriverContext>) -> R) -> R {
+        //safety: only non-null inside `scope`, which borrows the context it points to
+        f(unsafe { CURRENT.load(Ordering::Acquire).as_ref() })
+    }
+
+    ///make this context current whenever `future` is polled
+    pub fn wrap<F: Future + Unpin>(self, future: F) -> WithContext<F> {
+        WithContext {
+            context: self,
+            future,
+        }
+    }
+}
+
+impl Display for DriverContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+///see [`DriverContext::wrap`]
+pub struct WithContext<F> {
+    context: DriverContext,
+    future: F,
+}
+
+impl<F: Future + Unpin> Future for WithContext<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let future = &mut this.future;
+        this.context.scope(|| Pin::new(future).poll(cx))
+    }
+}
+
+///wraps platform logger, lines logged from within a driver instance get its label as prefix.
+///
+///install it in place of the platform logger, e.g. `log::set_logger(Box::leak(Box::new(ContextLogger::new(logger))))`
+pub struct ContextLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> ContextLogger<L> {
+    pub const fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for ContextLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        DriverContext::with_current(|context| match context {
+            Some(context) => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", context, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.inner.log(record),
+        })
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
@@ -1,3 +1,12 @@
+pub mod bulk_out;
+pub mod device_node;
 pub mod driverapi;
-#[cfg(feature = "packed-drivers")]
+#[cfg(any(
+    feature = "driver-hid",
+    feature = "driver-hub",
+    feature = "driver-mass-storage"
+))]
 pub mod implemented_drivers;
+pub mod log_context;
+pub mod status_endpoint;
+pub mod timing;
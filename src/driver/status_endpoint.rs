@@ -0,0 +1,147 @@
+use core::marker::PhantomData;
+
+use alloc::{sync::Arc, vec::Vec};
+use futures::Stream;
+use log::{trace, warn};
+use usb_descriptor_decoder::descriptors::desc_endpoint::{Endpoint, EndpointType};
+
+use crate::{
+    abstractions::{dma::DMA, PlatformAbstractions},
+    host::device::USBDevice,
+    usb::operations::{
+        interrupt::{CompletionDeadline, DeadlineStats, PeriodicStream},
+        UsbError,
+    },
+};
+
+///buffers a status endpoint is filled over, see [`StatusEndpointListener`]
+pub const STATUS_BUFFERS: usize = 2;
+
+///payload of an interrupt IN status endpoint, e.g. hub change bitmap or CDC notification
+pub trait StatusNotification: Sized + Send {
+    ///None if `raw` is not a valid notification, such fills are skipped
+    fn decode(raw: &[u8]) -> Option<Self>;
+}
+
+///undecoded bytes, for classes that parse notifications themselves
+impl StatusNotification for Vec<u8> {
+    fn decode(raw: &[u8]) -> Option<Self> {
+        Some(raw.to_vec())
+    }
+}
+
+///keeps an interrupt IN status endpoint filling, and hands out decoded notifications.
+///
+///controller fills [`STATUS_BUFFERS`] buffers in turn and hands each over once filled, it gets
+///it back only after decoding. notifications come in order, none is overwritten; once every
+///buffer is waiting to be taken, endpoint pauses until [`Self::next`] is called again.
+///
+///buffers must outlive the endpoint, release the function before dropping the listener.
+pub struct StatusEndpointListener<O, N, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+    N: StatusNotification,
+{
+    device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    endpoint_id: usize,
+    buffers: Vec<DMA<[u8], O>>,
+    stream: Arc<PeriodicStream>,
+    __marker: PhantomData<N>,
+}
+
+impl<O, N, const RING_BUFFER_SIZE: usize> StatusEndpointListener<O, N, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+    N: StatusNotification,
+{
+    ///endpoint must be an interrupt IN of an already enabled function
+    pub async fn start(
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        endpoint: &Endpoint,
+    ) -> Result<Self, UsbError>
+    where
+        O: 'static,
+    {
+        let endpoint_id = endpoint.doorbell_value_aka_dci() as usize;
+        if endpoint.endpoint_type() != EndpointType::InterruptIn {
+            warn!(
+                "endpoint {} of {:?} is no status endpoint",
+                endpoint_id,
+                endpoint.endpoint_type()
+            );
+            return Err(UsbError::BadDescriptor);
+        }
+        let len = (endpoint.max_packet_size & 0x7ff).max(1) as usize;
+        let buffers = (0..STATUS_BUFFERS)
+            .map(|_| {
+                DMA::try_new_vec(0u8, len, 64, device.config.os.dma_alloc())
+                    .map_err(|_| UsbError::OutOfMemory)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let stream = device
+            .keep_interrupt_rotating(
+                endpoint_id,
+                buffers
+                    .iter()
+                    .map(|buffer| buffer.phys_addr_len_tuple().into())
+                    .collect(),
+            )
+            .await?;
+        trace!("status endpoint {} listening", endpoint_id);
+
+        Ok(Self {
+            device,
+            endpoint_id,
+            buffers,
+            stream,
+            __marker: PhantomData,
+        })
+    }
+
+    pub fn endpoint_id(&self) -> usize {
+        self.endpoint_id
+    }
+
+    pub fn device(&self) -> &Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        &self.device
+    }
+
+    ///warn through event bus when notifications keep being taken later than `deadline`
+    pub fn set_deadline(&self, deadline: CompletionDeadline) {
+        self.stream.set_deadline(deadline)
+    }
+
+    pub fn deadline_stats(&self) -> DeadlineStats {
+        self.stream.deadline_stats()
+    }
+
+    ///wait for next notification that decodes, None once endpoint stopped filling,
+    ///e.g. its function got released or device is gone
+    pub async fn next(&self) -> Option<N> {
+        loop {
+            let (idx, length) = self.stream.next_filled().await?;
+            let buffer = &self.buffers[idx];
+            let notification = N::decode(&buffer[..length.min(buffer.len())]);
+            //decoded into an owned value, buffer goes back to controller
+            self.device
+                .release_interrupt_buffer(self.endpoint_id, &self.stream, idx)
+                .await;
+            match notification {
+                Some(notification) => return Some(notification),
+                None => trace!(
+                    "status endpoint {} undecodable fill skipped",
+                    self.endpoint_id
+                ),
+            }
+        }
+    }
+
+    ///notifications as a stream, ends once endpoint stopped filling
+    pub fn into_stream(self) -> impl Stream<Item = N> {
+        futures::stream::unfold(self, |listener| async move {
+            let notification = listener.next().await?;
+            Some((notification, listener))
+        })
+    }
+}
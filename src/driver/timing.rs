@@ -0,0 +1,26 @@
+//! waiting inside driver instances, which run on whatever executor the platform gives them
+
+use core::time::Duration;
+
+use embassy_futures::yield_now;
+
+use crate::abstractions::PlatformAbstractions;
+
+///wait `duration` by clock source of `os`, without one just give others some turns
+pub async fn sleep<O: PlatformAbstractions>(os: &O, duration: Duration) {
+    match os.now() {
+        Some(start) => {
+            while os
+                .now()
+                .is_some_and(|now| now.saturating_sub(start) < duration)
+            {
+                yield_now().await
+            }
+        }
+        None => {
+            for _ in 0..duration.as_millis() {
+                yield_now().await
+            }
+        }
+    }
+}
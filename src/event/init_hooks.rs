@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use async_lock::RwLock;
+use futures::future::BoxFuture;
+use log::debug;
+
+use crate::{abstractions::PlatformAbstractions, host::device::USBDevice};
+
+///what an [`InitHook`] decided about a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitVerdict {
+    Proceed,
+    ///device is left rejected, hooks after this one are not asked
+    Veto,
+}
+
+///awaited before device is enumerated, it may take its time, e.g. until a security module approves
+pub type InitHook<'a, O, const RING_BUFFER_SIZE: usize> =
+    dyn Fn(Arc<USBDevice<O, RING_BUFFER_SIZE>>) -> BoxFuture<'a, InitVerdict> + Send + Sync + 'a;
+
+///returned by [`InitHooks::register`], takes the hook out again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitHookId(u64);
+
+struct Registered<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    id: InitHookId,
+    priority: i32,
+    name: String,
+    hook: Arc<InitHook<'a, O, RING_BUFFER_SIZE>>,
+}
+
+///hooks run one after another before a device gets its address, lower priority first,
+///equal ones in registration order. unlike [`super::EventBus::pre_initialize_device`]
+///they can hold enumeration back or veto it.
+///
+///only topology of device is known by then, identity based rules belong to
+///[`crate::abstractions::filter::DeviceFilter`]
+pub struct InitHooks<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    hooks: RwLock<Vec<Registered<'a, O, RING_BUFFER_SIZE>>>,
+    next_id: AtomicU64,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> InitHooks<'a, O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    pub fn new() -> Self {
+        Self {
+            hooks: RwLock::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    ///`name` shows up in logs once hook vetoed a device
+    pub async fn register(
+        &self,
+        name: impl Into<String>,
+        priority: i32,
+        hook: Arc<InitHook<'a, O, RING_BUFFER_SIZE>>,
+    ) -> InitHookId {
+        let id = InitHookId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut hooks = self.hooks.write().await;
+        let at = hooks.partition_point(|registered| registered.priority <= priority);
+        hooks.insert(
+            at,
+            Registered {
+                id,
+                priority,
+                name: name.into(),
+                hook,
+            },
+        );
+        id
+    }
+
+    ///false if no such hook, e.g. removed already
+    pub async fn unregister(&self, id: InitHookId) -> bool {
+        let mut hooks = self.hooks.write().await;
+        let before = hooks.len();
+        hooks.retain(|registered| registered.id != id);
+        hooks.len() != before
+    }
+
+    ///name of the hook that vetoed device, None if all of them let it proceed
+    pub(crate) async fn run(&self, device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>) -> Option<String> {
+        //hooks may register or remove others while running
+        let hooks: Vec<_> = self
+            .hooks
+            .read()
+            .await
+            .iter()
+            .map(|registered| (registered.name.clone(), registered.hook.clone()))
+            .collect();
+        for (name, hook) in hooks {
+            if hook(device.clone()).await == InitVerdict::Veto {
+                return Some(name);
+            }
+            debug!(
+                "init hook {} passed device at {}",
+                name, device.topology_path
+            );
+        }
+        None
+    }
+}
@@ -1,20 +1,50 @@
-use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc};
+use core::time::Duration;
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
 use async_lock::RwLock;
 use squeak::Delegate;
 
 use crate::{
-    abstractions::PlatformAbstractions,
+    abstractions::{LostTdAction, PlatformAbstractions},
     driver::{self, driverapi::USBSystemDriverModuleInstanceFunctionalInterface},
-    host::device::USBDevice,
+    host::device::{EnumerationLatency, USBDevice},
+    usb::{
+        operations::{
+            interrupt::{CompletionDeadline, DeadlineStats},
+            RequestId, RequestResult,
+        },
+        power::{ConfigPower, PowerDecision},
+        standards::RouteError,
+    },
 };
 
+mod init_hooks;
+pub use init_hooks::{InitHook, InitHookId, InitHooks, InitVerdict};
+
 pub struct EventBus<'a, O, const RING_BUFFER_SIZE: usize>
 where
     O: PlatformAbstractions,
 {
     pub pre_initialize_device: Delegate<'a, Arc<USBDevice<O, RING_BUFFER_SIZE>>>,
+    ///awaited after `pre_initialize_device`, may delay or veto enumeration
+    pub init_hooks: InitHooks<'a, O, RING_BUFFER_SIZE>,
     pub post_initialized_device: Delegate<'a, Arc<USBDevice<O, RING_BUFFER_SIZE>>>,
     pub pre_drop_device: Delegate<'a, Arc<USBDevice<O, RING_BUFFER_SIZE>>>,
+    ///device got unplugged, its slot is already released and requests are refused
+    pub device_removed: Delegate<'a, Arc<USBDevice<O, RING_BUFFER_SIZE>>>,
+    pub remote_wakeup: Delegate<'a, RemoteWakeup<O, RING_BUFFER_SIZE>>,
+    ///device is back from suspend, drivers restart their interrupt polling here
+    pub device_resumed: Delegate<'a, DeviceResumed<O, RING_BUFFER_SIZE>>,
+    pub power_over_budget: Delegate<'a, PowerOverBudget<O, RING_BUFFER_SIZE>>,
+    pub deadline_missed: Delegate<'a, DeadlineMissed<O, RING_BUFFER_SIZE>>,
+    pub route_rejected: Delegate<'a, RouteRejected<O, RING_BUFFER_SIZE>>,
+    pub lost_transfer: Delegate<'a, LostTransfer<O, RING_BUFFER_SIZE>>,
+    ///device was offered to every driver module, carries how long it took to get there
+    pub device_ready: Delegate<'a, DeviceReady<O, RING_BUFFER_SIZE>>,
+    ///raised by controller for every root port status change, before debouncing
+    pub port_status_changed: Delegate<'a, PortStatusChanged>,
+    pub transfer_error: Delegate<'a, TransferError>,
+    pub controller_error: Delegate<'a, ControllerError>,
     pub new_interface: Delegate<
         'a,
         (
@@ -24,6 +54,137 @@ where
     >,
 }
 
+/// a suspended device asked to wake the system up, controller finishes waking it
+/// and follows up with [`DeviceResumed`]
+pub struct RemoteWakeup<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub cause: WakeCause,
+}
+
+/// link of device is active again and endpoints stopped for suspend are restarted
+pub struct DeviceResumed<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ///None if host resumed it, see [`USBDevice::resume`]
+    pub cause: Option<WakeCause>,
+}
+
+/// current configuration of device asks for more bus power than its port could deliver
+pub struct PowerOverBudget<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub config: ConfigPower,
+    pub decision: PowerDecision,
+    ///device got refused according to power policy
+    pub refused: bool,
+}
+
+/// periodic endpoint with a declared deadline kept being taken late,
+/// usually scheduler or wake method of the platform is not keeping up
+pub struct DeadlineMissed<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub endpoint_id: usize,
+    pub deadline: CompletionDeadline,
+    pub stats: DeadlineStats,
+}
+
+/// device showed up where route string can't address it, e.g. behind a 6th hub.
+/// no slot is made for it, it stays attached but unusable
+pub struct RouteRejected<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub hub: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub port: u8,
+    ///root port, then every hub port down to the offending one
+    pub chain: Vec<u8>,
+    pub error: RouteError,
+}
+
+/// a TD saw no event within [`crate::abstractions::WatchdogPolicy::after`]
+pub struct LostTransfer<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub endpoint_id: usize,
+    pub request: RequestId,
+    pub waited: Duration,
+    ///what watchdog did about it
+    pub action: LostTdAction,
+}
+
+/// enumeration of device is over, see [`USBDevice::enumeration_latency`]
+pub struct DeviceReady<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub latency: EnumerationLatency,
+}
+
+/// root port status as controller reported it, raised on every change before
+/// [`crate::abstractions::USBSystemConfig::port_debounce`] settles it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatusChanged {
+    ///position in [`crate::abstractions::USBSystemConfig::controller_descs`]
+    pub controller: u8,
+    ///1 based, as controller numbers its ports
+    pub port: u8,
+    pub connected: bool,
+    pub enabled: bool,
+    pub over_current: bool,
+    ///connect status changed since last event
+    pub connect_changed: bool,
+    ///speed id of attached device, 0 if none
+    pub speed: u8,
+}
+
+/// a transfer completed with an error, e.g. stall, babble or transaction error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferError {
+    pub controller: u8,
+    pub slot: u8,
+    ///DCI, as in transfers
+    pub endpoint_id: usize,
+    pub code: Result<RequestResult, u8>,
+}
+
+/// controller itself ran into trouble, devices on it may be gone with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerError {
+    pub controller: u8,
+    pub kind: ControllerErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerErrorKind {
+    ///controller could not reach system memory and halted
+    HostSystemError,
+    ///controller detected an internal error and halted, needs a reset
+    HostControllerError,
+    ///host controller event, e.g. event ring full
+    Event(Result<RequestResult, u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeCause {
+    ///usb2 style resume signaling seen on root port
+    PortResume { port: u8 },
+    ///usb3 function wake device notification
+    FunctionWake { interface: u8 },
+}
+
 unsafe impl<'a, O, const RING_BUFFER_SIZE: usize> Sync for EventBus<'a, O, RING_BUFFER_SIZE>
 //safety: it wont mut
 where
@@ -44,8 +205,20 @@ where
         Self {
             post_initialized_device: Delegate::new(),
             pre_drop_device: Delegate::new(),
+            device_removed: Delegate::new(),
+            remote_wakeup: Delegate::new(),
+            device_resumed: Delegate::new(),
+            power_over_budget: Delegate::new(),
+            deadline_missed: Delegate::new(),
+            route_rejected: Delegate::new(),
+            lost_transfer: Delegate::new(),
+            device_ready: Delegate::new(),
+            port_status_changed: Delegate::new(),
+            transfer_error: Delegate::new(),
+            controller_error: Delegate::new(),
             new_interface: Delegate::new(),
             pre_initialize_device: Delegate::new(),
+            init_hooks: InitHooks::new(),
         }
     }
 }
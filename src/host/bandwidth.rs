@@ -0,0 +1,152 @@
+//! periodic bandwidth of each bus, estimated before endpoints of an interface get configured.
+//!
+//! like [`crate::usb::operations::hub::TtBandwidth`], costs are averaged over endpoint intervals
+//! and no microframe is actually scheduled. controller still has the last word on it
+
+use alloc::collections::btree_map::BTreeMap;
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::EndpointType, desc_interface::USBInterface,
+};
+
+use crate::{
+    abstractions::spin::SpinCell,
+    usb::{
+        companion::SsEndpointCompanion,
+        operations::{hub::DeviceSpeed, UsbError},
+        standards::TopologyRoute,
+    },
+};
+
+///token, handshake and gaps of one transaction rounded up, refer usb2 spec 5.11.3
+const TRANSACTION_OVERHEAD: u32 = 13;
+const MICROFRAMES_PER_FRAME: u32 = 8;
+
+///bytes per microframe periodic transfers may take on a bus of `speed`.
+///90% of a full speed frame, 80% of a high speed microframe, 90% of a superspeed one after 8b/10b
+pub fn periodic_budget(speed: DeviceSpeed) -> u32 {
+    match speed {
+        //low speed costs are scaled to full speed bytes
+        DeviceSpeed::Low | DeviceSpeed::Full => 1500 * 9 / 10 / MICROFRAMES_PER_FRAME,
+        DeviceSpeed::High => 7500 * 8 / 10,
+        DeviceSpeed::Super => 62500 * 9 / 10,
+    }
+}
+
+///bytes per microframe periodic endpoints of `interface` take, 0 without any.
+///`companion` looks up superspeed companion by endpoint address
+pub fn interface_cost(
+    speed: DeviceSpeed,
+    interface: &USBInterface,
+    companion: impl Fn(u8) -> Option<SsEndpointCompanion>,
+) -> u32 {
+    interface
+        .endpoints
+        .iter()
+        .filter_map(|endpoint| {
+            let frames = match endpoint.endpoint_type() {
+                EndpointType::InterruptIn | EndpointType::InterruptOut => {
+                    speed.needs_tt() && endpoint.interval > 0
+                }
+                EndpointType::IsochIn | EndpointType::IsochOut => false,
+                _ => return None,
+            };
+            //full/low speed interrupt intervals are in frames, everything else is an exponent
+            let interval = if frames {
+                endpoint.interval as u32 * MICROFRAMES_PER_FRAME
+            } else {
+                let interval = 1u32 << (endpoint.interval.clamp(1, 16) - 1);
+                if speed.needs_tt() {
+                    interval * MICROFRAMES_PER_FRAME
+                } else {
+                    interval
+                }
+            };
+
+            let dci = endpoint.doorbell_value_aka_dci() as u8;
+            let address = (dci / 2) | if dci % 2 == 1 { 0x80 } else { 0 };
+            let packet = (endpoint.max_packet_size & 0x7ff) as u32;
+            let payload = match (speed, companion(address)) {
+                (DeviceSpeed::Super, Some(companion)) if companion.bytes_per_interval > 0 => {
+                    companion.bytes_per_interval as u32
+                }
+                (DeviceSpeed::Super, Some(companion)) => {
+                    packet * (companion.max_burst as u32 + 1) * (companion.mult() as u32 + 1)
+                }
+                //additional transactions per microframe, refer usb2 spec 9.6.6
+                (DeviceSpeed::High, _) => {
+                    packet * (1 + ((endpoint.max_packet_size >> 11) & 0x3) as u32)
+                }
+                _ => packet,
+            } + TRANSACTION_OVERHEAD;
+            //low speed bits are 8 times as long
+            let payload = if speed == DeviceSpeed::Low {
+                payload * 8
+            } else {
+                payload
+            };
+            Some(payload.div_ceil(interval))
+        })
+        .sum()
+}
+
+///periodic load of every bus of one controller.
+///
+///each root port is a bus of its own, shared by every device below it. devices behind a TT
+///are left to [`crate::usb::operations::hub::TtBandwidth`]
+#[derive(Default)]
+pub struct BandwidthLedger {
+    ///(controller, root port idx) -> (device route, interface number) -> bytes per microframe
+    buses: SpinCell<BTreeMap<(u8, u8), BTreeMap<(TopologyRoute, u8), u32>>>,
+}
+
+impl BandwidthLedger {
+    fn bus_of(route: &TopologyRoute) -> (u8, u8) {
+        (route.controller(), route.port_idx())
+    }
+
+    ///nothing is claimed if bus can't take it. claiming an interface again replaces its old claim
+    pub fn claim(
+        &self,
+        route: &TopologyRoute,
+        interface: u8,
+        speed: DeviceSpeed,
+        cost: u32,
+    ) -> Result<(), UsbError> {
+        let budget = periodic_budget(speed);
+        self.buses.with(|buses| {
+            let claims = buses.entry(Self::bus_of(route)).or_default();
+            let key = (route.clone(), interface);
+            let used: u32 = claims
+                .iter()
+                .filter(|(claimed, _)| **claimed != key)
+                .map(|(_, cost)| cost)
+                .sum();
+            if used + cost > budget {
+                return Err(UsbError::Bandwidth {
+                    required: cost,
+                    available: budget.saturating_sub(used),
+                });
+            }
+            claims.insert(key, cost);
+            Ok(())
+        })
+    }
+
+    pub fn release(&self, route: &TopologyRoute, interface: u8) {
+        self.release_where(|(claimed, number)| claimed == route && *number == interface)
+    }
+
+    ///every interface of device at `route`, e.g. once it got deconfigured or unplugged
+    pub fn release_device(&self, route: &TopologyRoute) {
+        self.release_where(|(claimed, _)| claimed == route)
+    }
+
+    fn release_where(&self, released: impl Fn(&(TopologyRoute, u8)) -> bool) {
+        self.buses.with(|buses| {
+            buses.values_mut().for_each(|claims| {
+                claims.retain(|claimed, _| !released(claimed));
+            });
+            buses.retain(|_, claims| !claims.is_empty());
+        })
+    }
+}
@@ -0,0 +1,500 @@
+//! bookkeeping every backend does the same way, whatever its rings or schedules look like
+
+use core::{
+    future::poll_fn,
+    hint::spin_loop,
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use async_lock::OnceCell;
+use async_ringbuf::traits::AsyncObserver;
+use embassy_futures::yield_now;
+use futures::task::AtomicWaker;
+use log::{debug, info, trace};
+use ringbuf::traits::Observer;
+
+use crate::{
+    abstractions::{dma::cpu_view, spin::SpinCell, PlatformAbstractions, USBSystemConfig},
+    event::WakeCause,
+    host::device::{ArcAsyncRingBufCons, DeviceState, USBDevice},
+    usb::{
+        operations::{
+            interrupt::{CompletionDeadline, DeadlineStats, PeriodicStream},
+            RequestResult, USBRequest,
+        },
+        standards::TopologyRoute,
+    },
+};
+
+use super::poll_by_latency;
+
+///polls [`spin_until`] gives up after on platforms without a clock source
+const SPIN_LIMIT: usize = 1 << 24;
+
+///busy wait of sync paths like init and shutdown, false if `done` never held within `timeout`.
+///
+///without a clock source it is bounded by a poll count instead
+pub(crate) fn spin_until<O: PlatformAbstractions>(
+    os: &O,
+    timeout: Duration,
+    mut done: impl FnMut() -> bool,
+) -> bool {
+    let start = os.now();
+    let mut spins = 0;
+    while !done() {
+        let expired = match (start, os.now()) {
+            (Some(start), Some(now)) => now.saturating_sub(start) > timeout,
+            _ => {
+                spins += 1;
+                spins > SPIN_LIMIT
+            }
+        };
+        if expired {
+            return done();
+        }
+        spin_loop()
+    }
+    true
+}
+
+///requests held back while controller is quiesced, see [`super::Controller::quiesce`]
+#[derive(Default)]
+pub(crate) struct Parking {
+    quiescing: AtomicBool,
+    parked: SpinCell<Vec<(Arc<OnceCell<u8>>, USBRequest)>>,
+}
+
+impl Parking {
+    pub fn is_quiescing(&self) -> bool {
+        self.quiescing.load(Ordering::Acquire)
+    }
+
+    ///keeps `req` while quiescing, otherwise hands it back to be submitted
+    pub fn park(
+        &self,
+        req: USBRequest,
+        slot: Arc<OnceCell<u8>>,
+    ) -> Option<(USBRequest, Arc<OnceCell<u8>>)> {
+        if self.is_quiescing() {
+            self.parked.with(|parked| parked.push((slot, req)));
+            None
+        } else {
+            Some((req, slot))
+        }
+    }
+
+    pub fn quiesce(&self) {
+        self.quiescing.store(true, Ordering::Release);
+    }
+
+    ///requests parked meanwhile, in the order they came
+    pub fn resume(&self) -> Vec<(Arc<OnceCell<u8>>, USBRequest)> {
+        self.quiescing.store(false, Ordering::Release);
+        self.parked.with(core::mem::take)
+    }
+
+    pub fn parked(&self) -> usize {
+        self.parked.with(|parked| parked.len())
+    }
+
+    ///requests of a device gone meanwhile, or every one on reset
+    pub fn forget(&self, gone: impl Fn(&Arc<OnceCell<u8>>) -> bool) {
+        self.parked
+            .with(|parked| parked.retain(|(slot, _)| !gone(slot)));
+    }
+}
+
+///root ports whose connect status changed, by time of their last change
+#[derive(Default)]
+pub(crate) struct Debouncer {
+    ports: SpinCell<BTreeMap<usize, Bounce>>,
+}
+
+///last change of a port, and [`Debouncer::settled`] polls seen since for platforms without a clock
+#[derive(Clone, Copy)]
+struct Bounce {
+    since: Option<Duration>,
+    polls: u128,
+}
+
+impl Debouncer {
+    ///every bounce restarts the window
+    pub fn bounced(&self, port_idx: usize, now: Option<Duration>) {
+        self.ports.with(|ports| {
+            ports.insert(
+                port_idx,
+                Bounce {
+                    since: now,
+                    polls: 0,
+                },
+            )
+        });
+    }
+
+    ///ports that stayed stable for a whole `window`, taken out along with when they changed.
+    ///
+    ///without a clock source the window is counted in calls instead, one per millisecond of it,
+    ///the same way [`crate::driver::timing::sleep`] falls back
+    pub fn settled(
+        &self,
+        now: Option<Duration>,
+        window: Duration,
+    ) -> Vec<(usize, Option<Duration>)> {
+        self.ports.with(|ports| {
+            let settled: Vec<_> = ports
+                .iter_mut()
+                .filter_map(|(idx, bounce)| {
+                    bounce.polls += 1;
+                    let stable = match (now, bounce.since) {
+                        (Some(now), Some(since)) => now.saturating_sub(since) >= window,
+                        _ => bounce.polls > window.as_millis(),
+                    };
+                    stable.then_some((*idx, bounce.since))
+                })
+                .collect();
+            settled.iter().for_each(|(idx, _)| {
+                ports.remove(idx);
+            });
+            settled
+        })
+    }
+}
+
+///one lane of a device, see [`crate::usb::operations::RequestLane`]
+struct Receiver<const RING_BUFFER_SIZE: usize> {
+    slot: Arc<OnceCell<u8>>,
+    receiver: ArcAsyncRingBufCons<USBRequest, RING_BUFFER_SIZE>,
+}
+
+///devices on a controller, along with the lanes their requests are taken from
+pub(crate) struct Attached<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    ///looked up by event loops while attach and detach change it, copies go out, never references
+    devices: SpinCell<Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>>>,
+    ///one per lane of every device, drained by [`Self::next`] under the lock, dispatched after it
+    requests: SpinCell<Vec<Receiver<RING_BUFFER_SIZE>>>,
+    ///[`Self::next`] waiting on `requests`, a freshly attached device has not taken its waker yet
+    arrivals: AtomicWaker,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> Default for Attached<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    fn default() -> Self {
+        Self {
+            devices: SpinCell::new(Vec::new()),
+            requests: SpinCell::new(Vec::new()),
+            arrivals: AtomicWaker::new(),
+        }
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> Attached<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///`receivers` are lanes [`USBDevice::new`] handed out along with `device`, drained side by side
+    pub fn attach(
+        &self,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        receivers: Vec<ArcAsyncRingBufCons<USBRequest, RING_BUFFER_SIZE>>,
+    ) {
+        self.devices.with(|devices| devices.push(device.clone()));
+        self.requests.with(|requests| {
+            requests.extend(receivers.into_iter().map(|receiver| Receiver {
+                slot: device.slot_id.clone(),
+                receiver,
+            }))
+        });
+        self.arrivals.wake();
+    }
+
+    ///a copy, attach and detach may change them any time
+    pub fn all(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.devices.with(|devices| devices.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.with(|devices| devices.len())
+    }
+
+    ///whole route is compared, a device behind a hub on a root port is not the one on that port
+    pub fn at(&self, route: &TopologyRoute) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.devices.with(|devices| {
+            devices
+                .iter()
+                .find(|dev| dev.topology_path == *route)
+                .cloned()
+        })
+    }
+
+    ///by slot id, or device address where backend has no slots
+    pub fn of_slot(&self, slot_id: u8) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.devices.with(|devices| {
+            devices
+                .iter()
+                .find(|dev| dev.slot_id.get() == Some(&slot_id))
+                .cloned()
+        })
+    }
+
+    ///device at `route` and everything behind it, if it's a hub, deepest ones first
+    pub fn take_route(&self, route: &TopologyRoute) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        let mut gone: Vec<_> = self.devices.with(|devices| {
+            let (gone, kept) = mem::take(devices)
+                .into_iter()
+                .partition(|dev| dev.topology_path == *route || dev.topology_path.is_behind(route));
+            *devices = kept;
+            gone
+        });
+        gone.sort_by_key(|dev| core::cmp::Reverse(dev.topology_path.depth()));
+        gone
+    }
+
+    ///device refuses further requests, its receivers are dropped on next [`Self::next`]
+    pub async fn stop_taking(&self, device: &USBDevice<O, RING_BUFFER_SIZE>) {
+        *device.state.write().await = DeviceState::PreDrop;
+        device.mark_gone().await;
+        self.requests.with(|requests| {
+            requests
+                .iter()
+                .filter(|r| Arc::ptr_eq(&r.slot, &device.slot_id))
+                .for_each(|r| r.receiver.close())
+        });
+    }
+
+    ///every device at once, on shutdown or reset. nothing is announced
+    pub async fn forget(&self) {
+        for device in self.devices.with(mem::take) {
+            *device.state.write().await = DeviceState::PreDrop;
+            device.mark_gone().await;
+        }
+        self.requests.with(|requests| requests.clear());
+    }
+
+    ///requests waiting in lanes, only lengths are read, nothing is taken out
+    pub fn queued(&self) -> usize {
+        self.requests.with(|requests| {
+            requests
+                .iter()
+                .map(|r| r.receiver.occupied_len())
+                .sum::<usize>()
+        })
+    }
+
+    ///whatever devices posted, realtime ahead, once there is any
+    pub async fn next(&self) -> Vec<(USBRequest, Arc<OnceCell<u8>>)> {
+        let waiting = poll_fn(|cx| {
+            self.arrivals.register(cx.waker());
+            self.requests.with(|requests| {
+                //receivers of detached devices, nothing is pending on them between two polls
+                requests.retain(|r| !r.receiver.is_closed());
+                poll_by_latency(
+                    cx,
+                    requests
+                        .iter_mut()
+                        .map(|r| (&mut r.receiver, &r.slot))
+                        .collect(),
+                )
+            })
+        })
+        .await;
+        waiting
+            .into_iter()
+            .filter(|(req, slot)| match slot.get() {
+                //left in channel of a device that got detached meanwhile
+                Some(slot_id) if self.of_slot(*slot_id).is_none() => {
+                    debug!("{} of removed device {} dropped", req.id, slot_id);
+                    false
+                }
+                _ => true,
+            })
+            .collect()
+    }
+}
+
+///root port handling of backends, they tell how their ports read and how devices come and go
+pub(crate) trait RootPorts<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    const TAG: &'static str;
+
+    fn config(&self) -> &USBSystemConfig<O, RING_BUFFER_SIZE>;
+
+    fn attached(&self) -> &Attached<O, RING_BUFFER_SIZE>;
+
+    ///ports whose connect status changed, settle after config.port_debounce
+    fn debouncer(&self) -> &Debouncer;
+
+    ///port idx -> wake cause of ports whose device woke up by itself, see [`Self::wakeup_loop`]
+    fn waking(&self) -> &SpinCell<BTreeMap<usize, WakeCause>>;
+
+    ///route of device directly on root port of this controller
+    fn root_route(&self, port_idx: usize) -> TopologyRoute;
+
+    ///current connect status of root port
+    fn connected(&self, port_idx: usize) -> bool;
+
+    ///`connected_at` is when connect status changed, before debouncing
+    async fn probe_port(&self, port_idx: usize, connected_at: Option<Duration>);
+
+    ///free whatever controller holds for `device`, it's out of [`Self::attached`] already
+    async fn release_device(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>);
+
+    ///end resume of a port whose device woke up by itself
+    async fn finish_wakeup(&self, port_idx: usize, cause: WakeCause);
+
+    fn device_at(&self, route: &TopologyRoute) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached().at(route)
+    }
+
+    fn has_device_at_port(&self, port_idx: usize) -> bool {
+        self.device_at(&self.root_route(port_idx)).is_some()
+    }
+
+    ///device at `route` and everything behind it, if it's a hub. deepest ones go first
+    async fn detach_route(&self, route: &TopologyRoute) {
+        for device in self.attached().take_route(route) {
+            self.release_device(device).await;
+        }
+    }
+
+    async fn detach_device(&self, port_idx: usize) {
+        self.detach_route(&self.root_route(port_idx)).await;
+        info!("{} device at port {} removed", Self::TAG, port_idx);
+    }
+
+    ///settle ports whose connect status stayed stable for a whole debounce window
+    async fn debounce_loop(&self) {
+        loop {
+            let settled = self
+                .debouncer()
+                .settled(self.config().os.now(), self.config().port_debounce);
+            for (port_idx, connected_at) in settled {
+                match (self.connected(port_idx), self.has_device_at_port(port_idx)) {
+                    (true, false) => {
+                        info!("{} Port {} attach settled, probing", Self::TAG, port_idx);
+                        self.probe_port(port_idx, connected_at).await;
+                    }
+                    (false, true) => {
+                        info!(
+                            "{} Port {} detach settled, tearing down",
+                            Self::TAG,
+                            port_idx
+                        );
+                        self.detach_device(port_idx).await;
+                    }
+                    _ => trace!("{} Port {} bounced back, ignored", Self::TAG, port_idx),
+                }
+            }
+
+            yield_now().await
+        }
+    }
+
+    ///finish resume of ports whose device woke up by itself
+    async fn wakeup_loop(&self) {
+        loop {
+            let waking = self.waking().with(mem::take);
+            for (port_idx, cause) in waking {
+                self.finish_wakeup(port_idx, cause).await;
+            }
+
+            yield_now().await
+        }
+    }
+}
+
+///what a completion of a kept periodic transfer leaves to do once its template lock is released,
+///along with a deadline report the stream raised
+pub(crate) enum RefillStep<T> {
+    ///nothing to enqueue, template holds on until resume, buffer release or halt clearing
+    Held(Option<(CompletionDeadline, DeadlineStats)>),
+    Refill(T, Option<(CompletionDeadline, DeadlineStats)>),
+}
+
+impl<T> RefillStep<T> {
+    pub fn into_parts(self) -> (Option<T>, Option<(CompletionDeadline, DeadlineStats)>) {
+        match self {
+            RefillStep::Held(missed) => (None, missed),
+            RefillStep::Refill(refill, missed) => (Some(refill), missed),
+        }
+    }
+}
+
+///where a stream goes after one of its buffers completed
+pub(crate) enum StreamStep {
+    ///template is to be dropped, which closes the stream
+    Cancelled,
+    ///every buffer is still held by its reader
+    Starved(Option<(CompletionDeadline, DeadlineStats)>),
+    Next((usize, usize), Option<(CompletionDeadline, DeadlineStats)>),
+}
+
+///report `transferred` bytes of `buffer` to `stream` and take its next buffer
+///
+///# Safety
+///controller is done with `buffer`, and won't see it again before this returns
+pub(crate) unsafe fn advance_stream<O: PlatformAbstractions>(
+    stream: &PeriodicStream,
+    code: Result<RequestResult, u8>,
+    (buffer, len): (usize, usize),
+    transferred: usize,
+    inbound: bool,
+) -> StreamStep {
+    let length = transferred.min(len);
+    let received = if inbound { length } else { 0 };
+    stream.report(code, length, unsafe { cpu_view::<O>(buffer, received) });
+    if stream.is_cancelled() {
+        return StreamStep::Cancelled;
+    }
+    stream.complete(buffer, transferred);
+    let missed = stream.take_deadline_report();
+    match stream.take_free() {
+        Some(next) => StreamStep::Next(next, missed),
+        None => StreamStep::Starved(missed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_clock_window_is_counted_in_polls() {
+        let debouncer = Debouncer::default();
+        let window = Duration::from_millis(3);
+        debouncer.bounced(1, None);
+        for _ in 0..3 {
+            assert!(debouncer.settled(None, window).is_empty());
+        }
+        debouncer.bounced(1, None);
+        for _ in 0..3 {
+            assert!(debouncer.settled(None, window).is_empty());
+        }
+        assert_eq!(debouncer.settled(None, window), [(1, None)]);
+        assert!(debouncer.settled(None, window).is_empty());
+    }
+
+    #[test]
+    fn with_clock_window_is_measured() {
+        let debouncer = Debouncer::default();
+        let window = Duration::from_millis(100);
+        let at = Some(Duration::from_millis(10));
+        debouncer.bounced(2, at);
+        assert!(debouncer
+            .settled(Some(Duration::from_millis(50)), window)
+            .is_empty());
+        assert_eq!(
+            debouncer.settled(Some(Duration::from_millis(110)), window),
+            [(2, at)]
+        );
+    }
+}
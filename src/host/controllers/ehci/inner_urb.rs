@@ -0,0 +1,337 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+use futures::task::AtomicWaker;
+
+use crate::{
+    abstractions::{
+        dma::{TransferBuffer, DMA},
+        PlatformAbstractions,
+    },
+    host::controllers::TrbKind,
+    usb::operations::{
+        interrupt::{InterruptTransfer, PeriodicStream},
+        CompleteAction, RequestId, RequestResult,
+    },
+};
+
+use super::schedule::{Pid, QueueHead, TransferDescriptor, TERMINATE};
+
+///what to do once a qTD chain completes, keyed by address of its last qTD
+#[derive(Debug)]
+pub struct TransferJob {
+    pub id: RequestId,
+    pub action: CompleteAction,
+    ///(address, dci) and when to give up, see [`crate::abstractions::TimeoutPolicy`]
+    pub deadline: Option<((u8, u8), Duration)>,
+}
+
+impl TransferJob {
+    pub fn new(id: RequestId, action: CompleteAction) -> Self {
+        Self {
+            id,
+            action,
+            deadline: None,
+        }
+    }
+
+    ///give up on chain at `now + timeout`, no deadline without a clock or timeout
+    pub fn expiring(
+        mut self,
+        endpoint: (u8, u8),
+        now: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        self.deadline = now
+            .zip(timeout)
+            .map(|(now, timeout)| (endpoint, now + timeout));
+        self
+    }
+}
+
+///qTDs of one request, followed by an inactive sentinel that short packets are routed to
+pub struct TdChain<O>
+where
+    O: PlatformAbstractions,
+{
+    pub qtds: DMA<[TransferDescriptor], O>,
+    ///bytes requested by each qTD, sentinel excluded
+    pub requested: Vec<usize>,
+    ///setup packet of control transfers, must outlive the chain
+    pub setup: Option<TransferBuffer<O>>,
+    ///control transfers always run status stage, even after short data stage
+    pub control: bool,
+}
+
+impl<O> TdChain<O>
+where
+    O: PlatformAbstractions,
+{
+    ///`stages` are (pid, toggle, (phys addr, len)), ioc is set on the last one
+    pub fn new(
+        os: &O,
+        stages: Vec<(Pid, Option<bool>, (usize, usize))>,
+        setup: Option<TransferBuffer<O>>,
+        control: bool,
+    ) -> Self {
+        let count = stages.len();
+        let mut qtds: DMA<[TransferDescriptor], O> = DMA::zeroed(count + 1, 32, os.dma_alloc());
+        let base = O::PhysAddr::from(qtds.addr()).into();
+        let phys = |idx: usize| (base + idx * size_of::<TransferDescriptor>()) as u32;
+        let requested = stages.iter().map(|(_, _, (_, len))| *len).collect();
+
+        for (idx, (pid, toggle, buffer)) in stages.into_iter().enumerate() {
+            let mut qtd = TransferDescriptor::new(pid, toggle, buffer);
+            if idx + 1 < count {
+                qtd.next = phys(idx + 1);
+            } else {
+                qtd.set_ioc();
+            }
+            qtd.alt_next = if control {
+                //short data stage skips straight to status stage
+                phys(count - 1)
+            } else {
+                phys(count)
+            };
+            qtds[idx] = qtd;
+        }
+        qtds[count] = TransferDescriptor {
+            next: TERMINATE,
+            alt_next: TERMINATE,
+            ..Default::default()
+        };
+
+        Self {
+            qtds,
+            requested,
+            setup,
+            control,
+        }
+    }
+
+    pub fn first(&self) -> u32 {
+        O::PhysAddr::from(self.qtds.addr()).into() as u32
+    }
+
+    ///TRB doing the job of each qTD on xhci, for [`crate::host::controllers::Statistics`]
+    pub fn kinds(&self) -> impl Iterator<Item = TrbKind> + '_ {
+        let last = self.requested.len().saturating_sub(1);
+        (0..self.requested.len()).map(move |idx| match (self.control, idx) {
+            (false, _) => TrbKind::Normal,
+            (true, 0) => TrbKind::Setup,
+            (true, idx) if idx == last => TrbKind::Status,
+            (true, _) => TrbKind::Data,
+        })
+    }
+
+    pub fn key(&self) -> usize {
+        O::PhysAddr::from(self.qtds.addr()).into()
+            + (self.requested.len() - 1) * size_of::<TransferDescriptor>()
+    }
+
+    ///re-arm a single stage chain in place, used by kept interrupt endpoints
+    pub fn rearm(&mut self, buffer: (usize, usize)) {
+        let sentinel =
+            self.first() + (self.requested.len() * size_of::<TransferDescriptor>()) as u32;
+        let mut qtd = TransferDescriptor::new(Pid::In, None, buffer);
+        qtd.set_ioc();
+        qtd.alt_next = sentinel;
+        self.qtds[0] = qtd;
+        self.requested[0] = buffer.1;
+    }
+
+    ///None while controller still works on it, otherwise result and transferred bytes
+    pub fn poll(&self) -> Option<(RequestResult, usize)> {
+        let last = self.requested.len() - 1;
+        if self.control {
+            //a halted stage stops the chain, status stage would never run
+            let failed = self.qtds[..last]
+                .iter()
+                .find_map(|qtd| qtd.result().filter(|r| *r != RequestResult::Success));
+            return failed
+                .or_else(|| self.qtds[last].result())
+                .map(|result| (result, self.transferred()));
+        }
+
+        for qtd in &self.qtds[..=last] {
+            let result = qtd.result()?;
+            if result != RequestResult::Success {
+                return Some((result, self.transferred()));
+            }
+            if qtd.remaining() > 0 && qtd.pid() == Pid::In as u32 {
+                return Some((RequestResult::ShortPacket, self.transferred()));
+            }
+        }
+        Some((RequestResult::Success, self.transferred()))
+    }
+
+    ///setup stage of control chains is not counted, only data is
+    fn transferred(&self) -> usize {
+        self.qtds
+            .iter()
+            .zip(self.requested.iter())
+            .skip(self.control as usize)
+            .filter(|(qtd, _)| !qtd.is_active())
+            .map(|(qtd, requested)| requested.saturating_sub(qtd.remaining()))
+            .sum()
+    }
+}
+
+///one endpoint of one device, chains are run one after another
+pub struct EndpointQueue<O>
+where
+    O: PlatformAbstractions,
+{
+    pub qh: DMA<QueueHead, O>,
+    pub max_packet_size: u16,
+    pub periodic: bool,
+    pub current: Option<TdChain<O>>,
+    pub backlog: VecDeque<TdChain<O>>,
+}
+
+impl<O> EndpointQueue<O>
+where
+    O: PlatformAbstractions,
+{
+    pub fn new(os: &O, qh: QueueHead, max_packet_size: u16, periodic: bool) -> Self {
+        Self {
+            qh: DMA::new(qh, 32, os.dma_alloc()),
+            max_packet_size,
+            periodic,
+            current: None,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    pub fn phys(&self) -> usize {
+        O::PhysAddr::from(self.qh.addr()).into()
+    }
+
+    ///start chain right away if endpoint is idle, otherwise queue it
+    pub fn push(&mut self, chain: TdChain<O>) {
+        if self.current.is_some() {
+            self.backlog.push_back(chain);
+        } else {
+            self.start(chain);
+        }
+    }
+
+    pub fn start(&mut self, chain: TdChain<O>) {
+        let first = chain.first();
+        self.current = Some(chain);
+        core::sync::atomic::fence(Ordering::Release);
+        self.qh.start(first);
+    }
+
+    ///every chain, whether controller got to it or not. QH must be unlinked meanwhile
+    pub fn cancel_all(&mut self) -> Vec<TdChain<O>> {
+        self.qh.idle();
+        self.current
+            .take()
+            .into_iter()
+            .chain(self.backlog.drain(..))
+            .collect()
+    }
+
+    ///takes finished chain out, next queued one is started in its place
+    pub fn take_finished(&mut self) -> Option<(TdChain<O>, RequestResult, usize)> {
+        let (result, transferred) = self.current.as_ref()?.poll()?;
+        let chain = self.current.take()?;
+        if let Some(next) = self.backlog.pop_front() {
+            self.start(next);
+        } else {
+            self.qh.idle();
+        }
+        Some((chain, result, transferred))
+    }
+}
+
+///resubmission of a kept-filling interrupt endpoint, keyed by (address, dci).
+///
+///steady state refill only re-arms the finished qTD, no allocation.
+pub struct PeriodicTemplate<O>
+where
+    O: PlatformAbstractions,
+{
+    pub id: RequestId,
+    pub buffer_addr_len: (usize, usize),
+    pub refill: Option<Arc<PeriodicStream>>,
+    ///key of the chain refills are rearmed on, only its completion belongs to the template
+    pub key: Option<usize>,
+    ///completed while quiescing, resubmit on resume
+    pub parked: Option<TdChain<O>>,
+    ///stalled or failed, resubmit once halt is cleared
+    pub halted: Option<TdChain<O>>,
+    ///no free buffer to refill, resubmit once driver releases one
+    pub starved: Option<TdChain<O>>,
+}
+
+impl<O> PeriodicTemplate<O>
+where
+    O: PlatformAbstractions,
+{
+    pub fn new(id: RequestId, transfer: &InterruptTransfer) -> Self {
+        Self {
+            id,
+            buffer_addr_len: transfer.buffer_addr_len,
+            refill: transfer.refill.clone(),
+            key: None,
+            parked: None,
+            halted: None,
+            starved: None,
+        }
+    }
+
+    ///`transfer` resubmits this starved endpoint, instead of starting it over
+    pub fn resumed_by(&self, transfer: &InterruptTransfer) -> bool {
+        self.starved.is_some()
+            && self
+                .refill
+                .as_ref()
+                .zip(transfer.refill.as_ref())
+                .is_some_and(|(ours, theirs)| Arc::ptr_eq(ours, theirs))
+    }
+}
+
+///endpoint left refilling, whoever holds the stream learns buffer is free
+impl<O> Drop for PeriodicTemplate<O>
+where
+    O: PlatformAbstractions,
+{
+    fn drop(&mut self) {
+        if let Some(stream) = &self.refill {
+            stream.close();
+        }
+    }
+}
+
+///woken by interrupt, timer or yield loop, depends on wake method
+#[derive(Default)]
+pub struct EventSignal {
+    waker: AtomicWaker,
+    pending: AtomicBool,
+}
+
+impl EventSignal {
+    pub fn wake(&self) {
+        self.pending.store(true, Ordering::Release);
+        self.waker.wake();
+    }
+
+    pub async fn wait(&self) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            if self.pending.swap(false, Ordering::AcqRel) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
@@ -0,0 +1,1996 @@
+use core::{
+    future::join,
+    sync::atomic::{fence, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
+use async_lock::{Mutex, OnceCell, RwLock};
+use bit_field::BitField;
+use embassy_futures::{block_on, yield_now};
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+use inner_urb::{EndpointQueue, EventSignal, PeriodicTemplate, TdChain, TransferJob};
+use log::{debug, error, info, trace, warn};
+use regs::{portsc, usbcmd, usbsts, CapabilityRegisters, OperationalRegisters};
+use schedule::{qh_link, split_qtd_buffers, Pid, QueueHead, TERMINATE};
+use tock_registers::interfaces::{Readable, Writeable};
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::{Endpoint, EndpointType},
+    desc_interface::USBInterface,
+    USBStandardDescriptorTypes,
+};
+
+use crate::{
+    abstractions::{
+        dma::{SmallBufferPool, DMA},
+        dma_tracker::{self, DmaKind},
+        filter::DeviceIdentity,
+        spin::SpinCell,
+        PlatformAbstractions, USBSystemConfig, WakeMethod,
+    },
+    event::{
+        ControllerError, ControllerErrorKind, DeadlineMissed, DeviceResumed, EventBus,
+        RemoteWakeup, RouteRejected, WakeCause,
+    },
+    host::{
+        bandwidth::BandwidthLedger,
+        device::{DeviceState, EnumerationMilestone, USBDevice},
+    },
+    usb::{
+        capabilities::Capabilities,
+        conformance::CheckedEndpoint,
+        operations::{
+            bulk::BulkTransfer,
+            control::{bRequest, bRequestStandard, ControlTransfer, Recipient},
+            hub::{DeviceSpeed, HubAttachment, HubPortAttach},
+            interrupt::InterruptTransfer,
+            CompleteAction, Direction, ExtraAction, RequestId, RequestResult, RequestedOperation,
+            USBRequest, UsbError,
+        },
+        standards::TopologyRoute,
+    },
+};
+
+use super::{
+    common::{
+        advance_stream, spin_until, Attached, Debouncer, Parking, RefillStep, RootPorts, StreamStep,
+    },
+    statistics::StatsCell,
+    Controller, InitError, Outstanding, PolledLoop, Statistics,
+};
+
+mod inner_urb;
+mod regs;
+mod schedule;
+
+const TAG: &str = "[EHCI]";
+const CONTROL_DCI: u8 = 1;
+const DEVICE_DESC_LEN: usize = 18;
+const DEVICE_DESC_MAX_PACKET_SIZE_OFFSET: usize = 7;
+///high speed devices always use 64 bytes on ep0
+const EP0_MAX_PACKET_SIZE: u16 = 64;
+const FRAME_LIST_LEN: usize = 1024;
+///FRINDEX counts micro-frames, wraps with frame list, refer ehci spec 2.3.4
+const FRINDEX_MASK: u32 = (FRAME_LIST_LEN as u32 * 8) - 1;
+const MICRO_FRAME: Duration = Duration::from_micros(125);
+///refer usb2 spec 7.1.7.5
+const PORT_RESET_HOLD: Duration = Duration::from_millis(50);
+///refer usb2 spec 9.2.6.3
+const SET_ADDRESS_RECOVERY: Duration = Duration::from_millis(2);
+const PORT_POWER_SETTLE: Duration = Duration::from_millis(20);
+///refer usb2 spec 7.1.7.7
+const RESUME_SIGNALING: Duration = Duration::from_millis(20);
+///controller ends resume with an EOP before clearing suspend, refer ehci spec 4.3.1
+const RESUME_TIMEOUT: Duration = Duration::from_millis(10);
+const MAX_DEVICE_ADDR: u8 = 127;
+///HCHalted follows Run/Stop within 16 micro-frames, either way, refer ehci spec 2.3.2
+const HALT_TIMEOUT: Duration = Duration::from_micros(16 * 125);
+///spec gives no bound on HCReset, controllers take a few ms at most
+const HC_RESET_TIMEOUT: Duration = Duration::from_millis(250);
+///interrupt threshold of 1 micro-frame, completions should not wait
+const INTERRUPT_THRESHOLD: u32 = 1;
+
+///EHCI backend, drives high speed devices on root ports.
+///
+///full/low speed devices are handed to companion controllers, split transactions are not supported,
+///so only high speed devices are taken behind external hubs.
+///device address takes place of xhci slot id, endpoints are keyed by (address, dci) just like xhci.
+pub struct EHCIController<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+    //safety: registers MUST exist in mem, same as xhci
+    capability_base: usize,
+    operational_base: usize,
+    n_ports: usize,
+    frame_list: DMA<[u32], O>,
+    ///dummy head of async schedule, never executed
+    async_head: SpinCell<DMA<QueueHead, O>>,
+    ///every frame list entry points here, interrupt QHs are chained after it
+    periodic_head: SpinCell<DMA<QueueHead, O>>,
+    ///serializes linking and unlinking of QHs
+    schedule_lock: Mutex<()>,
+    ///backing of setup packets and short control transfers issued by controller itself
+    small_buffers: Arc<SmallBufferPool<O>>,
+    event: EventSignal,
+    ///devices and the lanes their requests come through
+    attached: Attached<O, RING_BUFFER_SIZE>,
+    ///periodic bandwidth of root port buses, handed to every device attached
+    bandwidth: Arc<BandwidthLedger>,
+    endpoints: RwLock<BTreeMap<(u8, u8), EndpointQueue<O>>>,
+    finish_jobs: RwLock<BTreeMap<usize, TransferJob>>,
+    extra_works: SpinCell<BTreeMap<usize, (Arc<OnceCell<u8>>, USBRequest)>>,
+    periodic: SpinCell<BTreeMap<(u8, u8), PeriodicTemplate<O>>>,
+    addresses: SpinCell<BTreeSet<u8>>,
+    ///requests held back while quiescing
+    parking: Parking,
+    ///ports whose connect status changed, settle after config.port_debounce
+    debouncer: Debouncer,
+    ///ports host is driving resume signaling on, their RESUME bit is no remote wakeup
+    resuming: SpinCell<BTreeSet<usize>>,
+    ///port idx -> wake cause of ports the controller put into resume for their device
+    waking: SpinCell<BTreeMap<usize, WakeCause>>,
+    event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+    ///position in [`USBSystemConfig::controller_descs`], tagged onto every route
+    index: u8,
+    ///event and port loops, see [`Controller::poll_events`]
+    event_loop: PolledLoop<'a>,
+    ///request loop, see [`Controller::poll_scheduler`]
+    scheduler_loop: PolledLoop<'a>,
+    stats: StatsCell,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> EHCIController<'a, O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    fn caps(&self) -> &CapabilityRegisters {
+        unsafe { &*(self.capability_base as *const CapabilityRegisters) }
+    }
+
+    fn regs(&self) -> &OperationalRegisters {
+        unsafe { &*(self.operational_base as *const OperationalRegisters) }
+    }
+
+    fn halt(&self) -> Result<&Self, InitError> {
+        let regs = self.regs();
+        regs.update_usbcmd(|c| {
+            c.set_bit(usbcmd::RUN_STOP, false);
+        });
+        debug!("{TAG} Until halt");
+        if !spin_until(&self.config.os, HALT_TIMEOUT, || {
+            regs.status_bit(usbsts::HC_HALTED)
+        }) {
+            return Err(InitError::Timeout { what: "halt" });
+        }
+        debug!("{TAG} Halted");
+        Ok(self)
+    }
+
+    fn chip_hardware_reset(&self) -> Result<&Self, InitError> {
+        debug!("{TAG} Reset begin");
+        self.halt()?;
+        let regs = self.regs();
+
+        regs.update_usbcmd(|c| {
+            c.set_bit(usbcmd::HC_RESET, true);
+        });
+        if !spin_until(&self.config.os, HC_RESET_TIMEOUT, || {
+            !regs.usbcmd.get().get_bit(usbcmd::HC_RESET)
+        }) {
+            return Err(InitError::Timeout { what: "reset" });
+        }
+
+        info!("{TAG} EHCI reset ok");
+        Ok(self)
+    }
+
+    fn setup_schedules(&self) -> &Self {
+        let regs = self.regs();
+        let async_head_addr = self.async_head.with(|async_head| {
+            let async_head_addr: usize = O::PhysAddr::from(async_head.addr()).into();
+            //async list is circular, a lonely head points to itself
+            async_head.set_horizontal(qh_link(async_head_addr));
+            async_head_addr
+        });
+
+        let frame_list_addr: usize = O::PhysAddr::from(self.frame_list.addr()).into();
+        debug!(
+            "{TAG} Writing PERIODICLISTBASE: {:X}, ASYNCLISTADDR: {:X}",
+            frame_list_addr, async_head_addr
+        );
+        regs.ctrldssegment.set(0);
+        regs.periodiclistbase.set(frame_list_addr as u32);
+        regs.asynclistaddr.set(async_head_addr as u32);
+
+        self
+    }
+
+    fn init_ir(&self) -> &Self {
+        let regs = self.regs();
+        if self.config.wake_method.is_interrupt() {
+            debug!("{TAG} Enabling interrupts");
+            let mut intr = 0u32;
+            intr.set_bit(usbsts::USBINT, true)
+                .set_bit(usbsts::USBERRINT, true)
+                .set_bit(usbsts::PORT_CHANGE, true)
+                .set_bit(usbsts::HOST_SYSTEM_ERROR, true);
+            regs.usbintr.set(intr);
+            self.config
+                .wake_method
+                .register_primary(&|| self.event.wake());
+        } else {
+            debug!("{TAG} Disable interrupts");
+            regs.usbintr.set(0);
+        }
+
+        self
+    }
+
+    fn start(&self) -> Result<&Self, InitError> {
+        let regs = self.regs();
+        debug!("{TAG} Start run");
+        regs.update_usbcmd(|c| {
+            c.set_bits(usbcmd::ITC, INTERRUPT_THRESHOLD)
+                //1024 entries frame list
+                .set_bits(2..4, 0)
+                .set_bit(usbcmd::PERIODIC_ENABLE, true)
+                .set_bit(usbcmd::ASYNC_ENABLE, true)
+                .set_bit(usbcmd::RUN_STOP, true);
+        });
+
+        if !spin_until(&self.config.os, HALT_TIMEOUT, || {
+            !regs.status_bit(usbsts::HC_HALTED)
+        }) {
+            return Err(InitError::Timeout { what: "start" });
+        }
+
+        //route every port to us, full/low speed ones are handed back after reset
+        regs.configflag.set(1);
+        info!("{TAG} Is running");
+
+        Ok(self)
+    }
+
+    fn power_ports(&self) -> &Self {
+        if !self.caps().port_power_control() {
+            return self;
+        }
+        for i in 0..self.n_ports {
+            self.regs().update_port(i, |p| {
+                p.set_bit(portsc::POWER, true);
+            });
+        }
+        block_on(self.sleep(PORT_POWER_SETTLE));
+        self
+    }
+
+    fn reset_ports(&self) -> &Self {
+        for i in 0..self.n_ports {
+            //safety: no event needed, only polling port register
+            block_on(self.reset_port_inner(i));
+        }
+        self
+    }
+
+    ///wait by clock source if platform has one, otherwise count micro-frames
+    async fn sleep(&self, duration: Duration) {
+        if let Some(start) = self.config.os.now() {
+            while self
+                .config
+                .os
+                .now()
+                .is_some_and(|now| now.saturating_sub(start) < duration)
+            {
+                yield_now().await
+            }
+            return;
+        }
+
+        let target = (duration.as_micros() / MICRO_FRAME.as_micros()) as u32;
+        let mut elapsed = 0;
+        let mut last = self.regs().frindex.get();
+        while elapsed < target {
+            yield_now().await;
+            let now = self.regs().frindex.get();
+            elapsed += now.wrapping_sub(last) & FRINDEX_MASK;
+            last = now;
+        }
+    }
+
+    async fn reset_port_inner(&self, i: usize) -> bool {
+        const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+        let regs = self.regs();
+        let port = regs.port(i);
+        if !port.get_bit(portsc::CONNECT) {
+            return false;
+        }
+        if port.get_bits(portsc::LINE_STATUS) == portsc::LINE_STATUS_K {
+            //low speed device, never goes through reset here
+            self.release_port(i);
+            return false;
+        }
+
+        debug!("{TAG} Port {} start reset", i);
+        regs.update_port(i, |p| {
+            p.set_bit(portsc::ENABLED, false)
+                .set_bit(portsc::RESET, true);
+        });
+        self.sleep(PORT_RESET_HOLD).await;
+        regs.update_port(i, |p| {
+            p.set_bit(portsc::RESET, false);
+        });
+
+        let start = self.config.os.now();
+        while regs.port(i).get_bit(portsc::RESET) {
+            if let (Some(start), Some(now)) = (start, self.config.os.now())
+                && now.saturating_sub(start) > RESET_TIMEOUT
+            {
+                warn!("{TAG} Port {} reset timeout!", i);
+                return false;
+            }
+            yield_now().await
+        }
+
+        regs.clear_port_changes(i, 1 << portsc::ENABLE_CHANGE);
+        let enabled = regs.port(i).get_bit(portsc::ENABLED);
+        if !enabled {
+            //full speed device, chirp did not happen
+            self.release_port(i);
+        }
+        debug!("{TAG} Port {} reset ok, enabled: {}", i, enabled);
+        enabled
+    }
+
+    fn release_port(&self, i: usize) {
+        info!("{TAG} Port {} is not high speed, handed to companion", i);
+        self.regs().update_port(i, |p| {
+            p.set_bit(portsc::OWNER, true);
+        });
+    }
+
+    fn initial_probe(&self) -> &Self {
+        for port_idx in 0..self.n_ports {
+            let port = self.regs().port(port_idx);
+            info!(
+                "{TAG} Port {}: Enabled: {}, Connected: {}, Owned by companion: {}, Power {}",
+                port_idx,
+                port.get_bit(portsc::ENABLED),
+                port.get_bit(portsc::CONNECT),
+                port.get_bit(portsc::OWNER),
+                port.get_bit(portsc::POWER)
+            );
+
+            if !port.get_bit(portsc::ENABLED) || port.get_bit(portsc::OWNER) {
+                continue;
+            }
+
+            self.regs()
+                .clear_port_changes(port_idx, 1 << portsc::CONNECT_CHANGE);
+            self.attach_device(port_idx)
+                .mark_milestone(EnumerationMilestone::Connected);
+        }
+
+        info!(
+            "initial probe completed! device count:{}",
+            self.attached.len()
+        );
+
+        self
+    }
+
+    fn attach_device(&self, port_idx: usize) -> Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        self.attach_at(self.root_route(port_idx), None)
+    }
+
+    fn attach_at(
+        &self,
+        route: TopologyRoute,
+        attachment: Option<HubAttachment>,
+    ) -> Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        let (mut usbdevice, _, receivers) = USBDevice::new(self.config.clone());
+        usbdevice.topology_path = route;
+        usbdevice.attachment = attachment;
+        //companion controllers get everything slower
+        usbdevice.speed = DeviceSpeed::High;
+        usbdevice.bandwidth = self.bandwidth.clone();
+
+        let devref: Arc<_> = usbdevice.into();
+        self.attached.attach(&devref, receivers);
+        self.event_bus
+            .pre_initialize_device
+            .broadcast(devref.clone());
+        devref
+    }
+
+    fn device_of_addr(&self, addr: u8) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.of_slot(addr)
+    }
+
+    ///root port of device at addr, None behind hubs, whose ports belong to hub driver
+    fn root_port_of_addr(&self, addr: u8) -> Option<usize> {
+        self.device_of_addr(addr)
+            .filter(|device| device.attachment.is_none())
+            .map(|device| device.topology_path.port_idx() as _)
+    }
+
+    ///refer ehci spec 4.3.1, port stops SOFs and device enters suspend after 3ms of idle bus
+    fn suspend_device(&self, addr: u8) -> RequestResult {
+        let Some(i) = self.root_port_of_addr(addr) else {
+            debug!(
+                "{TAG} device {} is not on a root port, suspend refused",
+                addr
+            );
+            return RequestResult::Invalid;
+        };
+        let regs = self.regs();
+        if !regs.port(i).get_bit(portsc::ENABLED) {
+            return RequestResult::Invalid;
+        }
+        regs.update_port(i, |p| {
+            p.set_bit(portsc::SUSPEND, true);
+        });
+        info!("{TAG} device {} suspended at port {}", addr, i);
+        RequestResult::Success
+    }
+
+    async fn resume_device(&self, addr: u8) -> RequestResult {
+        let Some(i) = self.root_port_of_addr(addr) else {
+            return RequestResult::Invalid;
+        };
+        self.resume_port(i).await
+    }
+
+    ///drive resume signaling, controller clears suspend once it ends.
+    ///RESUME is already set if device woke up by itself, refer ehci spec 4.3.1.2
+    async fn resume_port(&self, i: usize) -> RequestResult {
+        let regs = self.regs();
+        if !regs.port(i).get_bit(portsc::SUSPEND) {
+            return RequestResult::Success;
+        }
+        self.resuming.with(|resuming| resuming.insert(i));
+        regs.update_port(i, |p| {
+            p.set_bit(portsc::RESUME, true);
+        });
+        self.sleep(RESUME_SIGNALING).await;
+        regs.update_port(i, |p| {
+            p.set_bit(portsc::RESUME, false);
+        });
+
+        let start = self.config.os.now();
+        let mut code = RequestResult::Success;
+        while regs.port(i).get_bit(portsc::SUSPEND) {
+            if let (Some(start), Some(now)) = (start, self.config.os.now())
+                && now.saturating_sub(start) > RESUME_TIMEOUT
+            {
+                warn!("{TAG} Port {} resume timeout!", i);
+                code = RequestResult::Invalid;
+                break;
+            }
+            yield_now().await
+        }
+        self.resuming.with(|resuming| resuming.remove(&i));
+        if let RequestResult::Success = code {
+            info!("{TAG} port {} resumed", i);
+        }
+        code
+    }
+
+    ///device state follows controller, drivers get told to restart their polling
+    fn announce_resumed(
+        &self,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        cause: Option<WakeCause>,
+    ) {
+        device.mark_resumed();
+        self.event_bus
+            .device_resumed
+            .broadcast(DeviceResumed { device, cause });
+    }
+
+    ///QH restarts by itself on next qTD, only device side halt and data toggle need care,
+    ///refer usb2 spec 9.4.5
+    async fn clear_stall(&self, addr: u8, dci: u8) -> RequestResult {
+        //default control pipe clears its stall on next SETUP by itself
+        if dci == CONTROL_DCI {
+            return RequestResult::Success;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.post_control_transfer(
+            RequestId::next(),
+            ControlTransfer::clear_endpoint_halt(dci),
+            CompleteAction::SimpleResponse(sender),
+            addr,
+        )
+        .await;
+        let code = match receiver.await {
+            Ok(Ok(code)) => code,
+            _ => RequestResult::Invalid,
+        };
+        if code != RequestResult::Success {
+            warn!(
+                "{TAG} clear halt of device {} endpoint {} failed! {:?}",
+                addr, dci, code
+            );
+            return code;
+        }
+
+        if let Some(queue) = self.endpoints.write().await.get_mut(&(addr, dci))
+            && queue.current.is_none()
+        {
+            queue.qh.reset_toggle();
+        }
+        let rearm = self.periodic.with(|periodic| {
+            let template = periodic.get_mut(&(addr, dci))?;
+            let mut chain = template.halted.take()?;
+            chain.rearm(template.buffer_addr_len);
+            if self.parking.is_quiescing() {
+                template.parked = Some(chain);
+                None
+            } else {
+                Some(chain)
+            }
+        });
+        if let Some(chain) = rearm {
+            self.enqueue(addr, dci, chain).await;
+        }
+        debug!("{TAG} stall of device {} endpoint {} cleared", addr, dci);
+        RequestResult::Success
+    }
+
+    ///no TT handling here, so anything but high speed is refused
+    fn attach_child(&self, hub_addr: u8, attach: HubPortAttach) -> RequestResult {
+        let Some(hub) = self.device_of_addr(hub_addr) else {
+            return RequestResult::SlotNotEnabledError;
+        };
+        if attach.speed != DeviceSpeed::High {
+            warn!(
+                "{TAG} {:?} speed device on port {} of hub {} needs split transactions, ignored",
+                attach.speed, attach.port, hub.topology_path
+            );
+            return RequestResult::SplitTransactionError;
+        }
+        let route = match hub.topology_path.checked_child(attach.port) {
+            Ok(route) => route,
+            Err(error) => {
+                warn!(
+                    "{TAG} port {} of hub {} can't be routed to: {}, ignored",
+                    attach.port, hub.topology_path, error
+                );
+                let mut chain = hub.topology_path.ports();
+                chain.push(attach.port);
+                self.event_bus.route_rejected.broadcast(RouteRejected {
+                    hub: hub.clone(),
+                    port: attach.port,
+                    chain,
+                    error,
+                });
+                return RequestResult::ParameterError;
+            }
+        };
+
+        info!("{TAG} high speed device at {}", route);
+        let device = self.attach_at(
+            route,
+            Some(HubAttachment {
+                speed: attach.speed,
+                tt: None,
+            }),
+        );
+        device.mark_milestone_at(EnumerationMilestone::Connected, attach.connected_at);
+        device.mark_milestone_at(EnumerationMilestone::PortReset, attach.reset_at);
+        RequestResult::Success
+    }
+
+    async fn on_event_arrived(&self) {
+        self.event.wait().await;
+        self.stats.woken();
+        let changes = self.regs().take_status_changes();
+        if changes.get_bit(usbsts::HOST_SYSTEM_ERROR) {
+            error!("{TAG} host system error, controller halted");
+            self.event_bus.controller_error.broadcast(ControllerError {
+                controller: self.index,
+                kind: ControllerErrorKind::HostSystemError,
+            });
+        }
+        if changes.get_bit(usbsts::PORT_CHANGE) {
+            for port_idx in 0..self.n_ports {
+                self.on_port_status_changed(port_idx);
+            }
+        }
+
+        //no event ring here, finished chains are found by looking at qTD tokens
+        let finished: Vec<_> = self
+            .endpoints
+            .write()
+            .await
+            .iter_mut()
+            .filter_map(|(key, queue)| {
+                queue
+                    .take_finished()
+                    .map(|(chain, result, transferred)| (*key, chain, result, transferred))
+            })
+            .collect();
+
+        for (key, chain, result, transferred) in finished {
+            self.mark_transfer_completed(result, key, chain, transferred)
+                .await;
+        }
+    }
+
+    fn on_port_status_changed(&self, idx: usize) {
+        let regs = self.regs();
+        let port = regs.port(idx);
+
+        if port.get_bit(portsc::CONNECT_CHANGE) {
+            regs.clear_port_changes(idx, 1 << portsc::CONNECT_CHANGE);
+            //every bounce restarts the window
+            trace!("{TAG} port {} connect status changed, debouncing", idx);
+            self.debouncer.bounced(idx, self.config.os.now());
+        }
+
+        //controller sets RESUME on a suspended port once it sees K-state from device
+        if port.get_bit(portsc::SUSPEND)
+            && port.get_bit(portsc::RESUME)
+            && !self.resuming.with(|resuming| resuming.contains(&idx))
+            && !self.waking.with(|waking| waking.contains_key(&idx))
+        {
+            if let Some(device) = self.device_at(&self.root_route(idx)) {
+                let cause = WakeCause::PortResume {
+                    port: idx as u8 + 1,
+                };
+                info!("{TAG} remote wakeup from port {}", idx);
+                self.event_bus
+                    .remote_wakeup
+                    .broadcast(RemoteWakeup { device, cause });
+                //software ends resume signaling after 20ms, can't be waited for here
+                self.waking.with(|waking| waking.insert(idx, cause));
+            }
+        }
+    }
+
+    ///controller is halted, so QHs are dropped without unlinking handshake. devices refuse further requests
+    async fn forget_devices(&self) {
+        self.attached.forget().await;
+        self.finish_jobs.write().await.clear();
+        self.extra_works.with(|extra_works| extra_works.clear());
+        self.periodic.with(|periodic| periodic.clear());
+        self.parking.forget(|_| true);
+        self.addresses.with(|addresses| addresses.clear());
+        //async head is relinked to itself on init, periodic one is not
+        self.periodic_head
+            .with(|head| head.set_horizontal(TERMINATE));
+        self.endpoints.write().await.clear();
+    }
+
+    async fn mark_transfer_completed(
+        &self,
+        code: RequestResult,
+        (addr, dci): (u8, u8),
+        mut chain: TdChain<O>,
+        transferred: usize,
+    ) {
+        //one completion per chain, as xhci reports one per TD
+        if let Some(kind) = chain.kinds().last() {
+            self.stats.completed(kind);
+        }
+        self.stats.completed_with(Ok(code));
+        if let Some(device) = self.device_of_addr(addr) {
+            device.record_completion(dci as _, Ok(code));
+            if matches!(code, RequestResult::Success | RequestResult::ShortPacket)
+                && transferred > 0
+            {
+                self.stats.transferred(&device.topology_path, transferred);
+                device.record_bytes(dci as _, transferred);
+            }
+        }
+
+        //template is only touched under its lock, chain is enqueued once it's released
+        let step = self.periodic.with(|periodic| {
+            let Some(template) = periodic
+                .get_mut(&(addr, dci))
+                .filter(|template| template.key == Some(chain.key()))
+            else {
+                return Err(chain);
+            };
+            if !matches!(code, RequestResult::Success | RequestResult::ShortPacket) {
+                warn!(
+                    "{TAG} device {} endpoint {} failed with {:?}, held until halt cleared",
+                    addr, dci, code
+                );
+                if let Some(stream) = &template.refill {
+                    stream.report(Ok(code), 0, &[]);
+                }
+                template.halted = Some(chain);
+                return Ok(RefillStep::Held(None));
+            }
+            let mut missed = None;
+            if let Some(stream) = &template.refill {
+                //safety: qTD retired and buffer stays put until chain is rearmed below
+                let step = unsafe {
+                    advance_stream::<O>(
+                        stream,
+                        Ok(code),
+                        template.buffer_addr_len,
+                        transferred,
+                        pid_of_dci(dci) == Pid::In,
+                    )
+                };
+                match step {
+                    StreamStep::Cancelled => {
+                        debug!("{TAG} device {} endpoint {} refilling cancelled", addr, dci);
+                        //dropping template closes the stream
+                        periodic.remove(&(addr, dci));
+                        return Ok(RefillStep::Held(None));
+                    }
+                    StreamStep::Starved(report) => {
+                        trace!(
+                            "{TAG} device {} endpoint {} starved, wait for release",
+                            addr,
+                            dci
+                        );
+                        template.starved = Some(chain);
+                        return Ok(RefillStep::Held(report));
+                    }
+                    StreamStep::Next(next, report) => {
+                        template.buffer_addr_len = next;
+                        missed = report;
+                    }
+                }
+            }
+            chain.rearm(template.buffer_addr_len);
+            if self.parking.is_quiescing() {
+                template.parked = Some(chain);
+                Ok(RefillStep::Held(missed))
+            } else {
+                Ok(RefillStep::Refill(chain, missed))
+            }
+        });
+        let chain = match step {
+            Ok(step) => {
+                let (chain, missed) = step.into_parts();
+                if let Some((deadline, stats)) = missed
+                    && let Some(device) = self.device_of_addr(addr)
+                {
+                    warn!(
+                        "{TAG} device {} endpoint {} missed {:?} deadline, {:?}",
+                        addr, dci, deadline.deadline, stats
+                    );
+                    self.event_bus.deadline_missed.broadcast(DeadlineMissed {
+                        device,
+                        endpoint_id: dci as _,
+                        deadline,
+                        stats,
+                    });
+                }
+                if let Some(chain) = chain {
+                    self.enqueue(addr, dci, chain).await;
+                }
+                return;
+            }
+            Err(chain) => chain,
+        };
+
+        let key = chain.key();
+        //qTDs are not referenced by controller any more
+        drop(chain);
+
+        trace!("received complete of {:x}", key);
+        if let Some(job) = self.finish_jobs.write().await.remove(&key) {
+            let id = job.id;
+            trace!("{TAG} {} completed at qtd {:x}: {:?}", id, key, code);
+            match job.action {
+                CompleteAction::NOOP => {}
+                CompleteAction::SimpleResponse(sender) => {
+                    trace!("{TAG} {} send complete!", id);
+                    let _ = sender.send(Ok(code));
+                }
+                CompleteAction::LengthResponse(sender) => {
+                    trace!("{TAG} {} send complete, {} bytes!", id, transferred);
+                    let _ = sender.send(Ok((code, transferred)));
+                }
+                CompleteAction::DropSem(configure_semaphore) => match UsbError::check(Ok(code)) {
+                    Ok(_) => drop(configure_semaphore),
+                    Err(err) => {
+                        warn!("{TAG} {} failed at qtd {:x}: {}", id, key, err);
+                        configure_semaphore.fail(err).await
+                    }
+                },
+            }
+        }
+
+        if let Some((slot, mut morereq)) = self
+            .extra_works
+            .with(|extra_works| extra_works.remove(&key))
+        {
+            trace!("{TAG} {} refill after qtd {:x}", morereq.id, key);
+            match &mut morereq.operation {
+                RequestedOperation::Bulk(transfer) => {
+                    let refill = transfer
+                        .refill
+                        .as_mut()
+                        .expect("kept bulk transfer must have a stream");
+                    refill.stream.complete(refill.buffer_idx, transferred).await;
+                    match refill.stream.take_free().await {
+                        Some(idx) => {
+                            refill.buffer_idx = idx;
+                            transfer.buffer_addr_len = refill.stream.buffer(idx);
+                            self.submit_or_park(morereq, slot).await
+                        }
+                        None => trace!("{TAG} {} starved, wait for recycle", morereq.id),
+                    }
+                }
+                _ => self.submit_or_park(morereq, slot).await,
+            }
+        }
+
+        trace!("transfer completion procress complete!");
+    }
+
+    ///while quiescing, new submissions and refills are held back until resume
+    async fn submit_or_park(&self, req: USBRequest, slot: Arc<OnceCell<u8>>) {
+        let id = req.id;
+        match self.parking.park(req, slot) {
+            Some((req, slot)) => self.post_transfer(req, slot).await,
+            None => trace!("{TAG} {} parked while quiescing", id),
+        }
+    }
+
+    async fn quiesce_inner(&self) {
+        self.parking.quiesce();
+        info!("{TAG} quiescing, waiting for outstanding jobs");
+        while !self.finish_jobs.read().await.is_empty() {
+            yield_now().await
+        }
+        info!("{TAG} quiesced");
+    }
+
+    async fn resume_inner(&self) {
+        let parked = self.parking.resume();
+        let parked_chains: Vec<_> = self.periodic.with(|periodic| {
+            periodic
+                .iter_mut()
+                .filter_map(|(key, template)| template.parked.take().map(|chain| (*key, chain)))
+                .collect()
+        });
+        for ((addr, dci), chain) in parked_chains {
+            self.enqueue(addr, dci, chain).await;
+        }
+        info!("{TAG} resuming, {} parked requests", parked.len());
+        for (slot, req) in parked {
+            self.post_transfer(req, slot).await
+        }
+    }
+
+    ///requests of devices gone meanwhile are dropped by [`Attached::next`]
+    async fn run_once(&'a self) {
+        for (req, slot) in self.attached.next().await {
+            self.submit_or_park(req, slot).await
+        }
+    }
+
+    ///job is registered before chain goes live, completion can't outrun it
+    async fn post_chain(
+        &self,
+        id: RequestId,
+        (addr, dci): (u8, u8),
+        chain: TdChain<O>,
+        cmp: Option<CompleteAction>,
+        timeout: Option<Duration>,
+    ) -> usize {
+        let key = chain.key();
+        trace!("{TAG} {} queued at qtd {:x}", id, key);
+        dma_tracker::tag(chain.qtds.addr().into(), DmaKind::Schedule, Some(addr));
+        if let Some(cmp) = cmp {
+            self.finish_jobs.write().await.insert(
+                key,
+                TransferJob::new(id, cmp).expiring((addr, dci), self.config.os.now(), timeout),
+            );
+        }
+        self.enqueue(addr, dci, chain).await;
+        key
+    }
+
+    ///endpoint may be dropped while its chain was built or held, whoever waits on it is failed then
+    async fn enqueue(&self, addr: u8, dci: u8, chain: TdChain<O>) {
+        let key = chain.key();
+        if let Some(queue) = self.endpoints.write().await.get_mut(&(addr, dci)) {
+            self.stats.submitted(chain.kinds());
+            queue.push(chain);
+            return;
+        }
+        //never reached controller
+        drop(chain);
+        warn!(
+            "{TAG} endpoint {} of device {} not enabled, qtd {:x} dropped",
+            dci, addr, key
+        );
+        self.periodic.with(|periodic| {
+            if periodic
+                .get(&(addr, dci))
+                .is_some_and(|template| template.key == Some(key))
+            {
+                periodic.remove(&(addr, dci));
+            }
+        });
+        self.extra_works
+            .with(|extra_works| extra_works.remove(&key));
+        if let Some(job) = self.finish_jobs.write().await.remove(&key) {
+            self.refuse(job.id, (addr, dci), Some(job.action)).await;
+        }
+    }
+
+    ///request on an endpoint not enabled, or not any more
+    async fn refuse(&self, id: RequestId, (addr, dci): (u8, u8), cmp: Option<CompleteAction>) {
+        debug!(
+            "{TAG} {} refused, endpoint {} of device {} not enabled",
+            id, dci, addr
+        );
+        if let Some(cmp) = cmp {
+            let code = RequestResult::EndpointNotEnabledError;
+            cmp.fail(UsbError::Completion(code), code).await;
+        }
+    }
+
+    async fn post_control_transfer(
+        &self,
+        id: RequestId,
+        control_transfer: ControlTransfer,
+        cmp: CompleteAction,
+        addr: u8,
+    ) {
+        let chain = self.control_transfer(control_transfer);
+        self.post_chain(
+            id,
+            (addr, CONTROL_DCI),
+            chain,
+            Some(cmp),
+            self.config.timeouts.control,
+        )
+        .await;
+    }
+
+    async fn post_interrupt_transfer(
+        &self,
+        id: RequestId,
+        transfer: &InterruptTransfer,
+        cmp: Option<CompleteAction>,
+        addr: u8,
+    ) {
+        let dci = transfer.endpoint_id as u8;
+        match self.interrupt_transfer(addr, transfer).await {
+            Some(chain) => {
+                self.post_chain(id, (addr, dci), chain, cmp, None).await;
+            }
+            None => self.refuse(id, (addr, dci), cmp).await,
+        }
+    }
+
+    #[allow(unused_variables)]
+    async fn post_transfer(&self, req: USBRequest, slot: Arc<OnceCell<u8>>) {
+        trace!("{TAG} {} dispatching {:?}", req.id, req.operation);
+        match req.operation {
+            RequestedOperation::Control(control_transfer) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                self.post_control_transfer(req.id, control_transfer, req.complete_action, addr)
+                    .await;
+            }
+            RequestedOperation::Bulk(mut bulk_transfer) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                let dci = bulk_transfer.endpoint_id as u8;
+                if bulk_transfer.stream_id.is_some() {
+                    debug!("{TAG} {} names a stream, usb2 bulk has none", req.id);
+                    return req
+                        .complete_action
+                        .fail(UsbError::Unsupported, RequestResult::Invalid)
+                        .await;
+                }
+                let Some(chain) = self.bulk_transfer(addr, &bulk_transfer).await else {
+                    return self
+                        .refuse(req.id, (addr, dci), Some(req.complete_action))
+                        .await;
+                };
+                match req.extra_action {
+                    ExtraAction::NOOP => {
+                        self.post_chain(
+                            req.id,
+                            (addr, dci),
+                            chain,
+                            Some(req.complete_action),
+                            self.config.timeouts.bulk,
+                        )
+                        .await;
+                    }
+                    ExtraAction::KeepFill => {
+                        let key = chain.key();
+                        let refill = bulk_transfer
+                            .refill
+                            .take()
+                            .expect("kept bulk transfer must have a stream");
+                        self.extra_works.with(|extra_works| {
+                            extra_works.insert(
+                                key,
+                                (
+                                    slot,
+                                    USBRequest::keep_bulk(bulk_transfer, refill)
+                                        .with_id(req.id)
+                                        .into(),
+                                ),
+                            )
+                        });
+                        self.post_chain(req.id, (addr, dci), chain, None, None)
+                            .await;
+                    }
+                }
+            }
+            RequestedOperation::Interrupt(interrupt_transfer) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                match req.extra_action {
+                    ExtraAction::NOOP => {
+                        self.post_interrupt_transfer(
+                            req.id,
+                            &interrupt_transfer,
+                            Some(req.complete_action),
+                            addr,
+                        )
+                        .await;
+                    }
+                    ExtraAction::KeepFill => {
+                        //later refills never go through here, see mark_transfer_completed
+                        let dci = interrupt_transfer.endpoint_id as u8;
+                        let resumed = self.periodic.with(|periodic| {
+                            let template = periodic
+                                .get_mut(&(addr, dci))
+                                .filter(|template| template.resumed_by(&interrupt_transfer))?;
+                            let mut chain = template.starved.take()?;
+                            template.buffer_addr_len = interrupt_transfer.buffer_addr_len;
+                            chain.rearm(template.buffer_addr_len);
+                            Some(chain)
+                        });
+                        if let Some(chain) = resumed {
+                            self.enqueue(addr, dci, chain).await;
+                        } else {
+                            let Some(chain) =
+                                self.interrupt_transfer(addr, &interrupt_transfer).await
+                            else {
+                                return self
+                                    .refuse(req.id, (addr, dci), Some(req.complete_action))
+                                    .await;
+                            };
+                            let mut template = PeriodicTemplate::new(req.id, &interrupt_transfer);
+                            //chain is rearmed in place, so its key stays for every refill
+                            template.key = Some(chain.key());
+                            self.periodic
+                                .with(|periodic| periodic.insert((addr, dci), template));
+                            self.post_chain(req.id, (addr, dci), chain, None, None)
+                                .await;
+                        }
+                    }
+                }
+            }
+            RequestedOperation::Isoch(_) => {
+                warn!("{TAG} {} isochronous transfers are not supported", req.id);
+                req.complete_action
+                    .fail(UsbError::Unsupported, RequestResult::Invalid)
+                    .await
+            }
+            RequestedOperation::InitializeDevice(route) => {
+                let result = match self.device_at(&route) {
+                    Some(dev) => self.assign_address_device(&dev).await,
+                    None => Err(UsbError::DeviceGone),
+                };
+                trace!("assign address device complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    warn!("{TAG} assigning device at {} failed: {}", route, err);
+                    sem.fail(err).await;
+                }
+            }
+            RequestedOperation::NOOP => {
+                debug!("{TAG}-device {:#?} transfer nope!", slot)
+            }
+            RequestedOperation::EnableFunction(config_val, interface) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                let result = self.enable_function(addr, interface).await;
+                trace!("enable function for device complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    sem.fail(err).await;
+                }
+            }
+            RequestedOperation::DisableFunction(interface) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                self.disable_function(addr, interface).await;
+                trace!("disable function for device complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action {
+                    drop(sem);
+                }
+            }
+            RequestedOperation::Deconfigure => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                self.deconfigure(addr).await;
+                trace!("deconfigure device complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action {
+                    drop(sem);
+                }
+            }
+            //high speed hubs need nothing from controller, routing is by address
+            RequestedOperation::ConfigureHub(configuration) => {
+                debug!("{TAG} hub {:?} configured", configuration);
+                req.complete_action.respond(RequestResult::Success);
+            }
+            RequestedOperation::AttachChild(attach) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                req.complete_action.respond(self.attach_child(addr, attach));
+            }
+            RequestedOperation::ClearStall(dci) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                let result = self.clear_stall(addr, dci as _).await;
+                req.complete_action.respond(result);
+            }
+            RequestedOperation::Suspend => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                req.complete_action.respond(self.suspend_device(addr));
+            }
+            RequestedOperation::Resume => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                let result = self.resume_device(addr).await;
+                if let RequestResult::Success = result
+                    && let Some(device) = self.device_of_addr(addr)
+                {
+                    self.announce_resumed(device, None);
+                }
+                req.complete_action.respond(result);
+            }
+            RequestedOperation::DetachChild(port) => {
+                let addr = unsafe { slot.get_unchecked().clone() };
+                if let Some(hub) = self.device_of_addr(addr)
+                    && let Ok(route) = hub.topology_path.checked_child(port)
+                {
+                    self.detach_route(&route).await;
+                    info!("{TAG} device at port {} of hub {} removed", port, addr);
+                }
+                req.complete_action.respond(RequestResult::Success);
+            }
+        }
+    }
+
+    ///configuration itself is set by driver with SET_CONFIGURATION, only QHs are needed here.
+    ///
+    ///endpoints are checked against spec first, nothing is set up if any of them is refused
+    async fn enable_function(
+        &self,
+        addr: u8,
+        interface: Arc<USBInterface>,
+    ) -> Result<(), UsbError> {
+        let device = self.device_of_addr(addr).ok_or(UsbError::DeviceGone)?;
+        let mut endpoints = Vec::with_capacity(interface.endpoints.len());
+        for ep in &interface.endpoints {
+            endpoints.push(self.check_endpoint(&device, ep)?);
+        }
+        for (ep, checked) in interface.endpoints.iter().zip(endpoints) {
+            self.setup_endpoint(ep, addr, checked).await
+        }
+        Ok(())
+    }
+
+    ///isochronous endpoints have no iTD schedule here, so they are refused along with the interface
+    fn check_endpoint(
+        &self,
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+        ep: &Endpoint,
+    ) -> Result<CheckedEndpoint, UsbError> {
+        let checked = CheckedEndpoint::check(
+            self.config.spec_policy,
+            &device.topology_path,
+            device.speed,
+            ep,
+        )?;
+        match ep.endpoint_type() {
+            EndpointType::IsochOut | EndpointType::IsochIn => {
+                warn!(
+                    "{TAG} isochronous endpoint {} of {} is not supported",
+                    ep.doorbell_value_aka_dci(),
+                    device.topology_path
+                );
+                Err(UsbError::Unsupported)
+            }
+            EndpointType::NotValid => {
+                warn!(
+                    "{TAG} endpoint {} of {} has no valid type",
+                    ep.doorbell_value_aka_dci(),
+                    device.topology_path
+                );
+                Err(UsbError::Unsupported)
+            }
+            _ => Ok(checked),
+        }
+    }
+
+    ///drop QHs of interface, so another driver could claim it again
+    async fn disable_function(&self, addr: u8, interface: Arc<USBInterface>) {
+        for ep in &interface.endpoints {
+            self.drop_endpoint(addr, ep.doorbell_value_aka_dci() as u8)
+                .await;
+        }
+    }
+
+    ///every QH of device but ep0's
+    async fn deconfigure(&self, addr: u8) {
+        let dcis: Vec<u8> = self
+            .endpoints
+            .read()
+            .await
+            .keys()
+            .filter(|(owner, dci)| *owner == addr && *dci > 1)
+            .map(|(_, dci)| *dci)
+            .collect();
+        for dci in dcis {
+            self.drop_endpoint(addr, dci).await;
+        }
+    }
+
+    ///`checked` went through [`Self::check_endpoint`], so only bulk and interrupt come here
+    async fn setup_endpoint(&self, ep: &Arc<Endpoint>, addr: u8, checked: CheckedEndpoint) {
+        let dci = ep.doorbell_value_aka_dci() as u8;
+        let number = dci / 2;
+        let max_packet_size = checked.max_packet_size & 0x7ff;
+        trace!("setup endpoint for dci {dci} type {:?}", ep.endpoint_type());
+        if self.endpoints.read().await.contains_key(&(addr, dci)) {
+            debug!("{TAG} endpoint {} of device {} already set up", dci, addr);
+            return;
+        }
+
+        let periodic = match ep.endpoint_type() {
+            EndpointType::Control => return,
+            EndpointType::BulkOut | EndpointType::BulkIn => false,
+            EndpointType::InterruptOut | EndpointType::InterruptIn => true,
+            EndpointType::IsochOut | EndpointType::IsochIn | EndpointType::NotValid => return,
+        };
+
+        let mut qh = QueueHead::new(addr, number, max_packet_size, false, false);
+        if periodic {
+            //high bandwidth endpoints, refer usb2 spec 9.6.6
+            let mult = ((checked.max_packet_size >> 11) & 0x3) as u8 + 1;
+            qh.set_interrupt_schedule(mult);
+        }
+        let queue = EndpointQueue::new(&self.config.os, qh, max_packet_size, periodic);
+        self.link_queue(addr, dci, queue).await;
+    }
+
+    async fn link_queue(&self, addr: u8, dci: u8, mut queue: EndpointQueue<O>) {
+        let _guard = self.schedule_lock.lock().await;
+        dma_tracker::tag(queue.qh.addr().into(), DmaKind::Schedule, Some(addr));
+        self.link(&mut queue);
+        self.endpoints.write().await.insert((addr, dci), queue);
+    }
+
+    ///put QH first in its schedule, caller holds schedule lock
+    fn link(&self, queue: &mut EndpointQueue<O>) {
+        let head = if queue.periodic {
+            &self.periodic_head
+        } else {
+            &self.async_head
+        };
+        queue.qh.set_horizontal(head.with(|head| head.horizontal()));
+        fence(Ordering::Release);
+        head.with(|head| head.set_horizontal(qh_link(queue.phys())));
+    }
+
+    ///take QH of `endpoint` out of its schedule, it stays in `endpoints`. caller holds schedule
+    ///lock until controller can't be looking at it, see [`Self::wait_unlinked`].
+    ///returns whether it was periodic, None if endpoint is not set up
+    fn unlink(
+        &self,
+        endpoints: &mut BTreeMap<(u8, u8), EndpointQueue<O>>,
+        endpoint: (u8, u8),
+    ) -> Option<bool> {
+        let queue = endpoints.get(&endpoint)?;
+        let link = qh_link(queue.phys());
+        let next = queue.qh.horizontal();
+        let periodic = queue.periodic;
+        let head = if periodic {
+            &self.periodic_head
+        } else {
+            &self.async_head
+        };
+        let unlinked_from_head = head.with(|head| {
+            let first = head.horizontal() == link;
+            if first {
+                head.set_horizontal(next);
+            }
+            first
+        });
+        if !unlinked_from_head
+            && let Some(prev) = endpoints
+                .values_mut()
+                .find(|other| other.periodic == periodic && other.qh.horizontal() == link)
+        {
+            prev.qh.set_horizontal(next);
+        }
+        Some(periodic)
+    }
+
+    async fn wait_unlinked(&self, periodic: bool) {
+        if periodic {
+            self.wait_frame().await;
+        } else {
+            self.wait_async_advance().await;
+        }
+    }
+
+    ///unlink QH and wait until controller can't be looking at it, pending jobs on it are dropped
+    async fn drop_endpoint(&self, addr: u8, dci: u8) {
+        let queue = {
+            let _guard = self.schedule_lock.lock().await;
+            let queue = {
+                let mut endpoints = self.endpoints.write().await;
+                self.unlink(&mut endpoints, (addr, dci))
+                    .and_then(|_| endpoints.remove(&(addr, dci)))
+            };
+            let Some(queue) = queue else {
+                return;
+            };
+            self.wait_unlinked(queue.periodic).await;
+            queue
+        };
+
+        //pending jobs on dropped QH would never complete
+        let keys: Vec<usize> = queue
+            .current
+            .iter()
+            .chain(queue.backlog.iter())
+            .map(|chain| chain.key())
+            .collect();
+        self.finish_jobs
+            .write()
+            .await
+            .retain(|key, _| !keys.contains(key));
+        self.extra_works
+            .with(|extra_works| extra_works.retain(|key, _| !keys.contains(key)));
+        self.periodic.with(|periodic| periodic.remove(&(addr, dci)));
+        trace!("{TAG} endpoint {} of device {} dropped", dci, addr);
+    }
+
+    ///gives up on transfers past their deadline, see [`crate::abstractions::TimeoutPolicy`]
+    async fn timeout_loop(&self) {
+        loop {
+            if let Some(now) = self.config.os.now() {
+                for (addr, dci) in self.expired_transfers(now).await {
+                    self.cancel_endpoint(addr, dci).await;
+                }
+            }
+            yield_now().await
+        }
+    }
+
+    ///endpoints with a transfer past its deadline, each once
+    async fn expired_transfers(&self, now: Duration) -> Vec<(u8, u8)> {
+        let mut expired: Vec<(u8, u8)> = self
+            .finish_jobs
+            .read()
+            .await
+            .values()
+            .filter_map(|job| job.deadline)
+            .filter(|(_, deadline)| now >= *deadline)
+            .map(|(endpoint, _)| endpoint)
+            .collect();
+        expired.sort_unstable();
+        expired.dedup();
+        expired
+    }
+
+    ///unlink QH, take every chain off it and link it back idle. waiters of chains that did not
+    ///finish meanwhile resolve as timed out, data toggle is kept
+    async fn cancel_endpoint(&self, addr: u8, dci: u8) {
+        warn!(
+            "{TAG} transfer on device {} endpoint {} timed out, unlinking",
+            addr, dci
+        );
+        let (finished, cancelled) = {
+            //held throughout, a QH out of schedule must not be taken for a predecessor
+            let _guard = self.schedule_lock.lock().await;
+            let Some(periodic) = self.unlink(&mut *self.endpoints.write().await, (addr, dci))
+            else {
+                return;
+            };
+            self.wait_unlinked(periodic).await;
+
+            let mut endpoints = self.endpoints.write().await;
+            let Some(queue) = endpoints.get_mut(&(addr, dci)) else {
+                return;
+            };
+            let mut finished = Vec::new();
+            while let Some(done) = queue.take_finished() {
+                finished.push(done);
+            }
+            let cancelled = queue.cancel_all();
+            self.link(queue);
+            (finished, cancelled)
+        };
+
+        for (chain, result, transferred) in finished {
+            self.mark_transfer_completed(result, (addr, dci), chain, transferred)
+                .await;
+        }
+        for chain in cancelled {
+            let key = chain.key();
+            //qTDs are not referenced by controller any more
+            drop(chain);
+            self.extra_works
+                .with(|extra_works| extra_works.remove(&key));
+            let Some(job) = self.finish_jobs.write().await.remove(&key) else {
+                continue;
+            };
+            trace!("{TAG} {} cancelled", job.id);
+            match job.action {
+                CompleteAction::DropSem(sem) => sem.fail(UsbError::Timeout).await,
+                action => action.respond(RequestResult::Stopped),
+            }
+        }
+    }
+
+    ///refer ehci spec 4.8.2, controller may cache async QHs until doorbell is answered
+    async fn wait_async_advance(&self) {
+        let regs = self.regs();
+        regs.update_usbcmd(|c| {
+            c.set_bit(usbcmd::ASYNC_ADVANCE_DOORBELL, true);
+        });
+        while !regs.status_bit(usbsts::ASYNC_ADVANCE) {
+            yield_now().await
+        }
+        regs.usbsts.set(1 << usbsts::ASYNC_ADVANCE);
+    }
+
+    ///a periodic QH is only visited once per frame
+    async fn wait_frame(&self) {
+        self.sleep(MICRO_FRAME * 8 * 2).await
+    }
+
+    async fn assign_address_device(
+        &self,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ) -> Result<(), UsbError> {
+        let addr = self.alloc_address().ok_or(UsbError::NoSlot)?;
+        debug!("address acquired! {addr} for {}", device.topology_path);
+
+        //default pipe is shared, but requests are dispatched one by one so enumeration never overlaps
+        let queue = EndpointQueue::new(
+            &self.config.os,
+            QueueHead::new(0, 0, EP0_MAX_PACKET_SIZE, true, false),
+            EP0_MAX_PACKET_SIZE,
+            false,
+        );
+        self.link_queue(0, CONTROL_DCI, queue).await;
+
+        {
+            let (sender, receiver) = oneshot::channel();
+            self.post_control_transfer(
+                RequestId::next(),
+                ControlTransfer::new(
+                    Direction::Out,
+                    Recipient::Device,
+                    bRequest::Standard(bRequestStandard::SetAddress),
+                    addr as u16,
+                    0,
+                    None,
+                ),
+                CompleteAction::SimpleResponse(sender),
+                0,
+            )
+            .await;
+            let request_result = receiver.await;
+            trace!("got result: {:?}", request_result);
+            if let Err(err) = UsbError::check(request_result.unwrap_or(Ok(RequestResult::Invalid)))
+            {
+                //default pipe still answers on address 0
+                self.disable_slot(0).await;
+                self.addresses.with(|addresses| addresses.remove(&addr));
+                return Err(err);
+            }
+        }
+        self.sleep(SET_ADDRESS_RECOVERY).await;
+
+        {
+            //QH is idle, safe to retarget in place
+            let mut endpoints = self.endpoints.write().await;
+            let Some(mut queue) = endpoints.remove(&(0, CONTROL_DCI)) else {
+                //default pipe got dropped meanwhile, e.g. controller reset
+                drop(endpoints);
+                self.addresses.with(|addresses| addresses.remove(&addr));
+                return Err(UsbError::DeviceGone);
+            };
+            queue.qh.set_device_addr(addr);
+            dma_tracker::tag(queue.qh.addr().into(), DmaKind::Schedule, Some(addr));
+            endpoints.insert((addr, CONTROL_DCI), queue);
+        }
+        //unplugged while addressing, released without an address
+        if device.is_gone() {
+            self.disable_slot(addr).await;
+            return Err(UsbError::DeviceGone);
+        }
+        let _ = device.slot_id.set(addr).await;
+
+        let device_desc = match self.get_device_descriptor(addr).await {
+            Ok(desc) => desc,
+            Err(err) => {
+                self.disable_slot(addr).await;
+                return Err(err);
+            }
+        };
+        //ep0 stays at 64 bytes anyway
+        let declared = device_desc[DEVICE_DESC_MAX_PACKET_SIZE_OFFSET];
+        if !DeviceSpeed::High.is_valid_ep0_packet_size(declared)
+            && let Err(err) = self.config.spec_policy.check(format_args!(
+                "{TAG} device {} bMaxPacketSize0 {} at high speed",
+                addr, declared
+            ))
+        {
+            self.disable_slot(addr).await;
+            return Err(err);
+        }
+        let identity = DeviceIdentity::from_device_desc(&device_desc);
+        if !self.config.device_filter.is_allowed(&identity).await {
+            warn!(
+                "{TAG} device {:x?} at {} rejected by filter",
+                identity, device.topology_path
+            );
+            self.disable_slot(addr).await;
+            *device.state.write().await = DeviceState::Rejected;
+            return Ok(());
+        }
+
+        let _ = device.device_desc_raw.set(device_desc).await;
+        Ok(())
+    }
+
+    fn alloc_address(&self) -> Option<u8> {
+        self.addresses.with(|addresses| {
+            let addr = (1..=MAX_DEVICE_ADDR).find(|addr| !addresses.contains(addr))?;
+            addresses.insert(addr);
+            Some(addr)
+        })
+    }
+
+    ///no slot on ehci, drops every QH of device and frees its address
+    async fn disable_slot(&self, addr: u8) -> RequestResult {
+        let dcis: Vec<u8> = self
+            .endpoints
+            .read()
+            .await
+            .keys()
+            .filter(|(a, _)| *a == addr)
+            .map(|(_, dci)| *dci)
+            .collect();
+        for dci in dcis {
+            self.drop_endpoint(addr, dci).await;
+        }
+        self.addresses.with(|addresses| addresses.remove(&addr));
+        dma_tracker::report_owner(addr);
+        debug!("{TAG} device {} disabled", addr);
+        RequestResult::Success
+    }
+
+    async fn get_device_descriptor(&self, addr: u8) -> Result<Vec<u8>, UsbError> {
+        self.get_descriptor_bytes(
+            addr,
+            USBStandardDescriptorTypes::Device as u8,
+            DEVICE_DESC_LEN,
+        )
+        .await
+        .ok_or(UsbError::BadDescriptor)
+    }
+
+    async fn get_descriptor_bytes(&self, addr: u8, desc_type: u8, len: usize) -> Option<Vec<u8>> {
+        let buffer = self.small_buffers.alloc_or_dma(len, 64);
+        let (sender, receiver) = oneshot::channel();
+
+        self.post_control_transfer(
+            RequestId::next(),
+            ControlTransfer::get_descriptor(
+                Recipient::Device,
+                desc_type,
+                0,
+                0,
+                buffer.phys_addr_len_tuple().into(),
+            ),
+            CompleteAction::SimpleResponse(sender),
+            addr,
+        )
+        .await;
+
+        match receiver.await {
+            Ok(Ok(RequestResult::Success | RequestResult::ShortPacket)) => Some(buffer.to_vec()),
+            other => {
+                debug!("get desc {} failed! {:#?}", desc_type, other);
+                None
+            }
+        }
+    }
+
+    ///setup, data split on qTD boundaries, then status stage with toggle forced to 1
+    fn control_transfer(&self, urb_req: ControlTransfer) -> TdChain<O> {
+        let direction = urb_req.request_type.direction;
+        let (data_addr, data_len) = urb_req.data.unwrap_or((0, 0));
+
+        let mut setup = self.small_buffers.alloc_or_dma(8, 32);
+        setup[0] = urb_req.request_type.clone().into();
+        setup[1] = urb_req.request.clone().into();
+        setup[2..4].copy_from_slice(&urb_req.value.to_le_bytes());
+        setup[4..6].copy_from_slice(&urb_req.index.to_le_bytes());
+        setup[6..8].copy_from_slice(&(data_len as u16).to_le_bytes());
+        trace!("{TAG} setup packet {:x?}", &setup[..8]);
+
+        let mut stages: Vec<(Pid, Option<bool>, (usize, usize))> = Vec::new();
+        stages.push((Pid::Setup, Some(false), setup.phys_addr_len_tuple().into()));
+
+        if urb_req.data.is_some() {
+            let pid = match direction {
+                Direction::In => Pid::In,
+                Direction::Out => Pid::Out,
+            };
+            let mut packets = 0;
+            for chunk in split_qtd_buffers(data_addr, data_len, EP0_MAX_PACKET_SIZE as _) {
+                stages.push((pid, Some(packets % 2 == 0), chunk));
+                packets += chunk.1.div_ceil(EP0_MAX_PACKET_SIZE as _);
+            }
+        }
+
+        let status_pid = if urb_req.response { Pid::In } else { Pid::Out };
+        stages.push((status_pid, Some(true), (0, 0)));
+
+        TdChain::new(&self.config.os, stages, Some(setup), true)
+    }
+
+    ///bulk chain ends on last qTD, short packet on the way jumps to sentinel and ends it early
+    async fn bulk_transfer(&self, addr: u8, urb_req: &BulkTransfer) -> Option<TdChain<O>> {
+        self.data_chain(addr, urb_req.endpoint_id as u8, urb_req.segments())
+            .await
+    }
+
+    ///interrupt TD is built like a bulk one, usually a single qTD
+    async fn interrupt_transfer(
+        &self,
+        addr: u8,
+        urb_req: &InterruptTransfer,
+    ) -> Option<TdChain<O>> {
+        self.data_chain(addr, urb_req.endpoint_id as u8, urb_req.segments())
+            .await
+    }
+
+    ///one qTD per page-bounded piece of every segment, a scatter list just adds more of them.
+    ///None if endpoint is not enabled
+    async fn data_chain(
+        &self,
+        addr: u8,
+        dci: u8,
+        segments: &[(usize, usize)],
+    ) -> Option<TdChain<O>> {
+        let max_packet_size = self
+            .endpoints
+            .read()
+            .await
+            .get(&(addr, dci))
+            .map(|queue| queue.max_packet_size)?;
+        let pid = pid_of_dci(dci);
+
+        let stages = match segments {
+            [(buffer, len)] => split_qtd_buffers(*buffer, *len, max_packet_size as _),
+            segments => segments
+                .iter()
+                .filter(|(_, len)| *len > 0)
+                .flat_map(|(buffer, len)| split_qtd_buffers(*buffer, *len, max_packet_size as _))
+                .collect(),
+        }
+        .into_iter()
+        .map(|chunk| (pid, None, chunk))
+        .collect();
+        Some(TdChain::new(&self.config.os, stages, None, false))
+    }
+
+    async fn wake_event_ring(&self) {
+        match &self.config.wake_method {
+            WakeMethod::Timer(semaphore) => loop {
+                semaphore.acquire().await.forget();
+                self.event.wake();
+            },
+            WakeMethod::Yield => loop {
+                self.event.wake();
+                yield_now().await;
+            },
+            WakeMethod::Interrupt(_) | WakeMethod::Vectored(_) => {
+                self.event.wake();
+            }
+        }
+    }
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> RootPorts<O, RING_BUFFER_SIZE>
+    for EHCIController<'a, O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    const TAG: &'static str = TAG;
+
+    fn config(&self) -> &USBSystemConfig<O, RING_BUFFER_SIZE> {
+        &self.config
+    }
+
+    fn attached(&self) -> &Attached<O, RING_BUFFER_SIZE> {
+        &self.attached
+    }
+
+    fn debouncer(&self) -> &Debouncer {
+        &self.debouncer
+    }
+
+    fn waking(&self) -> &SpinCell<BTreeMap<usize, WakeCause>> {
+        &self.waking
+    }
+
+    fn root_route(&self, port_idx: usize) -> TopologyRoute {
+        TopologyRoute::from_port_idx(port_idx).on_controller(self.index)
+    }
+
+    fn connected(&self, port_idx: usize) -> bool {
+        self.regs().port(port_idx).get_bit(portsc::CONNECT)
+    }
+
+    ///`connected_at` is when connect status changed, before debouncing
+    async fn probe_port(&self, port_idx: usize, connected_at: Option<Duration>) {
+        if !self.reset_port_inner(port_idx).await {
+            warn!("{TAG} Port {} not enabled after reset, skip", port_idx);
+            return;
+        }
+        let reset_at = self.config.os.now();
+        self.regs()
+            .clear_port_changes(port_idx, 1 << portsc::CONNECT_CHANGE);
+        let device = self.attach_device(port_idx);
+        device.mark_milestone_at(EnumerationMilestone::Connected, connected_at);
+        device.mark_milestone_at(EnumerationMilestone::PortReset, reset_at);
+    }
+
+    ///stop taking requests from device, free its address and tell everyone it's gone
+    async fn release_device(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        self.attached.stop_taking(&device).await;
+        self.event_bus.pre_drop_device.broadcast(device.clone());
+
+        if let Some(&addr) = device.slot_id.get() {
+            self.parking.forget(|a| a.get() == Some(&addr));
+            //dropping its QHs purges every job left on them
+            self.disable_slot(addr).await;
+        }
+        debug!("{TAG} device at {} released", device.topology_path);
+        self.event_bus.device_removed.broadcast(device);
+    }
+
+    async fn finish_wakeup(&self, port_idx: usize, cause: WakeCause) {
+        if let RequestResult::Success = self.resume_port(port_idx).await
+            && let Some(device) = self.device_at(&self.root_route(port_idx))
+        {
+            self.announce_resumed(device, Some(cause));
+        }
+    }
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> Controller<'a, O, RING_BUFFER_SIZE>
+    for EHCIController<'a, O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    fn new(
+        config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+        index: u8,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        let capability_base: usize = config.base_addr.clone().into();
+        let caps = unsafe { &*(capability_base as *const CapabilityRegisters) };
+        let operational_base = capability_base + caps.caplength.get() as usize;
+        let n_ports = caps.n_ports();
+        debug!(
+            "{TAG} version: {:x}, ports: {}, port power control: {}, 64 bit: {}",
+            caps.hciversion.get(),
+            n_ports,
+            caps.port_power_control(),
+            caps.addressing_64bit()
+        );
+
+        let mut async_head = DMA::new(
+            QueueHead::new(0, 0, EP0_MAX_PACKET_SIZE, false, true),
+            32,
+            config.os.dma_alloc(),
+        )
+        .tagged(DmaKind::Schedule, None);
+        async_head.set_halted();
+        let mut periodic_head = DMA::new(QueueHead::default(), 32, config.os.dma_alloc())
+            .tagged(DmaKind::Schedule, None);
+        periodic_head.set_halted().set_horizontal(TERMINATE);
+        let periodic_head_addr: usize = O::PhysAddr::from(periodic_head.addr()).into();
+        //frame list must be 4K aligned, refer ehci spec 2.3.7
+        let frame_list = DMA::new_vec(
+            qh_link(periodic_head_addr),
+            FRAME_LIST_LEN,
+            0x1000,
+            config.os.dma_alloc(),
+        )
+        .tagged(DmaKind::Schedule, None);
+
+        Self {
+            config: config.clone(),
+            capability_base,
+            operational_base,
+            n_ports,
+            frame_list,
+            async_head: async_head.into(),
+            periodic_head: periodic_head.into(),
+            schedule_lock: Mutex::new(()),
+            small_buffers: SmallBufferPool::new(config.os.dma_alloc()),
+            event: EventSignal::default(),
+            attached: Attached::default(),
+            bandwidth: Arc::default(),
+            endpoints: BTreeMap::new().into(),
+            finish_jobs: BTreeMap::new().into(),
+            extra_works: BTreeMap::new().into(),
+            periodic: BTreeMap::new().into(),
+            addresses: BTreeSet::new().into(),
+            parking: Parking::default(),
+            debouncer: Debouncer::default(),
+            resuming: BTreeSet::new().into(),
+            waking: BTreeMap::new().into(),
+            event_bus,
+            index,
+            event_loop: PolledLoop::new(),
+            scheduler_loop: PolledLoop::new(),
+            stats: StatsCell::default(),
+        }
+    }
+
+    ///no PAGESIZE on ehci, qTD pages are always 4K
+    fn init(&self) -> Result<(), InitError> {
+        //qTD chains are sized per transfer, nothing to reserve up front
+        if self.config.bounded_memory.is_some() {
+            return Err(InitError::BoundedMemoryUnsupported);
+        }
+        //QH overlays share cache lines with links CPU writes, they'd need uncached memory
+        if !self.config.os.dma_coherent() {
+            return Err(InitError::NonCoherentDmaUnsupported);
+        }
+        self.chip_hardware_reset()?
+            .setup_schedules()
+            .init_ir()
+            .start()?
+            .power_ports()
+            .reset_ports()
+            .initial_probe();
+        self.event_loop.start();
+        self.scheduler_loop.start();
+        Ok(())
+    }
+
+    fn device_accesses(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.all()
+    }
+
+    fn rescan(&self) {
+        for port_idx in 0..self.n_ports {
+            let port = self.regs().port(port_idx);
+            if !port.get_bit(portsc::CONNECT)
+                || port.get_bit(portsc::OWNER)
+                || self.has_device_at_port(port_idx)
+            {
+                continue;
+            }
+
+            info!("{TAG} Port {} attached after init, probing", port_idx);
+            block_on(self.probe_port(port_idx, self.config.os.now()));
+        }
+    }
+
+    fn reset_port(&'a self, port_idx: usize) -> BoxFuture<'a, bool> {
+        self.reset_port_inner(port_idx).boxed()
+    }
+
+    fn quiesce(&'a self) -> BoxFuture<'a, ()> {
+        self.quiesce_inner().boxed()
+    }
+
+    fn resume(&'a self) -> BoxFuture<'a, ()> {
+        self.resume_inner().boxed()
+    }
+
+    fn poll_events(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.event_loop.poll(cx, || {
+            let on_event_loop = async move {
+                loop {
+                    self.on_event_arrived().await
+                }
+            };
+
+            let debounce_loop = self.debounce_loop();
+            let wakeup_loop = self.wakeup_loop();
+
+            if self.config.wake_method.is_interrupt() {
+                join!(on_event_loop, debounce_loop, wakeup_loop)
+                    .map(|_| ())
+                    .boxed()
+            } else {
+                let event_ring_waker = self.wake_event_ring();
+                join!(on_event_loop, event_ring_waker, debounce_loop, wakeup_loop)
+                    .map(|_| ())
+                    .boxed()
+            }
+        })
+    }
+
+    fn poll_scheduler(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.scheduler_loop.poll(cx, || {
+            let request_loop = async move {
+                loop {
+                    self.run_once().await
+                }
+            };
+            join!(request_loop, self.timeout_loop()).map(|_| ()).boxed()
+        })
+    }
+
+    ///schedules are owned by controller struct, nothing to free until it drops
+    fn shutdown(&self) {
+        self.event_loop.stop();
+        self.scheduler_loop.stop();
+        if let Err(err) = self.halt() {
+            warn!("{TAG} {}", err);
+        }
+        block_on(self.forget_devices());
+        info!("{TAG} shut down");
+    }
+
+    ///no link power management on usb2 controllers, and no hot plug promised, full/low speed
+    ///devices plugged later end up on companion controllers
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::BACKEND_EHCI | Capabilities::HUBS
+    }
+
+    ///no command ring, completions are delivered as soon as qTDs are seen retired
+    fn outstanding(&self) -> Outstanding {
+        Outstanding {
+            queued: self.attached.queued(),
+            parked: self.parking.parked(),
+            transfers: block_on(self.finish_jobs.read()).len()
+                + self.extra_works.with(|extra_works| extra_works.len()),
+            commands: 0,
+            completions: 0,
+            kept: self.periodic.with(|periodic| periodic.len()),
+        }
+    }
+
+    fn stats(&self) -> Statistics {
+        self.stats.snapshot()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+///dci keeps xhci meaning across backends: endpoint number * 2, plus 1 for IN
+fn pid_of_dci(dci: u8) -> Pid {
+    if dci % 2 == 1 {
+        Pid::In
+    } else {
+        Pid::Out
+    }
+}
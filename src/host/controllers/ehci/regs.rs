@@ -0,0 +1,127 @@
+use bit_field::BitField;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::register_structs;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+///ehci allows at most 15 root ports, refer ehci spec 2.2.3
+pub const MAX_PORTS: usize = 15;
+
+register_structs! {
+    pub CapabilityRegisters {
+        (0x000 => pub caplength: ReadOnly<u8>),
+        (0x001 => _reserved),
+        (0x002 => pub hciversion: ReadOnly<u16>),
+        (0x004 => pub hcsparams: ReadOnly<u32>),
+        (0x008 => pub hccparams: ReadOnly<u32>),
+        (0x00C => @END),
+    }
+}
+
+register_structs! {
+    pub OperationalRegisters {
+        (0x000 => pub usbcmd: ReadWrite<u32>),
+        (0x004 => pub usbsts: ReadWrite<u32>),
+        (0x008 => pub usbintr: ReadWrite<u32>),
+        (0x00C => pub frindex: ReadWrite<u32>),
+        (0x010 => pub ctrldssegment: ReadWrite<u32>),
+        (0x014 => pub periodiclistbase: ReadWrite<u32>),
+        (0x018 => pub asynclistaddr: ReadWrite<u32>),
+        (0x01C => _reserved),
+        (0x040 => pub configflag: ReadWrite<u32>),
+        (0x044 => pub portsc: [ReadWrite<u32>; MAX_PORTS]),
+        (0x080 => @END),
+    }
+}
+
+///bit positions, refer ehci spec 2.3
+pub mod usbcmd {
+    pub const RUN_STOP: usize = 0;
+    pub const HC_RESET: usize = 1;
+    pub const PERIODIC_ENABLE: usize = 4;
+    pub const ASYNC_ENABLE: usize = 5;
+    pub const ASYNC_ADVANCE_DOORBELL: usize = 6;
+    ///interrupt threshold, in micro-frames
+    pub const ITC: core::ops::Range<usize> = 16..24;
+}
+
+pub mod usbsts {
+    pub const USBINT: usize = 0;
+    pub const USBERRINT: usize = 1;
+    pub const PORT_CHANGE: usize = 2;
+    pub const HOST_SYSTEM_ERROR: usize = 4;
+    pub const ASYNC_ADVANCE: usize = 5;
+    pub const HC_HALTED: usize = 12;
+    pub const PERIODIC_STATUS: usize = 14;
+    pub const ASYNC_STATUS: usize = 15;
+    ///every status change bit is write 1 to clear
+    pub const CHANGE_MASK: u32 = 0x3f;
+}
+
+pub mod portsc {
+    pub const CONNECT: usize = 0;
+    pub const CONNECT_CHANGE: usize = 1;
+    pub const ENABLED: usize = 2;
+    pub const ENABLE_CHANGE: usize = 3;
+    pub const OVER_CURRENT_CHANGE: usize = 5;
+    pub const RESUME: usize = 6;
+    pub const SUSPEND: usize = 7;
+    pub const RESET: usize = 8;
+    pub const LINE_STATUS: core::ops::Range<usize> = 10..12;
+    pub const POWER: usize = 12;
+    pub const OWNER: usize = 13;
+    ///line status of a low speed device, which belongs to companion controller
+    pub const LINE_STATUS_K: u32 = 0b01;
+    ///write 1 to clear bits, must be masked out on read-modify-write
+    pub const CHANGE_MASK: u32 =
+        (1 << CONNECT_CHANGE) | (1 << ENABLE_CHANGE) | (1 << OVER_CURRENT_CHANGE);
+}
+
+impl CapabilityRegisters {
+    pub fn n_ports(&self) -> usize {
+        (self.hcsparams.get().get_bits(0..4) as usize).min(MAX_PORTS)
+    }
+
+    ///whether ports have power switches, refer ehci spec 2.2.3
+    pub fn port_power_control(&self) -> bool {
+        self.hcsparams.get().get_bit(4)
+    }
+
+    pub fn addressing_64bit(&self) -> bool {
+        self.hccparams.get().get_bit(0)
+    }
+}
+
+impl OperationalRegisters {
+    pub fn update_usbcmd(&self, f: impl FnOnce(&mut u32)) {
+        let mut value = self.usbcmd.get();
+        f(&mut value);
+        self.usbcmd.set(value);
+    }
+
+    pub fn status_bit(&self, bit: usize) -> bool {
+        self.usbsts.get().get_bit(bit)
+    }
+
+    ///returns change bits that were set, and acknowledges them
+    pub fn take_status_changes(&self) -> u32 {
+        let changes = self.usbsts.get() & usbsts::CHANGE_MASK;
+        self.usbsts.set(changes);
+        changes
+    }
+
+    pub fn port(&self, idx: usize) -> u32 {
+        self.portsc[idx].get()
+    }
+
+    ///change bits are preserved as 0 so they would not be cleared by accident
+    pub fn update_port(&self, idx: usize, f: impl FnOnce(&mut u32)) {
+        let mut value = self.portsc[idx].get() & !portsc::CHANGE_MASK;
+        f(&mut value);
+        self.portsc[idx].set(value);
+    }
+
+    pub fn clear_port_changes(&self, idx: usize, mask: u32) {
+        let value = self.portsc[idx].get() & !portsc::CHANGE_MASK;
+        self.portsc[idx].set(value | (mask & portsc::CHANGE_MASK));
+    }
+}
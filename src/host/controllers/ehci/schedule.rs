@@ -0,0 +1,266 @@
+use core::ptr::{addr_of, addr_of_mut};
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+
+use crate::usb::operations::RequestResult;
+
+///set on link pointers that point nowhere
+pub const TERMINATE: u32 = 1;
+///link pointer type of queue heads, refer ehci spec 3.1
+pub const TYPE_QH: u32 = 0b01 << 1;
+///qTD buffer pointers cover at most 5 pages, refer ehci spec 3.5.4
+pub const QTD_PAGES: usize = 5;
+pub const QTD_PAGE_SIZE: usize = 0x1000;
+///high speed, the only speed this backend drives, others go to companion controllers
+pub const EPS_HIGH: u32 = 0b10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Pid {
+    Out = 0,
+    In = 1,
+    Setup = 2,
+}
+
+///queue element transfer descriptor, refer ehci spec 3.5 and appendix B.
+///
+///laid out in 64 bit form so it works on either kind of controller, high halves stay 0
+///since CTRLDSSEGMENT is 0, padded to keep every qTD of a chain 32 byte aligned.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TransferDescriptor {
+    pub next: u32,
+    pub alt_next: u32,
+    pub token: u32,
+    pub buffers: [u32; QTD_PAGES],
+    pub buffers_hi: [u32; QTD_PAGES],
+    _reserved: [u32; 3],
+}
+
+///token status bits, refer ehci spec 3.5.3
+pub mod status {
+    pub const ACTIVE: usize = 7;
+    pub const HALTED: usize = 6;
+    pub const DATA_BUFFER_ERROR: usize = 5;
+    pub const BABBLE: usize = 4;
+    pub const TRANSACTION_ERROR: usize = 3;
+    pub const MISSED_MICRO_FRAME: usize = 2;
+}
+
+impl TransferDescriptor {
+    ///`addr` must be physical, at most `max_len(addr)` bytes would be taken
+    pub fn new(pid: Pid, toggle: Option<bool>, (addr, len): (usize, usize)) -> Self {
+        let mut token = 0u32;
+        token
+            .set_bit(status::ACTIVE, true)
+            .set_bits(8..10, pid as u32)
+            //3 retries before halting
+            .set_bits(10..12, 3)
+            .set_bits(16..31, len as u32);
+        if let Some(toggle) = toggle {
+            token.set_bit(31, toggle);
+        }
+
+        let mut buffers = [0u32; QTD_PAGES];
+        if len > 0 {
+            buffers[0] = addr as u32;
+            let mut page = addr & !(QTD_PAGE_SIZE - 1);
+            for buffer in buffers.iter_mut().skip(1) {
+                page += QTD_PAGE_SIZE;
+                *buffer = page as u32;
+            }
+        }
+
+        Self {
+            next: TERMINATE,
+            alt_next: TERMINATE,
+            token,
+            buffers,
+            ..Default::default()
+        }
+    }
+
+    ///bytes a single qTD could carry starting from `addr`
+    pub fn max_len(addr: usize) -> usize {
+        QTD_PAGES * QTD_PAGE_SIZE - (addr & (QTD_PAGE_SIZE - 1))
+    }
+
+    pub fn set_ioc(&mut self) -> &mut Self {
+        self.token.set_bit(15, true);
+        self
+    }
+
+    pub fn token(&self) -> u32 {
+        unsafe { addr_of!(self.token).read_volatile() }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.token().get_bit(status::ACTIVE)
+    }
+
+    ///bytes not transferred yet
+    pub fn remaining(&self) -> usize {
+        self.token().get_bits(16..31) as usize
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.token().get_bits(8..10)
+    }
+
+    ///maps halt reason onto same result codes as xhci completion codes
+    pub fn result(&self) -> Option<RequestResult> {
+        let token = self.token();
+        if token.get_bit(status::ACTIVE) {
+            return None;
+        }
+        Some(if !token.get_bit(status::HALTED) {
+            RequestResult::Success
+        } else if token.get_bit(status::BABBLE) {
+            RequestResult::BabbleDetectedError
+        } else if token.get_bit(status::DATA_BUFFER_ERROR) {
+            RequestResult::DataBufferError
+        } else if token.get_bit(status::TRANSACTION_ERROR)
+            || token.get_bit(status::MISSED_MICRO_FRAME)
+        {
+            RequestResult::UsbTransactionError
+        } else {
+            //halted without any error bit, device answered STALL
+            RequestResult::StallError
+        })
+    }
+}
+
+///queue head, refer ehci spec 3.6, must be allocated 32 byte aligned
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct QueueHead {
+    pub horizontal: u32,
+    pub characteristics: u32,
+    pub capabilities: u32,
+    pub current: u32,
+    ///transfer overlay, controller copies active qTD here
+    pub overlay: TransferDescriptor,
+}
+
+impl QueueHead {
+    ///`reclamation_head` marks head of async list, exactly one QH there must have it
+    pub fn new(
+        device_addr: u8,
+        endpoint: u8,
+        max_packet_size: u16,
+        control: bool,
+        reclamation_head: bool,
+    ) -> Self {
+        let mut characteristics = 0u32;
+        characteristics
+            .set_bits(0..7, device_addr as u32)
+            .set_bits(8..12, endpoint as u32)
+            .set_bits(12..14, EPS_HIGH)
+            //control endpoints take data toggle from qTD, others keep it in QH
+            .set_bit(14, control)
+            .set_bit(15, reclamation_head)
+            .set_bits(16..27, (max_packet_size & 0x7ff) as u32)
+            //NAK reload
+            .set_bits(28..32, 4);
+
+        let mut capabilities = 0u32;
+        //one transaction per micro-frame
+        capabilities.set_bits(30..32, 1);
+
+        let mut overlay = TransferDescriptor::default();
+        overlay.next = TERMINATE;
+        overlay.alt_next = TERMINATE;
+
+        Self {
+            horizontal: TERMINATE,
+            characteristics,
+            capabilities,
+            current: 0,
+            overlay,
+        }
+    }
+
+    ///poll on micro-frame 0 of every frame it is linked into, `mult` transactions each time
+    pub fn set_interrupt_schedule(&mut self, mult: u8) -> &mut Self {
+        self.capabilities
+            .set_bits(0..8, 0x01)
+            .set_bits(30..32, mult.clamp(1, 3) as u32);
+        self
+    }
+
+    ///never executed, used as list heads
+    pub fn set_halted(&mut self) -> &mut Self {
+        self.overlay.token.set_bit(status::HALTED, true);
+        self
+    }
+
+    pub fn set_device_addr(&mut self, device_addr: u8) {
+        let mut characteristics = unsafe { addr_of!(self.characteristics).read_volatile() };
+        characteristics.set_bits(0..7, device_addr as u32);
+        unsafe { addr_of_mut!(self.characteristics).write_volatile(characteristics) }
+    }
+
+    pub fn set_horizontal(&mut self, link: u32) {
+        unsafe { addr_of_mut!(self.horizontal).write_volatile(link) }
+    }
+
+    pub fn horizontal(&self) -> u32 {
+        unsafe { addr_of!(self.horizontal).read_volatile() }
+    }
+
+    ///hand a qTD chain to an idle QH, controller picks it up on next visit
+    pub fn start(&mut self, first_qtd: u32) {
+        let overlay_token = addr_of_mut!(self.overlay.token);
+        unsafe {
+            //clear active and halted, keep data toggle for non control endpoints
+            let token = overlay_token.read_volatile() & (1 << 31);
+            overlay_token.write_volatile(token);
+            addr_of_mut!(self.overlay.alt_next).write_volatile(TERMINATE);
+            addr_of_mut!(self.overlay.next).write_volatile(first_qtd);
+        }
+    }
+
+    ///detach finished chain, so controller stops looking at memory about to be freed
+    pub fn idle(&mut self) {
+        unsafe {
+            addr_of_mut!(self.overlay.alt_next).write_volatile(TERMINATE);
+            addr_of_mut!(self.overlay.next).write_volatile(TERMINATE);
+        }
+    }
+
+    ///device restarts at DATA0 after its halt got cleared, only touch an idle QH
+    pub fn reset_toggle(&mut self) {
+        let overlay_token = addr_of_mut!(self.overlay.token);
+        unsafe { overlay_token.write_volatile(overlay_token.read_volatile() & !(1 << 31)) }
+    }
+
+    ///overlay halted, endpoint would not move until restarted
+    pub fn is_halted(&self) -> bool {
+        unsafe { addr_of!(self.overlay.token).read_volatile() }.get_bit(status::HALTED)
+    }
+}
+
+pub fn qh_link(phys: usize) -> u32 {
+    phys as u32 | TYPE_QH
+}
+
+///split buffer into qTD sized chunks, each chunk ends on max packet boundary unless it's the last
+pub fn split_qtd_buffers(addr: usize, len: usize, max_packet_size: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut cur = addr;
+    let end = addr + len;
+    while cur < end {
+        let mut chunk = TransferDescriptor::max_len(cur).min(end - cur);
+        if cur + chunk < end && max_packet_size > 0 {
+            chunk -= chunk % max_packet_size;
+        }
+        chunks.push((cur, chunk));
+        cur += chunk;
+    }
+    if chunks.is_empty() {
+        //zero length packet
+        chunks.push((addr, 0));
+    }
+    chunks
+}
@@ -0,0 +1,255 @@
+//! golden descriptor corpus, dumps of real devices run through enumeration, endpoint setup and
+//! driver matching on the mock backend.
+//!
+//! dumps are kept as devices sent them, one descriptor per row, so a regression in any stage
+//! between decoder and driver matching shows up against known good hardware
+
+use alloc::vec;
+
+use super::Fixture;
+use crate::usb::operations::hub::DeviceSpeed;
+
+///low speed keyboard 046d:c31c, boot keyboard plus a consumer control interface
+const KEYBOARD_DEVICE: [u8; 18] = [
+    0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0x6d, 0x04, 0x1c, 0xc3, 0x10, 0x64, 0x01, 0x02,
+    0x00, 0x01,
+];
+const KEYBOARD_CONFIG: [u8; 59] = [
+    0x09, 0x02, 0x3b, 0x00, 0x02, 0x01, 0x00, 0xa0, 0x2d, //
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, //
+    0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, //
+    0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0a, //
+    0x09, 0x04, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, //
+    0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x9f, 0x00, //
+    0x07, 0x05, 0x82, 0x03, 0x04, 0x00, 0xff,
+];
+
+///high speed single TT hub 05e3:0608
+const HUB_DEVICE: [u8; 18] = [
+    0x12, 0x01, 0x00, 0x02, 0x09, 0x00, 0x01, 0x40, 0xe3, 0x05, 0x08, 0x06, 0x32, 0x85, 0x00, 0x01,
+    0x00, 0x01,
+];
+const HUB_CONFIG: [u8; 25] = [
+    0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0xe0, 0x32, //
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x09, 0x00, 0x00, 0x00, //
+    0x07, 0x05, 0x81, 0x03, 0x01, 0x00, 0x0c,
+];
+
+///high speed webcam 046d:0825, video and microphone grouped by interface associations.
+///streaming interfaces take no bandwidth in alternate setting 0
+const WEBCAM_DEVICE: [u8; 18] = [
+    0x12, 0x01, 0x00, 0x02, 0xef, 0x02, 0x01, 0x40, 0x6d, 0x04, 0x25, 0x08, 0x12, 0x00, 0x00, 0x00,
+    0x02, 0x01,
+];
+const WEBCAM_CONFIG: [u8; 257] = [
+    0x09, 0x02, 0x01, 0x01, 0x04, 0x01, 0x00, 0x80, 0xfa, //
+    //video function
+    0x08, 0x0b, 0x00, 0x02, 0x0e, 0x03, 0x00, 0x00, //
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x0e, 0x01, 0x00, 0x00, //
+    0x0d, 0x24, 0x01, 0x00, 0x01, 0x28, 0x00, 0x80, 0xc3, 0xc9, 0x01, 0x01, 0x01, //
+    0x12, 0x24, 0x02, 0x01, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x0e,
+    0x00, 0x00, //
+    0x09, 0x24, 0x03, 0x02, 0x01, 0x01, 0x00, 0x01, 0x00, //
+    0x07, 0x05, 0x83, 0x03, 0x10, 0x00, 0x08, //
+    0x05, 0x25, 0x03, 0x10, 0x00, //
+    0x09, 0x04, 0x01, 0x00, 0x00, 0x0e, 0x02, 0x00, 0x00, //
+    0x0e, 0x24, 0x01, 0x01, 0x37, 0x00, 0x81, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, //
+    0x0b, 0x24, 0x06, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, //
+    0x1e, 0x24, 0x07, 0x01, 0x00, 0x80, 0x02, 0xe0, 0x01, 0x00, 0x00, 0x77, 0x01, 0x00, 0x00, 0xca,
+    0x08, 0x00, 0x60, 0x09, 0x00, 0x15, 0x16, 0x05, 0x00, 0x01, 0x15, 0x16, 0x05, 0x00, //
+    0x09, 0x04, 0x01, 0x01, 0x01, 0x0e, 0x02, 0x00, 0x00, //
+    0x07, 0x05, 0x81, 0x05, 0x00, 0x14, 0x01, //
+    //audio function
+    0x08, 0x0b, 0x02, 0x02, 0x01, 0x02, 0x00, 0x00, //
+    0x09, 0x04, 0x02, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, //
+    0x09, 0x24, 0x01, 0x00, 0x01, 0x1e, 0x00, 0x01, 0x03, //
+    0x0c, 0x24, 0x02, 0x01, 0x01, 0x02, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, //
+    0x09, 0x24, 0x03, 0x03, 0x01, 0x01, 0x00, 0x01, 0x00, //
+    0x09, 0x04, 0x03, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, //
+    0x09, 0x04, 0x03, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00, //
+    0x07, 0x24, 0x01, 0x03, 0x01, 0x01, 0x00, //
+    0x0b, 0x24, 0x02, 0x01, 0x01, 0x02, 0x10, 0x01, 0x80, 0x3e, 0x00, //
+    0x09, 0x05, 0x84, 0x05, 0x20, 0x00, 0x04, 0x00, 0x00, //
+    0x07, 0x25, 0x01, 0x01, 0x00, 0x00, 0x00,
+];
+
+///superspeed flash drive 0781:5581, bulk only transport with bursts of 16 packets
+const STORAGE_DEVICE: [u8; 18] = [
+    0x12, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x81, 0x07, 0x81, 0x55, 0x00, 0x01, 0x01, 0x02,
+    0x03, 0x01,
+];
+const STORAGE_CONFIG: [u8; 44] = [
+    0x09, 0x02, 0x2c, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, //
+    0x09, 0x04, 0x00, 0x00, 0x02, 0x08, 0x06, 0x50, 0x00, //
+    0x07, 0x05, 0x81, 0x02, 0x00, 0x04, 0x00, //
+    0x06, 0x30, 0x0f, 0x00, 0x00, 0x00, //
+    0x07, 0x05, 0x02, 0x02, 0x00, 0x04, 0x00, //
+    0x06, 0x30, 0x0f, 0x00, 0x00, 0x00,
+];
+
+fn fixture(speed: DeviceSpeed, device_desc: &[u8], config_desc: &[u8]) -> Fixture {
+    Fixture {
+        port_idx: 0,
+        speed,
+        device_desc: device_desc.to_vec(),
+        config_descs: vec![config_desc.to_vec()],
+    }
+}
+
+pub(super) fn keyboard() -> Fixture {
+    fixture(DeviceSpeed::Low, &KEYBOARD_DEVICE, &KEYBOARD_CONFIG)
+}
+
+pub(super) fn hub() -> Fixture {
+    fixture(DeviceSpeed::High, &HUB_DEVICE, &HUB_CONFIG)
+}
+
+pub(super) fn webcam() -> Fixture {
+    fixture(DeviceSpeed::High, &WEBCAM_DEVICE, &WEBCAM_CONFIG)
+}
+
+pub(super) fn storage() -> Fixture {
+    fixture(DeviceSpeed::Super, &STORAGE_DEVICE, &STORAGE_CONFIG)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use usb_descriptor_decoder::descriptors::desc_interface::TopologyUSBFunction;
+
+    use super::{hub, keyboard, storage, webcam, Fixture};
+    use crate::{
+        abstractions::{testing::test_config, SpecPolicy},
+        host::controllers::mock::tests::{enumerated, mock_with, run, Device, Mock},
+    };
+
+    ///under strict policy, anything decoder or spec checks refuse fails enumeration loudly
+    fn enumerate(fixture: Fixture) -> (&'static Mock, Arc<Device>, u8) {
+        let mut config = (*test_config()).clone();
+        config.spec_policy = SpecPolicy::Strict;
+        let mock = mock_with(Arc::new(config), fixture);
+        let (device, addr) = enumerated(mock);
+        (mock, device, addr)
+    }
+
+    ///interface number and count of alternate settings of every function of first
+    ///configuration, None for functions grouped by an interface association
+    fn functions(device: &Device) -> Vec<Option<(u8, usize)>> {
+        device.descriptor.get().unwrap().configs[0]
+            .functions
+            .iter()
+            .map(|function| match function.as_ref() {
+                TopologyUSBFunction::Interface(alts) => {
+                    Some((alts[0].interface.interface_number, alts.len()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    ///(dci, interval, wMaxPacketSize) of every endpoint, after enabling every interface
+    fn set_up(mock: &'static Mock, device: &Arc<Device>, addr: u8) -> Vec<(u8, u8, u16)> {
+        for interface in device.interfaces(device.current_config()) {
+            run(mock, device.enable_function(interface)).unwrap();
+        }
+        mock.programmed(addr)
+            .into_iter()
+            .map(|(dci, checked)| (dci, checked.interval, checked.max_packet_size))
+            .collect()
+    }
+
+    #[test]
+    fn keyboard_interfaces_stand_alone() {
+        let (mock, device, addr) = enumerate(keyboard());
+        assert_eq!(functions(&device), vec![Some((0, 1)), Some((1, 1))]);
+        //HID descriptor follows each interface
+        for interface in 0..2 {
+            let class = device.class_descriptors(interface, 0);
+            assert_eq!(class.interface.len(), 1);
+            assert_eq!(class.interface[0].descriptor_type(), 0x21);
+        }
+        //low speed interrupt intervals are in frames
+        assert_eq!(set_up(mock, &device, addr), vec![(3, 10, 8), (5, 255, 4)]);
+    }
+
+    #[test]
+    fn hub_has_one_status_endpoint() {
+        let (mock, device, addr) = enumerate(hub());
+        assert_eq!(functions(&device), vec![Some((0, 1))]);
+        //high speed intervals are exponents
+        assert_eq!(set_up(mock, &device, addr), vec![(3, 12, 1)]);
+    }
+
+    #[test]
+    fn webcam_functions_are_grouped_by_association() {
+        let (_, device, _) = enumerate(webcam());
+        assert_eq!(functions(&device), vec![None, None]);
+
+        //video control: header, camera and streaming terminals, then interrupt endpoint status
+        let control = device.class_descriptors(0, 0);
+        let subtypes: Vec<_> = control.interface.iter().map(|d| d.subtype()).collect();
+        assert_eq!(subtypes, vec![Some(0x01), Some(0x02), Some(0x03)]);
+        assert_eq!(control.endpoint(0x83).count(), 1);
+        //video streaming: header, MJPEG format and its frame, nothing in bandwidth setting
+        assert_eq!(device.class_descriptors(1, 0).interface.len(), 3);
+        assert!(device.class_descriptors(1, 1).interface.is_empty());
+        //audio streaming: general and format type, 9 byte endpoint keeps its class descriptor
+        let microphone = device.class_descriptors(3, 1);
+        assert_eq!(microphone.interface.len(), 2);
+        assert_eq!(
+            microphone.endpoint(0x84).next().unwrap().descriptor_type(),
+            0x25
+        );
+    }
+
+    #[test]
+    fn storage_bulk_pair_at_superspeed() {
+        let (mock, device, addr) = enumerate(storage());
+        assert!(device.is_superspeed());
+        assert_eq!(functions(&device), vec![Some((0, 1))]);
+        //companions are not class descriptors
+        assert!(device.class_descriptors(0, 0).endpoints.is_empty());
+        assert_eq!(
+            set_up(mock, &device, addr),
+            vec![(3, 0, 1024), (4, 0, 1024)]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "packed-drivers")]
+    fn packed_drivers_match_their_class_only() {
+        use crate::{
+            abstractions::testing::{TestPlatform, TEST_RING_BUFFER_SIZE},
+            driver::{
+                driverapi::{MatchedInterface, USBSystemDriverModule},
+                implemented_drivers::{
+                    hid_mouse::HIDMouseModule, hub::HubModule, mass_storage::MassStorageModule,
+                },
+            },
+        };
+
+        type Module = dyn USBSystemDriverModule<'static, TestPlatform, TEST_RING_BUFFER_SIZE>;
+        let modules: [(&str, &Module); 3] = [
+            ("hub", &HubModule),
+            ("hid mouse", &HIDMouseModule),
+            ("mass storage", &MassStorageModule::new()),
+        ];
+        //(module, interface it got) for every module whose rules matched
+        let matched = |fixture: Fixture| -> Vec<(&str, u8)> {
+            let (_, device, _) = enumerate(fixture);
+            modules
+                .iter()
+                .filter_map(|(name, module)| {
+                    MatchedInterface::find(&device, module.match_rules())
+                        .map(|found| (*name, found.interface.interface.interface_number))
+                })
+                .collect()
+        };
+
+        //neither boot keyboard nor consumer control is a mouse
+        assert_eq!(matched(keyboard()), vec![]);
+        assert_eq!(matched(hub()), vec![("hub", 0)]);
+        assert_eq!(matched(webcam()), vec![]);
+        assert_eq!(matched(storage()), vec![("mass storage", 0)]);
+    }
+}
@@ -0,0 +1,699 @@
+//! controller without hardware behind it, devices are fixtures answering from their descriptors.
+//!
+//! every device gets memory standing in for its context and rings, tagged the way real backends
+//! tag theirs, so teardown paths can be checked against [`dma_tracker`]
+
+mod corpus;
+
+use core::{
+    future::pending,
+    mem,
+    ptr::copy_nonoverlapping,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use async_lock::OnceCell;
+use embassy_futures::block_on;
+use futures::{future::BoxFuture, FutureExt};
+use log::{debug, info, trace};
+use usb_descriptor_decoder::descriptors::{
+    desc_interface::USBInterface, USBStandardDescriptorTypes,
+};
+
+use crate::{
+    abstractions::{
+        dma::DMA,
+        dma_tracker::{self, DmaKind},
+        spin::SpinCell,
+        PlatformAbstractions, USBSystemConfig,
+    },
+    event::EventBus,
+    host::device::USBDevice,
+    usb::{
+        capabilities::Capabilities,
+        conformance::CheckedEndpoint,
+        operations::{
+            control::{bRequest, bRequestStandard, ControlTransfer},
+            hub::DeviceSpeed,
+            CompleteAction, Direction, RequestResult, RequestedOperation, USBRequest, UsbError,
+        },
+        standards::TopologyRoute,
+    },
+};
+
+use super::{
+    common::Attached, statistics::StatsCell, Controller, InitError, Outstanding, PolledLoop,
+    Statistics,
+};
+
+const TAG: &str = "[MOCK]";
+const CONTROL_DCI: u8 = 1;
+const DEVICE_DESC_MAX_PACKET_SIZE_OFFSET: usize = 7;
+///size of what stands in for a device context and for a transfer ring
+const CONTEXT_SIZE: usize = 2048;
+const RING_SIZE: usize = 256;
+
+///never reused within a test binary, and above any usb address, so leak reports of tests
+///running side by side never mix up their owners
+static NEXT_ADDR: AtomicU8 = AtomicU8::new(128);
+
+///device plugged into a [`MockController`]
+#[derive(Clone)]
+pub(crate) struct Fixture {
+    pub port_idx: usize,
+    pub speed: DeviceSpeed,
+    pub device_desc: Vec<u8>,
+    ///by configuration index
+    pub config_descs: Vec<Vec<u8>>,
+}
+
+///memory a real backend would hand its controller for a device
+struct MockSlot<O>
+where
+    O: PlatformAbstractions,
+{
+    route: TopologyRoute,
+    _context: DMA<[u8], O>,
+    ///by dci, ep0 included
+    rings: BTreeMap<u8, DMA<[u8], O>>,
+    ///what a real backend would program into endpoint contexts, by dci, ep0 left out
+    endpoints: BTreeMap<u8, CheckedEndpoint>,
+}
+
+///answers standard control requests of fixtures plugged into its root ports.
+///
+///bulk, interrupt and isochronous traffic and hubs are refused as unsupported. requests complete
+///as soon as they are taken, so nothing is ever outstanding
+pub(crate) struct MockController<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+    fixtures: SpinCell<Vec<Fixture>>,
+    attached: Attached<O, RING_BUFFER_SIZE>,
+    slots: SpinCell<BTreeMap<u8, MockSlot<O>>>,
+    event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+    index: u8,
+    event_loop: PolledLoop<'a>,
+    scheduler_loop: PolledLoop<'a>,
+    stats: StatsCell,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> MockController<'a, O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    ///attached on init, or on [`Controller::rescan`] once running
+    pub fn plug(&self, fixture: Fixture) {
+        self.fixtures.with(|fixtures| fixtures.push(fixture));
+    }
+
+    ///disconnect on root port, device is released along with everything it was handed
+    pub async fn unplug(&self, port_idx: usize) {
+        let route = self.root_route(port_idx);
+        self.fixtures
+            .with(|fixtures| fixtures.retain(|fixture| fixture.port_idx != port_idx));
+        for device in self.attached.take_route(&route) {
+            self.release_device(device).await;
+        }
+        info!("{TAG} device at port {} removed", port_idx);
+    }
+
+    fn root_route(&self, port_idx: usize) -> TopologyRoute {
+        TopologyRoute::from_port_idx(port_idx).on_controller(self.index)
+    }
+
+    fn fixture_at(&self, route: &TopologyRoute) -> Option<Fixture> {
+        self.fixtures.with(|fixtures| {
+            fixtures
+                .iter()
+                .find(|fixture| self.root_route(fixture.port_idx) == *route)
+                .cloned()
+        })
+    }
+
+    fn device_of_addr(&self, addr: u8) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.of_slot(addr)
+    }
+
+    fn fixture_of_addr(&self, addr: u8) -> Option<Fixture> {
+        let route = self
+            .slots
+            .with(|slots| slots.get(&addr).map(|slot| slot.route.clone()))?;
+        self.fixture_at(&route)
+    }
+
+    fn attach(&self, fixture: &Fixture) {
+        let (mut usbdevice, _, receivers) = USBDevice::new(self.config.clone());
+        usbdevice.topology_path = self.root_route(fixture.port_idx);
+        usbdevice.speed = fixture.speed;
+
+        let devref: Arc<_> = usbdevice.into();
+        self.attached.attach(&devref, receivers);
+        self.event_bus
+            .pre_initialize_device
+            .broadcast(devref.clone());
+    }
+
+    fn alloc(&self, len: usize, kind: DmaKind, addr: u8) -> Option<DMA<[u8], O>> {
+        DMA::try_new_vec(0u8, len, 64, self.config.os.dma_alloc())
+            .ok()
+            .map(|dma| dma.tagged(kind, Some(addr)))
+    }
+
+    async fn assign_address(
+        &self,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ) -> Result<(), UsbError> {
+        let route = device.topology_path.clone();
+        let fixture = self.fixture_at(&route).ok_or(UsbError::DeviceGone)?;
+        let declared = *fixture
+            .device_desc
+            .get(DEVICE_DESC_MAX_PACKET_SIZE_OFFSET)
+            .ok_or(UsbError::BadDescriptor)?;
+        if !fixture.speed.is_valid_ep0_packet_size(declared) {
+            self.config.spec_policy.check(format_args!(
+                "{TAG} device at {} bMaxPacketSize0 {} at {:?}",
+                route, declared, fixture.speed
+            ))?;
+        }
+        let addr = NEXT_ADDR
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |addr| {
+                addr.checked_add(1)
+            })
+            .map_err(|_| UsbError::NoSlot)?;
+        let (Some(context), Some(ring)) = (
+            self.alloc(CONTEXT_SIZE, DmaKind::DeviceContext, addr),
+            self.alloc(RING_SIZE, DmaKind::TransferRing, addr),
+        ) else {
+            return Err(UsbError::OutOfMemory);
+        };
+        self.slots.with(|slots| {
+            slots.insert(
+                addr,
+                MockSlot {
+                    route,
+                    _context: context,
+                    rings: BTreeMap::from([(CONTROL_DCI, ring)]),
+                    endpoints: BTreeMap::new(),
+                },
+            )
+        });
+        debug!(
+            "{TAG} address {} assigned to {}",
+            addr, device.topology_path
+        );
+
+        let _ = device.slot_id.set(addr).await;
+        let _ = device.device_desc_raw.set(fixture.device_desc).await;
+        Ok(())
+    }
+
+    ///drops whatever device was handed
+    fn disable_slot(&self, addr: u8) {
+        drop(self.slots.with(|slots| slots.remove(&addr)));
+        dma_tracker::report_owner(addr);
+        debug!("{TAG} device {} disabled", addr);
+    }
+
+    async fn release_device(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        self.attached.stop_taking(&device).await;
+        self.event_bus.pre_drop_device.broadcast(device.clone());
+
+        if let Some(&addr) = device.slot_id.get() {
+            self.disable_slot(addr);
+        }
+        self.event_bus.device_removed.broadcast(device);
+    }
+
+    ///GET_DESCRIPTOR of device and configuration descriptors, any other request without data
+    ///succeeds, and any other one reading data stalls
+    fn control(&self, addr: u8, transfer: &ControlTransfer) -> (RequestResult, usize) {
+        let Some(fixture) = self.fixture_of_addr(addr) else {
+            return (RequestResult::SlotNotEnabledError, 0);
+        };
+        let (buffer, len) = transfer.data.unwrap_or((0, 0));
+        let reply = match &transfer.request {
+            bRequest::Standard(bRequestStandard::GetDescriptor) => {
+                let [desc_index, desc_type] = transfer.value.to_le_bytes();
+                if desc_type == USBStandardDescriptorTypes::Device as u8 {
+                    Some(&fixture.device_desc)
+                } else if desc_type == USBStandardDescriptorTypes::Configuration as u8 {
+                    fixture.config_descs.get(desc_index as usize)
+                } else {
+                    None
+                }
+            }
+            _ if matches!(transfer.request_type.direction, Direction::Out) || len == 0 => {
+                return (RequestResult::Success, 0);
+            }
+            _ => None,
+        };
+        let Some(reply) = reply else {
+            trace!("{TAG} device {} stalls {:?}", addr, transfer.request);
+            return (RequestResult::StallError, 0);
+        };
+        let length = reply.len().min(len);
+        let virt: usize = O::VirtAddr::from(O::PhysAddr::from(buffer)).into();
+        //safety: buffer is lent to controller until request completes
+        unsafe { copy_nonoverlapping(reply.as_ptr(), virt as *mut u8, length) };
+        let code = if length < len {
+            RequestResult::ShortPacket
+        } else {
+            RequestResult::Success
+        };
+        (code, length)
+    }
+
+    ///endpoints are checked against spec first, nothing is set up if any of them is refused
+    fn enable_function(&self, addr: u8, interface: &USBInterface) -> Result<(), UsbError> {
+        let fixture = self.fixture_of_addr(addr).ok_or(UsbError::DeviceGone)?;
+        let route = self.root_route(fixture.port_idx);
+        let mut checked = Vec::with_capacity(interface.endpoints.len());
+        for ep in &interface.endpoints {
+            let values =
+                CheckedEndpoint::check(self.config.spec_policy, &route, fixture.speed, ep)?;
+            checked.push((ep.doorbell_value_aka_dci() as u8, values));
+        }
+        for (dci, values) in checked {
+            let ring = self
+                .alloc(RING_SIZE, DmaKind::TransferRing, addr)
+                .ok_or(UsbError::OutOfMemory)?;
+            self.slots.with(|slots| {
+                if let Some(slot) = slots.get_mut(&addr) {
+                    slot.rings.insert(dci, ring);
+                    slot.endpoints.insert(dci, values);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn disable_function(&self, addr: u8, interface: &USBInterface) {
+        let dcis: Vec<u8> = interface
+            .endpoints
+            .iter()
+            .map(|ep| ep.doorbell_value_aka_dci() as u8)
+            .collect();
+        self.slots.with(|slots| {
+            if let Some(slot) = slots.get_mut(&addr) {
+                slot.rings.retain(|dci, _| !dcis.contains(dci));
+                slot.endpoints.retain(|dci, _| !dcis.contains(dci));
+            }
+        });
+    }
+
+    fn deconfigure(&self, addr: u8) {
+        self.slots.with(|slots| {
+            if let Some(slot) = slots.get_mut(&addr) {
+                slot.rings.retain(|dci, _| *dci == CONTROL_DCI);
+                slot.endpoints.clear();
+            }
+        });
+    }
+
+    ///endpoint values device at `addr` got set up with, by dci
+    pub fn programmed(&self, addr: u8) -> BTreeMap<u8, CheckedEndpoint> {
+        self.slots.with(|slots| {
+            slots
+                .get(&addr)
+                .map(|slot| slot.endpoints.clone())
+                .unwrap_or_default()
+        })
+    }
+
+    async fn complete(&self, action: CompleteAction, code: RequestResult, transferred: usize) {
+        self.stats.completed_with(Ok(code));
+        match action {
+            CompleteAction::LengthResponse(sender) => {
+                let _ = sender.send(Ok((code, transferred)));
+            }
+            CompleteAction::DropSem(sem) => {
+                if let Err(err) = UsbError::check(Ok(code)) {
+                    sem.fail(err).await
+                }
+            }
+            action => action.respond(code),
+        }
+    }
+
+    async fn post_transfer(&self, req: USBRequest, slot: Arc<OnceCell<u8>>) {
+        trace!("{TAG} {} dispatching {:?}", req.id, req.operation);
+        let addr = slot.get().copied();
+        match (req.operation, addr) {
+            (RequestedOperation::InitializeDevice(route), _) => {
+                let result = match self.attached.at(&route) {
+                    Some(dev) => self.assign_address(&dev).await,
+                    None => Err(UsbError::DeviceGone),
+                };
+                if let Err(err) = result {
+                    req.complete_action.fail(err, RequestResult::Invalid).await
+                }
+            }
+            (RequestedOperation::NOOP, _) => {}
+            (_, None) => {
+                req.complete_action
+                    .fail(UsbError::DeviceGone, RequestResult::SlotNotEnabledError)
+                    .await
+            }
+            (RequestedOperation::Control(transfer), Some(addr)) => {
+                let (code, transferred) = self.control(addr, &transfer);
+                if let Some(device) = self.device_of_addr(addr) {
+                    device.record_completion(CONTROL_DCI as _, Ok(code));
+                    if matches!(code, RequestResult::Success | RequestResult::ShortPacket) {
+                        device.record_bytes(CONTROL_DCI as _, transferred);
+                    }
+                }
+                self.complete(req.complete_action, code, transferred).await
+            }
+            (RequestedOperation::EnableFunction(_, interface), Some(addr)) => {
+                match self.enable_function(addr, &interface) {
+                    Ok(()) => {
+                        self.complete(req.complete_action, RequestResult::Success, 0)
+                            .await
+                    }
+                    Err(err) => req.complete_action.fail(err, RequestResult::Invalid).await,
+                }
+            }
+            (RequestedOperation::DisableFunction(interface), Some(addr)) => {
+                self.disable_function(addr, &interface);
+                self.complete(req.complete_action, RequestResult::Success, 0)
+                    .await
+            }
+            (RequestedOperation::Deconfigure, Some(addr)) => {
+                self.deconfigure(addr);
+                self.complete(req.complete_action, RequestResult::Success, 0)
+                    .await
+            }
+            (
+                RequestedOperation::ClearStall(_)
+                | RequestedOperation::Suspend
+                | RequestedOperation::Resume,
+                Some(_),
+            ) => req.complete_action.respond(RequestResult::Success),
+            (operation, Some(addr)) => {
+                debug!("{TAG} {:?} of device {} not supported", operation, addr);
+                req.complete_action
+                    .fail(UsbError::Unsupported, RequestResult::Invalid)
+                    .await
+            }
+        }
+    }
+
+    ///requests of devices gone meanwhile are dropped by [`Attached::next`]
+    async fn run_once(&'a self) {
+        for (req, slot) in self.attached.next().await {
+            self.post_transfer(req, slot).await
+        }
+    }
+
+    ///devices refuse further requests, and everything they were handed is dropped
+    async fn forget_devices(&self) {
+        self.attached.forget().await;
+        let slots = self.slots.with(mem::take);
+        let addrs: Vec<u8> = slots.keys().copied().collect();
+        drop(slots);
+        for addr in addrs {
+            dma_tracker::report_owner(addr);
+        }
+    }
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> Controller<'a, O, RING_BUFFER_SIZE>
+    for MockController<'a, O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    fn new(
+        config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+        event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+        index: u8,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            config,
+            fixtures: Vec::new().into(),
+            attached: Attached::default(),
+            slots: BTreeMap::new().into(),
+            event_bus,
+            index,
+            event_loop: PolledLoop::new(),
+            scheduler_loop: PolledLoop::new(),
+            stats: StatsCell::default(),
+        }
+    }
+
+    fn init(&self) -> Result<(), InitError> {
+        for fixture in self.fixtures.with(|fixtures| fixtures.clone()) {
+            self.attach(&fixture);
+        }
+        self.event_loop.start();
+        self.scheduler_loop.start();
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.event_loop.stop();
+        self.scheduler_loop.stop();
+        block_on(self.forget_devices());
+        info!("{TAG} shut down");
+    }
+
+    fn device_accesses(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.all()
+    }
+
+    fn rescan(&self) {
+        for fixture in self.fixtures.with(|fixtures| fixtures.clone()) {
+            if self
+                .attached
+                .at(&self.root_route(fixture.port_idx))
+                .is_none()
+            {
+                self.attach(&fixture);
+            }
+        }
+    }
+
+    fn reset_port(&'a self, port_idx: usize) -> BoxFuture<'a, bool> {
+        let present = self.fixture_at(&self.root_route(port_idx)).is_some();
+        async move { present }.boxed()
+    }
+
+    fn quiesce(&'a self) -> BoxFuture<'a, ()> {
+        async {}.boxed()
+    }
+
+    fn resume(&'a self) -> BoxFuture<'a, ()> {
+        async {}.boxed()
+    }
+
+    ///no events, fixtures are plugged and unplugged by hand
+    fn poll_events(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.event_loop.poll(cx, || pending().boxed())
+    }
+
+    fn poll_scheduler(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.scheduler_loop.poll(cx, || {
+            async move {
+                loop {
+                    self.run_once().await
+                }
+            }
+            .boxed()
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    fn outstanding(&self) -> Outstanding {
+        Outstanding {
+            queued: self.attached.queued(),
+            ..Default::default()
+        }
+    }
+
+    fn stats(&self) -> Statistics {
+        self.stats.snapshot()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::{poll_fn, Future};
+
+    use alloc::{boxed::Box, sync::Arc, vec};
+    use async_lock::RwLock;
+    use embassy_futures::{
+        block_on,
+        select::{select, Either},
+    };
+    use usb_descriptor_decoder::DescriptorDecoder;
+
+    use super::{Fixture, MockController};
+    use crate::{
+        abstractions::{
+            dma_tracker,
+            testing::{test_config, TestPlatform, TEST_RING_BUFFER_SIZE},
+            SpecPolicy, USBSystemConfig,
+        },
+        event::EventBus,
+        host::{controllers::Controller, device::USBDevice},
+        usb::{
+            operations::{hub::DeviceSpeed, UsbError},
+            snapshot::DeviceSnapshot,
+        },
+    };
+
+    pub(super) type Mock = MockController<'static, TestPlatform, TEST_RING_BUFFER_SIZE>;
+    pub(super) type Device = USBDevice<TestPlatform, TEST_RING_BUFFER_SIZE>;
+
+    ///vendor specific device 1234:5678 with a single configuration
+    const DEVICE_DESC: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0xff, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x01,
+    ];
+    ///one vendor specific interface with a bulk IN/OUT pair
+    const CONFIG_DESC: [u8; 32] = [
+        0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, //
+        0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, //
+        0x07, 0x05, 0x81, 0x02, 0x00, 0x02, 0x00, //
+        0x07, 0x05, 0x02, 0x02, 0x00, 0x02, 0x00,
+    ];
+
+    ///device above at `speed`, declaring `b_max_packet_size0` for ep0
+    fn fixture(speed: DeviceSpeed, b_max_packet_size0: u8) -> Fixture {
+        let mut device_desc = DEVICE_DESC.to_vec();
+        if speed == DeviceSpeed::Super {
+            device_desc[2..4].copy_from_slice(&0x0300u16.to_le_bytes());
+        }
+        device_desc[7] = b_max_packet_size0;
+        Fixture {
+            port_idx: 0,
+            speed,
+            device_desc,
+            config_descs: vec![CONFIG_DESC.to_vec()],
+        }
+    }
+
+    ///running, with `fixture` plugged
+    pub(super) fn mock_with(
+        config: Arc<USBSystemConfig<TestPlatform, TEST_RING_BUFFER_SIZE>>,
+        fixture: Fixture,
+    ) -> &'static Mock {
+        let mock: &'static Mock =
+            Box::leak(Box::new(Mock::new(config, Arc::new(EventBus::new()), 0)));
+        mock.plug(fixture);
+        mock.init().unwrap();
+        mock
+    }
+
+    fn mock() -> &'static Mock {
+        mock_with(test_config(), fixture(DeviceSpeed::High, 64))
+    }
+
+    ///`fut` with scheduler of `mock` polled alongside
+    pub(super) fn run<T>(mock: &'static Mock, fut: impl Future<Output = T>) -> T {
+        match block_on(select(fut, poll_fn(|cx| mock.poll_scheduler(cx)))) {
+            Either::First(output) => output,
+            Either::Second(_) => panic!("scheduler stopped"),
+        }
+    }
+
+    pub(super) fn assign(mock: &'static Mock) -> (Arc<Device>, Result<(), UsbError>) {
+        let device = mock.device_accesses().pop().unwrap();
+        let result = run(mock, async {
+            device
+                .add_decoder(Arc::new(RwLock::new(DescriptorDecoder::new())))
+                .await;
+            device.request_assign().await
+        });
+        (device, result)
+    }
+
+    pub(super) fn enumerated(mock: &'static Mock) -> (Arc<Device>, u8) {
+        let (device, result) = assign(mock);
+        result.unwrap();
+        let addr = *device.slot_id.get().unwrap();
+        (device, addr)
+    }
+
+    #[test]
+    fn enumerates_from_fixture() {
+        let (device, _) = enumerated(mock());
+        assert_eq!(device.vendor_id.get(), Some(&0x1234));
+        assert_eq!(device.product_id.get(), Some(&0x5678));
+    }
+
+    #[test]
+    fn snapshot_counts_control_transfers() {
+        let mock = mock();
+        let (device, _) = enumerated(mock);
+        let snapshot = block_on(DeviceSnapshot::capture(&device));
+        assert!(snapshot.control.completed > snapshot.control.errors);
+        assert!(snapshot.control.bytes >= CONFIG_DESC.len());
+        let endpoints = &snapshot.configs[0].interfaces[0].endpoints;
+        assert_eq!(endpoints.len(), 2);
+        assert!(endpoints
+            .iter()
+            .all(|endpoint| endpoint.stats.completed == 0));
+    }
+
+    #[test]
+    fn enumerates_at_every_speed() {
+        for (speed, b_max_packet_size0) in [
+            (DeviceSpeed::Full, 8),
+            (DeviceSpeed::Full, 64),
+            (DeviceSpeed::High, 64),
+            (DeviceSpeed::Super, 9),
+        ] {
+            let (device, _) =
+                enumerated(mock_with(test_config(), fixture(speed, b_max_packet_size0)));
+            assert_eq!(device.speed, speed);
+            assert_eq!(device.is_superspeed(), speed == DeviceSpeed::Super);
+        }
+    }
+
+    #[test]
+    fn strict_policy_refuses_usb2_ep0_size_at_superspeed() {
+        let mut config = (*test_config()).clone();
+        config.spec_policy = SpecPolicy::Strict;
+        let (device, result) = assign(mock_with(Arc::new(config), fixture(DeviceSpeed::Super, 64)));
+        assert!(matches!(result, Err(UsbError::SpecViolation)));
+        assert!(device.slot_id.get().is_none());
+    }
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore = "nothing is tracked in release builds")]
+    fn unplug_releases_device_memory() {
+        let mock = mock();
+        let (_, addr) = enumerated(mock);
+        assert!(dma_tracker::live()
+            .iter()
+            .any(|record| record.owner == Some(addr)));
+        run(mock, mock.unplug(0));
+        assert_eq!(dma_tracker::report_owner(addr), 0);
+    }
+
+    #[test]
+    #[cfg_attr(not(debug_assertions), ignore = "nothing is tracked in release builds")]
+    fn shutdown_releases_device_memory() {
+        let mock = mock();
+        let (_, addr) = enumerated(mock);
+        mock.shutdown();
+        assert_eq!(dma_tracker::report_owner(addr), 0);
+    }
+}
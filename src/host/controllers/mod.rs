@@ -1,15 +1,229 @@
-use core::future::Future;
+use core::{
+    fmt::Display,
+    future::{poll_fn, Future},
+    pin::pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
 
 ///host layer
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
-use futures::{future::BoxFuture, task::FutureObj};
+use async_lock::OnceCell;
+use async_ringbuf::traits::AsyncConsumer;
+use futures::{
+    future::{join, BoxFuture},
+    task::FutureObj,
+    FutureExt,
+};
+use ringbuf::traits::Consumer;
 
 use crate::{
-    abstractions::{PlatformAbstractions, USBSystemConfig},
+    abstractions::{spin::SpinCell, ControllerKind, PlatformAbstractions, USBSystemConfig},
     event::EventBus,
+    usb::{
+        capabilities::Capabilities,
+        operations::{Latency, USBRequest},
+    },
 };
 
-use super::device::USBDevice;
+use super::device::{ArcAsyncRingBufCons, USBDevice};
+
+pub(crate) mod common;
+pub(crate) mod statistics;
+#[cfg(feature = "trace_trb_ring")]
+pub(crate) mod trace;
+
+pub use statistics::{Statistics, TrbKind};
+#[cfg(feature = "trace_trb_ring")]
+pub use trace::{TrbOrigin, TrbRecord, TrbTrace};
+
+///every request already waiting in `channels`, see [`Latency`] for the order
+pub(crate) fn drain_by_latency<'r, const N: usize>(
+    channels: impl Iterator<
+        Item = (
+            &'r mut ArcAsyncRingBufCons<USBRequest, N>,
+            &'r Arc<OnceCell<u8>>,
+        ),
+    >,
+) -> Vec<(USBRequest, Arc<OnceCell<u8>>)> {
+    let (mut urgent, mut normal) = (Vec::new(), Vec::new());
+    for (receiver, slot) in channels {
+        let mut pending = Vec::new();
+        while let Some(mut req) = receiver.try_pop() {
+            //picked up, no longer queued as far as flow control is concerned
+            req.flow = None;
+            pending.push(req);
+        }
+        let inherited = pending
+            .iter()
+            .rposition(|req| req.latency == Latency::Realtime)
+            .map_or(0, |idx| idx + 1);
+        for (idx, req) in pending.into_iter().enumerate() {
+            if idx < inherited {
+                urgent.push((req, slot.clone()));
+            } else {
+                normal.push((req, slot.clone()));
+            }
+        }
+    }
+    urgent.append(&mut normal);
+    urgent
+}
+
+///[`drain_by_latency`] once any of `channels` has something.
+///a pop left pending keeps waker of `cx` with its channel, so the next push wakes caller
+pub(crate) fn poll_by_latency<const N: usize>(
+    cx: &mut Context<'_>,
+    mut channels: Vec<(&mut ArcAsyncRingBufCons<USBRequest, N>, &Arc<OnceCell<u8>>)>,
+) -> Poll<Vec<(USBRequest, Arc<OnceCell<u8>>)>> {
+    let waiting = drain_by_latency(
+        channels
+            .iter_mut()
+            .map(|(receiver, slot)| (&mut **receiver, *slot)),
+    );
+    if !waiting.is_empty() {
+        return Poll::Ready(waiting);
+    }
+    //pushed since drained, taken by the pop that registers the waker
+    let mut raced = Vec::new();
+    for (receiver, slot) in channels {
+        if let Poll::Ready(Some(mut req)) = pin!(receiver.pop()).poll(cx) {
+            req.flow = None;
+            raced.push((req, slot.clone()));
+        }
+    }
+    if raced.is_empty() {
+        Poll::Pending
+    } else {
+        Poll::Ready(raced)
+    }
+}
+
+///controller could not be brought up.
+///
+///page size support, controller PAGESIZE against `O::PAGE_SIZE`:
+///- equal: always works
+///- controller smaller: platform pages are multiples of it, always works
+///- controller larger: works if platform allocator honors alignment up to controller page size,
+///  checked once at init
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    ///PAGESIZE register reported no supported page size at all
+    NoPageSize,
+    ///allocator could not give DMA memory aligned to controller page size
+    PageAlignment { controller: usize, platform: usize },
+    ///allocator ran out of DMA memory for structure handed to controller at init
+    DmaExhausted { what: &'static str, size: usize },
+    ///[`crate::abstractions::USBSystemConfig::bounded_memory`] is set, but backend can't reserve up front
+    BoundedMemoryUnsupported,
+    ///[`crate::abstractions::PlatformAbstractions::dma_coherent`] is false, but backend doesn't
+    ///clean or invalidate caches around what it hands to controller
+    NonCoherentDmaUnsupported,
+    ///controller did not halt, reset or start within the time spec gives it
+    Timeout { what: &'static str },
+}
+
+impl Display for InitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            InitError::NoPageSize => write!(f, "controller reports no supported page size"),
+            InitError::PageAlignment {
+                controller,
+                platform,
+            } => write!(
+                f,
+                "controller page size {:#x} exceeds platform page size {:#x}, \
+                 and allocator can't align to it",
+                controller, platform
+            ),
+            InitError::DmaExhausted { what, size } => {
+                write!(f, "out of DMA memory for {} ({:#x} bytes)", what, size)
+            }
+            InitError::BoundedMemoryUnsupported => {
+                write!(f, "backend can't run on bounded DMA memory")
+            }
+            InitError::NonCoherentDmaUnsupported => {
+                write!(f, "backend can't run without cache coherent DMA")
+            }
+            InitError::Timeout { what } => write!(f, "controller did not {} in time", what),
+        }
+    }
+}
+
+///work a controller still holds, see [`Controller::outstanding`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Outstanding {
+    ///posted by devices, not taken by controller yet
+    pub queued: usize,
+    ///held back while quiescing
+    pub parked: usize,
+    ///submitted to controller, not completed yet
+    pub transfers: usize,
+    ///issued to controller, not completed yet. 0 for backends without command ring
+    pub commands: usize,
+    ///reported by controller, not delivered to whoever waits on them yet
+    pub completions: usize,
+    ///kept-filling interrupt endpoints
+    pub kept: usize,
+}
+
+impl Outstanding {
+    pub fn is_idle(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Display for Outstanding {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} queued, {} parked, {} transfers, {} commands, {} undelivered completions, \
+             {} kept endpoints",
+            self.queued, self.parked, self.transfers, self.commands, self.completions, self.kept
+        )
+    }
+}
+
+///loops of a controller kept between polls, see [`Controller::poll_events`]
+pub(crate) struct PolledLoop<'a> {
+    future: SpinCell<Option<BoxFuture<'a, ()>>>,
+    running: AtomicBool,
+}
+
+impl<'a> PolledLoop<'a> {
+    pub const fn new() -> Self {
+        Self {
+            future: SpinCell::new(None),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    ///controller got initialized, loops are made anew on next poll
+    pub fn start(&self) {
+        self.running.store(true, Ordering::Release);
+    }
+
+    ///controller shut down, loops are dropped so a later init does not resume them on stale state
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Release);
+        //dropped outside of the lock, loops may hold anything
+        drop(self.future.with(Option::take));
+    }
+
+    ///poll loops made by `make` on first call, Ready while controller is not running
+    pub fn poll(&self, cx: &mut Context<'_>, make: impl FnOnce() -> BoxFuture<'a, ()>) -> Poll<()> {
+        self.future.with(|future| {
+            if !self.running.load(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            let poll = future.get_or_insert_with(make).as_mut().poll(cx);
+            if poll.is_ready() {
+                *future = None;
+            }
+            poll
+        })
+    }
+}
 
 pub trait Controller<'a, O, const RING_BUFFER_SIZE: usize>: Send + Sync
 where
@@ -18,43 +232,102 @@ where
     fn new(
         config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
         event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+        index: u8,
     ) -> Self
     where
         Self: Sized;
 
-    fn init(&self);
+    ///could be called again after [`Self::shutdown`], memory given to controller is allocated anew.
+    ///
+    ///on failure controller is left reset, holding nothing allocated by this call
+    fn init(&self) -> Result<(), InitError>;
 
-    fn device_accesses(&self) -> &Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>>;
+    ///halt controller and free memory it was handed at init, devices stay with whoever holds them
+    fn shutdown(&self);
 
-    fn workaround(&'a self) -> BoxFuture<'a, ()>;
-}
+    ///devices attached right now, a copy since attach and detach may change them any time
+    fn device_accesses(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>>;
 
-match_cfg! {
-    #[cfg(feature = "backend-xhci")]=>{
-        mod xhci;
-
-        pub fn initialize_controller<'a, O,const RING_BUFFER_SIZE:usize>(
-            config: Arc<USBSystemConfig<O,RING_BUFFER_SIZE>>,
-            event_bus:Arc<EventBus<'a,O,RING_BUFFER_SIZE>>
-        ) -> Box<dyn Controller<'a, O,RING_BUFFER_SIZE>>
-        where
-        //wtf
-            O: PlatformAbstractions+'static,
-            'a:'static,
-        {
-            Box::new(xhci::XHCIController::new(config,event_bus))
-        }
+    ///pick up devices attached after init, before event processing started
+    fn rescan(&self);
+
+    ///reset a root port and wait until reset change reported, returns whether port got enabled
+    fn reset_port(&'a self, port_idx: usize) -> BoxFuture<'a, bool>;
+
+    ///hold back new submissions and wait until every outstanding job completed
+    fn quiesce(&'a self) -> BoxFuture<'a, ()>;
+
+    ///submit everything held back while quiescing
+    fn resume(&'a self) -> BoxFuture<'a, ()>;
+
+    ///drive event handling from an external reactor, e.g. through `poll_fn`.
+    ///
+    ///decodes events and follows up on root port changes, `cx` is woken once there is more.
+    ///Ready while controller is not running, i.e. before init and after shutdown.
+    ///one task at a time polls it
+    fn poll_events(&'a self, cx: &mut Context<'_>) -> Poll<()>;
+
+    ///takes requests in, delivers completions and watches deadlines, see [`Self::poll_events`].
+    ///completions only arrive while events are polled as well
+    fn poll_scheduler(&'a self, cx: &mut Context<'_>) -> Poll<()>;
+
+    ///both polls above until controller shuts down
+    fn workaround(&'a self) -> BoxFuture<'a, ()> {
+        join(
+            poll_fn(|cx| self.poll_events(cx)),
+            poll_fn(|cx| self.poll_scheduler(cx)),
+        )
+        .map(|_| ())
+        .boxed()
     }
-    _=>{
-        pub fn initialize_controller<'a, O>(
-            config: Arc<USBSystemConfig<O>>,
-        ) -> Box<dyn Controller<'a, O>>
-        where
-            O: PlatformAbstractions+'static,
-            'a:'static, [(); O::RING_BUFFER_SIZE]://wtf
-        {
-            Box::new(DummyController::new(config))
-        }
+
+    ///abilities of this backend, on top of [`Capabilities::compiled`]
+    fn capabilities(&self) -> Capabilities;
+
+    ///snapshot of work not finished yet, for debugging hangs
+    fn outstanding(&self) -> Outstanding;
+
+    ///snapshot of counters kept since creation or last [`Self::reset_stats`]
+    fn stats(&self) -> Statistics;
+
+    fn reset_stats(&self);
+
+    ///recent TRBs per slot, empty unless [`USBSystemConfig::trb_trace`] is set
+    #[cfg(feature = "trace_trb_ring")]
+    fn trb_trace(&self) -> TrbTrace {
+        TrbTrace::default()
+    }
+}
+
+#[cfg(feature = "backend-ehci")]
+mod ehci;
+#[cfg(test)]
+pub(crate) mod mock;
+#[cfg(feature = "backend-xhci")]
+mod xhci;
+
+///None if backend of `kind` is not compiled in
+pub fn initialize_controller<'a, O, const RING_BUFFER_SIZE: usize>(
+    kind: ControllerKind,
+    index: u8,
+    config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+    event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+) -> Option<Box<dyn Controller<'a, O, RING_BUFFER_SIZE>>>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    match kind {
+        #[cfg(feature = "backend-xhci")]
+        ControllerKind::Xhci => Some(Box::new(xhci::XHCIController::new(
+            config, event_bus, index,
+        ))),
+        #[cfg(feature = "backend-ehci")]
+        ControllerKind::Ehci => Some(Box::new(ehci::EHCIController::new(
+            config, event_bus, index,
+        ))),
+        #[allow(unreachable_patterns)]
+        _ => None,
     }
 }
 
@@ -67,6 +340,7 @@ where
     fn new(
         _config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
         _evtbus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+        _index: u8,
     ) -> Self
     where
         Self: Sized,
@@ -74,15 +348,56 @@ where
         panic!("dummy controller")
     }
 
-    fn init(&self) {
+    fn init(&self) -> Result<(), InitError> {
         panic!("dummy controller")
     }
 
-    fn device_accesses(&self) -> &Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+    fn device_accesses(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
         panic!("dummy controller")
     }
 
-    fn workaround(&'a self) -> BoxFuture<'a, ()> {
+    fn rescan(&self) {
+        panic!("dummy controller")
+    }
+
+    fn reset_port(&'a self, _port_idx: usize) -> BoxFuture<'a, bool> {
+        panic!("dummy controller")
+    }
+
+    fn quiesce(&'a self) -> BoxFuture<'a, ()> {
+        panic!("dummy controller")
+    }
+
+    fn resume(&'a self) -> BoxFuture<'a, ()> {
+        panic!("dummy controller")
+    }
+
+    fn poll_events(&'a self, _cx: &mut Context<'_>) -> Poll<()> {
+        panic!("dummy controller")
+    }
+
+    fn poll_scheduler(&'a self, _cx: &mut Context<'_>) -> Poll<()> {
+        panic!("dummy controller")
+    }
+
+    ///folded into [`crate::USBSystem::capabilities`] like any other controller
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    fn shutdown(&self) {
+        panic!("dummy controller")
+    }
+
+    fn outstanding(&self) -> Outstanding {
+        panic!("dummy controller")
+    }
+
+    fn stats(&self) -> Statistics {
+        panic!("dummy controller")
+    }
+
+    fn reset_stats(&self) {
         panic!("dummy controller")
     }
 }
@@ -0,0 +1,136 @@
+use core::fmt::Display;
+
+use alloc::collections::btree_map::BTreeMap;
+
+use crate::{
+    abstractions::spin::SpinCell,
+    usb::{operations::RequestResult, standards::TopologyRoute},
+};
+
+///TRB types counted apart. ehci has no TRBs, its qTDs are counted as the TRB doing their job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrbKind {
+    Normal,
+    Setup,
+    Data,
+    Status,
+    Isoch,
+    EventData,
+    NoOp,
+    Link,
+    Command,
+}
+
+///counters a controller keeps while running, for tuning wake method and ring sizes on real hardware.
+///
+///counting starts at controller creation, see [`crate::USBSystem::reset_stats`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    ///handed to controller
+    pub submitted: BTreeMap<TrbKind, u64>,
+    ///reported back by controller, one per completion event, not per TRB it covers
+    pub completed: BTreeMap<TrbKind, u64>,
+    ///failed completions of transfers and commands, codes unknown to us by raw value
+    pub errors: BTreeMap<Result<RequestResult, u8>, u64>,
+    ///event handler woken, by interrupt or by [`crate::abstractions::WakeMethod`] polling
+    pub wakeups: u64,
+    ///submissions that found their transfer ring full and had to wait, always 0 on ehci
+    pub ring_full: u64,
+    ///bytes transferred by completed transfers, of every device seen since last reset
+    pub device_bytes: BTreeMap<TopologyRoute, u64>,
+}
+
+impl Statistics {
+    pub fn submitted_total(&self) -> u64 {
+        self.submitted.values().sum()
+    }
+
+    pub fn completed_total(&self) -> u64 {
+        self.completed.values().sum()
+    }
+
+    pub fn errors_total(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+impl Display for Statistics {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} submitted, {} completed, {} errors, {} wakeups, {} ring full waits, \
+             {} bytes over {} devices",
+            self.submitted_total(),
+            self.completed_total(),
+            self.errors_total(),
+            self.wakeups,
+            self.ring_full,
+            self.device_bytes.values().sum::<u64>(),
+            self.device_bytes.len()
+        )
+    }
+}
+
+///[`Statistics`] as kept by a controller, updated from event and scheduler loops alike
+pub(crate) struct StatsCell(SpinCell<Statistics>);
+
+impl Default for StatsCell {
+    fn default() -> Self {
+        Self(SpinCell::new(Statistics::default()))
+    }
+}
+
+impl StatsCell {
+    pub fn submitted(&self, kinds: impl IntoIterator<Item = TrbKind>) {
+        self.0.with(|stats| {
+            kinds
+                .into_iter()
+                .for_each(|kind| *stats.submitted.entry(kind).or_default() += 1)
+        });
+    }
+
+    pub fn completed(&self, kind: TrbKind) {
+        self.0
+            .with(|stats| *stats.completed.entry(kind).or_default() += 1);
+    }
+
+    ///success, short packet and stops asked for are not counted
+    pub fn completed_with(&self, code: Result<RequestResult, u8>) {
+        if matches!(
+            code,
+            Ok(RequestResult::Success
+                | RequestResult::ShortPacket
+                | RequestResult::Stopped
+                | RequestResult::StoppedLengthInvalid
+                | RequestResult::StoppedShortPacket)
+        ) {
+            return;
+        }
+        self.0
+            .with(|stats| *stats.errors.entry(code).or_default() += 1);
+    }
+
+    pub fn woken(&self) {
+        self.0.with(|stats| stats.wakeups += 1);
+    }
+
+    pub fn ring_full(&self) {
+        self.0.with(|stats| stats.ring_full += 1);
+    }
+
+    pub fn transferred(&self, route: &TopologyRoute, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.0
+            .with(|stats| *stats.device_bytes.entry(route.clone()).or_default() += bytes as u64);
+    }
+
+    pub fn snapshot(&self) -> Statistics {
+        self.0.with(|stats| stats.clone())
+    }
+
+    pub fn reset(&self) {
+        self.0.with(|stats| *stats = Statistics::default());
+    }
+}
@@ -0,0 +1,95 @@
+use core::{fmt::Display, time::Duration};
+
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    vec::Vec,
+};
+
+use crate::abstractions::spin::SpinCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum TrbOrigin {
+    ///written to a transfer or command ring by us
+    Submitted,
+    ///read from an event ring
+    Event,
+}
+
+///one TRB as it was in memory, see [`TrbTrace`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TrbRecord {
+    ///[`crate::abstractions::PlatformAbstractions::now`] when recorded
+    pub at: Option<Duration>,
+    pub origin: TrbOrigin,
+    ///endpoint the TRB belongs to, 0 for commands and events of no endpoint
+    pub dci: u8,
+    pub raw: [u32; 4],
+}
+
+///recent TRBs of a controller, oldest first, for bug reports from real hardware.
+///
+///slot 0 holds commands and events not tied to a slot, e.g. port status changes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TrbTrace {
+    pub slots: BTreeMap<u8, Vec<TrbRecord>>,
+}
+
+///one line per TRB, raw dwords in the order xhci spec draws them
+impl Display for TrbTrace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (slot, records) in &self.slots {
+            for record in records {
+                match record.at {
+                    Some(at) => write!(f, "{:>12?} ", at)?,
+                    None => write!(f, "{:>12} ", "-")?,
+                }
+                let [d0, d1, d2, d3] = record.raw;
+                writeln!(
+                    f,
+                    "slot {:>3} dci {:>2} {:?} {:08x} {:08x} {:08x} {:08x}",
+                    slot, record.dci, record.origin, d0, d1, d2, d3
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///keeps last `depth` records of every slot, older ones are dropped
+pub(crate) struct TrbTracer {
+    depth: usize,
+    slots: SpinCell<BTreeMap<u8, VecDeque<TrbRecord>>>,
+}
+
+impl TrbTracer {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            slots: SpinCell::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record(&self, slot: u8, record: TrbRecord) {
+        self.slots.with(|slots| {
+            let records = slots.entry(slot).or_default();
+            if records.len() >= self.depth {
+                records.pop_front();
+            }
+            records.push_back(record);
+        });
+    }
+
+    pub fn dump(&self) -> TrbTrace {
+        TrbTrace {
+            slots: self.slots.with(|slots| {
+                slots
+                    .iter()
+                    .map(|(slot, records)| (*slot, records.iter().copied().collect()))
+                    .collect()
+            }),
+        }
+    }
+}
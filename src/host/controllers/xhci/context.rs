@@ -1,28 +1,32 @@
-use core::cell::SyncUnsafeCell;
+use core::alloc::AllocError;
 use core::usize;
 
 use crate::abstractions::dma::DMA;
+use crate::abstractions::dma_tracker::{self, DmaKind};
 use crate::abstractions::{PlatformAbstractions, SystemWordWide, USBSystemConfig};
 
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use alloc::{format, vec};
+use async_lock::Mutex;
 use core::ops::{Deref, DerefMut};
 use log::trace;
 use xhci::context::{Device, Device32Byte, DeviceHandler, Input64Byte, InputHandler};
 use xhci::context::{Device64Byte, Input32Byte};
 use xhci::ring::trb::transfer;
 
-use super::ring::Ring;
-const NUM_EPS: usize = 32;
+use super::super::InitError;
+use super::ring::{Ring, RingSpan, TrbData};
+use super::stream::{StreamContextArray, StreamHandle};
+pub const NUM_EPS: usize = 32;
 
 pub struct DeviceContextList<O, const RING_BUFFER_SIZE: usize>
 where
     O: PlatformAbstractions,
 {
     config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
-    pub dcbaa: SyncUnsafeCell<DMA<[u64; 256], O>>,
+    pub dcbaa: DMA<[u64; 256], O>,
     pub device_ctx_inners: BTreeMap<u8, DeviceCtxInner<O>>,
 }
 
@@ -32,7 +36,9 @@ where
 {
     pub out_ctx: DeviceCtx<O>,
     pub in_ctx: InputCtx<O>,
-    pub transfer_rings: Vec<Ring<O>>,
+    ///locked one by one, so submissions on different endpoints only share the list read lock
+    pub transfer_rings: Vec<Mutex<Ring<O>>>,
+    pub stream_arrays: BTreeMap<usize, StreamContextArray<O>>,
 }
 
 pub enum InputCtx<O>
@@ -55,14 +61,18 @@ where
     O: PlatformAbstractions,
 {
     pub fn new(ctx_size: SystemWordWide, a: O::DMA) -> Self {
-        match ctx_size {
+        Self::try_new(ctx_size, a).unwrap()
+    }
+
+    pub fn try_new(ctx_size: SystemWordWide, a: O::DMA) -> Result<Self, AllocError> {
+        Ok(match ctx_size {
             SystemWordWide::X64 => {
-                Self::B64(DMA::new(Device64Byte::new_64byte(), 4096, a).fill_zero())
+                Self::B64(DMA::try_new(Device64Byte::new_64byte(), 4096, a)?.fill_zero())
             }
             SystemWordWide::X32 => {
-                Self::B32(DMA::new(Device32Byte::new_32byte(), 4096, a).fill_zero())
+                Self::B32(DMA::try_new(Device32Byte::new_32byte(), 4096, a)?.fill_zero())
             }
-        }
+        })
     }
 
     pub fn access_mut(&mut self) -> &mut dyn DeviceHandler {
@@ -85,6 +95,21 @@ where
             DeviceCtx::B32(dma) => dma.addr(),
         }
     }
+
+    pub fn sync_for_device(&self, os: &O) {
+        match self {
+            DeviceCtx::B64(dma) => dma.sync_for_device(os),
+            DeviceCtx::B32(dma) => dma.sync_for_device(os),
+        }
+    }
+
+    ///controller updates output context on every context command
+    pub fn sync_for_cpu(&self, os: &O) {
+        match self {
+            DeviceCtx::B64(dma) => dma.sync_for_cpu(os),
+            DeviceCtx::B32(dma) => dma.sync_for_cpu(os),
+        }
+    }
 }
 
 impl<O> InputCtx<O>
@@ -92,14 +117,18 @@ where
     O: PlatformAbstractions,
 {
     pub fn new(ctx_size: SystemWordWide, a: O::DMA) -> Self {
-        match ctx_size {
+        Self::try_new(ctx_size, a).unwrap()
+    }
+
+    pub fn try_new(ctx_size: SystemWordWide, a: O::DMA) -> Result<Self, AllocError> {
+        Ok(match ctx_size {
             SystemWordWide::X64 => {
-                Self::B64(DMA::new(Input64Byte::new_64byte(), 4096, a.clone()).fill_zero())
+                Self::B64(DMA::try_new(Input64Byte::new_64byte(), 4096, a)?.fill_zero())
             }
             SystemWordWide::X32 => {
-                Self::B32(DMA::new(Input32Byte::new_32byte(), 4096, a.clone()).fill_zero())
+                Self::B32(DMA::try_new(Input32Byte::new_32byte(), 4096, a)?.fill_zero())
             }
-        }
+        })
     }
 
     pub fn access(&mut self) -> &mut dyn InputHandler {
@@ -116,6 +145,13 @@ where
         }
     }
 
+    pub fn sync_for_device(&self, os: &O) {
+        match self {
+            InputCtx::B64(dma) => dma.sync_for_device(os),
+            InputCtx::B32(dma) => dma.sync_for_device(os),
+        }
+    }
+
     pub fn copy_from_output(&mut self, output: &DeviceCtx<O>) {
         match (self, output) {
             (InputCtx::B64(i), DeviceCtx::B64(o)) => (&mut **i).copy_from_output(&**o),
@@ -130,15 +166,18 @@ where
     O: PlatformAbstractions,
 {
     pub fn new(cfg: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>) -> Self {
+        let dcbaa =
+            DMA::new([0u64; 256], 4096, cfg.os.dma_alloc()).tagged(DmaKind::DeviceContext, None);
+        dcbaa.sync_for_device(&cfg.os);
         Self {
             config: cfg.clone(),
-            dcbaa: DMA::new([0u64; 256], 4096, cfg.os.dma_alloc()).into(),
+            dcbaa,
             device_ctx_inners: BTreeMap::new(),
         }
     }
 
     pub fn dcbaap(&self) -> O::VirtAddr {
-        (unsafe { self.dcbaa.get().read_volatile() }.as_ptr() as usize).into()
+        self.dcbaa.addr()
     }
 
     pub fn write_transfer_ring(&mut self, slot: u8, dci: usize) -> Option<&mut Ring<O>> {
@@ -147,9 +186,11 @@ where
             .expect(format!("no such transfer ring at slot {slot}").as_str())
             .transfer_rings
             .get_mut(dci - 1)
+            .map(Mutex::get_mut)
     }
 
-    pub fn read_transfer_ring(&self, slot: u8, dci: usize) -> Option<&Ring<O>> {
+    ///enqueue path, only needs shared access to the list
+    pub fn transfer_ring(&self, slot: u8, dci: usize) -> Option<&Mutex<Ring<O>>> {
         assert!(dci > 0 && dci < 32);
         self.device_ctx_inners
             .get(&(slot as _))?
@@ -157,49 +198,195 @@ where
             .get(dci - 1)
     }
 
+    ///TRB addresses occupied by transfer ring and rings of its streams,
+    ///any completion key of that endpoint lies in them
+    pub async fn transfer_ring_range(&self, slot: u8, dci: usize) -> Option<RingSpan> {
+        let mut span = self.transfer_ring(slot, dci)?.lock().await.span();
+        if let Some(array) = self.stream_array(slot, dci) {
+            for (_, ring) in array.opened() {
+                span.0.extend(ring.lock().await.span().0);
+            }
+        }
+        Some(span)
+    }
+
+    ///start transfer ring over, used after endpoint got dropped
+    pub fn reset_transfer_ring(&mut self, slot: u8, dci: usize) {
+        if let Some(ring) = self.write_transfer_ring(slot, dci) {
+            ring.clear();
+            Self::prepare_transfer_ring(ring);
+        }
+    }
+
+    pub fn new_stream_array(
+        &mut self,
+        slot: u8,
+        dci: usize,
+        requested: u16,
+        max_psa_size: u8,
+    ) -> Option<&mut StreamContextArray<O>> {
+        let array = StreamContextArray::new(self.config.os.clone(), requested, max_psa_size)?;
+        let inner = self.device_ctx_inners.get_mut(&slot)?;
+        inner.stream_arrays.insert(dci, array);
+        inner.stream_arrays.get_mut(&dci)
+    }
+
+    ///validates `id` against stream array of endpoint, giving the stream its ring on first use
+    pub fn open_stream(&mut self, slot: u8, dci: usize, id: u16) -> Option<StreamHandle> {
+        let os = self.config.os.clone();
+        self.device_ctx_inners
+            .get_mut(&slot)?
+            .stream_arrays
+            .get_mut(&dci)?
+            .open(id, || {
+                let mut ring = Ring::try_new(os, 32, true)
+                    .ok()?
+                    .tagged(DmaKind::TransferRing, Some(slot));
+                Self::prepare_transfer_ring(&mut ring);
+                Some(ring)
+            })
+    }
+
+    pub fn stream_array(&self, slot: u8, dci: usize) -> Option<&StreamContextArray<O>> {
+        self.device_ctx_inners.get(&slot)?.stream_arrays.get(&dci)
+    }
+
+    ///SCT of stream rings and streams opened so far, None if endpoint has no streams
+    pub fn opened_streams(&self, slot: u8, dci: usize) -> Option<(u8, Vec<StreamHandle>)> {
+        let array = self.stream_array(slot, dci)?;
+        Some((
+            array.ring_context_type(),
+            array.opened().map(|(handle, _)| *handle).collect(),
+        ))
+    }
+
+    ///enqueue path of an opened stream, see [`Self::transfer_ring`]
+    pub fn stream_ring(
+        &self,
+        slot: u8,
+        dci: usize,
+        stream: &StreamHandle,
+    ) -> Option<&Mutex<Ring<O>>> {
+        self.device_ctx_inners
+            .get(&slot)?
+            .stream_arrays
+            .get(&dci)?
+            .ring(stream)
+    }
+
+    ///ring a TD goes on, the stream's one if it names a stream
+    pub fn ring_of(
+        &self,
+        slot: u8,
+        dci: usize,
+        stream: Option<&StreamHandle>,
+    ) -> Option<&Mutex<Ring<O>>> {
+        match stream {
+            Some(stream) => self.stream_ring(slot, dci, stream),
+            None => self.transfer_ring(slot, dci),
+        }
+    }
+
+    pub fn write_ring_of(
+        &mut self,
+        slot: u8,
+        dci: usize,
+        stream: Option<&StreamHandle>,
+    ) -> Option<&mut Ring<O>> {
+        match stream {
+            Some(stream) => self
+                .device_ctx_inners
+                .get_mut(&slot)?
+                .stream_arrays
+                .get_mut(&dci)?
+                .ring_mut(stream),
+            None => self.write_transfer_ring(slot, dci),
+        }
+    }
+
+    ///ring TRB at `addr` was enqueued on, transfer events don't tell streams apart
+    pub async fn ring_holding(&self, slot: u8, dci: usize, addr: usize) -> Option<&Mutex<Ring<O>>> {
+        let Some(array) = self.stream_array(slot, dci) else {
+            return self.transfer_ring(slot, dci);
+        };
+        for (_, ring) in array.opened() {
+            if ring.lock().await.trb_at(addr).is_some() {
+                return Some(ring);
+            }
+        }
+        None
+    }
+
     pub fn new_slot(
         &mut self,
         slot: u8,
         num_ep: usize, // cannot lesser than 0, and consider about alignment, use usize
-    ) {
-        let os = &self.config.os;
+    ) -> Result<(), AllocError> {
+        let inner = Self::alloc_slot(&self.config.os, slot, num_ep)?;
+        let dcbaap = inner.out_ctx.addr();
+        trace!("inserted new transfer ring at slot {}", slot);
+        self.device_ctx_inners.insert(slot, inner);
 
-        let out_ctx = DeviceCtx::new(O::WORD, os.dma_alloc());
-        let in_ctx = InputCtx::new(O::WORD, os.dma_alloc());
-        let dcbaap = out_ctx.addr();
+        self.dcbaa[slot as usize] = O::PhysAddr::from(dcbaap).into() as _;
+        self.dcbaa.sync_for_device(&self.config.os);
+        Ok(())
+    }
 
-        trace!("inserted new transfer ring at slot {}", slot);
+    ///memory of `devices` slots not bound to any, freeing it leaves it to bounded DMA
+    pub fn alloc_spare_slots(
+        &self,
+        devices: usize,
+        num_ep: usize,
+    ) -> Result<Vec<DeviceCtxInner<O>>, AllocError> {
+        (0..devices)
+            .map(|_| Self::alloc_slot(&self.config.os, 0, num_ep))
+            .collect()
+    }
 
-        self.device_ctx_inners.insert(
-            slot,
-            DeviceCtxInner {
-                in_ctx,
-                out_ctx,
-                transfer_rings: {
-                    (0..num_ep)
-                        .map(|i| (i, Ring::new(os.clone(), 32, true)))
-                        .map(|(i, r)| {
-                            // if i == 0 {
-                            // r
-                            // } else {
-                            Self::prepare_transfer_ring(r)
-                            // }
-                        }) //only for non control endpoint
-                        .collect()
-                },
-            },
-        );
+    ///bytes [`Self::new_slot`] allocates
+    pub fn slot_bytes(num_ep: usize) -> usize {
+        let contexts = match O::WORD {
+            SystemWordWide::X64 => size_of::<Device64Byte>() + size_of::<Input64Byte>(),
+            SystemWordWide::X32 => size_of::<Device32Byte>() + size_of::<Input32Byte>(),
+        };
+        contexts + num_ep * 32 * size_of::<TrbData>()
+    }
+
+    fn alloc_slot(os: &O, slot: u8, num_ep: usize) -> Result<DeviceCtxInner<O>, AllocError> {
+        let out_ctx = DeviceCtx::try_new(O::WORD, os.dma_alloc())?;
+        let in_ctx = InputCtx::try_new(O::WORD, os.dma_alloc())?;
+        out_ctx.sync_for_device(os);
+        dma_tracker::tag(out_ctx.addr().into(), DmaKind::DeviceContext, Some(slot));
+        dma_tracker::tag(in_ctx.addr().into(), DmaKind::InputContext, Some(slot));
+
+        let transfer_rings = (0..num_ep)
+            .map(|_| {
+                let mut ring =
+                    Ring::try_new(os.clone(), 32, true)?.tagged(DmaKind::TransferRing, Some(slot));
+                Self::prepare_transfer_ring(&mut ring);
+                Ok(Mutex::new(ring))
+            })
+            .collect::<Result<_, AllocError>>()?;
+
+        Ok(DeviceCtxInner {
+            in_ctx,
+            out_ctx,
+            transfer_rings,
+            stream_arrays: BTreeMap::new(),
+        })
+    }
 
-        let get_mut = self.dcbaa.get_mut();
-        get_mut[slot as usize] = O::PhysAddr::from(dcbaap).into() as _;
+    pub fn free_slot(&mut self, slot: u8) {
+        self.device_ctx_inners.remove(&slot);
+        self.dcbaa[slot as usize] = 0;
+        self.dcbaa.sync_for_device(&self.config.os);
     }
 
-    fn prepare_transfer_ring(mut r: Ring<O>) -> Ring<O> {
+    fn prepare_transfer_ring(r: &mut Ring<O>) {
         //in our code, the init state of transfer ring always has ccs = 0, so we use ccs =1 to fill transfer ring
         let mut norm = transfer::Normal::default();
         norm.set_cycle_bit();
         r.enque_trbs_no_check(vec![norm.into_raw(); r.len() - 1]); //the n'th is link trb
-        r
     }
 }
 
@@ -236,28 +423,132 @@ impl<O> ScratchpadBufferArray<O>
 where
     O: PlatformAbstractions,
 {
-    pub fn new(entries: u32, os: O) -> Self {
-        let page_size = O::PAGE_SIZE;
+    ///scratchpad pages are sized and aligned by `page_size` of controller, not of platform
+    pub fn new(entries: u32, os: O, page_size: usize) -> Result<Self, InitError> {
         let align = 64;
+        let misaligned = InitError::PageAlignment {
+            controller: page_size,
+            platform: O::PAGE_SIZE,
+        };
 
         let mut entries: DMA<[ScratchpadBufferEntry], O> =
-            DMA::zeroed(entries as usize, align, os.dma_alloc());
-
+            DMA::try_zeroed(entries as usize, align, os.dma_alloc())
+                .map_err(|_| InitError::DmaExhausted {
+                    what: "scratchpad array",
+                    size: entries as usize * size_of::<ScratchpadBufferEntry>(),
+                })?
+                .tagged(DmaKind::Scratchpad, None);
+
+        //pages allocated so far are freed on early return
         let pages = entries
             .iter_mut()
             .map(|entry| {
-                let dma = DMA::zeroed(page_size, align, os.dma_alloc());
+                let dma = DMA::try_zeroed(page_size, page_size, os.dma_alloc())
+                    .map_err(|_| {
+                        if page_size > O::PAGE_SIZE {
+                            misaligned
+                        } else {
+                            InitError::DmaExhausted {
+                                what: "scratchpad page",
+                                size: page_size,
+                            }
+                        }
+                    })?
+                    .tagged(DmaKind::Scratchpad, None);
                 let paddr = O::PhysAddr::from(dma.addr()).into();
 
-                assert_eq!(paddr % page_size, 0);
+                //virtual alignment doesn't imply physical one
+                if paddr % page_size != 0 {
+                    return Err(misaligned);
+                }
                 entry.set_addr(paddr as _);
-                dma
+                dma.sync_for_device(&os);
+                Ok(dma)
             })
-            .collect();
+            .collect::<Result<_, _>>()?;
+        entries.sync_for_device(&os);
 
-        Self { entries, pages }
+        Ok(Self { entries, pages })
     }
     pub fn register(&self) -> O::VirtAddr {
         self.entries.addr()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{println, thread, time::Instant};
+
+    use alloc::sync::Arc;
+    use async_lock::RwLock;
+    use embassy_futures::block_on;
+    use xhci::ring::trb::transfer;
+
+    use super::DeviceContextList;
+    use crate::abstractions::testing::{test_config, TestPlatform, TEST_RING_BUFFER_SIZE};
+
+    type List = RwLock<DeviceContextList<TestPlatform, TEST_RING_BUFFER_SIZE>>;
+
+    const DEVICES: u8 = 8;
+    const SUBMISSIONS: usize = 20_000;
+
+    fn list() -> Arc<List> {
+        let mut list = DeviceContextList::new(test_config());
+        for slot in 1..=DEVICES {
+            list.new_slot(slot, 2).unwrap();
+        }
+        Arc::new(RwLock::new(list))
+    }
+
+    ///one TRB enqueued and finished right away, so the ring never runs full
+    fn submit(ring: &mut super::Ring<TestPlatform>) {
+        let addr = ring.enque_transfer(transfer::Allowed::Normal(transfer::Normal::default()));
+        ring.consumed(addr);
+    }
+
+    ///nanoseconds per submission with every device submitting on its own thread at once
+    fn contend(list: &Arc<List>, per_ring: bool) -> u128 {
+        let start = Instant::now();
+        thread::scope(|scope| {
+            for slot in 1..=DEVICES {
+                scope.spawn(move || {
+                    for _ in 0..SUBMISSIONS {
+                        block_on(async {
+                            if per_ring {
+                                let list = list.read().await;
+                                submit(&mut *list.transfer_ring(slot, 1).unwrap().lock().await);
+                            } else {
+                                submit(list.write().await.write_transfer_ring(slot, 1).unwrap());
+                            }
+                        })
+                    }
+                });
+            }
+        });
+        start.elapsed().as_nanos() / (DEVICES as u128 * SUBMISSIONS as u128)
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with --release -- --ignored --nocapture"]
+    fn concurrent_submission() {
+        let list = list();
+        let whole_list = contend(&list, false);
+        let per_ring = contend(&list, true);
+        println!(
+            "{} devices submitting at once: {} ns per TD locking the whole list, {} ns locking its ring",
+            DEVICES, whole_list, per_ring
+        );
+    }
+
+    #[test]
+    fn ring_range_is_read_without_list_write_lock() {
+        let list = list();
+        let reader = block_on(list.read());
+        let span = block_on(reader.transfer_ring_range(1, 1)).unwrap();
+        let first = block_on(reader.transfer_ring(1, 1).unwrap().lock()).span();
+        assert_eq!(span.0, first.0);
+        assert!(block_on(reader.transfer_ring_range(DEVICES + 1, 1)).is_none());
+    }
+}
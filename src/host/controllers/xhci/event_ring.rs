@@ -1,14 +1,14 @@
-use core::future::Future;
 use core::sync::atomic::{fence, Ordering};
-use core::task::{Poll, Waker};
 
 pub use super::ring::Ring;
 use crate::abstractions::dma::DMA;
+use crate::abstractions::dma_tracker::DmaKind;
+#[cfg(feature = "observe_raw_event_trb")]
+use crate::abstractions::EventTrbObserver;
 use crate::abstractions::PlatformAbstractions;
 
-use async_ringbuf::consumer::PopFuture;
-use futures::future::FusedFuture;
-use futures::task::AtomicWaker;
+#[cfg(feature = "observe_raw_event_trb")]
+use alloc::sync::Arc;
 use tock_registers::interfaces::Writeable;
 use tock_registers::register_structs;
 use tock_registers::registers::ReadWrite;
@@ -25,13 +25,29 @@ register_structs! {
     }
 }
 
+///ERDP is written back once this share of the ring got consumed, even mid pass
+const ERDP_BATCH_DIVISOR: usize = 4;
+
+///when dequeue pointer is written back, refer xhci spec 4.9.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErdpPolicy {
+    ///interrupt driven. every pass ends with a write clearing EHB, next interrupt waits for it
+    EndOfPass,
+    ///polled, nobody waits on EHB. written only once a batch of events piled up
+    Batched,
+}
+
 pub struct EventRing<O>
 where
     O: PlatformAbstractions,
 {
-    pub waker: AtomicWaker,
     pub ring: Ring<O>,
     pub ste: DMA<[EventRingSte], O>,
+    policy: ErdpPolicy,
+    ///events consumed since ERDP was last written
+    unacked: usize,
+    #[cfg(feature = "observe_raw_event_trb")]
+    observer: Option<(Arc<EventTrbObserver>, O)>,
 }
 
 impl<O> EventRing<O>
@@ -41,21 +57,37 @@ where
     pub fn new(os: O) -> Self {
         let a = os.dma_alloc();
         let mut ring = EventRing {
-            ste: DMA::zeroed(1, 64, a),
-            ring: Ring::new(os, 256, false),
-            waker: AtomicWaker::new(),
+            ste: DMA::zeroed(1, 64, a).tagged(DmaKind::EventRing, None),
+            ring: Ring::new(os.clone(), 256, false).tagged(DmaKind::EventRing, None),
+            policy: ErdpPolicy::EndOfPass,
+            unacked: 0,
+            #[cfg(feature = "observe_raw_event_trb")]
+            observer: None,
         };
         ring.ring.cycle = true;
         let ringaddr: usize = O::PhysAddr::from(ring.ring.register()).into();
         ring.ste[0].addr_low.set(ringaddr as u32);
         ring.ste[0].addr_high.set((ringaddr >> 32) as u32);
-        ring.ste[0].size.set(ring.ring.trbs.len() as u16);
+        ring.ste[0].size.set(ring.ring.len() as u16);
+        ring.ste.sync_for_device(&os);
 
         ring
     }
 
+    pub fn with_erdp_policy(mut self, policy: ErdpPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[cfg(feature = "observe_raw_event_trb")]
+    pub fn with_observer(mut self, observer: Option<Arc<EventTrbObserver>>, os: O) -> Self {
+        self.observer = observer.map(|observer| (observer, os));
+        self
+    }
+
     /// 完成一次循环返回 true
     pub fn next(&mut self) -> Option<(Allowed, bool)> {
+        self.ring.sync_current_for_cpu();
         let (data, flag) = self.ring.current_data();
         let data = unsafe {
             let mut out = [0u32; 4];
@@ -84,15 +116,23 @@ where
 
         fence(Ordering::SeqCst);
 
+        #[cfg(feature = "observe_raw_event_trb")]
+        if let Some((observer, os)) = &self.observer {
+            let mut bytes = [0u8; 16];
+            bytes
+                .chunks_exact_mut(4)
+                .zip(data)
+                .for_each(|(dst, word)| dst.copy_from_slice(&word.to_le_bytes()));
+            observer(&bytes, flag, os.now());
+        }
+
         let cycle = self.ring.inc_deque();
+        self.unacked += 1;
         Some((allowed, cycle))
     }
 
-    pub fn wake(&self) {
-        self.waker.wake();
-    }
-
     pub fn has_next(&self) -> bool {
+        self.ring.sync_current_for_cpu();
         let (data, flag) = self.ring.current_data();
         let data = unsafe {
             let mut out = [0u32; 4];
@@ -115,71 +155,31 @@ where
         })
     }
 
-    pub fn async_next<'a>(&'a mut self) -> NextFuture<'a, O> {
-        NextFuture {
-            owner: self,
-            done: false,
-        }
-    }
-
     pub fn erdp(&self) -> O::PhysAddr {
         (Into::<usize>::into(O::PhysAddr::from(self.ring.register())) & 0xFFFF_FFFF_FFFF_FFF0)
             .into()
     }
-    pub fn erstba(&self) -> O::VirtAddr {
-        let ptr = &self.ste[0];
-        (ptr as *const EventRingSte as usize).into()
-    }
-
-    fn register_waker(&self, waker: &Waker)
-    where
-        O: PlatformAbstractions,
-    {
-        self.waker.register(waker);
+    ///ERDP to write back and whether to clear EHB with it, None if no write is due yet.
+    ///`pass_done` is set once no more events are pending
+    pub fn dequeue_update(&mut self, pass_done: bool) -> Option<(O::PhysAddr, bool)> {
+        if self.unacked == 0 {
+            return None;
+        }
+        let end_of_pass = pass_done && self.policy == ErdpPolicy::EndOfPass;
+        if !end_of_pass && self.unacked < self.ring.len() / ERDP_BATCH_DIVISOR {
+            return None;
+        }
+        Some((self.acknowledge(), end_of_pass))
     }
-}
 
-pub struct NextFuture<'a, O>
-where
-    O: PlatformAbstractions,
-{
-    owner: &'a mut EventRing<O>,
-    done: bool,
-}
-
-impl<'a, O> FusedFuture for NextFuture<'a, O>
-where
-    O: PlatformAbstractions,
-{
-    fn is_terminated(&self) -> bool {
-        self.done
+    ///ERDP covering every consumed event, caller writes it right away
+    pub fn acknowledge(&mut self) -> O::PhysAddr {
+        self.unacked = 0;
+        self.erdp()
     }
-}
 
-impl<'a, O> Unpin for NextFuture<'a, O> where O: PlatformAbstractions {}
-
-impl<'a, O> Future for NextFuture<'a, O>
-where
-    O: PlatformAbstractions,
-{
-    type Output = (Allowed, bool);
-
-    fn poll(
-        mut self: core::pin::Pin<&mut Self>,
-        cx: &mut core::task::Context<'_>,
-    ) -> core::task::Poll<Self::Output> {
-        let mut waker_registered = false;
-        loop {
-            assert!(!self.done);
-            if let Some(item) = self.owner.next() {
-                self.done = true;
-                break Poll::Ready(item);
-            }
-            if waker_registered {
-                break Poll::Pending;
-            }
-            self.owner.register_waker(cx.waker());
-            waker_registered = true;
-        }
+    pub fn erstba(&self) -> O::VirtAddr {
+        let ptr = &self.ste[0];
+        (ptr as *const EventRingSte as usize).into()
     }
 }
@@ -1,18 +1,175 @@
+use core::time::Duration;
+
+use alloc::{sync::Arc, vec::Vec};
 use futures::channel::oneshot::Sender;
-use xhci::ring::trb::event::CommandCompletion;
+use xhci::ring::trb::{
+    event::{CommandCompletion, CompletionCode},
+    transfer::Normal,
+};
+
+use crate::usb::operations::{
+    interrupt::{InterruptTransfer, PeriodicStream},
+    CompleteAction, RequestId,
+};
 
-use crate::usb::operations::CompleteAction;
+///decoded by event loops, handed to completion loop in the order controller reported them
+#[derive(Debug)]
+pub enum Completion {
+    Transfer {
+        code: Result<CompletionCode, u8>,
+        ///(slot, dci)
+        endpoint: (u8, u8),
+        trb: usize,
+        transfer_length: usize,
+        event_data: bool,
+    },
+    Command {
+        trb: usize,
+        completion: CommandCompletion,
+    },
+}
 
+///waiting side of a command, keyed by command TRB address
 pub type XHCICommandCallbackValue = Sender<CommandCompletion>;
 
+///pending command, keyed by command TRB address
 #[derive(Debug)]
-pub enum XHCICompleteAction {
-    CommandCallback(XHCICommandCallbackValue),
-    STANDARD(CompleteAction),
+pub struct CommandJob {
+    pub sender: XHCICommandCallbackValue,
+    ///abort once [`crate::abstractions::PlatformAbstractions::now`] passes it
+    pub deadline: Option<Duration>,
+    ///command abort requested, completes with CommandAborted
+    pub aborting: bool,
 }
 
-impl From<CompleteAction> for XHCICompleteAction {
-    fn from(value: CompleteAction) -> Self {
-        Self::STANDARD(value)
+///what to do once a transfer TD completes, keyed by address of its last TRB
+#[derive(Debug)]
+pub struct TransferJob {
+    pub id: RequestId,
+    pub action: CompleteAction,
+    pub requested: usize,
+    ///reported by an earlier TRB of the TD, e.g. data stage of control transfer
+    pub transferred: Option<usize>,
+    ///(slot, dci) gets stopped once [`crate::abstractions::PlatformAbstractions::now`] passes it
+    pub deadline: Option<((u8, u8), Duration)>,
+    ///buffers controller writes into, invalidated on completion for non-coherent platforms
+    pub inbound: Vec<(usize, usize)>,
+    ///(slot, dci) and submission time, cleared once the watchdog reported it
+    pub watched: Option<((u8, u8), Duration)>,
+    ///doorbell was rung again for it
+    pub rung_again: bool,
+}
+
+impl TransferJob {
+    pub fn new(id: RequestId, action: CompleteAction) -> Self {
+        Self {
+            id,
+            action,
+            requested: 0,
+            transferred: None,
+            deadline: None,
+            inbound: Vec::new(),
+            watched: None,
+            rung_again: false,
+        }
+    }
+
+    ///give up on TD at `now + timeout`, no deadline without a clock or timeout
+    pub fn expiring(
+        mut self,
+        endpoint: (u8, u8),
+        now: Option<Duration>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        self.deadline = now
+            .zip(timeout)
+            .map(|(now, timeout)| (endpoint, now + timeout));
+        self
+    }
+
+    ///length of the TRB job is keyed on, turns residual of its event into transferred length
+    pub fn requesting(mut self, requested: usize) -> Self {
+        self.requested = requested;
+        self
+    }
+
+    pub fn receiving(mut self, inbound: &[(usize, usize)]) -> Self {
+        self.inbound = inbound.to_vec();
+        self
+    }
+
+    ///let watchdog look after it, nothing without a clock
+    pub fn watched(mut self, endpoint: (u8, u8), now: Option<Duration>) -> Self {
+        self.watched = now.map(|now| (endpoint, now));
+        self
     }
 }
+
+///pre-baked resubmission of a kept-filling interrupt endpoint, keyed by (slot, dci).
+///
+///steady state refill only patches buffer of the TRB and enqueues it again, no allocation.
+pub struct PeriodicTemplate {
+    pub id: RequestId,
+    pub trb: Normal,
+    pub requested_len: usize,
+    pub refill: Option<Arc<PeriodicStream>>,
+    ///TRB of the refill on ring, only its event belongs to the template
+    pub in_flight: Option<usize>,
+    ///completed while quiescing, resubmit on resume
+    pub parked: bool,
+    ///endpoint stalled or failed, resubmit once halt is cleared
+    pub halted: bool,
+    ///no free buffer to refill, resubmit once driver releases one
+    pub starved: bool,
+}
+
+impl PeriodicTemplate {
+    pub fn new(id: RequestId, transfer: &InterruptTransfer) -> Self {
+        let (addr, len) = transfer.buffer_addr_len;
+        Self {
+            id,
+            trb: interrupt_trb(addr, len),
+            requested_len: len,
+            refill: transfer.refill.clone(),
+            in_flight: None,
+            parked: false,
+            halted: false,
+            starved: false,
+        }
+    }
+
+    ///`transfer` resubmits this starved endpoint, instead of starting it over
+    pub fn resumed_by(&self, transfer: &InterruptTransfer) -> bool {
+        self.starved
+            && self
+                .refill
+                .as_ref()
+                .zip(transfer.refill.as_ref())
+                .is_some_and(|(ours, theirs)| Arc::ptr_eq(ours, theirs))
+    }
+
+    pub fn set_buffer(&mut self, (addr, len): (usize, usize)) {
+        self.trb
+            .set_data_buffer_pointer(addr as _)
+            .set_trb_transfer_length(len as _);
+        self.requested_len = len;
+    }
+}
+
+///endpoint left refilling, whoever holds the stream learns buffer is free
+impl Drop for PeriodicTemplate {
+    fn drop(&mut self) {
+        if let Some(stream) = &self.refill {
+            stream.close();
+        }
+    }
+}
+
+pub fn interrupt_trb(addr: usize, len: usize) -> Normal {
+    *Normal::default()
+        .set_data_buffer_pointer(addr as _)
+        .set_trb_transfer_length(len as _)
+        .set_interrupter_target(0)
+        .set_interrupt_on_short_packet()
+        .set_interrupt_on_completion()
+}
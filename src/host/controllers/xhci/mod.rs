@@ -1,31 +1,38 @@
 use core::{
-    cell::SyncUnsafeCell,
-    future::{join, Future, IntoFuture},
+    future::{join, poll_fn, Future, IntoFuture},
     mem,
     num::NonZeroUsize,
     ops::DerefMut,
-    sync::atomic::{fence, Ordering},
+    sync::atomic::{fence, AtomicBool, Ordering},
+    task::{Context, Poll},
+    time::Duration,
 };
 
-use ::futures::{stream, FutureExt, StreamExt};
-use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
+use ::futures::{FutureExt, StreamExt};
+use alloc::{
+    borrow::ToOwned,
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    sync::Arc,
+    vec::Vec,
+};
 use async_lock::{Mutex, OnceCell, RwLock};
 use async_ringbuf::traits::{AsyncConsumer, AsyncProducer};
-use axhid::hidreport::hid::Item;
-use context::{DeviceContextList, ScratchpadBufferArray};
+use context::{DeviceContextList, ScratchpadBufferArray, NUM_EPS};
 use embassy_futures::{block_on, yield_now};
-use event_ring::EventRing;
+use event_ring::{ErdpPolicy, EventRing};
 use futures::{
     channel::oneshot,
-    future::{join_all, select_ok, BoxFuture},
-    stream::Repeat,
-    task::FutureObj,
+    future::{join, join_all, select_ok, BoxFuture},
+    stream::{FuturesUnordered, Repeat},
+    task::{AtomicWaker, FutureObj},
 };
-use inner_urb::XHCICompleteAction;
+use inner_urb::{interrupt_trb, CommandJob, Completion, PeriodicTemplate, TransferJob};
 use log::{debug, error, info, trace, warn};
 use num_traits::{FromPrimitive, ToPrimitive};
-use ring::Ring;
-use ringbuf::traits::{Consumer, Split};
+use protocol::PortProtocol;
+use ring::{transfer_kind, trb_kind, Ring, RingSpan};
+use ringbuf::traits::{Consumer, Observer, Split};
+use stream::StreamHandle;
 use usb_descriptor_decoder::{
     descriptors::{
         desc_endpoint::{Endpoint, EndpointType},
@@ -38,6 +45,7 @@ use xhci::{
     accessor::Mapper,
     context::{DeviceHandler, EndpointState, Input, InputHandler},
     extended_capabilities::XhciSupportedProtocol,
+    registers::port::PortStatusAndControlRegister,
     ring::trb::{
         command::{self},
         event::{self, CommandCompletion, CompletionCode},
@@ -46,25 +54,59 @@ use xhci::{
 };
 
 use crate::{
-    abstractions::{dma::DMA, PlatformAbstractions, USBSystemConfig, WakeMethod},
-    event::EventBus,
-    host::device::{ArcAsyncRingBufCons, USBDevice},
-    usb::operations::{
-        control::{
-            bRequest, bRequestStandard, bmRequestType, construct_control_transfer_type,
-            ControlTransfer, DataTransferType, Recipient,
+    abstractions::{
+        dma::{sync_for_cpu, sync_for_device, SmallBufferPool, DMA},
+        dma_tracker::{self, DmaKind},
+        filter::DeviceIdentity,
+        spin::SpinCell,
+        LostTdAction, PlatformAbstractions, USBSystemConfig, WakeMethod, WatchdogPolicy,
+    },
+    event::{
+        ControllerError, ControllerErrorKind, DeadlineMissed, DeviceResumed, EventBus,
+        LostTransfer, PortStatusChanged, RemoteWakeup, RouteRejected, TransferError, WakeCause,
+    },
+    host::{
+        bandwidth::BandwidthLedger,
+        device::{
+            ArcAsyncRingBufCons, ArcAsyncRingBufPord, DeviceState, EnumerationMilestone, USBDevice,
+        },
+    },
+    usb::{
+        capabilities::Capabilities,
+        companion::SsEndpointCompanion,
+        conformance::CheckedEndpoint,
+        operations::{
+            bulk::BulkTransfer,
+            control::{
+                bRequest, bRequestStandard, bmRequestType, construct_control_transfer_type,
+                ControlTransfer, DataTransferType, Recipient,
+            },
+            hub::{
+                DeviceSpeed, HubAttachment, HubConfiguration, HubPortAttach, TransactionTranslator,
+                TtBandwidth, TT_PERIODIC_BUDGET,
+            },
+            interrupt::InterruptTransfer,
+            CompleteAction, Direction, ExtraAction, RequestId, RequestResult, RequestedOperation,
+            USBRequest, UsbError,
         },
-        interrupt::InterruptTransfer,
-        CompleteAction, Direction, ExtraAction, RequestResult, USBRequest,
+        standards::{LinkPowerCapabilities, TopologyRoute},
     },
 };
 
-use super::Controller;
+#[cfg(feature = "trace_trb_ring")]
+use super::trace::{TrbOrigin, TrbRecord, TrbTrace, TrbTracer};
+use super::{
+    common::{advance_stream, Attached, Debouncer, Parking, RefillStep, RootPorts, StreamStep},
+    statistics::StatsCell,
+    Controller, InitError, Outstanding, PolledLoop, Statistics, TrbKind,
+};
 
 mod context;
 mod event_ring;
 mod inner_urb;
+mod protocol;
 mod ring;
+mod stream;
 
 pub type RegistersBase = xhci::Registers<MemMapper>;
 pub type RegistersExtList = xhci::extended_capabilities::List<MemMapper>;
@@ -72,6 +114,57 @@ pub type SupportedProtocol = XhciSupportedProtocol<MemMapper>;
 
 const TAG: &str = "[XHCI]";
 const CONTROL_DCI: usize = 1;
+const DEVICE_DESC_LEN: usize = 18;
+const DEVICE_DESC_MAX_PACKET_SIZE_OFFSET: usize = 7;
+const BOS_DESC_TYPE: u8 = 15;
+const BOS_HEADER_LEN: usize = 5;
+///devices reporting bcdUSB >= 2.01 must have BOS
+const LPM_MIN_BCD_USB: u16 = 0x0201;
+///speed id of superspeed, refer xhci spec 7.2.2.1.1
+const SUPERSPEED: u8 = 4;
+///used when device does not report baseline besl
+const DEFAULT_BESL: u8 = 4;
+///refer xhci spec 5.4.8, PLS value of port in resume state
+const PLS_RESUME: u8 = 15;
+///PLS values of a running and a suspended port
+const PLS_U0: u8 = 0;
+const PLS_U3: u8 = 3;
+///usb3 link error states, only a warm reset gets out of them
+const PLS_INACTIVE: u8 = 6;
+const PLS_COMPLIANCE: u8 = 10;
+///port reset and warm reset both end well within this
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+///usb3 polling substates give up long before, refer usb3 spec 7.5.4
+const LINK_TRAINING_TIMEOUT: Duration = Duration::from_millis(500);
+///how long host drives resume signaling on usb2 ports, refer usb2 spec 7.1.7.7
+const RESUME_SIGNALING: Duration = Duration::from_millis(20);
+///limit on link state transitions written to PORTSC
+const LINK_STATE_TIMEOUT: Duration = Duration::from_millis(100);
+///MFINDEX ticks once per micro-frame and wraps at 14 bits, refer xhci spec 5.5.1
+const MICRO_FRAME: Duration = Duration::from_micros(125);
+const MFINDEX_MASK: u16 = 0x3fff;
+///refer xhci spec 6.4.2.7, notification type of function wake
+const NOTIFICATION_FUNCTION_WAKE: u8 = 1;
+///babbles in a row before a full speed endpoint gets a smaller packet size
+const BABBLE_RETRY_THRESHOLD: u8 = 3;
+///smallest packet size full speed endpoints are shrunk to
+const MIN_FS_PACKET_SIZE: u16 = 8;
+///streams a bulk endpoint gets a context array for, more than UASP ever keeps in flight
+const MAX_STREAMS_PER_ENDPOINT: u16 = 256;
+///completions decoded but not handled yet. once full, event loops stop advancing ERDP
+///and controller holds further events back
+const COMPLETION_QUEUE_DEPTH: usize = 64;
+
+///packet size bookkeeping of a full speed endpoint
+#[derive(Debug, Clone, Copy, Default)]
+struct BabbleState {
+    ///wMaxPacketSize currently in endpoint context
+    programmed: u16,
+    errors: u8,
+    ///smaller size decided on, applied on next stall clear
+    clamp: Option<u16>,
+    reprogram: bool,
+}
 
 #[derive(Clone)]
 pub struct MemMapper;
@@ -82,11 +175,6 @@ impl Mapper for MemMapper {
     fn unmap(&mut self, _virt_start: usize, _bytes: usize) {}
 }
 
-pub struct Receiver<const RINGBUF_SIZE: usize> {
-    pub slot: Arc<OnceCell<u8>>,
-    pub receiver: ArcAsyncRingBufCons<USBRequest, RINGBUF_SIZE>,
-}
-
 pub struct XHCIController<'a, O, const RING_BUFFER_SIZE: usize>
 //had to poll controller it self!
 where
@@ -95,20 +183,66 @@ where
 {
     config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
     //safety:regs MUST exist in mem otherwise would panic when construct
-    regs: SyncUnsafeCell<RegistersBase>,
+    ///event loops, submission paths and drivers reach it from whichever core they run on.
+    ///read-modify-write of USBCMD and PORTSC must not interleave, so every access is locked
+    regs: SpinCell<RegistersBase>,
     ext_list: Option<RegistersExtList>,
+    ///from supported protocol capabilities, tells usb3 ports and speed ids apart
+    protocols: Vec<PortProtocol>,
     max_slots: u8,
     max_ports: u8,
     max_irqs: u16,
-    scratchpad_buf_arr: OnceCell<ScratchpadBufferArray<O>>,
+    ///HCCPARAMS1.MaxPSASize, 0 if controller has no streams
+    max_psa_size: u8,
+    ///bytes, from PAGESIZE register, 0 if it reported none
+    page_size: usize,
+    ///owned while controller runs, freed on shutdown and allocated anew on next init
+    scratchpad_buf_arr: SpinCell<Option<ScratchpadBufferArray<O>>>,
+    ///backing of short control transfers issued by controller itself
+    small_buffers: Arc<SmallBufferPool<O>>,
     cmd: Mutex<Ring<O>>,
-    event: SyncUnsafeCell<EventRing<O>>,
+    ///one per interrupter in use, first one also gets command and port events
+    events: Vec<SpinCell<EventRing<O>>>,
+    ///one per event ring, kept outside its lock so interrupt handlers never spin on it
+    event_wakers: Vec<AtomicWaker>,
     dev_ctx: RwLock<DeviceContextList<O, RING_BUFFER_SIZE>>,
-    devices: SyncUnsafeCell<Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>>>,
-    requests: SyncUnsafeCell<Vec<Receiver<RING_BUFFER_SIZE>>>,
-    finish_jobs: RwLock<BTreeMap<usize, XHCICompleteAction>>,
-    extra_works: SyncUnsafeCell<BTreeMap<usize, (&'a OnceCell<u8>, USBRequest)>>,
+    ///devices and the lanes their requests come through
+    attached: Attached<O, RING_BUFFER_SIZE>,
+    ///periodic bandwidth of root port buses, handed to every device attached
+    bandwidth: Arc<BandwidthLedger>,
+    ///event loops push, completion loop pops, see [`COMPLETION_QUEUE_DEPTH`]
+    completion_tx: Mutex<ArcAsyncRingBufPord<Completion, COMPLETION_QUEUE_DEPTH>>,
+    completion_rx: Mutex<ArcAsyncRingBufCons<Completion, COMPLETION_QUEUE_DEPTH>>,
+    command_jobs: RwLock<BTreeMap<usize, CommandJob>>,
+    finish_jobs: RwLock<BTreeMap<usize, TransferJob>>,
+    ///data stage TRB -> (status stage TRB, requested length), its event tells transferred length
+    data_stages: SpinCell<BTreeMap<usize, (usize, usize)>>,
+    extra_works: SpinCell<BTreeMap<usize, (Arc<OnceCell<u8>>, USBRequest)>>,
+    periodic: SpinCell<BTreeMap<(u8, u8), PeriodicTemplate>>,
+    ///controller does not check TT budget of hubs by itself
+    tt_bandwidth: SpinCell<TtBandwidth>,
+    ///(slot, dci) of full speed endpoints, see [`BABBLE_RETRY_THRESHOLD`]
+    babble: SpinCell<BTreeMap<(u8, u8), BabbleState>>,
+    ///slot -> DCIs stopped when its port got suspended, doorbells restart them on resume
+    suspended: SpinCell<BTreeMap<u8, Vec<u8>>>,
+    ///port idx -> wake cause of ports their device left in Resume, see [`RootPorts::wakeup_loop`]
+    waking: SpinCell<BTreeMap<usize, WakeCause>>,
+    ///[`USBSystemConfig::bounded_memory`] is reserved on first init, kept across restarts
+    bounded_reserved: AtomicBool,
+    ///requests held back while quiescing
+    parking: Parking,
+    ///ports whose connect status changed, settle after config.port_debounce
+    debouncer: Debouncer,
     event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+    ///position in [`USBSystemConfig::controller_descs`], tagged onto every route
+    index: u8,
+    ///event and port loops, see [`Controller::poll_events`]
+    event_loop: PolledLoop<'a>,
+    ///request, completion and deadline loops, see [`Controller::poll_scheduler`]
+    scheduler_loop: PolledLoop<'a>,
+    stats: StatsCell,
+    #[cfg(feature = "trace_trb_ring")]
+    trb_trace: Option<TrbTracer>,
 }
 
 impl<'a, O, const RING_BUFFER_SIZE: usize> XHCIController<'a, O, RING_BUFFER_SIZE>
@@ -116,43 +250,49 @@ where
     'a: 'static,
     O: PlatformAbstractions + 'a,
 {
-    fn chip_hardware_reset(&self) -> &Self {
-        debug!("{TAG} Reset begin");
+    fn halt(&self) -> &Self {
         debug!("{TAG} Stop");
-        let regs = unsafe { self.regs.get().as_mut_unchecked() };
-
-        regs.operational.usbcmd.update_volatile(|c| {
-            c.clear_run_stop();
+        self.regs.with(|regs| {
+            regs.operational.usbcmd.update_volatile(|c| {
+                c.clear_run_stop();
+            });
+            debug!("{TAG} Until halt");
+            while !regs.operational.usbsts.read_volatile().hc_halted() {}
         });
-        debug!("{TAG} Until halt");
-        while !regs.operational.usbsts.read_volatile().hc_halted() {}
         debug!("{TAG} Halted");
+        self
+    }
 
-        let o = &mut regs.operational;
+    fn chip_hardware_reset(&self) -> &Self {
+        debug!("{TAG} Reset begin");
+        self.halt();
+        self.regs.with(|regs| {
+            let o = &mut regs.operational;
 
-        debug!("{TAG} Wait for ready...");
-        while o.usbsts.read_volatile().controller_not_ready() {}
-        debug!("{TAG} Ready");
+            debug!("{TAG} Wait for ready...");
+            while o.usbsts.read_volatile().controller_not_ready() {}
+            debug!("{TAG} Ready");
 
-        o.usbcmd.update_volatile(|f| {
-            f.set_host_controller_reset();
-        });
+            o.usbcmd.update_volatile(|f| {
+                f.set_host_controller_reset();
+            });
 
-        while o.usbcmd.read_volatile().host_controller_reset() {}
+            while o.usbcmd.read_volatile().host_controller_reset() {}
 
-        debug!("{TAG} Reset HC");
+            debug!("{TAG} Reset HC");
 
-        while regs
-            .operational
-            .usbcmd
-            .read_volatile()
-            .host_controller_reset()
-            || regs
+            while regs
                 .operational
-                .usbsts
+                .usbcmd
                 .read_volatile()
-                .controller_not_ready()
-        {}
+                .host_controller_reset()
+                || regs
+                    .operational
+                    .usbsts
+                    .read_volatile()
+                    .controller_not_ready()
+            {}
+        });
 
         info!("{TAG} XCHI reset ok");
         self
@@ -161,12 +301,11 @@ where
     fn set_max_device_slots(&self) -> &Self {
         let max_slots = self.max_slots;
         debug!("{TAG} Setting enabled slots to {}.", max_slots);
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .operational
-            .config
-            .update_volatile(|r| {
+        self.regs.with(|regs| {
+            regs.operational.config.update_volatile(|r| {
                 r.set_max_device_slots_enabled(max_slots);
-            });
+            })
+        });
         self
     }
 
@@ -177,12 +316,11 @@ where
             .expect("should gurantee exclusive access here")
             .dcbaap();
         debug!("{TAG} Writing DCBAAP: {:X}", dcbaap.clone().into());
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .operational
-            .dcbaap
-            .update_volatile(|r| {
+        self.regs.with(|regs| {
+            regs.operational.dcbaap.update_volatile(|r| {
                 r.set(O::PhysAddr::from(dcbaap).into() as u64);
-            });
+            })
+        });
         self
     }
 
@@ -195,94 +333,102 @@ where
         let cycle = ring.cycle;
 
         debug!("{TAG} Writing CRCR: {:X}", crcr.clone().into());
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .operational
-            .crcr
-            .update_volatile(|r| {
+        self.regs.with(|regs| {
+            regs.operational.crcr.update_volatile(|r| {
                 r.set_command_ring_pointer(O::PhysAddr::from(crcr).into() as _);
                 if cycle {
                     r.set_ring_cycle_state();
                 } else {
                     r.clear_ring_cycle_state();
                 }
-            });
+            })
+        });
 
         self
     }
 
     fn init_ir(&self) -> &Self {
         debug!("{TAG} Disable interrupts");
-        let regs = unsafe { self.regs.get().as_mut_unchecked() };
-
-        regs.operational.usbcmd.update_volatile(|r| {
-            r.clear_interrupter_enable();
-        });
+        self.regs.with(|regs| {
+            regs.operational.usbcmd.update_volatile(|r| {
+                r.clear_interrupter_enable();
+            });
 
-        let mut ir0 = regs.interrupter_register_set.interrupter_mut(0);
-        {
-            debug!("{TAG} Writing ERSTZ");
-            ir0.erstsz.update_volatile(|r| r.set(1));
-            let event_ring = unsafe { self.event.get().as_ref_unchecked() };
+            for (idx, event_ring) in self.events.iter().enumerate() {
+                let mut ir = regs.interrupter_register_set.interrupter_mut(idx);
+                debug!("{TAG} Writing ERSTZ of interrupter {}", idx);
+                ir.erstsz.update_volatile(|r| r.set(1));
+                let (erdp, erstba) =
+                    event_ring.with(|event_ring| (event_ring.acknowledge(), event_ring.erstba()));
 
-            let erdp = event_ring.erdp();
-            debug!("{TAG} Writing ERDP: {:X}", erdp.clone().into());
+                debug!("{TAG} Writing ERDP: {:X}", erdp.clone().into());
 
-            ir0.erdp.update_volatile(|r| {
-                r.set_event_ring_dequeue_pointer(erdp.into() as _);
-            });
+                ir.erdp.update_volatile(|r| {
+                    r.set_event_ring_dequeue_pointer(erdp.into() as _);
+                });
 
-            let erstba = event_ring.erstba();
-            debug!("{TAG} Writing ERSTBA: {:X}", erstba.clone().into());
+                debug!("{TAG} Writing ERSTBA: {:X}", erstba.clone().into());
 
-            ir0.erstba.update_volatile(|r| {
-                r.set(O::PhysAddr::from(erstba).into() as _);
-            });
-            ir0.imod.update_volatile(|im| {
-                im.set_interrupt_moderation_interval(0);
-                im.set_interrupt_moderation_counter(0);
-            });
+                ir.erstba.update_volatile(|r| {
+                    r.set(O::PhysAddr::from(erstba).into() as _);
+                });
+                ir.imod.update_volatile(|im| {
+                    im.set_interrupt_moderation_interval(0);
+                    im.set_interrupt_moderation_counter(0);
+                });
 
-            debug!("{TAG} Enabling primary interrupter.");
-            ir0.iman.update_volatile(|im| {
-                im.set_interrupt_enable();
-            });
-        }
+                debug!("{TAG} Enabling interrupter {}.", idx);
+                ir.iman.update_volatile(|im| {
+                    im.set_interrupt_enable();
+                });
+            }
+        });
 
-        if let WakeMethod::Interrupt(int_register) = &self.config.wake_method {
-            int_register(&|| block_on(self.wake_event_ring()))
+        match &self.config.wake_method {
+            WakeMethod::Interrupt(int_register) => {
+                int_register(&|| block_on(self.wake_event_ring()))
+            }
+            WakeMethod::Vectored(vectored) => {
+                for idx in 0..self.events.len() {
+                    (vectored.register)(idx as _, &move || self.event_wakers[idx].wake())
+                }
+            }
+            WakeMethod::Timer(_) | WakeMethod::Yield => {}
         }
 
         self
     }
 
-    async fn setup_scratchpads(&self) -> &Self {
+    ///controller must be halted, buffers of a previous init are freed once replaced
+    fn setup_scratchpads(&self) -> Result<&Self, InitError> {
+        self.release_scratchpads();
         let scratchpad_buf_arr = {
             let buf_count = {
-                let count = unsafe { self.regs.get().as_mut_unchecked() }
-                    .capability
-                    .hcsparams2
-                    .read_volatile()
-                    .max_scratchpad_buffers();
+                let count = self.regs.with(|regs| {
+                    regs.capability
+                        .hcsparams2
+                        .read_volatile()
+                        .max_scratchpad_buffers()
+                });
                 debug!("{TAG} Scratch buf count: {}", count);
                 count
             };
             if buf_count == 0 {
-                error!("buf count=0,is it a error?");
-                return self;
-            }
-            let scratchpad_buf_arr = ScratchpadBufferArray::new(buf_count, self.config.os.clone());
-
-            {
-                let mut read_volatile = unsafe {
-                    self.dev_ctx
-                        .try_read()
-                        .expect("should garantee exclusive access here")
-                        .dcbaa
-                        .get()
-                        .read_volatile()
-                };
-                read_volatile[0] = O::PhysAddr::from(scratchpad_buf_arr.register()).into() as u64;
+                debug!("{TAG} controller needs no scratchpad");
+                return Ok(self);
             }
+            let scratchpad_buf_arr =
+                ScratchpadBufferArray::new(buf_count, self.config.os.clone(), self.page_size)?;
+
+            //write in place, a volatile read would copy the DMA handle and free it on drop
+            let mut dev_ctx = self
+                .dev_ctx
+                .try_write()
+                .expect("should garantee exclusive access here");
+            let dcbaa = dev_ctx.dcbaa.get_mut();
+            dcbaa[0] = O::PhysAddr::from(scratchpad_buf_arr.register()).into() as u64;
+            dcbaa.sync_for_device(&self.config.os);
+            drop(dev_ctx);
 
             debug!(
                 "{TAG} Setting up {} scratchpads, at {:#0x}",
@@ -292,41 +438,247 @@ where
             scratchpad_buf_arr
         };
 
-        let _ = self.scratchpad_buf_arr.set(scratchpad_buf_arr).await;
-        self
+        self.scratchpad_buf_arr
+            .with(|held| *held = Some(scratchpad_buf_arr));
+        Ok(self)
+    }
+
+    ///allocate what devices need and free it again, bounded DMA keeps those blocks once sealed
+    fn reserve_bounded_memory(&self) -> Result<&Self, InitError> {
+        let Some(bounded) = &self.config.bounded_memory else {
+            return Ok(self);
+        };
+        if self.bounded_reserved.load(Ordering::Acquire) {
+            return Ok(self);
+        }
+        let devices = bounded.max_devices.min(self.max_slots as usize);
+        let slots = self
+            .dev_ctx
+            .try_read()
+            .expect("should garantee exclusive access here")
+            .alloc_spare_slots(devices, NUM_EPS)
+            .map_err(|_| InitError::DmaExhausted {
+                what: "device contexts and transfer rings",
+                size: devices * DeviceContextList::<O, RING_BUFFER_SIZE>::slot_bytes(NUM_EPS),
+            })?;
+        //held together with slots, so neither is cut from blocks of the other
+        let buffers = (0..bounded.transfer_buffers)
+            .map(|_| {
+                DMA::<[u8], O>::try_zeroed(
+                    bounded.transfer_buffer_len,
+                    O::PAGE_SIZE,
+                    self.config.os.dma_alloc(),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| InitError::DmaExhausted {
+                what: "transfer buffers",
+                size: bounded.transfer_buffers * bounded.transfer_buffer_len,
+            })?;
+        debug!(
+            "{TAG} reserved {} slots and {} transfer buffers",
+            slots.len(),
+            buffers.len()
+        );
+        self.bounded_reserved.store(true, Ordering::Release);
+        Ok(self)
+    }
+
+    ///undo a failed init, controller is reset so it no longer points into our memory
+    fn roll_back_init(&self, err: &InitError) {
+        error!("{TAG} init failed: {}, rolling back", err);
+        self.release_scratchpads();
+        self.chip_hardware_reset();
+    }
+
+    ///controller must be halted, a running one may still write into them
+    fn release_scratchpads(&self) {
+        if let Some(released) = self.scratchpad_buf_arr.with(Option::take) {
+            let mut dev_ctx = self
+                .dev_ctx
+                .try_write()
+                .expect("should garantee exclusive access here");
+            let dcbaa = dev_ctx.dcbaa.get_mut();
+            dcbaa[0] = 0;
+            dcbaa.sync_for_device(&self.config.os);
+            drop(dev_ctx);
+            debug!("{TAG} Released {} scratchpads", released.pages.len());
+        }
     }
 
+    ///connect changes seen here are covered by initial probe, debouncing needs not see them
     fn reset_ports(&self) -> &Self {
-        //TODO: reset usb 3 port
-        let regs = unsafe { self.regs.get().as_mut_unchecked() };
-        let port_len = regs.port_register_set.len();
+        let port_len = self.regs.with(|regs| regs.port_register_set.len());
 
         for i in 0..port_len {
-            debug!("{TAG} Port {} start reset", i,);
+            //safety: no event needed, only polling port register
+            block_on(self.reset_port_inner(i));
+            self.regs.with(|regs| {
+                regs.port_register_set.update_volatile_at(i, |port| {
+                    port.portsc.set_0_port_enabled_disabled();
+                    port.portsc.clear_connect_status_change();
+                })
+            });
+        }
+        self
+    }
+
+    ///usb2 ports get reset, usb3 ones train their link by themselves and only get waited for
+    async fn reset_port_inner(&self, i: usize) -> bool {
+        if !self.read_portsc(i).current_connect_status() {
+            trace!("{TAG} Port {} has nothing attached, skip reset", i);
+            return false;
+        }
+        if self.is_usb3_port(i) {
+            return self.train_usb3_port(i).await;
+        }
+
+        debug!("{TAG} Port {} start reset", i,);
+        self.regs.with(|regs| {
             regs.port_register_set.update_volatile_at(i, |port| {
                 port.portsc.set_0_port_enabled_disabled();
                 port.portsc.set_port_reset();
-            });
+            })
+        });
 
-            while regs
-                .port_register_set
-                .read_volatile_at(i)
-                .portsc
-                .port_reset()
-            {}
+        let reset_done = self
+            .wait_port(i, RESET_TIMEOUT, |portsc| {
+                //nothing attached anymore, change bit may never come
+                portsc.port_reset_change()
+                    || (!portsc.port_reset() && !portsc.current_connect_status())
+            })
+            .await;
+        if !reset_done {
+            warn!("{TAG} Port {} reset timeout!", i);
+            return false;
+        }
+        self.clear_reset_changes(i);
+
+        let enabled = self.read_portsc(i).port_enabled_disabled();
+        debug!("{TAG} Port {} reset ok, enabled: {}", i, enabled);
+        enabled
+    }
 
-            debug!("{TAG} Port {} reset ok", i);
+    ///refer xhci spec 4.19.1.2.4, warm reset first if link got stuck in an error state
+    async fn train_usb3_port(&self, i: usize) -> bool {
+        let link_state = self.read_portsc(i).port_link_state();
+        if matches!(link_state, PLS_INACTIVE | PLS_COMPLIANCE) && !self.warm_reset_port(i).await {
+            return false;
+        }
+
+        let trained = self
+            .wait_port(i, LINK_TRAINING_TIMEOUT, |portsc| {
+                portsc.port_link_state() == PLS_U0 && portsc.port_enabled_disabled()
+            })
+            .await;
+        let portsc = self.read_portsc(i);
+        if !trained {
+            warn!(
+                "{TAG} Port {} link training timeout, link state {}",
+                i,
+                portsc.port_link_state()
+            );
+            return false;
+        }
+        //enabling by link training also sets PRC, refer xhci spec 4.19.1.2.4.1
+        self.clear_reset_changes(i);
+        debug!(
+            "{TAG} Port {} link trained, speed {}",
+            i,
+            portsc.port_speed()
+        );
+        true
+    }
+
+    async fn warm_reset_port(&self, i: usize) -> bool {
+        warn!("{TAG} Port {} link in error state, warm reset", i);
+        self.regs.with(|regs| {
+            regs.port_register_set.update_volatile_at(i, |port| {
+                port.portsc.set_0_port_enabled_disabled();
+                port.portsc.set_warm_port_reset();
+            })
+        });
+        let done = self
+            .wait_port(i, RESET_TIMEOUT, |portsc| portsc.warm_port_reset_change())
+            .await;
+        if !done {
+            warn!("{TAG} Port {} warm reset timeout!", i);
+        }
+        self.clear_reset_changes(i);
+        done
+    }
+
+    ///write 1 to clear, other change bits are left for port status change handling
+    fn clear_reset_changes(&self, i: usize) {
+        self.regs.with(|regs| {
+            regs.port_register_set.update_volatile_at(i, |port| {
+                port.portsc.set_0_port_enabled_disabled();
+                port.portsc.clear_port_reset_change();
+                port.portsc.clear_warm_port_reset_change();
+                port.portsc.clear_port_enabled_disabled_change();
+            })
+        });
+    }
+
+    fn read_portsc(&self, i: usize) -> PortStatusAndControlRegister {
+        self.regs
+            .with(|regs| regs.port_register_set.read_volatile_at(i).portsc)
+    }
+
+    ///false if `done` did not hold within `timeout`
+    async fn wait_port(
+        &self,
+        i: usize,
+        timeout: Duration,
+        done: impl Fn(&PortStatusAndControlRegister) -> bool,
+    ) -> bool {
+        let start = self.config.os.now();
+        loop {
+            let portsc = self.read_portsc(i);
+            if done(&portsc) {
+                return true;
+            }
+            if let (Some(start), Some(now)) = (start, self.config.os.now())
+                && now.saturating_sub(start) > timeout
+            {
+                return false;
+            }
+            yield_now().await
+        }
+    }
+
+    fn protocol_of(&self, port_idx: usize) -> Option<&PortProtocol> {
+        self.protocols
+            .iter()
+            .find(|protocol| protocol.covers(port_idx))
+    }
+
+    ///guessed from port speed if controller lists no supported protocols
+    fn is_usb3_port(&self, port_idx: usize) -> bool {
+        match self.protocol_of(port_idx) {
+            Some(protocol) => protocol.is_usb3(),
+            None => self.get_speed(port_idx as _) >= SUPERSPEED,
+        }
+    }
+
+    ///speed of device on root port, PSIV decoded by supported protocol of port
+    fn port_speed(&self, port_idx: usize) -> DeviceSpeed {
+        let speed_id = self.get_speed(port_idx as _);
+        match self.protocol_of(port_idx) {
+            Some(protocol) => protocol.speed(speed_id),
+            None => DeviceSpeed::from_xhci_speed_id(speed_id),
         }
-        self
     }
 
     fn initial_probe(&self) -> &Self {
-        for (port_idx, port) in unsafe { self.regs.get().as_mut_unchecked() }
-            .port_register_set
-            .into_iter() //safety: checked, is read_volatile
-            .enumerate()
-        {
-            let portsc = port.portsc;
+        //attaching reads port speed, so not while holding registers
+        let ports: Vec<_> = self.regs.with(|regs| {
+            regs.port_register_set
+                .into_iter() //safety: checked, is read_volatile
+                .map(|port| port.portsc)
+                .collect()
+        });
+        for (port_idx, portsc) in ports.into_iter().enumerate() {
             info!(
                 "{TAG} Port {}: Enabled: {}, Connected: {}, Speed {}, Power {}",
                 port_idx,
@@ -341,293 +693,841 @@ where
                 continue;
             }
 
-            {
-                use async_ringbuf::{traits::*, AsyncStaticRb};
-                let (prod, cons) = AsyncStaticRb::<USBRequest, RING_BUFFER_SIZE>::default().split();
-
-                let (mut usbdevice, slot_ref) = USBDevice::new(self.config.clone(), prod);
-                usbdevice
-                    .topology_path
-                    .append_port_number((port_idx + 1) as _);
-
-                let devref: Arc<_> = usbdevice.into();
-                unsafe { self.devices.get().as_mut_unchecked() }.push(devref.clone());
-                self.event_bus.pre_initialize_device.broadcast(devref);
-                unsafe { self.requests.get().as_mut_unchecked() }.push(Receiver {
-                    slot: slot_ref,
-                    receiver: cons,
-                });
-            }
+            self.attach_device(port_idx)
+                .mark_milestone(EnumerationMilestone::Connected);
         }
 
         info!(
             "initial probe completed! device count:{}",
-            unsafe { self.devices.get().as_ref() }.unwrap().len()
+            self.attached.len()
         );
 
         self
     }
 
+    fn attach_device(&self, port_idx: usize) -> Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        self.attach_at(self.root_route(port_idx), None)
+    }
+
+    fn attach_at(
+        &self,
+        route: TopologyRoute,
+        attachment: Option<HubAttachment>,
+    ) -> Arc<USBDevice<O, RING_BUFFER_SIZE>> {
+        let (mut usbdevice, _, receivers) = USBDevice::new(self.config.clone());
+        usbdevice.speed = attachment
+            .map(|attachment| attachment.speed)
+            .unwrap_or_else(|| self.port_speed(route.port_idx() as _));
+        usbdevice.bandwidth = self.bandwidth.clone();
+        usbdevice.topology_path = route;
+        usbdevice.attachment = attachment;
+
+        let devref: Arc<_> = usbdevice.into();
+        self.attached.attach(&devref, receivers);
+        self.event_bus
+            .pre_initialize_device
+            .broadcast(devref.clone());
+        devref
+    }
+
+    ///see [`Statistics::device_bytes`] and [`crate::usb::operations::flow::EndpointFlow::stats`]
+    fn count_bytes(&self, (slot_id, dci): (u8, u8), bytes: usize) {
+        if bytes > 0
+            && let Some(device) = self.device_of_slot(slot_id)
+        {
+            self.stats.transferred(&device.topology_path, bytes);
+            device.record_bytes(dci as _, bytes);
+        }
+    }
+
+    fn device_of_slot(&self, slot_id: u8) -> Option<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.of_slot(slot_id)
+    }
+
     fn start(&self) -> &Self {
-        let regs = unsafe { self.regs.get().as_mut_unchecked() };
-        debug!("{TAG} Start run");
-        regs.operational.usbcmd.update_volatile(|r| {
-            r.set_run_stop();
-        });
+        self.regs.with(|regs| {
+            debug!("{TAG} Start run");
+            regs.operational.usbcmd.update_volatile(|r| {
+                r.set_run_stop();
+            });
 
-        while regs.operational.usbsts.read_volatile().hc_halted() {}
+            while regs.operational.usbsts.read_volatile().hc_halted() {}
 
-        info!("{TAG} Is running");
+            info!("{TAG} Is running");
 
-        regs.doorbell.update_volatile_at(0, |r| {
-            r.set_doorbell_stream_id(0);
-            r.set_doorbell_target(0);
-        });
+            regs.operational.dnctrl.update_volatile(|r| {
+                r.set(NOTIFICATION_FUNCTION_WAKE as _);
+            });
 
-        self
-    }
+            regs.doorbell.update_volatile_at(0, |r| {
+                r.set_doorbell_stream_id(0);
+                r.set_doorbell_target(0);
+            });
+        });
 
-    ///broken, read error on waiting!
-    fn test_cmd(&self) -> &Self {
-        //TODO:assert like this in runtime if build with debug mode?
-        debug!("{TAG} Test command ring");
-        for _ in 0..3 {
-            let completion = self
-                .post_cmd_busy(command::Allowed::Noop(command::Noop::new()))
-                .unwrap();
-        }
-        debug!("{TAG} Command ring ok");
         self
     }
 
     #[inline]
-    fn ring_db(&self, slot: u8, stream: Option<u16>, target: Option<u8>) {
+    fn ring_db(&self, slot: u8, stream: Option<StreamHandle>, target: Option<u8>) {
         // might waste efficient? or actually low cost compare to actual transfer(in hardware)
         trace!("dsi:{}", slot);
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .doorbell
-            .update_volatile_at(slot as _, |r| {
+        self.regs.with(|regs| {
+            regs.doorbell.update_volatile_at(slot as _, |r| {
                 stream.inspect(|stream| {
-                    r.set_doorbell_stream_id(*stream);
+                    r.set_doorbell_stream_id(stream.id());
                 });
                 target.inspect(|target| {
                     r.set_doorbell_target(*target);
                 });
-            });
+            })
+        });
     }
 
-    fn update_erdp(&self) {
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .interrupter_register_set
-            .interrupter_mut(0)
-            .erdp
-            .update_volatile(|f| {
-                f.set_event_ring_dequeue_pointer(
-                    unsafe { self.event.get().as_ref_unchecked() }.erdp().into() as _,
-                );
-            });
+    ///writes ERDP back if event ring says it's due, see [`ErdpPolicy`]
+    fn sync_dequeue(&self, interrupter: usize, pass_done: bool) {
+        let Some((erdp, clear_busy)) =
+            self.events[interrupter].with(|event_ring| event_ring.dequeue_update(pass_done))
+        else {
+            return;
+        };
+        trace!(
+            "{TAG} interrupter {} ERDP {:X}, clear EHB: {}",
+            interrupter,
+            erdp.clone().into(),
+            clear_busy
+        );
+        self.regs.with(|regs| {
+            regs.interrupter_register_set
+                .interrupter_mut(interrupter)
+                .erdp
+                .update_volatile(|f| {
+                    f.set_event_ring_dequeue_pointer(erdp.into() as _);
+                    if clear_busy {
+                        f.clear_event_handler_busy();
+                    }
+                })
+        });
     }
 
-    fn post_cmd_busy(
-        &self,
-        mut trb: command::Allowed,
-    ) -> Result<CommandCompletion, CompletionCode> {
-        let addr = self.cmd.try_lock().unwrap().enque_command(trb);
-
-        self.ring_db(0, 0.into(), 0.into());
-        fence(Ordering::Release);
+    ///CPU side of transfer buffers reaches memory before their TD gets queued
+    fn sync_buffers_for_device(&self, segments: &[(usize, usize)]) {
+        for (addr, len) in segments {
+            sync_for_device(&self.config.os, *addr, *len);
+        }
+    }
 
-        let addr = addr.into() as _;
-        debug!("Wait result");
-        loop {
-            if let Some((event, cycle)) = unsafe { self.event.get().read_volatile() }.next() {
-                match event {
-                    event::Allowed::CommandCompletion(c) => {
-                        self.update_erdp();
-                        let mut code = CompletionCode::Invalid;
-                        if let Ok(c) = c.completion_code() {
-                            code = c;
-                        } else {
-                            continue;
-                        }
-                        trace!(
-                            "[CMD] << {code:#?} @{:X} got result, cycle {}",
-                            c.command_trb_pointer(),
-                            c.cycle_bit()
-                        );
-                        if c.command_trb_pointer() != addr {
-                            continue;
-                        }
+    ///what controller wrote into transfer buffers becomes visible once their TD completed
+    fn sync_buffers_for_cpu(&self, segments: &[(usize, usize)]) {
+        for (addr, len) in segments {
+            sync_for_cpu(&self.config.os, *addr, *len);
+        }
+    }
 
-                        if let CompletionCode::Success = code {
-                            return Ok(c);
-                        }
-                        return Err(code);
-                    }
-                    _ => warn!("event: {:?}", event),
-                }
+    ///interrupter transfer events of endpoint `dci` on `slot` are routed to
+    fn interrupter_for(&self, slot: u8, dci: u8) -> u16 {
+        match &self.config.wake_method {
+            WakeMethod::Vectored(vectored) => {
+                vectored
+                    .routing
+                    .interrupter(self.events.len() as _, slot, dci)
             }
+            _ => 0,
         }
     }
 
-    async fn post_command(&self, trb: command::Allowed) -> CommandCompletion {
-        let addr = self.cmd.lock().await.enque_command(trb);
+    ///Err(DeviceGone) if controller forgot the command before it completed, e.g. on reset
+    async fn post_command(&self, trb: command::Allowed) -> Result<CommandCompletion, UsbError> {
+        let context = context_slot(&trb).filter(|_| !self.config.os.dma_coherent());
+        if let Some((slot, true)) = context
+            && let Some(ctx) = self.dev_ctx.read().await.device_ctx_inners.get(&slot)
+        {
+            ctx.in_ctx.sync_for_device(&self.config.os);
+        }
+
+        #[cfg(feature = "trace_trb_ring")]
+        let slot = context_slot(&trb).map_or(0, |(slot, _)| slot);
+        let addr = {
+            let mut cmd = self.cmd.lock().await;
+            let addr = cmd.enque_command(trb);
+            #[cfg(feature = "trace_trb_ring")]
+            if let Some(raw) = cmd.trb_at(addr.clone().into()) {
+                self.trace_trb(slot, 0, TrbOrigin::Submitted, raw);
+            }
+            addr
+        };
+        self.stats.submitted([TrbKind::Command]);
         let (sender, receiver) = oneshot::channel();
 
-        self.finish_jobs
-            .write()
-            .await
-            .insert(addr.into(), XHCICompleteAction::CommandCallback(sender));
+        let deadline = self
+            .config
+            .os
+            .now()
+            .zip(self.config.timeouts.command)
+            .map(|(now, timeout)| now + timeout);
+        self.command_jobs.write().await.insert(
+            addr.into(),
+            CommandJob {
+                sender,
+                deadline,
+                aborting: false,
+            },
+        );
 
-        self.ring_db(0, 0.into(), 0.into());
+        self.ring_db(0, None, 0.into());
         fence(Ordering::Release);
 
-        receiver.await.unwrap()
+        let completion = receiver.await.map_err(|_| UsbError::DeviceGone)?;
+        if let Some((slot, _)) = context
+            && let Some(ctx) = self.dev_ctx.read().await.device_ctx_inners.get(&slot)
+        {
+            ctx.out_ctx.sync_for_cpu(&self.config.os);
+        }
+        Ok(completion)
+    }
+
+    #[cfg(feature = "debug-selftest")]
+    async fn self_test_cmd_ring(&self) {
+        const ROUNDS: usize = 3;
+        const LATENCY_WARN: core::time::Duration = core::time::Duration::from_millis(10);
+
+        debug!("{TAG} Test command ring");
+        for round in 0..ROUNDS {
+            let start = self.config.os.now();
+            let completion = self
+                .post_command(command::Allowed::Noop(command::Noop::new()))
+                .await;
+            assert_eq!(
+                RequestResult::Success,
+                command_code(completion),
+                "{TAG} noop {} failed! {:#?}",
+                round,
+                completion
+            );
+
+            if let (Some(start), Some(end)) = (start, self.config.os.now()) {
+                let latency = end.saturating_sub(start);
+                debug!("{TAG} noop {} complete in {:?}", round, latency);
+                if latency > LATENCY_WARN {
+                    warn!(
+                        "{TAG} noop {} took {:?}, check wake method!",
+                        round, latency
+                    );
+                }
+            }
+        }
+        info!("{TAG} Command ring ok");
     }
 
     fn get_speed(&self, port: u8) -> u8 {
-        unsafe { self.regs.get().as_mut_unchecked() }
-            .port_register_set
-            .read_volatile_at(port as _)
-            .portsc
-            .port_speed()
+        self.read_portsc(port as _).port_speed()
+    }
+
+    ///secondary interrupters only see transfer events, their loops interleave with primary one.
+    ///drains every pending event in one pass, ERDP is written back once for all of them
+    async fn on_event_arrived(&self, interrupter: usize) {
+        let mut next = poll_fn(|cx| {
+            //registered first, an interrupt right after the check is not lost
+            self.event_wakers[interrupter].register(cx.waker());
+            match self.events[interrupter].with(EventRing::next) {
+                Some(event) => Poll::Ready(event),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        self.stats.woken();
+        loop {
+            self.on_event(interrupter, next).await;
+            match self.events[interrupter].with(EventRing::next) {
+                Some(event) => next = event,
+                None => break,
+            }
+            self.sync_dequeue(interrupter, false);
+        }
+        self.sync_dequeue(interrupter, true);
     }
 
     #[allow(unused_variables)]
-    async fn on_event_arrived(&self) {
-        let (event, cycle) = unsafe { self.event.get().as_mut_unchecked() }
-            .async_next()
-            .await;
-        debug!("{TAG}:[EVT] received event:{:?},cycle{cycle}", event);
+    ///decodes one event, completions are only queued for [`Self::on_completion`]
+    async fn on_event(&self, interrupter: usize, (event, cycle): (event::Allowed, bool)) {
+        debug!(
+            "{TAG}:[EVT] received event on interrupter {interrupter}:{:?},cycle{cycle}",
+            event
+        );
+
+        #[cfg(feature = "trace_trb_ring")]
+        {
+            let (slot, dci) = match event {
+                event::Allowed::TransferEvent(transfer_event) => {
+                    (transfer_event.slot_id(), transfer_event.endpoint_id())
+                }
+                event::Allowed::CommandCompletion(completion) => (completion.slot_id(), 0),
+                _ => (0, 0),
+            };
+            self.trace_trb(slot, dci, TrbOrigin::Event, event.into_raw());
+        }
 
         match event {
             event::Allowed::TransferEvent(transfer_event) => {
-                let addr = transfer_event.trb_pointer() as _;
-                //todo: transfer event trb had extra info compare to command event., should we split these two?
-                trace!("sending event complete program!");
-
-                self.mark_transfer_completed(transfer_event.completion_code(), addr)
-                    .await;
+                let code = transfer_event.completion_code();
+                self.stats.completed_with(code.map(Into::into));
+                //stopped ones were asked for, e.g. by cancellation or suspend
+                if !matches!(
+                    code,
+                    Ok(CompletionCode::Success
+                        | CompletionCode::ShortPacket
+                        | CompletionCode::Stopped
+                        | CompletionCode::StoppedLengthInvalid
+                        | CompletionCode::StoppedShortPacket)
+                ) {
+                    self.event_bus.transfer_error.broadcast(TransferError {
+                        controller: self.index,
+                        slot: transfer_event.slot_id(),
+                        endpoint_id: transfer_event.endpoint_id() as _,
+                        code: code.map(Into::into),
+                    });
+                }
+                self.queue_completion(Completion::Transfer {
+                    code,
+                    endpoint: (transfer_event.slot_id(), transfer_event.endpoint_id()),
+                    trb: transfer_event.trb_pointer() as _,
+                    transfer_length: transfer_event.trb_transfer_length() as _,
+                    event_data: transfer_event.event_data(),
+                })
+                .await;
             }
             event::Allowed::CommandCompletion(command_completion) => {
-                let addr = command_completion.command_trb_pointer() as _;
-
-                self.mark_command_completed(addr, command_completion).await;
+                self.stats.completed(TrbKind::Command);
+                self.stats
+                    .completed_with(command_completion.completion_code().map(Into::into));
+                self.queue_completion(Completion::Command {
+                    trb: command_completion.command_trb_pointer() as _,
+                    completion: command_completion,
+                })
+                .await;
             }
             event::Allowed::PortStatusChange(port_status_change) => {
                 warn!("{TAG} port status changed! {:#?}", port_status_change);
+                self.on_port_status_changed(port_status_change.port_id());
+            }
+            //only sent if software asks for it with a Get Port Bandwidth command, which is never
+            //issued. periodic budget is tracked on device side instead
+            event::Allowed::BandwidthRequest(bandwidth_request) => {
+                debug!("{TAG} bandwidth request {:?} ignored", bandwidth_request);
             }
-            event::Allowed::BandwidthRequest(bandwidth_request) => todo!(),
             event::Allowed::Doorbell(doorbell) => todo!(),
-            event::Allowed::HostController(host_controller) => todo!(),
-            event::Allowed::DeviceNotification(device_notification) => todo!(),
+            event::Allowed::HostController(host_controller) => {
+                let code = host_controller.completion_code();
+                error!("{TAG} host controller event {:?}", code);
+                self.event_bus.controller_error.broadcast(ControllerError {
+                    controller: self.index,
+                    kind: ControllerErrorKind::Event(code.map(Into::into)),
+                });
+                self.report_status_errors();
+            }
+            event::Allowed::DeviceNotification(device_notification) => {
+                self.on_device_notification(device_notification);
+            }
             event::Allowed::MfindexWrap(mfindex_wrap) => todo!(),
         }
+    }
 
-        self.update_erdp();
+    ///waits while queue is full, which keeps ERDP of this interrupter where it is
+    async fn queue_completion(&self, completion: Completion) {
+        trace!("{TAG} queueing {:?}", completion);
+        if self
+            .completion_tx
+            .lock()
+            .await
+            .push(completion)
+            .await
+            .is_err()
+        {
+            error!("{TAG} completion queue closed, completion lost");
+        }
     }
 
-    async fn mark_command_completed(&self, addr: usize, cmp: CommandCompletion) {
-        //should compile to jump table?
-        if self.finish_jobs.read().await.contains_key(&addr) {
-            self.finish_jobs
-                .write()
-                .then(|mut write| async move { write.remove(&addr).unwrap() })
-                .then(|action| async move {
-                    match action {
-                        XHCICompleteAction::CommandCallback(sender) => {
-                            trace!("sending callback");
-                            sender.send(cmp)
-                        }
-                        _ => {
-                            panic!("do not call command completion on transfer event!")
-                        }
-                    }
-                    .unwrap();
+    ///handles queued completions one by one, never waits on another completion
+    async fn on_completion(&self) {
+        let Some(completion) = self.completion_rx.lock().await.pop().await else {
+            return;
+        };
+        match completion {
+            Completion::Transfer {
+                code,
+                endpoint,
+                trb,
+                transfer_length,
+                event_data,
+            } => {
+                self.mark_transfer_completed(code, endpoint, trb, transfer_length, event_data)
+                    .await
+            }
+            Completion::Command { trb, completion } => {
+                self.mark_command_completed(trb, completion).await
+            }
+        }
+    }
+
+    fn on_port_status_changed(&self, port_id: u8) {
+        let idx = (port_id - 1) as usize;
+        let portsc = self.read_portsc(idx);
+        self.event_bus
+            .port_status_changed
+            .broadcast(PortStatusChanged {
+                controller: self.index,
+                port: port_id,
+                connected: portsc.current_connect_status(),
+                enabled: portsc.port_enabled_disabled(),
+                over_current: portsc.over_current_active(),
+                connect_changed: portsc.connect_status_change(),
+                speed: portsc.port_speed(),
+            });
+
+        if portsc.connect_status_change() {
+            self.regs.with(|regs| {
+                regs.port_register_set.update_volatile_at(idx, |port| {
+                    port.portsc.clear_connect_status_change();
                 })
-                .await
+            });
+            //every bounce restarts the window
+            trace!("{TAG} port {} connect status changed, debouncing", port_id);
+            self.debouncer.bounced(idx, self.config.os.now());
+        }
+
+        if portsc.port_link_state_change() && portsc.port_link_state() == PLS_RESUME {
+            self.regs.with(|regs| {
+                regs.port_register_set.update_volatile_at(idx, |port| {
+                    port.portsc.clear_port_link_state_change();
+                })
+            });
+
+            if let Some(device) = self.device_at(&self.root_route(idx)) {
+                let cause = WakeCause::PortResume { port: port_id };
+                info!("{TAG} remote wakeup from port {}", port_id);
+                self.event_bus
+                    .remote_wakeup
+                    .broadcast(RemoteWakeup { device, cause });
+                //resume signaling takes 20ms, can't be waited for here
+                self.waking.with(|waking| waking.insert(idx, cause));
+            }
+        }
+    }
+
+    ///controller is halted, so slots are freed without commands. devices refuse further requests
+    async fn forget_devices(&self) {
+        self.attached.forget().await;
+        self.command_jobs.write().await.clear();
+        self.finish_jobs.write().await.clear();
+        self.data_stages.with(|data_stages| data_stages.clear());
+        self.extra_works.with(|extra_works| extra_works.clear());
+        self.periodic.with(|periodic| periodic.clear());
+        self.tt_bandwidth.with(|tt_bandwidth| tt_bandwidth.clear());
+        self.parking.forget(|_| true);
+
+        let mut dev_ctx = self.dev_ctx.write().await;
+        let slots: Vec<u8> = dev_ctx.device_ctx_inners.keys().copied().collect();
+        slots.into_iter().for_each(|slot| dev_ctx.free_slot(slot));
+    }
+
+    ///jobs of a slot about to be disabled would never complete, their callbacks are dropped
+    async fn purge_slot_jobs(&self, slot: u8) {
+        {
+            let reader = self.dev_ctx.read().await;
+            for dci in 1..32 {
+                if let Some(range) = reader.transfer_ring_range(slot, dci).await {
+                    self.finish_jobs
+                        .write()
+                        .await
+                        .retain(|addr, _| !range.contains(addr));
+                    self.extra_works
+                        .with(|extra_works| extra_works.retain(|addr, _| !range.contains(addr)));
+                    self.data_stages
+                        .with(|data_stages| data_stages.retain(|addr, _| !range.contains(addr)));
+                }
+            }
+        }
+        self.periodic
+            .with(|periodic| periodic.retain(|(s, _), _| *s != slot));
+        self.parking.forget(|s| s.get() == Some(&slot));
+    }
+
+    fn on_device_notification(&self, notification: event::DeviceNotification) {
+        if notification.notification_type() != NOTIFICATION_FUNCTION_WAKE {
+            debug!("{TAG} ignored device notification {:?}", notification);
+            return;
+        }
+
+        let slot_id = notification.slot_id();
+        if let Some(device) = self.device_of_slot(slot_id) {
+            //interface field is the first byte of notification data
+            let interface = notification.device_notification_data() as u8;
+            info!(
+                "{TAG} function wake from slot {} interface {}",
+                slot_id, interface
+            );
+            self.event_bus.remote_wakeup.broadcast(RemoteWakeup {
+                device,
+                cause: WakeCause::FunctionWake { interface },
+            });
+        }
+    }
+
+    async fn mark_command_completed(&self, addr: usize, cmp: CommandCompletion) {
+        //follows an aborted command, points at next command which did not run yet
+        if let Ok(CompletionCode::CommandRingStopped) = cmp.completion_code() {
+            if !self.command_jobs.read().await.is_empty() {
+                debug!("{TAG} command ring stopped, restarting for pending commands");
+                self.ring_db(0, None, 0.into());
+            }
+            return;
+        }
+        if let Some(job) = self.command_jobs.write().await.remove(&addr) {
+            trace!("sending callback");
+            //waiter of a timed out command may be gone already
+            let _ = job.sender.send(cmp);
         }
     }
 
-    async fn mark_transfer_completed(&self, code: Result<CompletionCode, u8>, addr: usize) {
+    async fn mark_transfer_completed(
+        &self,
+        code: Result<CompletionCode, u8>,
+        (slot_id, dci): (u8, u8),
+        mut addr: usize,
+        transfer_length: usize,
+        event_data: bool,
+    ) {
+        //event data TRB reports accumulated length, normal TRB reports residual
+        let transferred = |requested: usize| {
+            if event_data {
+                transfer_length
+            } else {
+                requested.saturating_sub(transfer_length)
+            }
+        };
+
+        if let Ok(CompletionCode::BabbleDetectedError) = code {
+            self.note_babble(slot_id, dci);
+        }
+
+        //stopped for suspend, TD carries on from where it was once doorbell rings again
+        if matches!(
+            code,
+            Ok(CompletionCode::Stopped
+                | CompletionCode::StoppedLengthInvalid
+                | CompletionCode::StoppedShortPacket)
+        ) && self
+            .suspended
+            .with(|suspended| suspended.contains_key(&slot_id))
+        {
+            trace!("{TAG} slot {} dci {} stopped for suspend", slot_id, dci);
+            return;
+        }
+
+        //TD its TRB was enqueued for got skipped, whatever it reports is settled already
+        let mut stale = false;
+        {
+            let reader = self.dev_ctx.read().await;
+            let streams = reader.stream_array(slot_id, dci as _).is_some();
+            if let Some(ring) = reader.ring_holding(slot_id, dci as _, addr).await {
+                let mut ring = ring.lock().await;
+                stale = ring.is_stale(addr);
+                if stale {
+                    debug!(
+                        "{TAG} slot {} dci {} stale event at trb {:x}: {:?}",
+                        slot_id, dci, addr, code
+                    );
+                } else {
+                    if let Some(kind) = ring.trb_at(addr).as_ref().and_then(trb_kind) {
+                        self.stats.completed(kind);
+                    }
+                    if let Some(device) = self.device_of_slot(slot_id) {
+                        device.record_completion(dci as _, code.map(Into::into));
+                    }
+                    //stopped TRB did not finish, room behind it comes back once endpoint gets moved on
+                    if !matches!(
+                        code,
+                        Ok(CompletionCode::Stopped
+                            | CompletionCode::StoppedLengthInvalid
+                            | CompletionCode::StoppedShortPacket)
+                    ) {
+                        ring.consumed(addr);
+                        if !streams {
+                            self.publish_occupancy(slot_id, dci, &ring);
+                        }
+                    }
+                }
+            }
+        }
+
+        //endpoint reset re-armed the template already
+        if stale
+            && self
+                .periodic
+                .with(|periodic| periodic.contains_key(&(slot_id, dci)))
+        {
+            return;
+        }
+        //template is only touched under its lock, refill is enqueued once it's released
+        let step = self.periodic.with(|periodic| {
+            let template = periodic
+                .get_mut(&(slot_id, dci))
+                .filter(|template| template.in_flight == Some(addr))?;
+            template.in_flight = None;
+            if !matches!(
+                code,
+                Ok(CompletionCode::Success | CompletionCode::ShortPacket)
+            ) {
+                warn!(
+                    "{TAG} slot {} dci {} failed with {:?}, held until halt cleared",
+                    slot_id, dci, code
+                );
+                if let Some(stream) = &template.refill {
+                    stream.report(code.map(Into::into), 0, &[]);
+                }
+                template.halted = true;
+                return Some(RefillStep::Held(None));
+            }
+            let buffer = template.trb.data_buffer_pointer() as usize;
+            if dci_is_in(dci) {
+                sync_for_cpu(&self.config.os, buffer, template.requested_len);
+            }
+            let mut missed = None;
+            if let Some(stream) = &template.refill {
+                let requested = template.requested_len;
+                self.count_bytes((slot_id, dci), transferred(requested).min(requested));
+                //safety: TD completed and buffer stays put until it is enqueued again below
+                let step = unsafe {
+                    advance_stream::<O>(
+                        stream,
+                        code.map(Into::into),
+                        (buffer, requested),
+                        transferred(requested),
+                        dci_is_in(dci),
+                    )
+                };
+                match step {
+                    StreamStep::Cancelled => {
+                        debug!("{TAG} slot {} dci {} refilling cancelled", slot_id, dci);
+                        //dropping template closes the stream
+                        periodic.remove(&(slot_id, dci));
+                        return Some(RefillStep::Held(None));
+                    }
+                    StreamStep::Starved(report) => {
+                        trace!(
+                            "{TAG} slot {} dci {} starved, wait for release",
+                            slot_id,
+                            dci
+                        );
+                        template.starved = true;
+                        return Some(RefillStep::Held(report));
+                    }
+                    StreamStep::Next(next, report) => {
+                        template.set_buffer(next);
+                        missed = report;
+                    }
+                }
+            }
+            if self.parking.is_quiescing() {
+                template.parked = true;
+                Some(RefillStep::Held(missed))
+            } else {
+                Some(RefillStep::Refill(template.trb, missed))
+            }
+        });
+        if let Some(step) = step {
+            let (trb, missed) = step.into_parts();
+            if let Some((deadline, stats)) = missed
+                && let Some(device) = self.device_of_slot(slot_id)
+            {
+                warn!(
+                    "{TAG} slot {} dci {} missed {:?} deadline, {:?}",
+                    slot_id, dci, deadline.deadline, stats
+                );
+                self.event_bus.deadline_missed.broadcast(DeadlineMissed {
+                    device,
+                    endpoint_id: dci as _,
+                    deadline,
+                    stats,
+                });
+            }
+            if let Some(trb) = trb {
+                self.refill_periodic(slot_id, dci, trb).await;
+            }
+            return;
+        }
+
+        if !stale
+            && let Some((status_addr, requested)) = self
+                .data_stages
+                .with(|data_stages| data_stages.remove(&addr))
+        {
+            match code {
+                Ok(CompletionCode::Success | CompletionCode::ShortPacket) => {
+                    if let Some(job) = self.finish_jobs.write().await.get_mut(&status_addr) {
+                        job.transferred = Some(transferred(requested));
+                    }
+                    return;
+                }
+                //status stage never runs after a failed data stage, complete it from here
+                _ => addr = status_addr,
+            }
+        }
+
         //should compile to jump table?
         trace!("received complete event of {:x}", addr);
-        if self.finish_jobs.read().await.contains_key(&addr) {
+        if !stale && self.finish_jobs.read().await.contains_key(&addr) {
             trace!("indeed contains finish jobs!");
             self.finish_jobs
                 .write()
                 .then(|mut write| async move { write.remove(&addr).unwrap() })
-                .then(|action| async move {
-                    trace!("action is {:#?}", action);
-                    match action {
-                        XHCICompleteAction::STANDARD(CompleteAction::NOOP) => {}
-                        XHCICompleteAction::STANDARD(CompleteAction::SimpleResponse(sender)) => {
-                            trace!("send complete!");
+                .then(|job| async move {
+                    let id = job.id;
+                    trace!("{TAG} {} completed at trb {:x}: {:?}", id, addr, code);
+                    trace!("action is {:#?}", job.action);
+                    let length = job.transferred.unwrap_or(transferred(job.requested));
+                    if let Ok(CompletionCode::Success | CompletionCode::ShortPacket) = code {
+                        self.count_bytes((slot_id, dci), length);
+                    }
+                    self.sync_buffers_for_cpu(&job.inbound);
+                    match job.action {
+                        CompleteAction::NOOP => {}
+                        CompleteAction::SimpleResponse(sender) => {
+                            trace!("{TAG} {} send complete!", id);
                             let _ = sender.send(code.map(|a| a.into()).map_err(|a| a as _));
                         }
-                        XHCICompleteAction::STANDARD(CompleteAction::DropSem(
-                            configure_semaphore,
-                        )) => {
-                            match code.unwrap_or_else(|_| {
-                                panic!("got fail signal on executing trb {:x}", addr)
-                            }) {
-                                CompletionCode::Success | CompletionCode::ShortPacket => {
-                                    drop(configure_semaphore);
+                        CompleteAction::LengthResponse(sender) => {
+                            trace!("{TAG} {} send complete, {} bytes!", id, length);
+                            let _ =
+                                sender.send(code.map(|a| (a.into(), length)).map_err(|a| a as _));
+                        }
+                        CompleteAction::DropSem(configure_semaphore) => {
+                            match UsbError::check(code.map(Into::into)) {
+                                Ok(_) => drop(configure_semaphore),
+                                Err(err) => {
+                                    warn!("{TAG} {} failed at trb {:x}: {}", id, addr, err);
+                                    configure_semaphore.fail(err).await
                                 }
-                                other => panic!(
-                                    "got fail signal on executing trb {:x}-{:?}",
-                                    addr, other
-                                ),
                             }
                         }
-                        _ => {
-                            panic!("keep working request should not appear at here!")
-                        }
                     };
                 })
                 .await
         }
-        if let Some((slot, morereq)) =
-            unsafe { self.extra_works.get().as_mut_unchecked() }.remove(&addr)
+        if !stale
+            && let Some((slot, mut morereq)) = self
+                .extra_works
+                .with(|extra_works| extra_works.remove(&addr))
         {
-            self.post_transfer(morereq, slot).await
+            trace!("{TAG} {} refill after trb {:x}", morereq.id, addr);
+            match &mut morereq.operation {
+                RequestedOperation::Bulk(transfer) => {
+                    let refill = transfer
+                        .refill
+                        .as_mut()
+                        .expect("kept bulk transfer must have a stream");
+                    if dci_is_in(dci) {
+                        self.sync_buffers_for_cpu(&[transfer.buffer_addr_len]);
+                    }
+                    self.count_bytes((slot_id, dci), transferred(transfer.buffer_addr_len.1));
+                    refill
+                        .stream
+                        .complete(refill.buffer_idx, transferred(transfer.buffer_addr_len.1))
+                        .await;
+                    match refill.stream.take_free().await {
+                        Some(idx) => {
+                            refill.buffer_idx = idx;
+                            transfer.buffer_addr_len = refill.stream.buffer(idx);
+                            self.submit_or_park(morereq, slot).await
+                        }
+                        None => trace!("{TAG} {} starved, wait for recycle", morereq.id),
+                    }
+                }
+                _ => self.submit_or_park(morereq, slot).await,
+            }
         }
 
         trace!("transfer event procress complete!");
     }
 
+    ///while quiescing, new submissions and refills are held back until resume
+    async fn submit_or_park(&self, req: USBRequest, slot: Arc<OnceCell<u8>>) {
+        let id = req.id;
+        match self.parking.park(req, slot) {
+            Some((req, slot)) => self.post_transfer(req, slot).await,
+            None => trace!("{TAG} {} parked while quiescing", id),
+        }
+    }
+
+    async fn quiesce_inner(&self) {
+        self.parking.quiesce();
+        info!("{TAG} quiescing, waiting for outstanding jobs");
+        while !self.finish_jobs.read().await.is_empty()
+            || !self.command_jobs.read().await.is_empty()
+        {
+            yield_now().await
+        }
+        info!("{TAG} quiesced");
+    }
+
+    async fn resume_inner(&self) {
+        let parked = self.parking.resume();
+        let rearm: Vec<_> = self.periodic.with(|periodic| {
+            periodic
+                .iter_mut()
+                .filter_map(|(endpoint, template)| {
+                    mem::take(&mut template.parked).then_some((*endpoint, template.trb))
+                })
+                .collect()
+        });
+        for ((slot_id, dci), trb) in rearm {
+            self.refill_periodic(slot_id, dci, trb).await;
+        }
+        info!("{TAG} resuming, {} parked requests", parked.len());
+        for (slot, req) in parked {
+            self.post_transfer(req, slot).await
+        }
+    }
+
+    ///requests of devices gone meanwhile are dropped by [`Attached::next`]
     async fn run_once(&'a self) {
-        let collect = unsafe { self.requests.get().as_mut_unchecked() }
-            .iter_mut()
-            .map(|r| {
-                r.receiver
-                    .pop()
-                    .map(|res| res.map(|req| (req, &r.slot)))
-                    .into_stream()
-            })
-            .collect::<Vec<_>>();
-        stream::select_all(collect.into_iter())
-            .for_each(|a| async {
-                match a {
-                    Some((req, slot)) => self.post_transfer(req, slot).await,
-                    None => {}
-                }
-            })
-            .await;
+        for (req, slot) in self.attached.next().await {
+            self.submit_or_park(req, slot).await
+        }
     }
 
     async fn post_control_transfer(
         &self,
+        id: RequestId,
         control_transfer: ControlTransfer,
         cmp: CompleteAction,
         slot: u8,
     ) {
-        let key = self.control_transfer(slot, control_transfer).await;
-        self.finish_jobs.write().await.insert(key, cmp.into());
+        let inbound = control_transfer
+            .data
+            .filter(|_| matches!(control_transfer.request_type.direction, Direction::In));
+        let (key, data_stage) = self.control_transfer(slot, control_transfer).await;
+        trace!("{TAG} {} queued at trb {:x}", id, key);
+        if let Some((data_addr, requested)) = data_stage {
+            self.data_stages
+                .with(|data_stages| data_stages.insert(data_addr, (key, requested)));
+        }
+        self.finish_jobs.write().await.insert(
+            key,
+            TransferJob::new(id, cmp)
+                .expiring(
+                    (slot, CONTROL_DCI as _),
+                    self.config.os.now(),
+                    self.config.timeouts.control,
+                )
+                .receiving(inbound.as_slice())
+                .watched((slot, CONTROL_DCI as _), self.watch_start()),
+        );
     }
 
     async fn post_interrupt_transfer(
         &self,
+        id: RequestId,
         transfer: &InterruptTransfer,
         cmp: Option<CompleteAction>,
         slot: &OnceCell<u8>,
@@ -635,87 +1535,1126 @@ where
         let key = self
             .interrupt_transfer(*unsafe { slot.get_unchecked() }, transfer)
             .await;
-        if let Some(cmp) = cmp {
-            trace!("putting complete action on key{:x}!", key);
-            self.finish_jobs.write().await.insert(key, cmp.into());
+        trace!("{TAG} {} queued at trb {:x}", id, key);
+        if let Some(cmp) = cmp {
+            trace!("putting complete action on key{:x}!", key);
+            let endpoint = (*unsafe { slot.get_unchecked() }, transfer.endpoint_id as _);
+            self.finish_jobs.write().await.insert(
+                key,
+                TransferJob::new(id, cmp)
+                    .requesting(transfer.buffer_addr_len.1)
+                    .receiving(inbound_segments(
+                        transfer.endpoint_id as _,
+                        transfer.segments(),
+                    ))
+                    .watched(endpoint, self.watch_start()),
+            );
+        }
+        key
+    }
+
+    #[allow(unused_variables)]
+    async fn post_transfer(&self, req: USBRequest, slot: Arc<OnceCell<u8>>) {
+        trace!("{TAG} {} dispatching {:?}", req.id, req.operation);
+        match req.operation {
+            crate::usb::operations::RequestedOperation::Control(control_transfer) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                self.post_control_transfer(req.id, control_transfer, req.complete_action, slot) //purpose: avoid cycle dependency
+                    .await;
+            }
+            crate::usb::operations::RequestedOperation::Bulk(mut bulk_transfer) => {
+                let slot_id = unsafe { slot.get_unchecked().clone() };
+                let stream = match self.stream_of(slot_id, &bulk_transfer).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        req.complete_action.fail(err, RequestResult::Invalid).await;
+                        return;
+                    }
+                };
+                let key = self.bulk_transfer(slot_id, &bulk_transfer, stream).await;
+                trace!("{TAG} {} queued at trb {:x}", req.id, key);
+                match req.extra_action {
+                    ExtraAction::NOOP => {
+                        self.finish_jobs.write().await.insert(
+                            key,
+                            TransferJob::new(req.id, req.complete_action)
+                                .expiring(
+                                    (slot_id, bulk_transfer.endpoint_id as _),
+                                    self.config.os.now(),
+                                    self.config.timeouts.bulk,
+                                )
+                                .receiving(inbound_segments(
+                                    bulk_transfer.endpoint_id as _,
+                                    bulk_transfer.segments(),
+                                ))
+                                .watched(
+                                    (slot_id, bulk_transfer.endpoint_id as _),
+                                    self.watch_start(),
+                                ),
+                        );
+                    }
+                    ExtraAction::KeepFill => {
+                        let refill = bulk_transfer
+                            .refill
+                            .take()
+                            .expect("kept bulk transfer must have a stream");
+                        self.extra_works.with(|extra_works| {
+                            extra_works.insert(
+                                key,
+                                (
+                                    slot,
+                                    USBRequest::keep_bulk(bulk_transfer, refill)
+                                        .with_id(req.id)
+                                        .into(),
+                                ),
+                            )
+                        });
+                    }
+                }
+            }
+            crate::usb::operations::RequestedOperation::Interrupt(interrupt_transfer) => {
+                match req.extra_action {
+                    ExtraAction::NOOP => {
+                        let key = self
+                            .post_interrupt_transfer(
+                                req.id,
+                                &interrupt_transfer,
+                                Some(req.complete_action),
+                                &slot,
+                            )
+                            .await;
+                    }
+                    ExtraAction::KeepFill => {
+                        //later refills never go through here, see mark_transfer_completed
+                        let slot_id = unsafe { slot.get_unchecked().clone() };
+                        let dci = interrupt_transfer.endpoint_id as u8;
+                        let trb = self.periodic.with(|periodic| {
+                            match periodic.get_mut(&(slot_id, dci)) {
+                                Some(template) if template.resumed_by(&interrupt_transfer) => {
+                                    template.starved = false;
+                                    template.set_buffer(interrupt_transfer.buffer_addr_len);
+                                    template.trb
+                                }
+                                _ => {
+                                    let template =
+                                        PeriodicTemplate::new(req.id, &interrupt_transfer);
+                                    let trb = template.trb;
+                                    periodic.insert((slot_id, dci), template);
+                                    trb
+                                }
+                            }
+                        });
+                        self.refill_periodic(slot_id, dci, trb).await;
+                    }
+                }
+            }
+            crate::usb::operations::RequestedOperation::Isoch(_) => {
+                warn!("{TAG} {} isochronous transfers are not supported", req.id);
+                req.complete_action
+                    .fail(UsbError::Unsupported, RequestResult::Invalid)
+                    .await
+            }
+            crate::usb::operations::RequestedOperation::InitializeDevice(route) => {
+                //devices may grow meanwhile, don't hold a reference into it
+                let dev = self.device_at(&route);
+                let result = match dev {
+                    Some(dev) => self.assign_address_device(&dev).await,
+                    None => Err(UsbError::DeviceGone),
+                };
+                trace!("assign address device complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    warn!("{TAG} assigning device at {} failed: {}", route, err);
+                    sem.fail(err).await;
+                }
+            }
+            crate::usb::operations::RequestedOperation::NOOP => {
+                debug!("{TAG}-device {:#?} transfer nope!", slot)
+            }
+            crate::usb::operations::RequestedOperation::EnableFunction(config_val, interface) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.enable_function(slot, config_val, interface).await;
+                trace!("enable function for slot complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    sem.fail(err).await;
+                }
+            }
+            crate::usb::operations::RequestedOperation::DisableFunction(interface) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.disable_function(slot, interface).await;
+                trace!("disable function for slot complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    sem.fail(err).await;
+                }
+            }
+            crate::usb::operations::RequestedOperation::Deconfigure => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.deconfigure(slot).await;
+                trace!("deconfigure slot complete!");
+                if let CompleteAction::DropSem(sem) = req.complete_action
+                    && let Err(err) = result
+                {
+                    sem.fail(err).await;
+                }
+            }
+            crate::usb::operations::RequestedOperation::ConfigureHub(configuration) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.configure_hub(slot, configuration).await;
+                req.complete_action.respond(result);
+            }
+            crate::usb::operations::RequestedOperation::AttachChild(attach) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.attach_child(slot, attach).await;
+                req.complete_action.respond(result);
+            }
+            crate::usb::operations::RequestedOperation::ClearStall(dci) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.clear_stall(slot, dci as _).await;
+                req.complete_action.respond(result);
+            }
+            crate::usb::operations::RequestedOperation::Suspend => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.suspend_slot(slot).await;
+                req.complete_action.respond(result);
+            }
+            crate::usb::operations::RequestedOperation::Resume => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                let result = self.resume_slot(slot).await;
+                if let RequestResult::Success = result {
+                    self.announce_resumed(slot, None);
+                }
+                req.complete_action.respond(result);
+            }
+            crate::usb::operations::RequestedOperation::DetachChild(port) => {
+                let slot = unsafe { slot.get_unchecked().clone() };
+                if let Some(hub) = self.device_of_slot(slot)
+                    && let Ok(route) = hub.topology_path.checked_child(port)
+                {
+                    self.detach_route(&route).await;
+                    info!("{TAG} device at port {} of hub {} removed", port, slot);
+                }
+                req.complete_action.respond(RequestResult::Success);
+            }
+        }
+    }
+
+    ///move dequeue pointer of a halted or stopped endpoint to its enqueue pointer, refer xhci spec 4.6.10.
+    ///
+    ///returns addresses of its rings, stream ones included, TDs in them were skipped
+    async fn skip_pending_tds(
+        &self,
+        slot_id: u8,
+        dci: u8,
+    ) -> Result<Option<RingSpan>, RequestResult> {
+        let opened = self.dev_ctx.read().await.opened_streams(slot_id, dci as _);
+        let Some((sct, streams)) = opened else {
+            return self.skip_ring_tds(slot_id, dci, None).await;
+        };
+        //every stream keeps a dequeue pointer of its own, each gets moved on
+        let mut skipped = RingSpan(Vec::new());
+        for stream in streams {
+            if let Some(span) = self
+                .skip_ring_tds(slot_id, dci, Some((stream, sct)))
+                .await?
+            {
+                skipped.0.extend(span.0);
+            }
+        }
+        Ok(Some(skipped))
+    }
+
+    ///see [`Self::skip_pending_tds`], `stream` comes with SCT its ring is written with
+    async fn skip_ring_tds(
+        &self,
+        slot_id: u8,
+        dci: u8,
+        stream: Option<(StreamHandle, u8)>,
+    ) -> Result<Option<RingSpan>, RequestResult> {
+        let handle = stream.map(|(handle, _)| handle);
+        let (dequeue, cycle, range) = {
+            let mut writer = self.dev_ctx.write().await;
+            let Some(ring) = writer.write_ring_of(slot_id, dci as _, handle.as_ref()) else {
+                return Err(RequestResult::Invalid);
+            };
+            let range = Some(ring.span());
+            //skipped TRBs free their room as soon as command succeeds, nothing else runs meanwhile
+            ring.drained();
+            if stream.is_none() {
+                self.publish_occupancy(slot_id, dci, ring);
+            }
+            (
+                O::PhysAddr::from(ring.register()).into() as u64,
+                ring.cycle,
+                range,
+            )
+        };
+        let mut set_dequeue = command::SetTrDequeuePointer::default();
+        set_dequeue
+            .set_slot_id(slot_id)
+            .set_endpoint_id(dci)
+            .set_new_tr_dequeue_pointer(dequeue);
+        if cycle {
+            set_dequeue.set_dequeue_cycle_state();
+        } else {
+            set_dequeue.clear_dequeue_cycle_state();
+        }
+        if let Some((handle, sct)) = stream {
+            set_dequeue
+                .set_stream_id(handle.id())
+                .set_stream_context_type(sct);
+        }
+        let request_result = self
+            .post_command(command::Allowed::SetTrDequeuePointer(set_dequeue))
+            .await;
+        let code = command_code(request_result);
+        if code != RequestResult::Success {
+            warn!(
+                "{TAG} set dequeue of slot {} dci {} failed! {:?}",
+                slot_id, dci, code
+            );
+            return Err(code);
+        }
+        //events of skipped TDs may still be queued behind command completion
+        if let Some(ring) = self
+            .dev_ctx
+            .read()
+            .await
+            .ring_of(slot_id, dci as _, handle.as_ref())
+        {
+            ring.lock().await.skipped();
+        }
+        Ok(range)
+    }
+
+    ///gives up on commands and transfers past their deadline, see [`crate::abstractions::TimeoutPolicy`]
+    async fn timeout_loop(&self) {
+        //stopping an endpoint waits on a command, whose own deadline is checked in here
+        let mut stopping = FuturesUnordered::new();
+        let mut stopping_endpoints = BTreeSet::new();
+        loop {
+            if let Some(now) = self.config.os.now() {
+                self.abort_expired_command(now).await;
+                for (slot_id, dci) in self.expired_transfers(now).await {
+                    if stopping_endpoints.insert((slot_id, dci)) {
+                        stopping.push(async move {
+                            self.cancel_endpoint(slot_id, dci).await;
+                            (slot_id, dci)
+                        });
+                    }
+                }
+            }
+            while let Some(Some(endpoint)) = stopping.next().now_or_never() {
+                stopping_endpoints.remove(&endpoint);
+            }
+            yield_now().await
+        }
+    }
+
+    ///submission time of a TD, None while watchdog is off
+    fn watch_start(&self) -> Option<Duration> {
+        self.config.watchdog.as_ref().and(self.config.os.now())
+    }
+
+    ///flags TDs without any event for [`WatchdogPolicy::after`], see [`LostTdAction`]
+    async fn watchdog_loop(&self) {
+        let Some(policy) = self.config.watchdog.clone() else {
+            return;
+        };
+        loop {
+            if let Some(now) = self.config.os.now() {
+                self.check_lost_tds(now, &policy).await;
+            }
+            yield_now().await
+        }
+    }
+
+    async fn check_lost_tds(&self, now: Duration, policy: &WatchdogPolicy) {
+        let lost: Vec<_> = self
+            .finish_jobs
+            .write()
+            .await
+            .iter_mut()
+            .filter_map(|(trb, job)| {
+                let (endpoint, since) = job.watched?;
+                let waited = now.saturating_sub(since);
+                if waited < policy.after {
+                    return None;
+                }
+                let action = match policy.action {
+                    LostTdAction::RingAgain if !job.rung_again => {
+                        job.rung_again = true;
+                        job.watched = Some((endpoint, now));
+                        LostTdAction::RingAgain
+                    }
+                    LostTdAction::RingAgain => {
+                        job.watched = None;
+                        LostTdAction::Report
+                    }
+                    action => {
+                        job.watched = None;
+                        action
+                    }
+                };
+                Some((*trb, job.id, endpoint, waited, action))
+            })
+            .collect();
+
+        let mut failed: Vec<(u8, u8)> = Vec::new();
+        for (trb, id, (slot_id, dci), waited, action) in lost {
+            warn!(
+                "{TAG} {} lost: TD ending at trb {:x} on slot {} dci {} saw no event for {:?}, {:?}",
+                id, trb, slot_id, dci, waited, action
+            );
+            self.dump_lost_td(slot_id, dci).await;
+            match action {
+                LostTdAction::Report => {}
+                LostTdAction::RingAgain => self.ring_endpoint(slot_id, dci).await,
+                LostTdAction::Fail => failed.push((slot_id, dci)),
+            }
+            if let Some(device) = self.device_of_slot(slot_id) {
+                self.event_bus.lost_transfer.broadcast(LostTransfer {
+                    device,
+                    endpoint_id: dci as _,
+                    request: id,
+                    waited,
+                    action,
+                });
+            }
+        }
+
+        failed.sort_unstable();
+        failed.dedup();
+        for (slot_id, dci) in failed {
+            self.cancel_endpoint(slot_id, dci).await;
+        }
+    }
+
+    ///halting errors USBSTS tells about, published as [`ControllerError`]
+    fn report_status_errors(&self) {
+        let usbsts = self
+            .regs
+            .with(|regs| regs.operational.usbsts.read_volatile());
+        let errors = [
+            (
+                usbsts.host_system_error(),
+                ControllerErrorKind::HostSystemError,
+            ),
+            (
+                usbsts.host_controller_error(),
+                ControllerErrorKind::HostControllerError,
+            ),
+        ];
+        for (_, kind) in errors.into_iter().filter(|(set, _)| *set) {
+            error!("{TAG} {:?}, controller halted", kind);
+            self.event_bus.controller_error.broadcast(ControllerError {
+                controller: self.index,
+                kind,
+            });
+        }
+    }
+
+    ///what controller and context say about an endpoint whose TD got lost
+    async fn dump_lost_td(&self, slot_id: u8, dci: u8) {
+        let usbsts = self
+            .regs
+            .with(|regs| regs.operational.usbsts.read_volatile());
+        warn!(
+            "{TAG}   controller halted {}, host system error {}",
+            usbsts.hc_halted(),
+            usbsts.host_system_error()
+        );
+        //lost TDs are often the first sign of a controller that died
+        self.report_status_errors();
+
+        let reader = self.dev_ctx.read().await;
+        let Some(ctx) = reader.device_ctx_inners.get(&slot_id) else {
+            warn!("{TAG}   slot {} has no context", slot_id);
+            return;
+        };
+        ctx.out_ctx.sync_for_cpu(&self.config.os);
+        let endpoint = ctx.out_ctx.access().endpoint(dci as _);
+        warn!(
+            "{TAG}   slot state {:?}, endpoint state {:?}, dequeue at {:x}",
+            ctx.out_ctx.access().slot().slot_state(),
+            endpoint.endpoint_state(),
+            endpoint.tr_dequeue_pointer()
+        );
+        //ring is held by a submission right now, its enqueue pointer is moving anyway
+        if let Some(ring) = reader
+            .transfer_ring(slot_id, dci as _)
+            .and_then(|ring| ring.try_lock())
+        {
+            let enqueue: usize = O::PhysAddr::from(ring.register()).into();
+            warn!("{TAG}   enqueue at {:x}, cycle {}", enqueue, ring.cycle);
+        }
+    }
+
+    ///command abort stops the command ring, running command completes with CommandAborted.
+    ///refer xhci spec 4.6.1.2
+    async fn abort_expired_command(&self, now: Duration) {
+        let mut expired = false;
+        for (addr, job) in self.command_jobs.write().await.iter_mut() {
+            if !job.aborting && job.deadline.is_some_and(|deadline| now >= deadline) {
+                warn!("{TAG} command at {:x} timed out, aborting", addr);
+                job.aborting = true;
+                expired = true;
+            }
+        }
+        if expired {
+            self.regs.with(|regs| {
+                regs.operational.crcr.update_volatile(|r| {
+                    r.set_command_abort();
+                })
+            });
+        }
+    }
+
+    ///endpoints with a transfer past its deadline, each once
+    async fn expired_transfers(&self, now: Duration) -> Vec<(u8, u8)> {
+        let mut expired: Vec<(u8, u8)> = self
+            .finish_jobs
+            .read()
+            .await
+            .values()
+            .filter_map(|job| job.deadline)
+            .filter(|(_, deadline)| now >= *deadline)
+            .map(|(endpoint, _)| endpoint)
+            .collect();
+        expired.sort_unstable();
+        expired.dedup();
+        expired
+    }
+
+    ///stop endpoint and skip every TD on it, their waiters resolve as timed out.
+    ///refer xhci spec 4.6.9
+    async fn cancel_endpoint(&self, slot_id: u8, dci: u8) {
+        warn!(
+            "{TAG} transfer on slot {} dci {} timed out, stopping endpoint",
+            slot_id, dci
+        );
+        let code = command_code(
+            self.post_command(command::Allowed::StopEndpoint(
+                *command::StopEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_endpoint_id(dci),
+            ))
+            .await,
+        );
+        //context state error: endpoint already halted or stopped, ring is not running either way
+        if !matches!(
+            code,
+            RequestResult::Success | RequestResult::ContextStateError
+        ) {
+            warn!(
+                "{TAG} stop endpoint {} of slot {} failed! {:?}",
+                dci, slot_id, code
+            );
+            return;
+        }
+
+        let Ok(Some(range)) = self.skip_pending_tds(slot_id, dci).await else {
+            return;
+        };
+        //stopped TD may have completed through its Stopped event meanwhile, the rest did not run
+        let cancelled: Vec<TransferJob> = {
+            let mut finish_jobs = self.finish_jobs.write().await;
+            let addrs: Vec<usize> = finish_jobs
+                .keys()
+                .filter(|addr| range.contains(addr))
+                .copied()
+                .collect();
+            addrs
+                .into_iter()
+                .filter_map(|addr| finish_jobs.remove(&addr))
+                .collect()
+        };
+        self.extra_works
+            .with(|extra_works| extra_works.retain(|addr, _| !range.contains(addr)));
+        self.data_stages
+            .with(|data_stages| data_stages.retain(|addr, _| !range.contains(addr)));
+        for job in cancelled {
+            trace!("{TAG} {} cancelled", job.id);
+            match job.action {
+                CompleteAction::DropSem(sem) => sem.fail(UsbError::Timeout).await,
+                action => action.respond(RequestResult::Stopped),
+            }
+        }
+    }
+
+    ///root port of device on slot, None behind hubs, whose ports belong to hub driver
+    fn root_port_of_slot(&self, slot_id: u8) -> Option<usize> {
+        self.device_of_slot(slot_id)
+            .filter(|device| device.attachment.is_none())
+            .map(|device| device.topology_path.port_idx() as _)
+    }
+
+    ///stop running endpoints of slot, then put its root port in U3. refer xhci spec 4.15.1
+    async fn suspend_slot(&self, slot_id: u8) -> RequestResult {
+        let Some(port_idx) = self.root_port_of_slot(slot_id) else {
+            debug!(
+                "{TAG} slot {} is not on a root port, suspend refused",
+                slot_id
+            );
+            return RequestResult::Invalid;
+        };
+        if self
+            .suspended
+            .with(|suspended| suspended.contains_key(&slot_id))
+        {
+            return RequestResult::Success;
+        }
+
+        let running: Vec<u8> = match self.dev_ctx.read().await.device_ctx_inners.get(&slot_id) {
+            Some(ctx) => {
+                ctx.out_ctx.sync_for_cpu(&self.config.os);
+                (1..NUM_EPS as u8)
+                    .filter(|dci| {
+                        matches!(
+                            ctx.out_ctx.access().endpoint(*dci as _).endpoint_state(),
+                            EndpointState::Running
+                        )
+                    })
+                    .collect()
+            }
+            None => return RequestResult::SlotNotEnabledError,
+        };
+        //from here Stopped events of this slot are left alone
+        self.suspended
+            .with(|suspended| suspended.insert(slot_id, Vec::new()));
+
+        let mut stopped = Vec::new();
+        for dci in running {
+            let code = command_code(
+                self.post_command(command::Allowed::StopEndpoint(
+                    *command::StopEndpoint::default()
+                        .set_slot_id(slot_id)
+                        .set_endpoint_id(dci)
+                        .set_suspend(),
+                ))
+                .await,
+            );
+            match code {
+                RequestResult::Success => stopped.push(dci),
+                //halted or already stopped meanwhile, nothing to restart
+                RequestResult::ContextStateError => {}
+                code => warn!(
+                    "{TAG} stop endpoint {} of slot {} for suspend failed! {:?}",
+                    dci, slot_id, code
+                ),
+            }
+        }
+        self.suspended
+            .with(|suspended| suspended.insert(slot_id, stopped));
+
+        if !self.write_link_state(port_idx, PLS_U3).await {
+            warn!("{TAG} port {} did not enter U3", port_idx);
+            self.resume_slot(slot_id).await;
+            return RequestResult::Invalid;
+        }
+        info!("{TAG} slot {} suspended at port {}", slot_id, port_idx);
+        RequestResult::Success
+    }
+
+    ///bring root port of slot back to U0 and restart endpoints stopped by [`Self::suspend_slot`].
+    ///also finishes remote wakeup of a port host never suspended itself
+    async fn resume_slot(&self, slot_id: u8) -> RequestResult {
+        let Some(port_idx) = self.root_port_of_slot(slot_id) else {
+            return RequestResult::Invalid;
+        };
+        let stopped = self.suspended.with(|suspended| suspended.remove(&slot_id));
+        let link_state = self.read_portsc(port_idx).port_link_state();
+        if stopped.is_none() && link_state == PLS_U0 {
+            return RequestResult::Success;
+        }
+
+        let code = if self.resume_port(port_idx).await {
+            RequestResult::Success
+        } else {
+            warn!("{TAG} port {} did not return to U0", port_idx);
+            RequestResult::Invalid
+        };
+
+        fence(Ordering::Release);
+        for dci in stopped.unwrap_or_default() {
+            self.ring_endpoint(slot_id, dci).await;
+        }
+        info!("{TAG} slot {} resumed at port {}", slot_id, port_idx);
+        code
+    }
+
+    ///returns whether port is in U0 afterwards
+    async fn resume_port(&self, port_idx: usize) -> bool {
+        let portsc = self.read_portsc(port_idx);
+        if portsc.port_link_state() == PLS_U0 {
+            return true;
+        }
+        //usb3 ports go to U0 directly, usb2 ones drive resume signaling first.
+        //port is already in Resume if device woke up by itself
+        if !self.is_usb3_port(port_idx) {
+            if portsc.port_link_state() != PLS_RESUME {
+                self.write_link_state(port_idx, PLS_RESUME).await;
+            }
+            self.sleep(RESUME_SIGNALING).await;
+        }
+        self.write_link_state(port_idx, PLS_U0).await
+    }
+
+    ///device state follows controller, drivers get told to restart their polling
+    fn announce_resumed(&self, slot_id: u8, cause: Option<WakeCause>) {
+        if let Some(device) = self.device_of_slot(slot_id) {
+            device.mark_resumed();
+            self.event_bus
+                .device_resumed
+                .broadcast(DeviceResumed { device, cause });
+        }
+    }
+
+    ///returns whether port reached `state` in time, refer xhci spec 4.19.1.2
+    async fn write_link_state(&self, port_idx: usize, state: u8) -> bool {
+        self.regs.with(|regs| {
+            regs.port_register_set.update_volatile_at(port_idx, |port| {
+                port.portsc.set_0_port_enabled_disabled();
+                port.portsc
+                    .set_port_link_state(state)
+                    .set_port_link_state_write_strobe();
+            })
+        });
+
+        let start = self.config.os.now();
+        loop {
+            if self.read_portsc(port_idx).port_link_state() == state {
+                break;
+            }
+            if let (Some(start), Some(now)) = (start, self.config.os.now())
+                && now.saturating_sub(start) > LINK_STATE_TIMEOUT
+            {
+                return false;
+            }
+            yield_now().await
+        }
+
+        //host initiated transitions are no news for port status change handling
+        self.regs.with(|regs| {
+            regs.port_register_set.update_volatile_at(port_idx, |port| {
+                port.portsc.set_0_port_enabled_disabled();
+                port.portsc.clear_port_link_state_change();
+            })
+        });
+        true
+    }
+
+    ///wait by clock source if platform has one, otherwise count micro-frames
+    async fn sleep(&self, duration: Duration) {
+        if let Some(start) = self.config.os.now() {
+            while self
+                .config
+                .os
+                .now()
+                .is_some_and(|now| now.saturating_sub(start) < duration)
+            {
+                yield_now().await
+            }
+            return;
+        }
+
+        let mfindex = || {
+            self.regs
+                .with(|regs| regs.runtime.mfindex.read_volatile().microframe_index())
+        };
+        let target = (duration.as_micros() / MICRO_FRAME.as_micros()) as u32;
+        let mut elapsed = 0;
+        let mut last = mfindex();
+        while elapsed < target {
+            yield_now().await;
+            let now = mfindex();
+            elapsed += (now.wrapping_sub(last) & MFINDEX_MASK) as u32;
+            last = now;
+        }
+    }
+
+    ///recover a stalled endpoint, refer xhci spec 4.6.8 and usb2 spec 9.4.5.
+    ///
+    ///TDs queued behind the stalled one are skipped, kept-filling interrupt endpoint is re-armed
+    async fn clear_stall(&self, slot_id: u8, dci: u8) -> RequestResult {
+        let halted = match self.dev_ctx.read().await.device_ctx_inners.get(&slot_id) {
+            //controller halts endpoint on its own, no command told CPU about it
+            Some(ctx) => matches!(
+                {
+                    ctx.out_ctx.sync_for_cpu(&self.config.os);
+                    ctx.out_ctx.access().endpoint(dci as _).endpoint_state()
+                },
+                EndpointState::Halted
+            ),
+            None => return RequestResult::SlotNotEnabledError,
+        };
+
+        if halted {
+            let request_result = self
+                .post_command(command::Allowed::ResetEndpoint(
+                    *command::ResetEndpoint::default()
+                        .set_slot_id(slot_id)
+                        .set_endpoint_id(dci),
+                ))
+                .await;
+            let code = command_code(request_result);
+            if code != RequestResult::Success {
+                warn!(
+                    "{TAG} reset endpoint {} of slot {} failed! {:?}",
+                    dci, slot_id, code
+                );
+                return code;
+            }
+
+            let range = match self.skip_pending_tds(slot_id, dci).await {
+                Ok(range) => range,
+                Err(code) => return code,
+            };
+
+            //skipped TDs would never complete
+            if let Some(range) = range {
+                self.finish_jobs
+                    .write()
+                    .await
+                    .retain(|addr, _| !range.contains(addr));
+                self.extra_works
+                    .with(|extra_works| extra_works.retain(|addr, _| !range.contains(addr)));
+                self.data_stages
+                    .with(|data_stages| data_stages.retain(|addr, _| !range.contains(addr)));
+            }
+
+            if let Some(size) = self.take_pending_clamp(slot_id, dci) {
+                let code = self.apply_packet_clamp(slot_id, dci, size).await;
+                if code != RequestResult::Success {
+                    return code;
+                }
+            }
+        }
+
+        //default control pipe clears its stall on next SETUP by itself
+        if dci as usize != CONTROL_DCI {
+            let (sender, receiver) = oneshot::channel();
+            self.post_control_transfer(
+                RequestId::next(),
+                ControlTransfer::clear_endpoint_halt(dci),
+                CompleteAction::SimpleResponse(sender),
+                slot_id,
+            )
+            .await;
+            let code = match receiver.await {
+                Ok(Ok(code)) => code,
+                _ => RequestResult::Invalid,
+            };
+            if code != RequestResult::Success {
+                warn!(
+                    "{TAG} clear halt of slot {} dci {} failed! {:?}",
+                    slot_id, dci, code
+                );
+                return code;
+            }
+        }
+
+        let rearm = self.periodic.with(|periodic| {
+            let template = periodic
+                .get_mut(&(slot_id, dci))
+                .filter(|template| template.halted)?;
+            template.halted = false;
+            if self.parking.is_quiescing() {
+                template.parked = true;
+                None
+            } else {
+                Some(template.trb)
+            }
+        });
+        if let Some(trb) = rearm {
+            self.refill_periodic(slot_id, dci, trb).await;
+        }
+        debug!("{TAG} stall of slot {} dci {} cleared", slot_id, dci);
+        RequestResult::Success
+    }
+
+    ///hub fields of slot context could only be set with configure endpoint, refer xhci spec 4.6.6
+    async fn configure_hub(&self, slot_id: u8, configuration: HubConfiguration) -> RequestResult {
+        let input_addr: u64 = {
+            let mut writer = self.dev_ctx.write().await;
+            let ctx = writer.device_ctx_inners.get_mut(&slot_id).unwrap();
+            let input_access = ctx.in_ctx.access();
+            {
+                let control_mut = input_access.control_mut();
+                control_mut.clear_all_nonep0_add_flag();
+                control_mut.set_add_context_flag(0);
+            }
+            let slot_mut = input_access.device_mut().slot_mut();
+            slot_mut.set_hub();
+            slot_mut.set_number_of_ports(configuration.ports);
+            slot_mut.set_tt_think_time(configuration.tt_think_time);
+            if configuration.multi_tt {
+                slot_mut.set_multi_tt();
+            } else {
+                slot_mut.clear_multi_tt();
+            }
+
+            O::PhysAddr::from(ctx.in_ctx.addr()).into() as _
+        };
+
+        fence(Ordering::Release);
+        let request_result = self
+            .post_command(command::Allowed::ConfigureEndpoint(
+                *command::ConfigureEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(input_addr),
+            ))
+            .await;
+        let code = command_code(request_result);
+        if code != RequestResult::Success {
+            warn!("{TAG} configure hub at slot {} failed! {:?}", slot_id, code);
+        } else {
+            debug!("{TAG} slot {} is a hub now, {:?}", slot_id, configuration);
+        }
+        code
+    }
+
+    async fn attach_child(&self, hub_slot: u8, attach: HubPortAttach) -> RequestResult {
+        let Some(hub) = self.device_of_slot(hub_slot) else {
+            return RequestResult::SlotNotEnabledError;
+        };
+        let route = match hub.topology_path.checked_child(attach.port) {
+            Ok(route) => route,
+            Err(error) => {
+                warn!(
+                    "{TAG} port {} of hub {} can't be routed to: {}, ignored",
+                    attach.port, hub.topology_path, error
+                );
+                let mut chain = hub.topology_path.ports();
+                chain.push(attach.port);
+                self.event_bus.route_rejected.broadcast(RouteRejected {
+                    hub: hub.clone(),
+                    port: attach.port,
+                    chain,
+                    error,
+                });
+                return RequestResult::ParameterError;
+            }
+        };
+
+        let hub_speed = match hub.attachment {
+            Some(attachment) => attachment.speed,
+            None => self.port_speed(hub.topology_path.port_idx() as _),
+        };
+        //full/low speed hubs pass their own TT down
+        let tt = match hub.attachment.and_then(|attachment| attachment.tt) {
+            Some(tt) => Some(tt),
+            None if hub_speed == DeviceSpeed::High && attach.speed.needs_tt() => {
+                let multi_tt = self
+                    .dev_ctx
+                    .read()
+                    .await
+                    .device_ctx_inners
+                    .get(&hub_slot)
+                    .is_some_and(|ctx| ctx.out_ctx.access().slot().multi_tt());
+                Some(TransactionTranslator {
+                    hub_slot,
+                    port: attach.port,
+                    multi_tt,
+                })
+            }
+            None => None,
+        };
+
+        info!(
+            "{TAG} {:?} speed device at {}, tt {:?}",
+            attach.speed, route, tt
+        );
+        let device = self.attach_at(
+            route,
+            Some(HubAttachment {
+                speed: attach.speed,
+                tt,
+            }),
+        );
+        device.mark_milestone_at(EnumerationMilestone::Connected, attach.connected_at);
+        device.mark_milestone_at(EnumerationMilestone::PortReset, attach.reset_at);
+        RequestResult::Success
+    }
+
+    ///drop endpoints of interface from device context, so another driver could claim it again
+    async fn disable_function(
+        &self,
+        slot_id: u8,
+        interface: Arc<USBInterface>,
+    ) -> Result<(), UsbError> {
+        let dropped: Vec<usize> = interface
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.doorbell_value_aka_dci() as usize)
+            .collect();
+
+        let input_addr: u64 = {
+            let mut writer = self.dev_ctx.write().await;
+            let ctx = writer
+                .device_ctx_inners
+                .get_mut(&slot_id)
+                .ok_or(UsbError::DeviceGone)?;
+            let remaining_entries = (CONTROL_DCI..32)
+                .filter(|dci| !dropped.contains(dci))
+                .filter(|dci| {
+                    !matches!(
+                        ctx.out_ctx.access().endpoint(*dci).endpoint_state(),
+                        EndpointState::Disabled
+                    )
+                })
+                .max()
+                .unwrap_or(CONTROL_DCI);
+
+            let input_access = ctx.in_ctx.access();
+            {
+                let control_mut = input_access.control_mut();
+                control_mut.clear_all_nonep0_add_flag();
+                control_mut.set_add_context_flag(0);
+                dropped
+                    .iter()
+                    .for_each(|dci| control_mut.set_drop_context_flag(*dci));
+            }
+            input_access
+                .device_mut()
+                .slot_mut()
+                .set_context_entries(remaining_entries as u8);
+
+            O::PhysAddr::from(ctx.in_ctx.addr()).into() as _
+        };
+
+        fence(Ordering::Release);
+        let request_result = self
+            .post_command(command::Allowed::ConfigureEndpoint(
+                *command::ConfigureEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(input_addr),
+            ))
+            .await;
+        trace!("got result: {:?}", request_result);
+        let outcome = command_outcome(request_result);
+
+        if let Some(ctx) = self
+            .dev_ctx
+            .write()
+            .await
+            .device_ctx_inners
+            .get_mut(&slot_id)
+        {
+            let control_mut = ctx.in_ctx.access().control_mut();
+            dropped
+                .iter()
+                .for_each(|dci| control_mut.clear_drop_context_flag(*dci));
+        }
+        //endpoints stay as they were if controller refused to drop them
+        if outcome.is_ok() {
+            self.forget_dropped_endpoints(slot_id, &dropped).await;
+        }
+
+        self.trace_dump_context(slot_id);
+        outcome
+    }
+
+    ///drop every endpoint but ep0 at once, before device gets another configuration
+    async fn deconfigure(&self, slot_id: u8) -> Result<(), UsbError> {
+        let (dropped, input_addr) = {
+            let mut writer = self.dev_ctx.write().await;
+            let ctx = writer.device_ctx_inners.get_mut(&slot_id).unwrap();
+            let dropped: Vec<usize> = (CONTROL_DCI + 1..32)
+                .filter(|dci| {
+                    !matches!(
+                        ctx.out_ctx.access().endpoint(*dci).endpoint_state(),
+                        EndpointState::Disabled
+                    )
+                })
+                .collect();
+
+            let input_access = ctx.in_ctx.access();
+            {
+                let control_mut = input_access.control_mut();
+                control_mut.clear_all_nonep0_add_flag();
+                control_mut.set_add_context_flag(0);
+            }
+            input_access
+                .device_mut()
+                .slot_mut()
+                .set_context_entries(CONTROL_DCI as u8);
+            (dropped, O::PhysAddr::from(ctx.in_ctx.addr()).into() as u64)
+        };
+        if dropped.is_empty() {
+            return Ok(());
+        }
+
+        fence(Ordering::Release);
+        //refer xhci spec 4.6.6, deconfigure ignores add and drop flags
+        let request_result = self
+            .post_command(command::Allowed::ConfigureEndpoint(
+                *command::ConfigureEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(input_addr)
+                    .set_deconfigure(),
+            ))
+            .await;
+        trace!("got result: {:?}", request_result);
+        let outcome = command_outcome(request_result);
+        if outcome.is_ok() {
+            self.forget_dropped_endpoints(slot_id, &dropped).await;
+            debug!("{TAG} slot {} deconfigured", slot_id);
         }
-        key
+        outcome
     }
 
-    #[allow(unused_variables)]
-    async fn post_transfer(&self, req: USBRequest, slot: &'a OnceCell<u8>) {
-        match req.operation {
-            crate::usb::operations::RequestedOperation::Control(control_transfer) => {
-                let slot = unsafe { slot.get_unchecked().clone() };
-                self.post_control_transfer(control_transfer, req.complete_action, slot) //purpose: avoid cycle dependency
-                    .await;
-            }
-            crate::usb::operations::RequestedOperation::Bulk(bulk_transfer) => todo!(),
-            crate::usb::operations::RequestedOperation::Interrupt(interrupt_transfer) => {
-                match req.extra_action {
-                    ExtraAction::NOOP => {
-                        let key = self
-                            .post_interrupt_transfer(
-                                &interrupt_transfer,
-                                Some(req.complete_action),
-                                slot,
-                            )
-                            .await;
-                    }
-                    ExtraAction::KeepFill => {
-                        let key = self
-                            .post_interrupt_transfer(&interrupt_transfer, None, slot)
-                            .await;
-                        unsafe { self.extra_works.get().as_mut_unchecked() }.insert(
-                            key,
-                            (
-                                slot.clone(),
-                                USBRequest {
-                                    extra_action: req.extra_action,
-                                    operation:
-                                        crate::usb::operations::RequestedOperation::Interrupt(
-                                            interrupt_transfer,
-                                        ),
-                                    complete_action: CompleteAction::NOOP,
-                                },
-                            ), //reason: KeepFill must use NOOP CompleteAction
-                        );
-                    }
-                }
-            }
-            crate::usb::operations::RequestedOperation::Isoch(isoch_transfer) => todo!(),
-            crate::usb::operations::RequestedOperation::InitializeDevice(route) => {
-                let dev = unsafe { self.devices.get().as_ref_unchecked() }
-                    .iter()
-                    .find(|dev| dev.topology_path == route)
-                    .unwrap_or_else(|| {
-                        panic!(
-                            "want assign a new device, but such device with route {} notfound",
-                            route
-                        )
-                    });
-                self.assign_address_device(dev).await;
-                trace!("assign address device complete!");
-                if let CompleteAction::DropSem(sem) = req.complete_action {
-                    drop(sem);
-                } else {
-                }
+    ///pending jobs on dropped rings would never complete, rings start over for next configure.
+    ///
+    ///context list is only locked in between, job tables are awaited without holding it
+    async fn forget_dropped_endpoints(&self, slot_id: u8, dropped: &[usize]) {
+        for dci in dropped {
+            let range = self
+                .dev_ctx
+                .read()
+                .await
+                .transfer_ring_range(slot_id, *dci)
+                .await;
+            if let Some(range) = range {
+                self.finish_jobs
+                    .write()
+                    .await
+                    .retain(|addr, _| !range.contains(addr));
+                self.extra_works
+                    .with(|extra_works| extra_works.retain(|addr, _| !range.contains(addr)));
             }
-            crate::usb::operations::RequestedOperation::NOOP => {
-                debug!("{TAG}-device {:#?} transfer nope!", slot)
+            self.periodic
+                .with(|periodic| periodic.remove(&(slot_id, *dci as u8)));
+            self.tt_bandwidth
+                .with(|tt_bandwidth| tt_bandwidth.release((slot_id, *dci as u8)));
+            self.babble
+                .with(|babble| babble.remove(&(slot_id, *dci as u8)));
+            let mut writer = self.dev_ctx.write().await;
+            if let Some(inner) = writer.device_ctx_inners.get_mut(&slot_id) {
+                inner.stream_arrays.remove(dci);
             }
-            crate::usb::operations::RequestedOperation::EnableFunction(config_val, interface) => {
-                let slot = unsafe { slot.get_unchecked().clone() };
-                self.enable_function(slot, config_val, interface).await;
-                trace!("enable function for slot complete!");
-                if let CompleteAction::DropSem(sem) = req.complete_action {
-                    drop(sem);
-                } else {
-                }
+            writer.reset_transfer_ring(slot_id, *dci);
+            if let Some(ring) = writer.write_transfer_ring(slot_id, *dci) {
+                self.publish_occupancy(slot_id, *dci as u8, ring);
             }
         }
     }
-    async fn enable_function(&self, slot_id: u8, config: u8, interface: Arc<USBInterface>) {
+    async fn enable_function(
+        &self,
+        slot_id: u8,
+        config: u8,
+        interface: Arc<USBInterface>,
+    ) -> Result<(), UsbError> {
+        let device = self.device_of_slot(slot_id).ok_or(UsbError::DeviceGone)?;
+        let mut endpoints = Vec::with_capacity(interface.endpoints.len());
+        for ep in &interface.endpoints {
+            endpoints.push(self.check_endpoint(&device, config, &interface, ep).await?);
+        }
+        let claimed = self.claim_tt_bandwidth(slot_id, &interface)?;
         let input_addr: u64 = {
             let mut writer = self.dev_ctx.write().await;
             let ctx = writer.device_ctx_inners.get_mut(&slot_id).unwrap();
@@ -748,42 +2687,166 @@ where
 
         self.trace_dump_context(slot_id);
 
-        for ele in &interface.endpoints {
-            self.setup_endpoint(ele, slot_id).await
+        for (ele, (checked, companion)) in interface.endpoints.iter().zip(endpoints) {
+            self.setup_endpoint(ele, slot_id, checked, companion).await
         }
 
         fence(Ordering::Release);
-        {
-            let request_result = self
-                .post_command(command::Allowed::ConfigureEndpoint(
-                    *command::ConfigureEndpoint::default()
-                        .set_slot_id(slot_id)
-                        .set_input_context_pointer(input_addr),
-                ))
-                .await;
-            trace!("got result: {:?}", request_result);
-            assert_eq!(
-                RequestResult::Success,
-                Into::<RequestResult>::into(request_result.completion_code().unwrap()),
-                "configure endpoint failed! {:#?}",
-                request_result
-            );
-        }
+        let request_result = self
+            .post_command(command::Allowed::ConfigureEndpoint(
+                *command::ConfigureEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(input_addr),
+            ))
+            .await;
+        trace!("got result: {:?}", request_result);
 
         self.trace_dump_context(slot_id);
 
         fence(Ordering::Release);
+        command_outcome(request_result).inspect_err(|_| {
+            self.tt_bandwidth.with(|budget| {
+                claimed
+                    .iter()
+                    .for_each(|endpoint| budget.release(*endpoint))
+            });
+        })
+    }
+
+    ///periodic endpoints of a low/full speed device behind a hub share the TT budget of that hub.
+    ///refused with BandwidthError before controller is asked, returns endpoints claimed
+    fn claim_tt_bandwidth(
+        &self,
+        slot_id: u8,
+        interface: &USBInterface,
+    ) -> Result<Vec<(u8, u8)>, UsbError> {
+        let Some(attachment) = self
+            .device_of_slot(slot_id)
+            .and_then(|device| device.attachment)
+        else {
+            return Ok(Vec::new());
+        };
+        let Some(tt) = attachment.tt.filter(|_| attachment.speed.needs_tt()) else {
+            return Ok(Vec::new());
+        };
+
+        self.tt_bandwidth.with(|budget| {
+        let mut claimed = Vec::new();
+        for endpoint in &interface.endpoints {
+            //low/full speed intervals are in frames, isoch ones as exponent
+            let interval_frames = match endpoint.endpoint_type() {
+                EndpointType::InterruptIn | EndpointType::InterruptOut => endpoint.interval as u32,
+                EndpointType::IsochIn | EndpointType::IsochOut => {
+                    1u32 << (endpoint.interval.clamp(1, 16) - 1)
+                }
+                _ => continue,
+            };
+            let dci = endpoint.doorbell_value_aka_dci() as u8;
+            let cost = TtBandwidth::cost(
+                attachment.speed,
+                endpoint.max_packet_size & 0x7ff,
+                interval_frames,
+            );
+            if !budget.claim(&tt, (slot_id, dci), cost) {
+                warn!(
+                    "{TAG} TT of hub slot {} port {} can't take {} bit times for slot {} dci {}, {}/{} in use",
+                    tt.hub_slot,
+                    tt.port,
+                    cost,
+                    slot_id,
+                    dci,
+                    budget.used(&tt),
+                    TT_PERIODIC_BUDGET
+                );
+                claimed
+                    .iter()
+                    .for_each(|endpoint| budget.release(*endpoint));
+                return Err(UsbError::Completion(RequestResult::BandwidthError));
+            }
+            claimed.push((slot_id, dci));
+        }
+        Ok(claimed)
+        })
+    }
+
+    ///checked against spec, together with its companion on superspeed
+    async fn check_endpoint(
+        &self,
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+        config: u8,
+        interface: &USBInterface,
+        ep: &Endpoint,
+    ) -> Result<(CheckedEndpoint, Option<SsEndpointCompanion>), UsbError> {
+        let speed = self.speed_of(device);
+        let checked =
+            CheckedEndpoint::check(self.config.spec_policy, &device.topology_path, speed, ep)?;
+        let dci = ep.doorbell_value_aka_dci() as u8;
+        let address = (dci / 2) | if dci % 2 == 1 { 0x80 } else { 0 };
+        let companion = device
+            .ss_companion(
+                config,
+                interface.interface.interface_number,
+                interface.interface.alternate_setting,
+                address,
+            )
+            .await;
+        if speed == DeviceSpeed::Super && companion.is_none() {
+            self.config.spec_policy.check(format_args!(
+                "{} endpoint {:#x} has no superspeed companion",
+                device.topology_path, address
+            ))?;
+        }
+        Ok((checked, companion))
     }
 
-    async fn setup_endpoint(&self, ep: &Arc<Endpoint>, slot: u8) {
+    ///`companion` is there for superspeed endpoints, it tells burst, mult and streams
+    async fn setup_endpoint(
+        &self,
+        ep: &Arc<Endpoint>,
+        slot: u8,
+        checked: CheckedEndpoint,
+        companion: Option<SsEndpointCompanion>,
+    ) {
         let dci = ep.doorbell_value_aka_dci() as usize;
-        let max_packet_size = ep.max_packet_size;
+        let max_packet_size = self
+            .clamped_packet_size(ep, checked.max_packet_size, slot)
+            .await;
         trace!("setup endpoint for dci {dci} type {:?}", ep.endpoint_type());
         let mut writer = self.dev_ctx.write().await;
         trace!("fetched!");
         let ring = writer.write_transfer_ring(slot, dci).unwrap();
         let ring_addr = O::PhysAddr::from(ring.register()).into() as u64;
 
+        //streams of an earlier configuration go with it
+        if let Some(inner) = writer.device_ctx_inners.get_mut(&slot) {
+            inner.stream_arrays.remove(&dci);
+        }
+        //stream context array takes place of transfer ring, its streams get rings once opened
+        let streams = match (ep.endpoint_type(), companion) {
+            (EndpointType::BulkOut | EndpointType::BulkIn, Some(companion))
+                if companion.max_streams() > 0 =>
+            {
+                let requested = companion.max_streams().min(MAX_STREAMS_PER_ENDPOINT as _) as u16;
+                writer
+                    .new_stream_array(slot, dci, requested, self.max_psa_size)
+                    .map(|array| {
+                        (
+                            O::PhysAddr::from(array.register()).into() as u64,
+                            array.max_pstreams(),
+                            array.is_linear(),
+                        )
+                    })
+                    .or_else(|| {
+                        debug!(
+                            "{TAG} slot {} dci {} wants {} streams, controller offers none",
+                            slot, dci, requested
+                        );
+                        None
+                    })
+            }
+            _ => None,
+        };
+
         let ctx = writer.device_ctx_inners.get_mut(&slot).unwrap();
         let input_access = ctx.in_ctx.access();
 
@@ -797,7 +2860,7 @@ where
         }
 
         let ep_mut = input_access.device_mut().endpoint_mut(dci);
-        ep_mut.set_interval(ep.interval - 1);
+        ep_mut.set_interval(checked.interval.saturating_sub(1));
         ep_mut.set_endpoint_type(ep.endpoint_type().cast());
         ep_mut.set_tr_dequeue_pointer(ring_addr);
         ep_mut.set_max_packet_size(max_packet_size);
@@ -807,8 +2870,25 @@ where
         match endpoint_type {
             EndpointType::Control => {}
             EndpointType::BulkOut | EndpointType::BulkIn => {
-                ep_mut.set_max_burst_size(0);
-                ep_mut.set_max_primary_streams(0);
+                ep_mut.set_max_burst_size(companion.map_or(0, |companion| companion.max_burst));
+                match streams {
+                    Some((array_addr, max_pstreams, linear)) => {
+                        //bulk requests on it have to name a stream from now on
+                        ep_mut.set_tr_dequeue_pointer(array_addr);
+                        ep_mut.clear_dequeue_cycle_state();
+                        ep_mut.set_max_primary_streams(max_pstreams);
+                        if linear {
+                            ep_mut.set_linear_stream_array();
+                        } else {
+                            ep_mut.clear_linear_stream_array();
+                        }
+                        debug!(
+                            "{TAG} slot {} dci {} uses streams, MaxPStreams {}",
+                            slot, dci, max_pstreams
+                        );
+                    }
+                    None => ep_mut.set_max_primary_streams(0),
+                }
             }
             EndpointType::IsochOut
             | EndpointType::IsochIn
@@ -816,16 +2896,36 @@ where
             | EndpointType::InterruptIn => {
                 //init for isoch/interrupt
                 ep_mut.set_max_packet_size(max_packet_size & 0x7ff); //refer xhci page 162
-                ep_mut.set_max_burst_size(((max_packet_size & 0x1800) >> 11).try_into().unwrap());
-                ep_mut.set_mult(0); //always 0 for interrupt
+                match companion {
+                    //superspeed has no additional transactions in wMaxPacketSize, refer xhci 6.2.3.4
+                    Some(companion) => {
+                        ep_mut.set_max_burst_size(companion.max_burst);
+                        if let EndpointType::IsochOut | EndpointType::IsochIn = endpoint_type {
+                            ep_mut.set_mult(companion.mult());
+                        } else {
+                            ep_mut.set_mult(0); //always 0 for interrupt
+                        }
+                    }
+                    None => {
+                        ep_mut.set_max_burst_size(
+                            ((max_packet_size & 0x1800) >> 11).try_into().unwrap(),
+                        );
+                        ep_mut.set_mult(0); //always 0 for interrupt
+                    }
+                }
 
                 if let EndpointType::IsochOut | EndpointType::IsochIn = endpoint_type {
                     ep_mut.set_error_count(0);
                 }
 
                 ep_mut.set_tr_dequeue_pointer(ring_addr);
-                ep_mut.set_max_endpoint_service_time_interval_payload_low(4);
-                //best guess?
+                match companion {
+                    Some(companion) => ep_mut.set_max_endpoint_service_time_interval_payload_low(
+                        companion.bytes_per_interval,
+                    ),
+                    //best guess?
+                    None => ep_mut.set_max_endpoint_service_time_interval_payload_low(4),
+                }
 
                 ep_mut.set_interval(1); //need extra step?
             }
@@ -835,24 +2935,252 @@ where
         }
     }
 
-    async fn assign_address_device(&self, device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
-        let slot_id = self.enable_slot().await;
+    ///wMaxPacketSize to program, full speed ones clamped by quirk or by earlier babble
+    async fn clamped_packet_size(&self, ep: &Endpoint, declared: u16, slot: u8) -> u16 {
+        let dci = ep.doorbell_value_aka_dci() as u8;
+        let Some(device) = self.device_of_slot(slot) else {
+            return declared;
+        };
+        if dci as usize == CONTROL_DCI || self.speed_of(&device) != DeviceSpeed::Full {
+            return declared;
+        }
+        let quirk = match device.device_desc_raw.get() {
+            Some(raw) => {
+                self.config
+                    .quirks
+                    .lookup(&DeviceIdentity::from_device_desc(raw))
+                    .await
+                    .fs_max_packet_clamp
+            }
+            None => None,
+        };
+        let size = self.babble.with(|babble| {
+            let state = babble.entry((slot, dci)).or_default();
+            let size = [Some(declared & 0x7ff), quirk, state.clamp]
+                .into_iter()
+                .flatten()
+                .min()
+                .unwrap_or(declared);
+            state.programmed = size;
+            size
+        });
+        if size != declared & 0x7ff {
+            debug!(
+                "{TAG} slot {} dci {} programmed with {} bytes instead of {}",
+                slot, dci, size, declared
+            );
+        }
+        size
+    }
+
+    fn speed_of(&self, device: &USBDevice<O, RING_BUFFER_SIZE>) -> DeviceSpeed {
+        match device.attachment {
+            Some(attachment) => attachment.speed,
+            None => self.port_speed(device.topology_path.port_idx() as _),
+        }
+    }
+
+    ///cheap full speed devices babble when sent packets as large as they claim to take
+    fn note_babble(&self, slot_id: u8, dci: u8) {
+        self.babble.with(|babble| {
+            let Some(state) = babble.get_mut(&(slot_id, dci)) else {
+                return;
+            };
+            state.errors += 1;
+            if state.errors < BABBLE_RETRY_THRESHOLD || state.reprogram {
+                return;
+            }
+            state.errors = 0;
+            let smaller = (state.programmed / 2).max(MIN_FS_PACKET_SIZE);
+            if smaller < state.programmed {
+                warn!(
+                    "{TAG} slot {} dci {} keeps babbling, retrying with {} byte packets",
+                    slot_id, dci, smaller
+                );
+                state.clamp = Some(smaller);
+                state.reprogram = true;
+            }
+        })
+    }
+
+    fn take_pending_clamp(&self, slot_id: u8, dci: u8) -> Option<u16> {
+        self.babble.with(|babble| {
+            let state = babble.get_mut(&(slot_id, dci))?;
+            mem::take(&mut state.reprogram).then_some(state.clamp?)
+        })
+    }
+
+    ///endpoint is stopped after reset, drop and add it again with smaller packet size.
+    ///pending TDs are gone already, its ring starts over empty
+    async fn apply_packet_clamp(&self, slot_id: u8, dci: u8, size: u16) -> RequestResult {
+        let input_addr: u64 = {
+            let mut writer = self.dev_ctx.write().await;
+            writer.reset_transfer_ring(slot_id, dci as _);
+            let Some(ctx) = writer.device_ctx_inners.get_mut(&slot_id) else {
+                return RequestResult::SlotNotEnabledError;
+            };
+            let input_access = ctx.in_ctx.access();
+            {
+                let control_mut = input_access.control_mut();
+                control_mut.clear_all_nonep0_add_flag();
+                control_mut.set_add_context_flag(0);
+                control_mut.set_drop_context_flag(dci as _);
+                control_mut.set_add_context_flag(dci as _);
+            }
+            {
+                let slot_mut = input_access.device_mut().slot_mut();
+                if slot_mut.context_entries() < dci {
+                    slot_mut.set_context_entries(dci);
+                }
+            }
+            input_access
+                .device_mut()
+                .endpoint_mut(dci as _)
+                .set_max_packet_size(size);
+            O::PhysAddr::from(ctx.in_ctx.addr()).into() as _
+        };
+
+        fence(Ordering::Release);
+        let request_result = self
+            .post_command(command::Allowed::ConfigureEndpoint(
+                *command::ConfigureEndpoint::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(input_addr),
+            ))
+            .await;
+        if let Some(ctx) = self
+            .dev_ctx
+            .write()
+            .await
+            .device_ctx_inners
+            .get_mut(&slot_id)
+        {
+            ctx.in_ctx
+                .access()
+                .control_mut()
+                .clear_drop_context_flag(dci as _);
+        }
+        let code = command_code(request_result);
+        match code {
+            RequestResult::Success => {
+                self.babble.with(|babble| {
+                    if let Some(state) = babble.get_mut(&(slot_id, dci)) {
+                        state.programmed = size;
+                    }
+                });
+                info!(
+                    "{TAG} slot {} dci {} now uses {} byte packets",
+                    slot_id, dci, size
+                );
+            }
+            _ => warn!(
+                "{TAG} reprogramming slot {} dci {} failed! {:?}",
+                slot_id, dci, code
+            ),
+        }
+        code
+    }
+
+    ///None if endpoint has no stream array, or `id` lies outside of it
+    async fn open_stream(&self, slot: u8, dci: u8, id: u16) -> Option<StreamHandle> {
+        let handle = self.dev_ctx.write().await.open_stream(slot, dci as _, id);
+        if handle.is_none() {
+            debug!("{TAG} slot {} dci {} has no stream {}", slot, dci, id);
+        }
+        handle
+    }
+
+    ///stream a bulk TD goes on. a stream endpoint takes no TD without one, a plain one none with
+    async fn stream_of(
+        &self,
+        slot: u8,
+        transfer: &BulkTransfer,
+    ) -> Result<Option<StreamHandle>, UsbError> {
+        let dci = transfer.endpoint_id as u8;
+        match transfer.stream_id {
+            Some(id) => self
+                .open_stream(slot, dci, id)
+                .await
+                .map(Some)
+                .ok_or(UsbError::InvalidArgument),
+            None if self
+                .dev_ctx
+                .read()
+                .await
+                .stream_array(slot, dci as _)
+                .is_some() =>
+            {
+                debug!(
+                    "{TAG} slot {} dci {} uses streams, TD names none",
+                    slot, dci
+                );
+                Err(UsbError::InvalidArgument)
+            }
+            None => Ok(None),
+        }
+    }
+
+    ///doorbell for every opened stream of endpoint, or for endpoint itself if it has none
+    async fn ring_endpoint(&self, slot: u8, dci: u8) {
+        match self.dev_ctx.read().await.opened_streams(slot, dci as _) {
+            Some((_, streams)) => streams
+                .into_iter()
+                .for_each(|stream| self.ring_db(slot, Some(stream), Some(dci))),
+            None => self.ring_db(slot, None, Some(dci)),
+        }
+    }
+
+    async fn assign_address_device(
+        &self,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ) -> Result<(), UsbError> {
+        if let Some(bounded) = &self.config.bounded_memory
+            && self.dev_ctx.read().await.device_ctx_inners.len() >= bounded.max_devices
+        {
+            warn!(
+                "{TAG} bounded memory is reserved for {} devices, refusing {}",
+                bounded.max_devices, device.topology_path
+            );
+            return Err(UsbError::NoSlot);
+        }
+        let slot_id = self.enable_slot().await?;
+        //unplugged while slot got enabled, released without one
+        if device.is_gone() {
+            self.disable_slot(slot_id).await;
+            return Err(UsbError::DeviceGone);
+        }
         debug!("slot id acquired! {slot_id} for {}", device.topology_path);
         let _ = device.slot_id.set(slot_id).await;
 
-        self.dev_ctx.write().await.new_slot(slot_id, 32); //TODO: basically, now a days all usb device  should had 32 endpoints, but for now let's just hardcode it...
+        //TODO: basically, now a days all usb device  should had 32 endpoints, but for now let's just hardcode it...
+        if self
+            .dev_ctx
+            .write()
+            .await
+            .new_slot(slot_id, NUM_EPS)
+            .is_err()
+        {
+            warn!("{TAG} no DMA memory left for slot {}", slot_id);
+            self.disable_slot(slot_id).await;
+            return Err(UsbError::OutOfMemory);
+        }
         let idx = device.topology_path.port_idx();
         trace!("idx is {}", idx);
-        let port_speed = self.get_speed(idx);
-        let default_max_packet_size = parse_default_max_packet_size_from_speed(port_speed);
+        let port_speed = match device.attachment {
+            Some(attachment) => attachment.speed.xhci_speed_id(),
+            None => self.get_speed(idx),
+        };
+        let speed = match device.attachment {
+            Some(attachment) => attachment.speed,
+            None => self.port_speed(idx as _),
+        };
         let context_addr = {
+            let mut writer = self.dev_ctx.write().await;
             let (control_channel_addr, cycle_bit) = {
-                let _temp = self.dev_ctx.read().await;
-                let ring = _temp.read_transfer_ring(slot_id, CONTROL_DCI).unwrap();
+                let ring = writer.write_transfer_ring(slot_id, CONTROL_DCI).unwrap();
                 (ring.register(), ring.cycle)
             };
 
-            let mut writer = self.dev_ctx.write().await;
             let context_mut = &mut writer.device_ctx_inners.get_mut(&slot_id).unwrap().in_ctx;
 
             let control_context = context_mut.access().control_mut();
@@ -865,25 +3193,31 @@ where
             let slot_context = context_mut.access().device_mut().slot_mut();
             slot_context.clear_multi_tt();
             slot_context.clear_hub();
-            slot_context.set_route_string({
-                // let rs = device.topology_path.route_string();
-                // assert_eq!(rs, 1);
-                // rs
-                0
-                // for now, not support more hub ,so hardcode as 0.//TODO: generate route string
-            });
+            slot_context.set_route_string(device.topology_path.route_string());
             slot_context.set_context_entries(1);
             slot_context.set_max_exit_latency(0);
             slot_context.set_root_hub_port_number(device.topology_path.port_number()); // use port number
             slot_context.set_number_of_ports(0);
-            slot_context.set_parent_hub_slot_id(0);
+            match device.attachment.and_then(|attachment| attachment.tt) {
+                Some(tt) => {
+                    slot_context.set_parent_hub_slot_id(tt.hub_slot);
+                    slot_context.set_parent_port_number(tt.port);
+                    if tt.multi_tt {
+                        slot_context.set_multi_tt();
+                    }
+                }
+                None => {
+                    slot_context.set_parent_hub_slot_id(0);
+                    slot_context.set_parent_port_number(0);
+                }
+            }
             slot_context.set_tt_think_time(0);
             slot_context.set_interrupter_target(0);
             slot_context.set_speed(port_speed);
 
             let endpoint_0 = context_mut.access().device_mut().endpoint_mut(CONTROL_DCI);
             endpoint_0.set_endpoint_type(xhci::context::EndpointType::Control);
-            endpoint_0.set_max_packet_size(default_max_packet_size);
+            endpoint_0.set_max_packet_size(speed.default_ep0_packet_size());
             endpoint_0.set_max_burst_size(0);
             endpoint_0.set_error_count(3);
             trace!(
@@ -917,99 +3251,310 @@ where
                 ))
                 .await;
             trace!("got result: {:?}", request_result);
-            assert_eq!(
-                RequestResult::Success,
-                Into::<RequestResult>::into(request_result.completion_code().unwrap()),
-                "address device failed! {:#?}",
-                request_result
-            );
+            if let Err(err) = command_outcome(request_result) {
+                self.disable_slot(slot_id).await;
+                return Err(err);
+            }
         }
 
         self.trace_dump_context(slot_id);
 
         fence(Ordering::Release);
 
-        let actual_speed = {
-            //set speed
-            let buffer: DMA<[u8], O> = DMA::new_vec(0u8, 8, 64, self.config.os.dma_alloc());
-            let (sender, receiver) = oneshot::channel();
+        let device_desc = match self.enumerate_device_descriptor(slot_id, speed).await {
+            Ok(desc) => desc,
+            Err(err) => {
+                self.disable_slot(slot_id).await;
+                return Err(err);
+            }
+        };
+        let identity = DeviceIdentity::from_device_desc(&device_desc);
+        if !self.config.device_filter.is_allowed(&identity).await {
+            warn!(
+                "{TAG} device {:x?} at {} rejected by filter",
+                identity, device.topology_path
+            );
+            self.disable_slot(slot_id).await;
+            *device.state.write().await = DeviceState::Rejected;
+            return Ok(());
+        }
 
-            assert!(device.slot_id.is_initialized());
+        let bcd_usb = u16::from_le_bytes([device_desc[2], device_desc[3]]);
+        let _ = device.device_desc_raw.set(device_desc).await;
 
-            self.post_control_transfer(
-                ControlTransfer {
-                    request_type: bmRequestType::new(
-                        Direction::In,
-                        DataTransferType::Standard,
-                        Recipient::Device,
-                    ),
-                    request: bRequest::Standard(bRequestStandard::GetDescriptor),
-                    index: 0,
-                    value: construct_control_transfer_type(
-                        USBStandardDescriptorTypes::Device as u8,
-                        0,
-                    )
-                    .bits(),
-                    data: Some(buffer.phys_addr_len_tuple().into()),
-                    response: false,
-                },
-                CompleteAction::SimpleResponse(sender),
-                unsafe { device.slot_id.get_unchecked().clone() },
-            )
-            .await;
+        //link power registers belong to root port, not to devices behind hubs
+        if self.config.lpm_policy.is_enabled()
+            && bcd_usb >= LPM_MIN_BCD_USB
+            && device.attachment.is_none()
+        {
+            self.setup_link_power_management(slot_id, idx, speed).await;
+        }
+        Ok(())
+    }
 
-            let request_result = receiver.await;
-            if let Ok(Ok(RequestResult::Success)) = request_result {
-            } else {
-                panic!("get basic desc failed! {:#?}", request_result);
+    ///read device descriptor, fixup ep0 max packet size on the way.
+    ///
+    ///first read goes with default ep0 packet size, a device with a smaller ep0 ends it with a
+    ///short packet after its first bMaxPacketSize0 bytes. only then it's read again
+    async fn enumerate_device_descriptor(
+        &self,
+        slot_id: u8,
+        speed: DeviceSpeed,
+    ) -> Result<Vec<u8>, UsbError> {
+        let mut desc = self.get_device_descriptor(slot_id).await?;
+        trace!("got {:?}", desc);
+
+        let Some(&declared) = desc.get(DEVICE_DESC_MAX_PACKET_SIZE_OFFSET) else {
+            warn!(
+                "{TAG} slot {} device descriptor cut at {} bytes",
+                slot_id,
+                desc.len()
+            );
+            return Err(UsbError::BadDescriptor);
+        };
+        if !speed.is_valid_ep0_packet_size(declared) {
+            self.config.spec_policy.check(format_args!(
+                "{TAG} slot {} bMaxPacketSize0 {} ({:?} bytes) at {:?} speed",
+                slot_id,
+                declared,
+                speed.declared_ep0_packet_size(declared),
+                speed
+            ))?;
+        }
+
+        //superspeed ep0 stays at the 512 it got addressed with
+        let max_packet_size = speed.ep0_packet_size(declared);
+        if max_packet_size != speed.default_ep0_packet_size() {
+            self.evaluate_ep0_packet_size(slot_id, max_packet_size)
+                .await?;
+            if desc.len() < DEVICE_DESC_LEN {
+                desc = self.get_device_descriptor(slot_id).await?;
+            }
+        }
+        if desc.len() < DEVICE_DESC_LEN {
+            warn!(
+                "{TAG} slot {} device descriptor cut at {} bytes",
+                slot_id,
+                desc.len()
+            );
+            return Err(UsbError::BadDescriptor);
+        }
+        Ok(desc)
+    }
+
+    async fn get_device_descriptor(&self, slot_id: u8) -> Result<Vec<u8>, UsbError> {
+        self.get_descriptor_bytes(
+            slot_id,
+            USBStandardDescriptorTypes::Device as u8,
+            DEVICE_DESC_LEN,
+        )
+        .await
+        .ok_or(UsbError::BadDescriptor)
+    }
+
+    ///as many bytes as device actually sent, at most `len`
+    async fn get_descriptor_bytes(
+        &self,
+        slot_id: u8,
+        desc_type: u8,
+        len: usize,
+    ) -> Option<Vec<u8>> {
+        let buffer = self.small_buffers.try_alloc_or_dma(len, 64).ok()?;
+        let (sender, receiver) = oneshot::channel();
+
+        self.post_control_transfer(
+            RequestId::next(),
+            ControlTransfer::get_descriptor(
+                Recipient::Device,
+                desc_type,
+                0,
+                0,
+                buffer.phys_addr_len_tuple().into(),
+            ),
+            CompleteAction::LengthResponse(sender),
+            slot_id,
+        )
+        .await;
+
+        match receiver.await {
+            Ok(Ok((RequestResult::Success | RequestResult::ShortPacket, transferred))) => {
+                let mut bytes = buffer.to_vec();
+                bytes.truncate(transferred);
+                Some(bytes)
+            }
+            other => {
+                debug!("get desc {} failed! {:#?}", desc_type, other);
+                None
             }
+        }
+    }
+
+    async fn evaluate_ep0_packet_size(
+        &self,
+        slot_id: u8,
+        max_packet_size: u16,
+    ) -> Result<(), UsbError> {
+        debug!(
+            "CMD: evaluating context for set endpoint0 packet size {}",
+            max_packet_size
+        );
+        self.evaluate_context(slot_id, |input| {
+            input
+                .device_mut()
+                .endpoint_mut(CONTROL_DCI)
+                .set_max_packet_size(max_packet_size);
+        })
+        .await
+    }
+
+    async fn evaluate_context(
+        &self,
+        slot_id: u8,
+        modify: impl FnOnce(&mut dyn InputHandler),
+    ) -> Result<(), UsbError> {
+        let context_addr = {
+            let mut writer = self.dev_ctx.write().await;
+            let input = &mut writer.device_ctx_inners.get_mut(&slot_id).unwrap().in_ctx;
+            modify(input.access());
+            O::PhysAddr::from(input.addr()).into() as _
+        };
+
+        fence(Ordering::Release);
+        let request_result = self
+            .post_command(command::Allowed::EvaluateContext(
+                *command::EvaluateContext::default()
+                    .set_slot_id(slot_id)
+                    .set_input_context_pointer(context_addr),
+            ))
+            .await;
+        trace!("got result: {:?}", request_result);
+        command_outcome(request_result)
+    }
 
-            let mut data = [0u8; 8];
-            data[..8].copy_from_slice(&buffer);
-            trace!("got {:?}", data);
-            data.last()
-                .map(|len| if *len == 0 { 8u8 } else { *len })
-                .unwrap()
+    ///read BOS and enable U1/U2 or usb2 hardware LPM, according to policy
+    async fn setup_link_power_management(&self, slot_id: u8, port_idx: u8, speed: DeviceSpeed) {
+        let Some(header) = self
+            .get_descriptor_bytes(slot_id, BOS_DESC_TYPE, BOS_HEADER_LEN)
+            .await
+        else {
+            return;
+        };
+        let Some(&[low, high]) = header.get(2..4) else {
+            return;
+        };
+        let total_len = u16::from_le_bytes([low, high]) as usize;
+        let Some(bos) = self
+            .get_descriptor_bytes(slot_id, BOS_DESC_TYPE, total_len)
+            .await
+        else {
+            return;
         };
 
-        let context_addr = {
-            let mut writer = self.dev_ctx.write().await;
-            let input = &mut writer.device_ctx_inners.get_mut(&slot_id).unwrap().in_ctx;
+        let caps = LinkPowerCapabilities::parse_bos(&bos);
+        let policy = &self.config.lpm_policy;
+        debug!("{TAG} slot {} link power caps: {:?}", slot_id, caps);
 
-            input
-                .access()
-                .device_mut()
-                .endpoint_mut(1) //dci=1: endpoint 0
-                .set_max_packet_size(actual_speed as _);
+        if speed == DeviceSpeed::Super {
+            let (Some(u1_exit), Some(u2_exit)) = (caps.u1_exit_latency, caps.u2_exit_latency)
+            else {
+                return;
+            };
+            if policy.u1_timeout == 0 && policy.u2_timeout == 0 {
+                return;
+            }
 
-            debug!(
-                "CMD: evaluating context for set endpoint0 packet size {}",
-                actual_speed
+            self.set_sel(slot_id, u1_exit, u2_exit).await;
+            if let Err(err) = self
+                .evaluate_context(slot_id, |input| {
+                    input
+                        .device_mut()
+                        .slot_mut()
+                        .set_max_exit_latency((u1_exit as u16).max(u2_exit));
+                })
+                .await
+            {
+                warn!("{TAG} slot {} U1/U2 left off: {}", slot_id, err);
+                return;
+            }
+            self.regs.with(|regs| {
+                regs.port_register_set
+                    .update_volatile_at(port_idx as _, |port| {
+                        port.portpmsc.set_u1_timeout(policy.u1_timeout);
+                        port.portpmsc.set_u2_timeout(policy.u2_timeout);
+                    })
+            });
+            info!(
+                "{TAG} slot {} U1/U2 enabled, timeout {}/{}",
+                slot_id, policy.u1_timeout, policy.u2_timeout
             );
-            // (input as *const Input<16>).addr() as _
-            O::PhysAddr::from(input.addr()).into() as _
+        } else if policy.usb2_hardware_lpm && caps.usb2_lpm {
+            let besl = caps
+                .baseline_besl
+                .filter(|_| caps.besl)
+                .unwrap_or(DEFAULT_BESL);
+            if let Err(err) = self
+                .evaluate_context(slot_id, |input| {
+                    input
+                        .device_mut()
+                        .slot_mut()
+                        .set_max_exit_latency(besl_to_us(besl));
+                })
+                .await
+            {
+                warn!("{TAG} slot {} usb2 LPM left off: {}", slot_id, err);
+                return;
+            }
+            self.regs.with(|regs| {
+                regs.port_register_set
+                    .update_volatile_at(port_idx as _, |port| {
+                        port.portpmsc.set_best_effort_service_latency(besl);
+                        port.portpmsc.set_l1_device_slot(slot_id);
+                        port.portpmsc.set_hardware_lpm_enable();
+                    })
+            });
+            info!(
+                "{TAG} slot {} usb2 hardware LPM enabled, besl {}",
+                slot_id, besl
+            );
+        }
+    }
+
+    async fn set_sel(&self, slot_id: u8, u1_exit: u8, u2_exit: u16) {
+        let Ok(mut buffer) = self.small_buffers.try_alloc_or_dma(6, 64) else {
+            warn!("{TAG} no buffer left for SET_SEL of slot {}", slot_id);
+            return;
         };
+        let [u2_low, u2_high] = u2_exit.to_le_bytes();
+        //U1SEL, U1PEL, U2SEL, U2PEL. no hubs for now, so system exit latency = device exit latency
+        buffer.copy_from_slice(&[u1_exit, u1_exit, u2_low, u2_high, u2_low, u2_high]);
+        let (sender, receiver) = oneshot::channel();
 
-        fence(Ordering::Release);
-        {
-            let request_result = self
-                .post_command(command::Allowed::EvaluateContext(
-                    *command::EvaluateContext::default()
-                        .set_slot_id(slot_id)
-                        .set_input_context_pointer(context_addr),
-                ))
-                .await;
+        self.post_control_transfer(
+            RequestId::next(),
+            ControlTransfer {
+                request_type: bmRequestType::new(
+                    Direction::Out,
+                    DataTransferType::Standard,
+                    Recipient::Device,
+                ),
+                request: bRequestStandard::SetSel.into(),
+                index: 0,
+                value: 0,
+                data: Some(buffer.phys_addr_len_tuple().into()),
+                response: true,
+            },
+            CompleteAction::SimpleResponse(sender),
+            slot_id,
+        )
+        .await;
 
-            assert_eq!(
-                Into::<RequestResult>::into(request_result.completion_code().unwrap()),
-                RequestResult::Success,
-                "evaluate context failed! {:#?}",
-                request_result
-            );
+        if let Ok(Ok(RequestResult::Success)) = receiver.await {
+        } else {
+            warn!("{TAG} SET_SEL failed on slot {}", slot_id);
         }
     }
 
-    async fn enable_slot(&self) -> u8 {
+    async fn enable_slot(&self) -> Result<u8, UsbError> {
         let request_result = self
             .post_command(command::Allowed::EnableSlot(
                 *command::EnableSlot::default().set_slot_type({
@@ -1030,14 +3575,11 @@ where
             ))
             .await;
 
-        assert_eq!(
-            Into::<RequestResult>::into(request_result.completion_code().unwrap()),
-            RequestResult::Success,
-            "enable slot failed! {:#?}",
-            request_result
-        );
-
-        request_result.slot_id()
+        let completion = request_result?;
+        match completion.completion_code() {
+            Ok(CompletionCode::NoSlotsAvailableError) => Err(UsbError::NoSlot),
+            _ => command_outcome(Ok(completion)).map(|_| completion.slot_id()),
+        }
     }
 
     fn trace_dump_context(&self, slot: u8) {
@@ -1061,51 +3603,283 @@ where
         }
     }
 
-    async fn disable_slot(&mut self, _slot: u8) -> Result<RequestResult, u8> {
-        todo!()
+    async fn disable_slot(&self, slot: u8) -> RequestResult {
+        let request_result = self
+            .post_command(command::Allowed::DisableSlot(
+                *command::DisableSlot::default().set_slot_id(slot),
+            ))
+            .await;
+        let code = command_code(request_result);
+        if code != RequestResult::Success {
+            warn!("{TAG} disable slot {} failed! {:?}", slot, code);
+        }
+        self.tt_bandwidth
+            .with(|tt_bandwidth| tt_bandwidth.release_slot(slot));
+        self.babble
+            .with(|babble| babble.retain(|(s, _), _| *s != slot));
+        self.suspended.with(|suspended| suspended.remove(&slot));
+
+        self.dev_ctx.write().await.free_slot(slot);
+        dma_tracker::report_owner(slot);
+        debug!("{TAG} slot {} disabled", slot);
+        code
     }
 
+    ///single TRB if buffer fits one, otherwise a chained TD like bulk
     async fn interrupt_transfer(&self, slot: u8, urb_req: &InterruptTransfer) -> usize {
-        let (addr, len) = urb_req.buffer_addr_len;
+        let chunks = scatter_trb_buffers(urb_req.segments());
+        if let [(addr, len)] = chunks[..] {
+            return self
+                .enqueue_periodic(slot, urb_req.endpoint_id as _, interrupt_trb(addr, len))
+                .await;
+        }
+        self.sync_buffers_for_device(urb_req.segments());
+        let key = self
+            .enqueue_chained(slot, urb_req.endpoint_id as _, None, chunks, |_| false)
+            .await;
+        fence(Ordering::Release);
+        self.ring_db(slot, None, Some(urb_req.endpoint_id as _));
+        key
+    }
 
-        let trb_pointers: usize = {
-            let mut writer = self.dev_ctx.write().await;
-            trace!("fetch ring at slot{}", slot);
-            let ring = writer
-                .write_transfer_ring(slot, urb_req.endpoint_id as _)
-                .expect("initialization on transfer rings got some issue, fixit.");
-            ring.enque_transfer(transfer::Allowed::Normal(
-                *Normal::default()
+    ///enqueue `trb` for the template of (slot, dci), only its event is taken as the refill's
+    async fn refill_periodic(&self, slot: u8, dci: u8, trb: Normal) {
+        let key = self.enqueue_periodic(slot, dci, trb).await;
+        self.periodic.with(|periodic| {
+            if let Some(template) = periodic.get_mut(&(slot, dci)) {
+                template.in_flight = Some(key);
+            }
+        });
+    }
+
+    async fn enqueue_periodic(&self, slot: u8, dci: u8, mut trb: Normal) -> usize {
+        sync_for_device(
+            &self.config.os,
+            trb.data_buffer_pointer() as _,
+            trb.trb_transfer_length() as _,
+        );
+        trb.set_interrupter_target(self.interrupter_for(slot, dci));
+        let trb_pointers: usize = self
+            .with_room(slot, dci, None, 1, |ring| {
+                self.enque_counted(ring, (slot, dci), transfer::Allowed::Normal(trb))
+            })
+            .await
+            .into();
+
+        fence(Ordering::Release);
+        self.ring_db(slot, None, Some(dci));
+
+        trb_pointers
+    }
+
+    ///bulk TD may span multiple normal TRBs, it always ends with an event data TRB,
+    ///so short packet in middle of TD still reports completion on the returned key.
+    async fn bulk_transfer(
+        &self,
+        slot: u8,
+        urb_req: &BulkTransfer,
+        stream: Option<StreamHandle>,
+    ) -> usize {
+        self.sync_buffers_for_device(urb_req.segments());
+        let chunks = scatter_trb_buffers(urb_req.segments());
+        let key = self
+            .enqueue_chained(slot, urb_req.endpoint_id as _, stream, chunks, |idx| {
+                urb_req.ioc_policy.should_interrupt(idx)
+            })
+            .await;
+
+        fence(Ordering::Release);
+        self.ring_db(slot, stream, Some(urb_req.endpoint_id as _));
+
+        key
+    }
+
+    ///chained normal TRBs closed by an event data TRB, whose address is returned as key.
+    ///doorbell is left to caller
+    async fn enqueue_chained(
+        &self,
+        slot: u8,
+        dci: u8,
+        stream: Option<StreamHandle>,
+        chunks: Vec<(usize, usize)>,
+        interrupt_at: impl Fn(usize) -> bool,
+    ) -> usize {
+        let interrupter = self.interrupter_for(slot, dci);
+        self.with_room(slot, dci, stream, chunks.len() + 1, |ring| {
+            for (idx, (addr, len)) in chunks.into_iter().enumerate() {
+                let mut normal = Normal::default();
+                normal
                     .set_data_buffer_pointer(addr as _)
                     .set_trb_transfer_length(len as _)
-                    .set_interrupter_target(0)
-                    .set_interrupt_on_short_packet()
-                    .set_interrupt_on_completion(),
-            ))
+                    .set_interrupter_target(interrupter)
+                    .set_chain_bit();
+                if interrupt_at(idx) {
+                    normal.set_interrupt_on_completion();
+                }
+                self.enque_counted(ring, (slot, dci), transfer::Allowed::Normal(normal));
+            }
+
+            self.enqueue_event_data(ring, (slot, dci), interrupter)
+        })
+        .await
+    }
+
+    ///runs `enqueue` on transfer ring, or on ring of `stream`, once `trbs` TRBs fit in it.
+    ///a full ring grows up to [`USBSystemConfig::ring_growth`] segments, beyond that
+    ///submitter waits for controller to finish earlier TDs
+    async fn with_room<R>(
+        &self,
+        slot: u8,
+        dci: u8,
+        stream: Option<StreamHandle>,
+        trbs: usize,
+        enqueue: impl FnOnce(&mut Ring<O>) -> R,
+    ) -> R {
+        let max_segments = self.config.ring_growth.unwrap_or(1);
+        let mut waited = false;
+        loop {
+            {
+                let reader = self.dev_ctx.read().await;
+                trace!("fetch ring at slot{}", slot);
+                let mut ring = reader
+                    .ring_of(slot, dci as _, stream.as_ref())
+                    .expect("initialization on transfer rings got some issue, fixit.")
+                    .lock()
+                    .await;
+                if ring.make_room(trbs, max_segments) {
+                    let enqueued = enqueue(&mut ring);
+                    //flow control goes by endpoint ring, streams are paced by their ids
+                    if stream.is_none() {
+                        self.publish_occupancy(slot, dci, &ring);
+                    }
+                    return enqueued;
+                }
+                if !waited {
+                    self.stats.ring_full();
+                    debug!(
+                        "{TAG} slot {} dci {} ring full, {} of {} TRBs free, waiting",
+                        slot,
+                        dci,
+                        ring.free(),
+                        trbs
+                    );
+                    waited = true;
+                }
+            }
+            //locks are dropped, completions need them to give room back
+            yield_now().await
         }
-        .into();
+    }
 
-        fence(Ordering::Release);
-        self.ring_db(slot, None, Some(urb_req.endpoint_id as _));
+    ///every transfer TRB goes through here, so it is counted and traced
+    #[cfg_attr(not(feature = "trace_trb_ring"), allow(unused_variables))]
+    fn enque_counted(
+        &self,
+        ring: &mut Ring<O>,
+        (slot, dci): (u8, u8),
+        trb: transfer::Allowed,
+    ) -> O::PhysAddr {
+        self.stats.submitted([transfer_kind(&trb)]);
+        let addr = ring.enque_transfer(trb);
+        #[cfg(feature = "trace_trb_ring")]
+        if let Some(raw) = ring.trb_at(addr.clone().into()) {
+            self.trace_trb(slot, dci, TrbOrigin::Submitted, raw);
+        }
+        addr
+    }
 
-        trb_pointers
+    ///see [`USBSystemConfig::trb_trace`]
+    #[cfg(feature = "trace_trb_ring")]
+    fn trace_trb(&self, slot: u8, dci: u8, origin: TrbOrigin, raw: [u32; 4]) {
+        if let Some(tracer) = &self.trb_trace {
+            tracer.record(
+                slot,
+                TrbRecord {
+                    at: self.config.os.now(),
+                    origin,
+                    dci,
+                    raw,
+                },
+            );
+        }
+    }
+
+    ///see [`crate::usb::operations::flow::EndpointFlow`]
+    fn publish_occupancy(&self, slot: u8, dci: u8, ring: &Ring<O>) {
+        if let Some(device) = self.device_of_slot(slot) {
+            device.publish_ring_occupancy(dci as _, ring.in_flight(), ring.usable());
+        }
+    }
+
+    ///event data TRB carries its own address, which would be reported as TRB pointer
+    fn enqueue_event_data(
+        &self,
+        ring: &mut Ring<O>,
+        endpoint: (u8, u8),
+        interrupter: u16,
+    ) -> usize {
+        let event_data_addr: usize = O::PhysAddr::from(ring.register()).into();
+        self.enque_counted(
+            ring,
+            endpoint,
+            transfer::Allowed::EventData(
+                *transfer::EventData::default()
+                    .set_event_data(event_data_addr as _)
+                    .set_interrupter_target(interrupter)
+                    .set_interrupt_on_completion(),
+            ),
+        )
+        .into()
     }
 
-    async fn control_transfer(&self, slot: u8, urb_req: ControlTransfer) -> usize {
+    ///returns status stage TRB, and data stage TRB with its length if there is one
+    async fn control_transfer(
+        &self,
+        slot: u8,
+        urb_req: ControlTransfer,
+    ) -> (usize, Option<(usize, usize)>) {
         let direction = urb_req.request_type.direction;
         let buffer = urb_req.data;
+        let interrupter = self.interrupter_for(slot, CONTROL_DCI as _);
+
+        if let Some((addr, length)) = buffer {
+            sync_for_device(&self.config.os, addr, length);
+        }
 
         let mut len = 0;
-        let data = if let Some((addr, length)) = buffer {
-            let mut data = transfer::DataStage::default();
+        //data stage crossing a 64KB boundary continues in chained normal TRBs,
+        //closed by an event data TRB reporting length of the whole stage
+        let mut data: Vec<transfer::Allowed> = Vec::new();
+        if let Some((addr, length)) = buffer {
             len = length;
-            data.set_data_buffer_pointer(addr as u64)
-                .set_trb_transfer_length(len as _)
-                .set_direction(direction.into());
-            Some(data)
-        } else {
-            None
-        };
+            let chunks = split_trb_buffers(addr, len);
+            let chained = chunks.len() > 1;
+            for (idx, (addr, len)) in chunks.into_iter().enumerate() {
+                if idx == 0 {
+                    let mut stage = transfer::DataStage::default();
+                    stage
+                        .set_data_buffer_pointer(addr as u64)
+                        .set_trb_transfer_length(len as _)
+                        .set_direction(direction.into())
+                        .set_interrupter_target(interrupter);
+                    if chained {
+                        stage.set_chain_bit();
+                    } else {
+                        //its own event carries residual length, status stage one does not
+                        stage.set_interrupt_on_completion();
+                    }
+                    data.push(stage.into());
+                } else {
+                    data.push(transfer::Allowed::Normal(
+                        *Normal::default()
+                            .set_data_buffer_pointer(addr as _)
+                            .set_trb_transfer_length(len as _)
+                            .set_interrupter_target(interrupter)
+                            .set_chain_bit(),
+                    ));
+                }
+            }
+        }
 
         let setup = *transfer::SetupStage::default()
             .set_request_type(urb_req.request_type.into())
@@ -1125,70 +3899,157 @@ where
             .set_length(len as u16);
         trace!("{:#?}", setup);
 
-        let mut status = *transfer::StatusStage::default().set_interrupt_on_completion();
+        let mut status = *transfer::StatusStage::default()
+            .set_interrupter_target(interrupter)
+            .set_interrupt_on_completion();
 
         if urb_req.response {
             status.set_direction();
         }
 
         //=====post!=======
-        let mut trbs: Vec<transfer::Allowed> = Vec::new();
-
-        trbs.push(setup.into());
-        if let Some(data) = data {
-            trbs.push(data.into());
-        }
-        trbs.push(status.into());
-
-        let trb_pointers: Vec<usize> = {
-            let mut writer = self.dev_ctx.write().await;
-            trace!("fetch ring at slot{}", slot);
-            let ring = writer
-                .write_transfer_ring(slot, CONTROL_DCI)
-                .expect("initialization on transfer rings got some issue, fixit.");
-            trbs.into_iter()
-                .map(|trb| ring.enque_transfer(trb).into())
-                .collect()
-        };
+        let chained = data.len() > 1;
+        let trbs = 2 + data.len() + chained as usize;
+        let (setup_addr, data_addr, status_addr): (usize, Option<usize>, usize) = self
+            .with_room(slot, CONTROL_DCI as _, None, trbs, |ring| {
+                let endpoint = (slot, CONTROL_DCI as u8);
+                let setup_addr = self.enque_counted(ring, endpoint, setup.into()).into();
+                let data_addr = data
+                    .into_iter()
+                    .map(|trb| self.enque_counted(ring, endpoint, trb).into())
+                    .reduce(|first, _| first);
+                //the stage completes on event data TRB once chained
+                let data_addr = match data_addr {
+                    Some(_) if chained => {
+                        Some(self.enqueue_event_data(ring, endpoint, interrupter))
+                    }
+                    data_addr => data_addr,
+                };
+                let status_addr = self.enque_counted(ring, endpoint, status.into()).into();
+                (setup_addr, data_addr, status_addr)
+            })
+            .await;
 
-        if trb_pointers.len() == 2 {
-            trace!(
+        match data_addr {
+            None => trace!(
                 "[Transfer] >> setup@{:#X}, status@{:#X}",
-                trb_pointers[0],
-                trb_pointers[1]
-            );
-        } else {
-            trace!(
+                setup_addr,
+                status_addr
+            ),
+            Some(data_addr) => trace!(
                 "[Transfer] >> setup@{:#X}, data@{:#X}, status@{:#X}",
-                trb_pointers[0],
-                trb_pointers[1],
-                trb_pointers[2]
-            );
+                setup_addr,
+                data_addr,
+                status_addr
+            ),
         }
 
         fence(Ordering::Release);
         self.ring_db(slot, None, Some(1));
 
-        trb_pointers.last().unwrap().to_owned()
+        (status_addr, data_addr.map(|data_addr| (data_addr, len)))
+    }
+
+    fn wake_all_event_rings(&self) {
+        self.event_wakers.iter().for_each(AtomicWaker::wake);
     }
 
     async fn wake_event_ring(&self) {
         match &self.config.wake_method {
             WakeMethod::Timer(semaphore) => loop {
                 semaphore.acquire().await.forget();
-                unsafe { self.event.get().as_ref_unchecked() }.wake();
+                self.wake_all_event_rings();
             },
             WakeMethod::Yield => loop {
-                unsafe { self.event.get().as_ref_unchecked() }.wake();
+                self.wake_all_event_rings();
                 yield_now().await;
             },
-            WakeMethod::Interrupt(_) => {
-                unsafe { self.event.get().as_ref_unchecked() }.wake();
+            WakeMethod::Interrupt(_) | WakeMethod::Vectored(_) => {
+                self.wake_all_event_rings();
             }
         }
     }
 }
 
+impl<'a, O, const RING_BUFFER_SIZE: usize> RootPorts<O, RING_BUFFER_SIZE>
+    for XHCIController<'a, O, RING_BUFFER_SIZE>
+where
+    'a: 'static,
+    O: PlatformAbstractions + 'a,
+{
+    const TAG: &'static str = TAG;
+
+    fn config(&self) -> &USBSystemConfig<O, RING_BUFFER_SIZE> {
+        &self.config
+    }
+
+    fn attached(&self) -> &Attached<O, RING_BUFFER_SIZE> {
+        &self.attached
+    }
+
+    fn debouncer(&self) -> &Debouncer {
+        &self.debouncer
+    }
+
+    fn waking(&self) -> &SpinCell<BTreeMap<usize, WakeCause>> {
+        &self.waking
+    }
+
+    fn root_route(&self, port_idx: usize) -> TopologyRoute {
+        TopologyRoute::from_port_idx(port_idx).on_controller(self.index)
+    }
+
+    fn connected(&self, port_idx: usize) -> bool {
+        self.read_portsc(port_idx).current_connect_status()
+    }
+
+    ///`connected_at` is when connect status changed, before debouncing
+    async fn probe_port(&self, port_idx: usize, connected_at: Option<Duration>) {
+        //it missed the init-time reset
+        if !self.reset_port_inner(port_idx).await {
+            warn!("{TAG} Port {} not enabled after reset, skip", port_idx);
+            return;
+        }
+        let reset_at = self.config.os.now();
+        self.regs.with(|regs| {
+            regs.port_register_set.update_volatile_at(port_idx, |port| {
+                port.portsc.clear_connect_status_change();
+            })
+        });
+        let device = self.attach_device(port_idx);
+        device.mark_milestone_at(EnumerationMilestone::Connected, connected_at);
+        device.mark_milestone_at(EnumerationMilestone::PortReset, reset_at);
+    }
+
+    ///stop taking requests from device, release its slot and tell everyone it's gone
+    async fn release_device(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        self.attached.stop_taking(&device).await;
+        self.event_bus.pre_drop_device.broadcast(device.clone());
+
+        if let Some(&slot) = device.slot_id.get() {
+            self.purge_slot_jobs(slot).await;
+            self.disable_slot(slot).await;
+        }
+        debug!("{TAG} device at {} released", device.topology_path);
+        self.event_bus.device_removed.broadcast(device);
+    }
+
+    ///refer xhci spec 4.15.2.1
+    async fn finish_wakeup(&self, port_idx: usize, cause: WakeCause) {
+        let Some(slot_id) = self
+            .device_at(&self.root_route(port_idx))
+            .and_then(|dev| dev.slot_id.get().copied())
+        else {
+            //not addressed yet, still bring the link up
+            self.resume_port(port_idx).await;
+            return;
+        };
+        if let RequestResult::Success = self.resume_slot(slot_id).await {
+            self.announce_resumed(slot_id, Some(cause));
+        }
+    }
+}
+
 impl<'a, O, const RING_BUFFER_SIZE: usize> Controller<'a, O, RING_BUFFER_SIZE>
     for XHCIController<'a, O, RING_BUFFER_SIZE>
 where
@@ -1197,6 +4058,7 @@ where
     fn new(
         config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
         event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+        index: u8,
     ) -> Self
     where
         Self: Sized,
@@ -1204,19 +4066,29 @@ where
         let mmio_base = config.base_addr.clone().into();
         unsafe {
             let regs = RegistersBase::new(mmio_base, MemMapper);
-            let ext_list = RegistersExtList::new(
+            let mut ext_list = RegistersExtList::new(
                 mmio_base,
                 regs.capability.hccparams1.read_volatile(),
                 MemMapper,
             );
+            let protocols = ext_list.as_mut().map(protocol::collect).unwrap_or_default();
 
             let hcsp1 = regs.capability.hcsparams1.read_volatile();
             let max_slots = hcsp1.number_of_device_slots();
             let max_ports = hcsp1.number_of_ports();
             let max_irqs = hcsp1.number_of_interrupts();
-            let page_size = regs.operational.pagesize.read_volatile().get();
+            let max_psa_size = regs
+                .capability
+                .hccparams1
+                .read_volatile()
+                .max_primary_stream_array_size();
+            //bit n set means 2^(n+12) bytes, refer xhci spec 5.4.3
+            let page_size = match regs.operational.pagesize.read_volatile().get() {
+                0 => 0,
+                bits => 1usize << (bits.trailing_zeros() + 12),
+            };
             debug!(
-                "{TAG} Max_slots: {}, max_ports: {}, max_irqs: {}, page size: {}",
+                "{TAG} Max_slots: {}, max_ports: {}, max_irqs: {}, page size: {:#x}",
                 max_slots, max_ports, max_irqs, page_size
             );
 
@@ -1227,80 +4099,328 @@ where
             // DMA allocation (which is at least a 4k page).
             let entries_per_page = O::PAGE_SIZE / mem::size_of::<ring::TrbData>();
             trace!("new cmd ring");
-            let cmd = Ring::new(config.os.clone(), entries_per_page, true);
-            trace!("new evt ring");
-            let event = EventRing::new(config.os.clone());
+            //never straddles a controller page, whichever page size is smaller
+            let cmd_align = match page_size {
+                0 => O::PAGE_SIZE,
+                page_size => page_size.min(O::PAGE_SIZE),
+            };
+            let cmd = Ring::new_aligned(config.os.clone(), entries_per_page, true, cmd_align)
+                .tagged(DmaKind::CommandRing, None);
+            //secondary interrupters only make sense with a vector each
+            let interrupters = match &config.wake_method {
+                WakeMethod::Vectored(vectored) => vectored.vectors.min(max_irqs).max(1),
+                _ => 1,
+            };
+            trace!("new evt rings for {} interrupters", interrupters);
+            let events: Vec<_> = (0..interrupters)
+                .map(|_| {
+                    let event = EventRing::new(config.os.clone()).with_erdp_policy(
+                        if config.wake_method.is_interrupt() {
+                            ErdpPolicy::EndOfPass
+                        } else {
+                            ErdpPolicy::Batched
+                        },
+                    );
+                    #[cfg(feature = "observe_raw_event_trb")]
+                    let event =
+                        event.with_observer(config.event_observer.clone(), config.os.clone());
+                    event.into()
+                })
+                .collect();
             debug!("{TAG} ring size {}", cmd.len());
+            let (completion_tx, completion_rx) = {
+                use async_ringbuf::{traits::*, AsyncStaticRb};
+                AsyncStaticRb::<Completion, COMPLETION_QUEUE_DEPTH>::default().split()
+            };
 
             Self {
-                regs: regs.into(),
+                regs: SpinCell::new(regs),
                 ext_list,
+                protocols,
                 config: config.clone(),
                 max_slots,
                 max_ports,
                 max_irqs,
-                scratchpad_buf_arr: OnceCell::new(),
+                max_psa_size,
+                page_size,
+                scratchpad_buf_arr: None.into(),
+                small_buffers: SmallBufferPool::new(config.os.dma_alloc()),
                 cmd: cmd.into(),
-                event: event.into(),
+                event_wakers: events.iter().map(|_| AtomicWaker::new()).collect(),
+                events,
                 dev_ctx: dev_ctx.into(),
-                devices: Vec::new().into(),
+                attached: Attached::default(),
+                bandwidth: Arc::default(),
+                completion_tx: completion_tx.into(),
+                completion_rx: completion_rx.into(),
+                command_jobs: BTreeMap::new().into(),
                 finish_jobs: BTreeMap::new().into(),
-                requests: Vec::new().into(), //safety: only controller itself could fetch, all acccess via run_once
+                data_stages: BTreeMap::new().into(),
                 extra_works: BTreeMap::new().into(),
+                periodic: BTreeMap::new().into(),
+                babble: BTreeMap::new().into(),
+                tt_bandwidth: TtBandwidth::default().into(),
+                suspended: BTreeMap::new().into(),
+                waking: BTreeMap::new().into(),
+                bounded_reserved: AtomicBool::new(false),
+                parking: Parking::default(),
+                debouncer: Debouncer::default(),
                 event_bus,
+                index,
+                event_loop: PolledLoop::new(),
+                scheduler_loop: PolledLoop::new(),
+                stats: StatsCell::default(),
+                #[cfg(feature = "trace_trb_ring")]
+                trb_trace: config.trb_trace.map(TrbTracer::new),
             }
         }
     }
 
-    fn init(&self) {
-        block_on(
-            //safety: no need for reschedule, set() on Oncecell should complete instantly
-            self.chip_hardware_reset()
-                .set_max_device_slots()
-                .set_dcbaap()
-                .set_cmd_ring()
-                .init_ir()
-                .setup_scratchpads(),
-        )
-        .start()
-        .reset_ports()
-        // .test_cmd()
-        .initial_probe();
+    fn init(&self) -> Result<(), InitError> {
+        if self.page_size == 0 {
+            return Err(InitError::NoPageSize);
+        }
+        if self.page_size != O::PAGE_SIZE {
+            info!(
+                "{TAG} controller page size {:#x} differs from platform {:#x}",
+                self.page_size,
+                O::PAGE_SIZE
+            );
+        }
+        //fallible steps go before interrupt handler is registered, nothing to unregister on failure
+        self.chip_hardware_reset()
+            .set_max_device_slots()
+            .set_dcbaap()
+            .set_cmd_ring()
+            .setup_scratchpads()
+            .and_then(Self::reserve_bounded_memory)
+            .inspect_err(|err| self.roll_back_init(err))?
+            .init_ir()
+            .start()
+            .reset_ports()
+            .initial_probe();
+        self.event_loop.start();
+        self.scheduler_loop.start();
+        Ok(())
     }
 
-    fn device_accesses(&self) -> &Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
-        unsafe { self.devices.get().as_ref_unchecked() }
+    fn shutdown(&self) {
+        self.event_loop.stop();
+        self.scheduler_loop.stop();
+        self.halt().release_scratchpads();
+        block_on(self.forget_devices());
+        info!("{TAG} shut down");
     }
 
-    fn workaround(&'a self) -> BoxFuture<'a, ()> {
-        let on_event_loop = async move {
-            loop {
-                self.on_event_arrived().await
-            }
-        };
+    fn device_accesses(&self) -> Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>> {
+        self.attached.all()
+    }
 
-        let run_once_loop = async move {
-            loop {
-                self.run_once().await
+    fn rescan(&self) {
+        let port_len = self.regs.with(|regs| regs.port_register_set.len());
+
+        for port_idx in 0..port_len {
+            let portsc = self.read_portsc(port_idx);
+            if !portsc.current_connect_status() || self.has_device_at_port(port_idx) {
+                continue;
             }
-        };
 
-        if let WakeMethod::Interrupt(_) = &self.config.wake_method {
-            join!(on_event_loop, run_once_loop).map(|_| ()).boxed()
-        } else {
-            let event_ring_waker = self.wake_event_ring();
-            join!(on_event_loop, run_once_loop, event_ring_waker)
+            info!("{TAG} Port {} attached after init, probing", port_idx);
+            block_on(self.probe_port(port_idx, self.config.os.now()));
+        }
+    }
+
+    fn reset_port(&'a self, port_idx: usize) -> BoxFuture<'a, bool> {
+        self.reset_port_inner(port_idx).boxed()
+    }
+
+    fn quiesce(&'a self) -> BoxFuture<'a, ()> {
+        self.quiesce_inner().boxed()
+    }
+
+    fn resume(&'a self) -> BoxFuture<'a, ()> {
+        self.resume_inner().boxed()
+    }
+
+    ///event task only decodes events and queues completions, scheduler task takes requests in
+    ///and handles completions. the queue between them is the only order they agree on
+    fn poll_events(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.event_loop.poll(cx, || {
+            let event_task = join_all((0..self.events.len()).map(|interrupter| async move {
+                loop {
+                    self.on_event_arrived(interrupter).await
+                }
+            }));
+
+            //needs event loop running, so it can't be done in init
+            let self_test = async move {
+                #[cfg(feature = "debug-selftest")]
+                self.self_test_cmd_ring().await;
+            };
+
+            let debounce_loop = self.debounce_loop();
+            let wakeup_loop = self.wakeup_loop();
+
+            if self.config.wake_method.is_interrupt() {
+                join!(event_task, debounce_loop, wakeup_loop, self_test)
+                    .map(|_| ())
+                    .boxed()
+            } else {
+                let event_ring_waker = self.wake_event_ring();
+                join!(
+                    event_task,
+                    event_ring_waker,
+                    debounce_loop,
+                    wakeup_loop,
+                    self_test
+                )
                 .map(|_| ())
                 .boxed()
+            }
+        })
+    }
+
+    ///a request may wait on a command, so completions are handled beside, not after, requests
+    fn poll_scheduler(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.scheduler_loop.poll(cx, || {
+            let request_loop = async move {
+                loop {
+                    self.run_once().await
+                }
+            };
+            let completion_loop = async move {
+                loop {
+                    self.on_completion().await
+                }
+            };
+
+            join!(
+                request_loop,
+                completion_loop,
+                self.timeout_loop(),
+                self.watchdog_loop()
+            )
+            .map(|_| ())
+            .boxed()
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let caps = Capabilities::BACKEND_XHCI
+            | Capabilities::LINK_POWER_MANAGEMENT
+            | Capabilities::REMOTE_WAKEUP
+            | Capabilities::HOT_PLUG
+            | Capabilities::HUBS;
+        if self.max_psa_size > 0 {
+            caps | Capabilities::BULK_STREAMS
+        } else {
+            caps
+        }
+    }
+
+    fn outstanding(&self) -> Outstanding {
+        Outstanding {
+            queued: self.attached.queued(),
+            parked: self.parking.parked(),
+            transfers: block_on(self.finish_jobs.read()).len()
+                + self.extra_works.with(|extra_works| extra_works.len()),
+            commands: block_on(self.command_jobs.read()).len(),
+            completions: block_on(self.completion_rx.lock()).occupied_len(),
+            kept: self.periodic.with(|periodic| periodic.len()),
         }
     }
+
+    fn stats(&self) -> Statistics {
+        self.stats.snapshot()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    #[cfg(feature = "trace_trb_ring")]
+    fn trb_trace(&self) -> TrbTrace {
+        self.trb_trace
+            .as_ref()
+            .map(TrbTracer::dump)
+            .unwrap_or_default()
+    }
+}
+
+///normal TRB buffer must not cross 64K boundary, refer xhci spec 6.4.1
+fn split_trb_buffers(addr: usize, len: usize) -> Vec<(usize, usize)> {
+    const TRB_BUFFER_BOUNDARY: usize = 0x10000;
+    let mut chunks = Vec::new();
+    let mut cur = addr;
+    let end = addr + len;
+    while cur < end {
+        let next = ((cur / TRB_BUFFER_BOUNDARY) + 1) * TRB_BUFFER_BOUNDARY;
+        let chunk_end = next.min(end);
+        chunks.push((cur, chunk_end - cur));
+        cur = chunk_end;
+    }
+    if chunks.is_empty() {
+        //zero length packet
+        chunks.push((addr, 0));
+    }
+    chunks
+}
+
+///slot whose output context a command updates, and whether the command reads its input context
+fn context_slot(trb: &command::Allowed) -> Option<(u8, bool)> {
+    match trb {
+        command::Allowed::AddressDevice(c) => Some((c.slot_id(), true)),
+        command::Allowed::ConfigureEndpoint(c) => Some((c.slot_id(), true)),
+        command::Allowed::EvaluateContext(c) => Some((c.slot_id(), true)),
+        command::Allowed::ResetEndpoint(c) => Some((c.slot_id(), false)),
+        command::Allowed::StopEndpoint(c) => Some((c.slot_id(), false)),
+        command::Allowed::SetTrDequeuePointer(c) => Some((c.slot_id(), false)),
+        command::Allowed::ResetDevice(c) => Some((c.slot_id(), false)),
+        _ => None,
+    }
+}
+
+///odd DCIs are IN endpoints, DCI 1 is the bidirectional control one
+fn dci_is_in(dci: u8) -> bool {
+    dci > 1 && dci % 2 == 1
+}
+
+fn inbound_segments(dci: u8, segments: &[(usize, usize)]) -> &[(usize, usize)] {
+    if dci_is_in(dci) {
+        segments
+    } else {
+        &[]
+    }
 }
 
-fn parse_default_max_packet_size_from_speed(port_speed: u8) -> u16 {
-    match port_speed {
-        1 | 3 => 64,
-        2 => 8,
-        4 => 512,
-        v => unimplemented!("PSI: {}", v),
+fn scatter_trb_buffers(segments: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    match segments {
+        [(addr, len)] => split_trb_buffers(*addr, *len),
+        segments => segments
+            .iter()
+            .filter(|(_, len)| *len > 0)
+            .flat_map(|(addr, len)| split_trb_buffers(*addr, *len))
+            .collect(),
     }
 }
+
+///refer USB2 LPM ECN table X-X1, besl to microseconds
+fn besl_to_us(besl: u8) -> u16 {
+    const TABLE: [u16; 16] = [
+        125, 150, 200, 300, 400, 500, 1000, 2000, 3000, 4000, 5000, 6000, 7000, 8000, 9000, 10000,
+    ];
+    TABLE[(besl & 0xf) as usize]
+}
+
+///command completion, failure turned into error instead of asserted
+fn command_outcome(completion: Result<CommandCompletion, UsbError>) -> Result<(), UsbError> {
+    UsbError::check(completion?.completion_code().map(Into::into)).map(|_| ())
+}
+
+///Invalid if command got dropped before completion or its code is unknown
+fn command_code(completion: Result<CommandCompletion, UsbError>) -> RequestResult {
+    completion
+        .ok()
+        .and_then(|completion| completion.completion_code().ok())
+        .map_or(RequestResult::Invalid, Into::into)
+}
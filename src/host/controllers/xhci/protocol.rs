@@ -0,0 +1,91 @@
+use core::ops::RangeInclusive;
+
+use alloc::vec::Vec;
+use log::debug;
+use xhci::extended_capabilities::ExtendedCapability;
+
+use crate::usb::operations::hub::DeviceSpeed;
+
+use super::{RegistersExtList, SupportedProtocol, TAG};
+
+///refer xhci spec 7.2.2.1.1, PSIE is a power of 1000 on bits per second
+const PSI_BASE: u64 = 1000;
+
+///one supported protocol capability, refer xhci spec 7.2
+pub struct PortProtocol {
+    major: u8,
+    ///1 based port numbers it covers
+    ports: RangeInclusive<u8>,
+    ///(PSIV, bits per second), empty if protocol sticks to the default speed ids
+    speeds: Vec<(u8, u64)>,
+}
+
+impl PortProtocol {
+    fn read(protocol: &SupportedProtocol) -> Self {
+        let header = protocol.header.read_volatile();
+        let first = header.compatible_port_offset();
+        let count = header.compatible_port_count();
+        let speeds = protocol
+            .psis
+            .as_ref()
+            .map(|psis| {
+                (0..psis.len())
+                    .map(|i| psis.read_volatile_at(i))
+                    .map(|psi| {
+                        let rate = psi.protocol_speed_id_mantissa() as u64
+                            * PSI_BASE.pow(psi.protocol_speed_id_exponent() as u32);
+                        (psi.protocol_speed_id_value(), rate)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            major: header.major_revision(),
+            ports: first..=first.saturating_add(count).saturating_sub(1),
+            speeds,
+        }
+    }
+
+    pub fn is_usb3(&self) -> bool {
+        self.major >= 3
+    }
+
+    pub fn covers(&self, port_idx: usize) -> bool {
+        self.ports.contains(&(port_idx as u8 + 1))
+    }
+
+    ///decode PORTSC speed id, refer xhci spec 7.2.2.1.1
+    pub fn speed(&self, speed_id: u8) -> DeviceSpeed {
+        match self.speeds.iter().find(|(psiv, _)| *psiv == speed_id) {
+            Some((_, rate)) => match rate {
+                0..=1_500_000 => DeviceSpeed::Low,
+                1_500_001..=12_000_000 => DeviceSpeed::Full,
+                12_000_001..=480_000_000 => DeviceSpeed::High,
+                _ => DeviceSpeed::Super,
+            },
+            //default ids still tell usb2 speeds apart, anything on a usb3 port is superspeed
+            None if self.is_usb3() => DeviceSpeed::Super,
+            None => DeviceSpeed::from_xhci_speed_id(speed_id),
+        }
+    }
+}
+
+///every supported protocol capability of controller, empty if it lists none
+pub fn collect(list: &mut RegistersExtList) -> Vec<PortProtocol> {
+    list.into_iter()
+        .filter_map(|capability| match capability {
+            Ok(ExtendedCapability::XhciSupportedProtocol(protocol)) => {
+                Some(PortProtocol::read(&protocol))
+            }
+            _ => None,
+        })
+        .inspect(|protocol| {
+            debug!(
+                "{TAG} usb {} on ports {:?}, {} speed ids",
+                protocol.major,
+                protocol.ports,
+                protocol.speeds.len()
+            )
+        })
+        .collect()
+}
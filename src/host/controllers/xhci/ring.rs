@@ -1,36 +1,308 @@
-use alloc::vec::Vec;
-use log::trace;
+use core::alloc::AllocError;
+use core::ops::Range;
+
+use alloc::{vec, vec::Vec};
+use log::{debug, trace, warn};
 use xhci::ring::trb::{command, transfer, Link};
 
-use crate::abstractions::{dma::DMA, PlatformAbstractions};
+use crate::{
+    abstractions::{dma::DMA, dma_tracker::DmaKind, PlatformAbstractions},
+    host::controllers::TrbKind,
+};
 
 const TRB_LEN: usize = 4;
 pub type TrbData = [u32; TRB_LEN];
 
+///by TRB type field, refer xhci spec 6.4.6. None for event TRBs and reserved types
+pub fn trb_kind(trb: &TrbData) -> Option<TrbKind> {
+    Some(match (trb[3] >> 10) & 0x3f {
+        1 => TrbKind::Normal,
+        2 => TrbKind::Setup,
+        3 => TrbKind::Data,
+        4 => TrbKind::Status,
+        5 => TrbKind::Isoch,
+        6 => TrbKind::Link,
+        7 => TrbKind::EventData,
+        8 => TrbKind::NoOp,
+        9..=23 => TrbKind::Command,
+        _ => return None,
+    })
+}
+
+pub fn transfer_kind(trb: &transfer::Allowed) -> TrbKind {
+    match trb {
+        transfer::Allowed::Normal(_) => TrbKind::Normal,
+        transfer::Allowed::SetupStage(_) => TrbKind::Setup,
+        transfer::Allowed::DataStage(_) => TrbKind::Data,
+        transfer::Allowed::StatusStage(_) => TrbKind::Status,
+        transfer::Allowed::Isoch(_) => TrbKind::Isoch,
+        transfer::Allowed::Link(_) => TrbKind::Link,
+        transfer::Allowed::EventData(_) => TrbKind::EventData,
+        transfer::Allowed::Noop(_) => TrbKind::NoOp,
+    }
+}
+
+///physical address ranges of every segment of a ring, completion keys of the ring lie in them
+#[derive(Clone, Debug)]
+pub struct RingSpan(pub Vec<Range<usize>>);
+
+impl RingSpan {
+    pub fn contains(&self, addr: &usize) -> bool {
+        self.0.iter().any(|range| range.contains(addr))
+    }
+}
+
 pub struct Ring<O: PlatformAbstractions> {
+    os: O,
     link: bool,
-    pub trbs: DMA<[TrbData], O>,
+    align: usize,
+    tag: Option<(DmaKind, Option<u8>)>,
+    ///ring starts at first one, with `link` each ends in a Link TRB to the next
+    segs: Vec<DMA<[TrbData], O>>,
+    ///segment `i` points into
+    pub seg: usize,
     pub i: usize,
     pub cycle: bool,
+    ///(segment, index) of first TRB controller has not finished, see [`Self::consumed`]
+    deque: (usize, usize),
+    ///times TDs of this ring got skipped, see [`Self::skipped`]
+    epoch: u32,
+    ///epoch each TRB was enqueued in, along `segs`
+    enqueued_in: Vec<Vec<u32>>,
 }
 
 impl<O: PlatformAbstractions> Ring<O> {
     pub fn new(os: O, len: usize, link: bool) -> Self {
+        Self::new_aligned(os, len, link, 64)
+    }
+
+    ///`align` above 64 keeps ring from crossing controller page boundaries
+    pub fn new_aligned(os: O, len: usize, link: bool, align: usize) -> Self {
+        Self::try_new_aligned(os, len, link, align).unwrap()
+    }
+
+    pub fn try_new(os: O, len: usize, link: bool) -> Result<Self, AllocError> {
+        Self::try_new_aligned(os, len, link, 64)
+    }
+
+    pub fn try_new_aligned(
+        os: O,
+        len: usize,
+        link: bool,
+        align: usize,
+    ) -> Result<Self, AllocError> {
         let a = os.dma_alloc();
-        let trbs = DMA::new_vec([0; TRB_LEN], len, 64, a);
-        Self {
-            trbs,
+        let trbs = DMA::try_new_vec([0; TRB_LEN], len, align, a)?;
+        trbs.sync_for_device(&os);
+        Ok(Self {
+            os,
+            segs: vec![trbs],
+            seg: 0,
             i: 0,
             cycle: link,
             link,
-        }
+            align,
+            tag: None,
+            deque: (0, 0),
+            epoch: 0,
+            enqueued_in: vec![vec![0; len]],
+        })
+    }
+
+    ///back to the state of a new ring, keeping memory of its first segment
+    pub fn clear(&mut self) {
+        self.segs.truncate(1);
+        self.enqueued_in.truncate(1);
+        self.segs[0].iter_mut().for_each(|trb| *trb = [0; TRB_LEN]);
+        self.segs[0].sync_for_device(&self.os);
+        self.seg = 0;
+        self.i = 0;
+        self.deque = (0, 0);
+        self.cycle = self.link;
+    }
+
+    pub fn tagged(mut self, kind: DmaKind, owner: Option<u8>) -> Self {
+        self.segs = self
+            .segs
+            .into_iter()
+            .map(|seg| seg.tagged(kind, owner))
+            .collect();
+        self.tag = Some((kind, owner));
+        self
     }
+
+    ///TRBs of all segments, Link TRBs included
     pub fn len(&self) -> usize {
-        self.trbs.len()
+        self.segs.iter().map(|seg| seg.len()).sum()
+    }
+
+    pub fn segments(&self) -> usize {
+        self.segs.len()
     }
 
     fn get_trb(&self) -> &TrbData {
-        &self.trbs[self.i]
+        &self.segs[self.seg][self.i]
+    }
+
+    ///controller reads TRBs from memory, CPU caches may still hold them
+    fn sync_trb_for_device(&self, idx: usize) {
+        if !self.os.dma_coherent() {
+            let addr = self.segs[self.seg][idx].as_ptr() as usize;
+            self.os.dma_clean(addr.into(), size_of::<TrbData>());
+        }
+    }
+
+    fn phys_base(seg: &DMA<[TrbData], O>) -> usize {
+        O::PhysAddr::from(O::VirtAddr::from(seg.as_ptr() as usize)).into()
+    }
+
+    pub fn span(&self) -> RingSpan {
+        RingSpan(
+            self.segs
+                .iter()
+                .map(|seg| {
+                    let start = Self::phys_base(seg);
+                    start..start + seg.len() * size_of::<TrbData>()
+                })
+                .collect(),
+        )
+    }
+
+    ///TRBs a TD may take without overwriting unfinished ones.
+    ///one is always left out, so full ring never looks empty
+    pub fn free(&self) -> usize {
+        let capacity = self.capacity();
+        let in_flight =
+            (self.position((self.seg, self.i)) + capacity - self.position(self.deque)) % capacity;
+        capacity - 1 - in_flight
+    }
+
+    ///TRBs controller has not finished yet
+    pub fn in_flight(&self) -> usize {
+        self.usable() - self.free()
+    }
+
+    ///TRBs that could be in flight at once, see [`Self::free`]
+    pub fn usable(&self) -> usize {
+        self.capacity() - 1
+    }
+
+    fn capacity(&self) -> usize {
+        self.position((self.segs.len(), 0))
+    }
+
+    ///count of non-link TRBs before (segment, index)
+    fn position(&self, (seg, i): (usize, usize)) -> usize {
+        let link = self.link as usize;
+        self.segs[..seg]
+            .iter()
+            .map(|seg| seg.len() - link)
+            .sum::<usize>()
+            + i
+    }
+
+    ///(segment, index) of TRB at physical `addr`
+    fn locate(&self, addr: usize) -> Option<(usize, usize)> {
+        self.segs.iter().enumerate().find_map(|(n, trbs)| {
+            let offset = addr.checked_sub(Self::phys_base(trbs))?;
+            let idx = offset / size_of::<TrbData>();
+            (idx < trbs.len()).then_some((n, idx))
+        })
+    }
+
+    ///TRB at physical `addr` as software wrote it, None outside of this ring
+    pub fn trb_at(&self, addr: usize) -> Option<TrbData> {
+        self.locate(addr).map(|(seg, idx)| self.segs[seg][idx])
+    }
+
+    ///controller finished TRB at `addr` and everything before it.
+    ///addresses outside of this ring are ignored
+    pub fn consumed(&mut self, addr: usize) {
+        let Some((seg, idx)) = self.locate(addr) else {
+            return;
+        };
+        self.deque = if self.link && idx + 2 >= self.segs[seg].len() {
+            ((seg + 1) % self.segs.len(), 0)
+        } else {
+            (seg, idx + 1)
+        };
+    }
+
+    ///controller got moved to enqueue pointer, e.g. by Set TR Dequeue Pointer
+    pub fn drained(&mut self) {
+        self.deque = (self.seg, self.i);
+    }
+
+    ///TDs enqueued so far got skipped, events still reporting their TRBs are stale
+    pub fn skipped(&mut self) {
+        self.epoch += 1;
+    }
+
+    ///TRB at `addr` belongs to a TD skipped since it was enqueued, see [`Self::skipped`].
+    ///false outside of this ring
+    pub fn is_stale(&self, addr: usize) -> bool {
+        self.locate(addr)
+            .is_some_and(|(seg, idx)| self.enqueued_in[seg][idx] < self.epoch)
+    }
+
+    ///true once `n` TRBs fit. adds segments while fewer than `max_segments`,
+    ///or regardless of it if the ring could never hold `n`.
+    ///false means caller has to wait for controller to finish some TRBs
+    pub fn make_room(&mut self, n: usize, max_segments: usize) -> bool {
+        while self.free() < n {
+            let never_fits = n >= self.capacity();
+            if !(never_fits || self.segs.len() < max_segments) || !self.can_grow() {
+                return false;
+            }
+            if let Err(err) = self.grow() {
+                warn!("transfer ring failed to grow: {:?}", err);
+                return false;
+            }
+        }
+        true
+    }
+
+    ///new segment goes right after enqueue one, controller must not be on its way
+    ///through that segment's Link TRB, i.e. dequeue lies ahead in the same segment
+    fn can_grow(&self) -> bool {
+        self.link && !(self.deque.0 == self.seg && self.deque.1 > self.i)
+    }
+
+    fn grow(&mut self) -> Result<(), AllocError> {
+        let len = self.segs[0].len();
+        let mut seg = DMA::try_new_vec([0; TRB_LEN], len, self.align, self.os.dma_alloc())?;
+        if let Some((kind, owner)) = self.tag {
+            seg = seg.tagged(kind, owner);
+        }
+        //enqueue enters it with current cycle, until then controller must not own any of it
+        let mut norm = transfer::Normal::default();
+        if !self.cycle {
+            norm.set_cycle_bit();
+        }
+        seg.iter_mut()
+            .for_each(|trb| trb.copy_from_slice(&norm.into_raw()));
+        seg.sync_for_device(&self.os);
+
+        //link TRB of enqueue segment is written once enqueue passes it, so it picks the new one
+        self.segs.insert(self.seg + 1, seg);
+        self.enqueued_in.insert(self.seg + 1, vec![self.epoch; len]);
+        if self.deque.0 > self.seg {
+            self.deque.0 += 1;
+        }
+        debug!(
+            "transfer ring grew to {} segments of {} TRBs",
+            self.segs.len(),
+            len
+        );
+        Ok(())
+    }
+
+    ///controller wrote the current TRB, e.g. of event ring, drop what CPU cached of it
+    pub fn sync_current_for_cpu(&self) {
+        if !self.os.dma_coherent() {
+            self.os
+                .dma_invalidate(self.register(), size_of::<TrbData>());
+        }
     }
 
     pub fn register(&self) -> O::VirtAddr {
@@ -59,8 +331,10 @@ impl<O: PlatformAbstractions> Ring<O> {
     }
 
     fn enque_trb(&mut self, trb: TrbData) -> O::VirtAddr {
-        self.trbs[self.i].copy_from_slice(&trb);
-        let addr = self.trbs[self.i].as_ptr() as usize;
+        self.segs[self.seg][self.i].copy_from_slice(&trb);
+        self.enqueued_in[self.seg][self.i] = self.epoch;
+        self.sync_trb_for_device(self.i);
+        let addr = self.segs[self.seg][self.i].as_ptr() as usize;
 
         #[cfg(feature = "trace_xhci_enque_trb")]
         trace!(
@@ -74,7 +348,9 @@ impl<O: PlatformAbstractions> Ring<O> {
 
     pub fn enque_trbs_no_check(&mut self, trb: Vec<TrbData>) {
         for ele in trb {
-            self.trbs[self.i].copy_from_slice(&ele);
+            self.segs[self.seg][self.i].copy_from_slice(&ele);
+            self.enqueued_in[self.seg][self.i] = self.epoch;
+            self.sync_trb_for_device(self.i);
 
             self.next_index();
         }
@@ -82,29 +358,24 @@ impl<O: PlatformAbstractions> Ring<O> {
 
     fn next_index(&mut self) -> usize {
         self.i += 1;
-        let mut need_link = false;
-        let len = self.len();
+        let len = self.segs[self.seg].len();
 
-        // link模式下，最后一个是Link
+        // link模式下，每段最后一个是Link, 只有最后一段的Link翻转cycle
         if self.link && self.i >= len - 1 {
-            self.i = 0;
-            need_link = true;
-        } else if self.i >= len {
-            self.i = 0;
-        }
-
-        if need_link {
-            {
+            let last = self.seg + 1 == self.segs.len();
+            let next = if last { 0 } else { self.seg + 1 };
+            if last {
                 trace!(
                     "link! current cycle: {}, after link:{}",
                     self.cycle,
                     !self.cycle
                 );
             }
-            let address = O::VirtAddr::from(self.trbs[0].as_ptr() as usize);
             let mut link = Link::new();
-            link.set_ring_segment_pointer(O::PhysAddr::from(address).into() as u64)
-                .set_toggle_cycle();
+            link.set_ring_segment_pointer(Self::phys_base(&self.segs[next]) as u64);
+            if last {
+                link.set_toggle_cycle();
+            }
 
             if self.cycle {
                 link.set_cycle_bit();
@@ -113,10 +384,17 @@ impl<O: PlatformAbstractions> Ring<O> {
             }
             let trb = command::Allowed::Link(link);
             let link_trb = trb.into_raw();
-            let this_trb = &mut self.trbs[len - 1];
+            let this_trb = &mut self.segs[self.seg][len - 1];
             this_trb.copy_from_slice(&link_trb);
+            self.sync_trb_for_device(len - 1);
 
-            self.cycle = !self.cycle;
+            self.seg = next;
+            self.i = 0;
+            if last {
+                self.cycle = !self.cycle;
+            }
+        } else if self.i >= len {
+            self.i = 0;
         }
 
         self.i
@@ -138,10 +416,10 @@ impl<O: PlatformAbstractions> Ring<O> {
     }
 
     pub fn current_data(&self) -> (&TrbData, bool) {
-        (&self.trbs[self.i], self.cycle)
+        (self.get_trb(), self.cycle)
     }
 
     pub fn get_len(&self) -> usize {
-        self.trbs.len()
+        self.len()
     }
 }
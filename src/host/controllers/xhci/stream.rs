@@ -0,0 +1,229 @@
+use core::num::NonZeroU16;
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use async_lock::Mutex;
+use log::{debug, trace};
+use tock_registers::interfaces::Writeable;
+use tock_registers::register_structs;
+use tock_registers::registers::ReadWrite;
+
+use crate::abstractions::{dma::DMA, dma_tracker::DmaKind, PlatformAbstractions};
+
+use super::ring::Ring;
+
+register_structs! {
+    pub StreamContextEntry{
+        (0x000 => dequeue_low: ReadWrite<u32>),
+        (0x004 => dequeue_high: ReadWrite<u32>),
+        (0x008 => stopped_edtla: ReadWrite<u32>),
+        (0x00C => _reserved),
+        (0x010 => @END),
+    }
+}
+
+///refer xhci spec 6.2.4.1, stream context type
+const SCT_SECONDARY_TRANSFER_RING: u8 = 0;
+const SCT_PRIMARY_TRANSFER_RING: u8 = 1;
+///secondary stream array sizes are 8..=256 entries, aka 2^(SCT+1)
+const MIN_SECONDARY_BITS: u8 = 3;
+const MAX_SECONDARY_BITS: u8 = 8;
+
+impl StreamContextEntry {
+    pub fn set(&mut self, addr: u64, sct: u8, dcs: bool) {
+        let low = (addr as u32 & !0xf) | ((sct as u32 & 0x7) << 1) | dcs as u32;
+        self.dequeue_low.set(low);
+        self.dequeue_high.set((addr >> 32) as u32);
+        self.stopped_edtla.set(0);
+    }
+}
+
+/// A stream id that is known to be valid for the endpoint it was opened on.
+///
+/// stream 0 is reserved by spec, so a handle is never zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamHandle(NonZeroU16);
+
+impl StreamHandle {
+    pub fn id(&self) -> u16 {
+        self.0.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamArrayLayout {
+    Linear,
+    Secondary { secondary_bits: u8 },
+}
+
+pub struct StreamContextArray<O>
+where
+    O: PlatformAbstractions,
+{
+    os: O,
+    ///stream ids device takes, 1..=streams
+    streams: u16,
+    primary_bits: u8,
+    layout: StreamArrayLayout,
+    primary: DMA<[StreamContextEntry], O>,
+    secondaries: BTreeMap<u16, DMA<[StreamContextEntry], O>>,
+    ///locked one by one like endpoint rings, see [`super::context::DeviceCtxInner`]
+    rings: BTreeMap<StreamHandle, Mutex<Ring<O>>>,
+}
+
+unsafe impl<O: PlatformAbstractions> Sync for StreamContextArray<O> {}
+
+impl<O> StreamContextArray<O>
+where
+    O: PlatformAbstractions,
+{
+    /// `requested` is the stream count the endpoint want to use,
+    /// `max_psa_size` is HCCPARAMS1.MaxPSASize.
+    ///
+    /// returns None if controller does not support streams, or DMA memory ran out.
+    pub fn new(os: O, requested: u16, max_psa_size: u8) -> Option<Self> {
+        if max_psa_size == 0 || requested == 0 {
+            return None;
+        }
+
+        //entry 0 is reserved, so we need one more
+        let wanted_bits = bits_for(requested as u32 + 1).max(1);
+        let hw_primary_bits = (max_psa_size + 1).min(16);
+
+        let (primary_bits, layout) = if wanted_bits <= hw_primary_bits {
+            (wanted_bits, StreamArrayLayout::Linear)
+        } else {
+            let per_primary = (requested as u32).div_ceil((1u32 << hw_primary_bits) - 1);
+            let secondary_bits =
+                bits_for(per_primary + 1).clamp(MIN_SECONDARY_BITS, MAX_SECONDARY_BITS);
+            (
+                hw_primary_bits,
+                StreamArrayLayout::Secondary { secondary_bits },
+            )
+        };
+
+        debug!(
+            "stream array for {} streams: primary bits {}, layout {:?}",
+            requested, primary_bits, layout
+        );
+
+        Some(Self {
+            primary: DMA::try_zeroed(1 << primary_bits, 64, os.dma_alloc())
+                .ok()?
+                .tagged(DmaKind::StreamArray, None),
+            os,
+            streams: requested,
+            primary_bits,
+            layout,
+            secondaries: BTreeMap::new(),
+            rings: BTreeMap::new(),
+        })
+    }
+
+    ///value for MaxPStreams field of endpoint context
+    pub fn max_pstreams(&self) -> u8 {
+        self.primary_bits - 1
+    }
+
+    ///value for LSA field of endpoint context
+    pub fn is_linear(&self) -> bool {
+        matches!(self.layout, StreamArrayLayout::Linear)
+    }
+
+    ///value for TR Dequeue Pointer field of endpoint context
+    pub fn register(&self) -> O::VirtAddr {
+        self.primary.addr()
+    }
+
+    fn primary_mask(&self) -> u16 {
+        ((1u32 << self.primary_bits) - 1) as u16
+    }
+
+    /// check a raw stream id against this array, the only way to turn raw id into handle.
+    pub fn validate(&self, id: u16) -> Option<StreamHandle> {
+        let primary = id & self.primary_mask();
+        let secondary = (id as u32) >> self.primary_bits;
+        let valid = primary != 0
+            && id <= self.streams
+            && match self.layout {
+                StreamArrayLayout::Linear => secondary == 0,
+                StreamArrayLayout::Secondary { secondary_bits } => {
+                    secondary != 0 && secondary < (1 << secondary_bits)
+                }
+            };
+        if valid {
+            NonZeroU16::new(id).map(StreamHandle)
+        } else {
+            None
+        }
+    }
+
+    /// validate `id` and give it a transfer ring on first use, written into stream context.
+    ///
+    /// returns None for ids out of this array, or if `new_ring` could not allocate.
+    pub fn open(
+        &mut self,
+        id: u16,
+        new_ring: impl FnOnce() -> Option<Ring<O>>,
+    ) -> Option<StreamHandle> {
+        let handle = self.validate(id)?;
+        if self.rings.contains_key(&handle) {
+            return Some(handle);
+        }
+        let ring = new_ring()?;
+        let ring_addr = O::PhysAddr::from(ring.register()).into() as u64;
+        let primary_idx = (handle.id() & self.primary_mask()) as usize;
+
+        match self.layout {
+            StreamArrayLayout::Linear => {
+                self.primary[primary_idx].set(ring_addr, SCT_PRIMARY_TRANSFER_RING, ring.cycle);
+            }
+            StreamArrayLayout::Secondary { secondary_bits } => {
+                let secondary_idx = (handle.id() >> self.primary_bits) as usize;
+                let secondary = match self.secondaries.entry(primary_idx as u16) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(
+                        DMA::try_zeroed(1 << secondary_bits, 64, self.os.dma_alloc())
+                            .ok()?
+                            .tagged(DmaKind::StreamArray, None),
+                    ),
+                };
+                secondary[secondary_idx].set(ring_addr, SCT_SECONDARY_TRANSFER_RING, ring.cycle);
+                let secondary_addr = O::PhysAddr::from(secondary.addr()).into() as u64;
+                //SCT 2..=7 means secondary array with 2^(SCT+1) entries
+                self.primary[primary_idx].set(secondary_addr, secondary_bits - 1, false);
+                secondary.sync_for_device(&self.os);
+            }
+        }
+        self.primary.sync_for_device(&self.os);
+
+        trace!("opened stream {}", handle.id());
+        self.rings.insert(handle, Mutex::new(ring));
+        Some(handle)
+    }
+
+    pub fn ring(&self, handle: &StreamHandle) -> Option<&Mutex<Ring<O>>> {
+        self.rings.get(handle)
+    }
+
+    pub fn ring_mut(&mut self, handle: &StreamHandle) -> Option<&mut Ring<O>> {
+        self.rings.get_mut(handle).map(Mutex::get_mut)
+    }
+
+    ///streams opened so far, each with its ring
+    pub fn opened(&self) -> impl Iterator<Item = (&StreamHandle, &Mutex<Ring<O>>)> {
+        self.rings.iter()
+    }
+
+    ///SCT of stream rings, which Set TR Dequeue Pointer has to name
+    pub fn ring_context_type(&self) -> u8 {
+        match self.layout {
+            StreamArrayLayout::Linear => SCT_PRIMARY_TRANSFER_RING,
+            StreamArrayLayout::Secondary { .. } => SCT_SECONDARY_TRANSFER_RING,
+        }
+    }
+}
+
+///smallest n that 2^n >= v
+fn bits_for(v: u32) -> u8 {
+    (u32::BITS - (v.max(1) - 1).leading_zeros()) as u8
+}
@@ -0,0 +1,175 @@
+use core::sync::atomic::Ordering;
+
+use alloc::{sync::Arc, vec::Vec};
+use log::{info, warn};
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::Endpoint,
+    desc_interface::{TopologyUSBFunction, USBInterface},
+};
+
+use crate::{
+    abstractions::PlatformAbstractions,
+    usb::{
+        operations::{
+            control::{bRequest, bRequestStandard, ControlTransfer, Recipient},
+            Direction, RequestedOperation, USBRequest, UsbError,
+        },
+        power::ConfigPower,
+        snapshot::EndpointKind,
+    },
+};
+
+use super::{DeviceState, USBDevice};
+
+///one configuration device offers, see [`USBDevice::configurations`]
+#[derive(Debug, Clone)]
+pub struct ConfigurationInfo {
+    pub value: u8,
+    ///every alternate setting of every interface
+    pub interfaces: Vec<Arc<USBInterface>>,
+    ///None if its descriptor was too short to tell
+    pub power: Option<ConfigPower>,
+}
+
+impl ConfigurationInfo {
+    pub fn has_interface_class(&self, class: u8) -> bool {
+        self.interfaces
+            .iter()
+            .any(|interface| interface.interface.interface_class == class)
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///bConfigurationValue drivers bind against, 1 until another one is set
+    pub fn current_config(&self) -> u8 {
+        self.current_config.load(Ordering::Acquire)
+    }
+
+    ///configurations parsed while assigning, in descriptor order. empty before that
+    pub async fn configurations(&self) -> Vec<ConfigurationInfo> {
+        let Some(descriptor) = self.descriptor.get() else {
+            return Vec::new();
+        };
+        let powers = self.config_power.read().await;
+        descriptor
+            .configs
+            .iter()
+            .map(|config| {
+                let value = config.desc.config_val();
+                ConfigurationInfo {
+                    value,
+                    interfaces: self.interfaces(value),
+                    power: powers
+                        .iter()
+                        .find(|power| power.config_value == value)
+                        .copied(),
+                }
+            })
+            .collect()
+    }
+
+    ///every alternate setting of every interface of configuration `config`, in descriptor order.
+    ///empty before assigning or if device has no such configuration
+    pub fn interfaces(&self, config: u8) -> Vec<Arc<USBInterface>> {
+        self.descriptor
+            .get()
+            .and_then(|descriptor| {
+                descriptor
+                    .configs
+                    .iter()
+                    .find(|candidate| candidate.desc.config_val() == config)
+            })
+            .map(|config| {
+                config
+                    .functions
+                    .iter()
+                    .filter_map(|function| match function.as_ref() {
+                        TopologyUSBFunction::Interface(interfaces) => Some(interfaces.clone()),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    ///first alternate setting of current configuration with these class codes
+    pub fn find_interface(
+        &self,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+    ) -> Option<Arc<USBInterface>> {
+        self.interfaces(self.current_config())
+            .into_iter()
+            .find(|interface| {
+                let desc = &interface.interface;
+                desc.interface_class == class
+                    && desc.interface_subclass == subclass
+                    && desc.interface_protocol == protocol
+            })
+    }
+
+    ///first endpoint of `interface` of that kind and direction
+    pub fn endpoint(
+        &self,
+        interface: &USBInterface,
+        kind: EndpointKind,
+        direction: Direction,
+    ) -> Option<Arc<Endpoint>> {
+        interface
+            .endpoints
+            .iter()
+            .find(|endpoint| EndpointKind::of(endpoint.endpoint_type()) == (kind, direction))
+            .cloned()
+    }
+
+    ///switch device to configuration `value`.
+    ///
+    ///endpoints of current configuration are dropped, so drivers bound to it must be gone already.
+    ///interfaces of new one are enabled by drivers as usual
+    pub async fn set_configuration(&self, value: u8) -> Result<(), UsbError> {
+        if !self
+            .configurations()
+            .await
+            .iter()
+            .any(|config| config.value == value)
+        {
+            return Err(UsbError::UnknownConfiguration(value));
+        }
+
+        let (sem, _) = self.acquire_configure().await;
+        self.post_usb_request(
+            USBRequest::once(RequestedOperation::Deconfigure).release_on_complete(sem),
+        )
+        .await;
+        if let (_, Some(error)) = self.acquire_configure().await {
+            warn!("deconfiguring {} failed: {}", self.topology_path, error);
+            return Err(error);
+        }
+        self.take_functions(None).await;
+        *self.state.write().await = DeviceState::Assigned;
+        self.bandwidth.release_device(&self.topology_path);
+
+        UsbError::check(
+            self.request_once(RequestedOperation::Control(ControlTransfer::new(
+                Direction::Out,
+                Recipient::Device,
+                bRequest::Standard(bRequestStandard::SetConfiguration),
+                value as _,
+                0,
+                None,
+            )))
+            .await,
+        )?;
+        self.current_config.store(value, Ordering::Release);
+        info!(
+            "device at {} switched to configuration {}",
+            self.topology_path, value
+        );
+        Ok(())
+    }
+}
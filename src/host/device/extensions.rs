@@ -0,0 +1,83 @@
+use core::any::{Any, TypeId};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap};
+use async_lock::{Mutex, MutexGuard};
+
+///state OS integration layers hang on a device, one value per type,
+///e.g. a devfs node handle or a security label.
+///
+///unlike [`super::SharedClaims`] it's keyed by type, and drivers are not meant to use it
+#[derive(Default)]
+pub struct Extensions {
+    entries: Mutex<BTreeMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+///holds the extensions mutex, for read-modify-write of several values at once
+pub struct ExtensionsGuard<'a> {
+    entries: MutexGuard<'a, BTreeMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn lock(&self) -> ExtensionsGuard<'_> {
+        ExtensionsGuard {
+            entries: self.entries.lock().await,
+        }
+    }
+
+    ///returns previous value of this type
+    pub async fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<T> {
+        self.lock().await.insert(value)
+    }
+
+    pub async fn get<T: Any + Send + Sync + Clone>(&self) -> Option<T> {
+        self.lock().await.get::<T>().cloned()
+    }
+
+    pub async fn remove<T: Any + Send + Sync>(&self) -> Option<T> {
+        self.lock().await.remove()
+    }
+
+    pub async fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.lock().await.contains::<T>()
+    }
+}
+
+impl ExtensionsGuard<'_> {
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.entries.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.entries.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.entries.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.entries
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.entries
+            .remove(&TypeId::of::<T>())
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    pub fn get_or_insert_with<T: Any + Send + Sync>(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        self.entries
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(init()))
+            .downcast_mut()
+            .unwrap()
+    }
+}
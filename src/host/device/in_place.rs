@@ -0,0 +1,135 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{sync::Arc, task::Wake};
+use futures::channel::oneshot;
+
+use crate::{
+    abstractions::{
+        dma::{CallerBuffer, DMA},
+        spin::SpinCell,
+        PlatformAbstractions,
+    },
+    usb::operations::{
+        control::ControlRequestBuilder, Direction, LengthResult, RequestResult, RequestedOperation,
+        UsbError,
+    },
+};
+
+use super::USBDevice;
+
+///caller memory controller holds until its transfer completes
+struct Lent<'b, O>
+where
+    O: PlatformAbstractions + 'static,
+{
+    buffer: Option<CallerBuffer<'b, O>>,
+    ///None once answered, or if device was not usable at submit time
+    receiver: Option<oneshot::Receiver<LengthResult>>,
+}
+
+impl<O> Lent<'_, O>
+where
+    O: PlatformAbstractions + 'static,
+{
+    async fn wait(&mut self) -> LengthResult {
+        let Some(receiver) = self.receiver.as_mut() else {
+            return Ok((RequestResult::SlotNotEnabledError, 0));
+        };
+        //callback dropped unanswered, device got unplugged while request was pending
+        let result = receiver
+            .await
+            .unwrap_or(Ok((RequestResult::SlotNotEnabledError, 0)));
+        self.receiver = None;
+        result
+    }
+}
+
+///bounce buffer of a transfer given up on, kept until its completion comes in.
+///
+///registered as waker of the completion, controller drops the last reference by answering it
+struct Abandoned<O>
+where
+    O: PlatformAbstractions + 'static,
+{
+    held: SpinCell<Option<(oneshot::Receiver<LengthResult>, DMA<[u8], O>)>>,
+}
+
+impl<O> Wake for Abandoned<O>
+where
+    O: PlatformAbstractions + 'static,
+{
+    fn wake(self: Arc<Self>) {}
+}
+
+///cancelled before completion: controller may still write into the bounce buffer
+impl<O> Drop for Lent<'_, O>
+where
+    O: PlatformAbstractions + 'static,
+{
+    fn drop(&mut self) {
+        let (Some(mut receiver), Some(CallerBuffer::Bounce { bounce, .. })) =
+            (self.receiver.take(), self.buffer.take())
+        else {
+            return;
+        };
+        let abandoned = Arc::new(Abandoned {
+            held: SpinCell::new(None),
+        });
+        let waker = Waker::from(abandoned.clone());
+        if let Poll::Pending = Pin::new(&mut receiver).poll(&mut Context::from_waker(&waker)) {
+            abandoned.held.with(|held| *held = Some((receiver, bounce)));
+        }
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+{
+    ///transfer over `buffer` without a DMA buffer of caller's own, returns bytes transferred.
+    ///
+    ///`operation` is built over physical address and length of a bounce copy of `buffer`, see
+    ///[`CallerBuffer::staged`]. dropping the future early gives `buffer` back right away, the
+    ///copy is released once controller completes the transfer
+    pub async fn transfer_in_place(
+        &self,
+        buffer: &mut [u8],
+        direction: Direction,
+        operation: impl FnOnce((usize, usize)) -> RequestedOperation,
+    ) -> Result<usize, UsbError> {
+        let buffer = CallerBuffer::staged(&self.config.os, buffer, direction)
+            .map_err(|_| UsbError::OutOfMemory)?;
+        let request = operation(buffer.phys_addr_len());
+        let mut lent = Lent {
+            buffer: Some(buffer),
+            receiver: self.submit_with_length(request).await.receiver,
+        };
+        let result = lent.wait().await;
+        let length = match result {
+            Ok((RequestResult::SlotNotEnabledError, _)) => return Err(UsbError::DeviceGone),
+            Ok((result, length)) => UsbError::check(Ok(result)).map(|_| length)?,
+            Err(code) => return Err(UsbError::UnknownCode(code)),
+        };
+        if let Some(buffer) = lent.buffer.take() {
+            buffer.finish(direction, length);
+        }
+        Ok(length)
+    }
+
+    ///control request with `buffer` as data stage, see [`Self::transfer_in_place`]
+    pub async fn control_in_place(
+        &self,
+        request: ControlRequestBuilder,
+        buffer: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let direction = request.direction();
+        self.transfer_in_place(buffer, direction, |data| {
+            RequestedOperation::Control(request.data(data).build())
+        })
+        .await
+    }
+}
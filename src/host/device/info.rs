@@ -0,0 +1,75 @@
+use crate::abstractions::PlatformAbstractions;
+
+use super::USBDevice;
+
+///refer usb2 spec 9.6.1
+const DEVICE_DESC_LEN: usize = 18;
+
+///bDeviceClass, bDeviceSubClass and bDeviceProtocol, or their interface counterparts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassCode {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl ClassCode {
+    ///class 0 leaves it to each interface
+    pub fn per_interface(&self) -> bool {
+        self.class == 0
+    }
+}
+
+///device descriptor decoded, see [`USBDevice::info`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInfo {
+    ///bcdUSB
+    pub usb_version: u16,
+    pub class: ClassCode,
+    ///bMaxPacketSize0 as is, an exponent on superspeed
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    ///bcdDevice
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceInfo {
+    ///None if `desc` is shorter than a device descriptor
+    pub fn from_device_desc(desc: &[u8]) -> Option<Self> {
+        if desc.len() < DEVICE_DESC_LEN {
+            return None;
+        }
+        let read_u16 = |offset: usize| u16::from_le_bytes([desc[offset], desc[offset + 1]]);
+        Some(Self {
+            usb_version: read_u16(2),
+            class: ClassCode {
+                class: desc[4],
+                subclass: desc[5],
+                protocol: desc[6],
+            },
+            max_packet_size0: desc[7],
+            vendor_id: read_u16(8),
+            product_id: read_u16(10),
+            device_version: read_u16(12),
+            num_configurations: desc[17],
+        })
+    }
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///None until controller read whole device descriptor while addressing
+    pub fn info(&self) -> Option<DeviceInfo> {
+        self.device_desc_raw
+            .get()
+            .and_then(|raw| DeviceInfo::from_device_desc(raw))
+    }
+
+    pub fn device_class(&self) -> Option<ClassCode> {
+        self.info().map(|info| info.class)
+    }
+}
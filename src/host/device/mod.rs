@@ -1,16 +1,44 @@
-use core::mem;
+use core::{
+    future::Future,
+    mem,
+    pin::pin,
+    sync::atomic::{AtomicBool, AtomicU8},
+    time::Duration,
+};
+
+mod configuration;
+mod extensions;
+mod in_place;
+mod info;
+mod shared;
+mod strings;
+mod suspend;
+mod timing;
+pub use configuration::ConfigurationInfo;
+pub use extensions::{Extensions, ExtensionsGuard};
+pub use info::{ClassCode, DeviceInfo};
+pub use shared::{SharedClaims, SharedClaimsGuard};
+pub use strings::DeviceStrings;
+pub use timing::{EnumerationLatency, EnumerationMilestone, EnumerationTiming};
 
 use alloc::{
-    borrow::ToOwned,
+    collections::btree_map::BTreeMap,
+    string::String,
     sync::Arc,
     vec::{self, Vec},
 };
 
-use async_lock::{OnceCell, RwLock, Semaphore, SemaphoreGuardArc};
-use async_ringbuf::{traits::AsyncProducer, AsyncRb};
-use futures::{channel::oneshot, FutureExt};
-use log::{debug, info, trace};
-use nosy::Sink;
+use async_lock::{Mutex, OnceCell, RwLock, Semaphore};
+use async_ringbuf::{
+    traits::{AsyncProducer, Split},
+    AsyncRb, AsyncStaticRb,
+};
+use futures::{
+    channel::oneshot,
+    future::{select, Either},
+    FutureExt,
+};
+use log::{debug, info, trace, warn};
 use usb_descriptor_decoder::{
     descriptors::{
         desc_device::TopologyDeviceDesc,
@@ -22,21 +50,24 @@ use usb_descriptor_decoder::{
 
 use crate::{
     abstractions::{dma::DMA, PlatformAbstractions, USBSystemConfig},
+    host::bandwidth::{interface_cost, BandwidthLedger},
     usb::{
+        class_descriptors::{ConfigClassDescriptors, InterfaceClassDescriptors},
+        companion::{ConfigCompanions, SsEndpointCompanion},
         operations::{
-            // construct_keep_callback_listener,
+            bulk::{BulkInStream, BulkRefill, BulkTransfer},
             control::{
                 bRequest, bRequestStandard, bmRequestType, construct_control_transfer_type,
                 ControlTransfer, DataTransferType, Recipient,
             },
-            ChannelNumber,
-            CompleteAction,
-            Direction,
-            ExtraAction,
-            RequestResult,
-            RequestedOperation,
-            USBRequest,
+            flow::{EndpointFlow, FlowControl, FlowTicket},
+            hub::{DeviceSpeed, HubAttachment, HubConfiguration, HubPortAttach},
+            interrupt::{InterruptTransfer, PeriodicStream},
+            ChannelNumber, ConfigureSemaphore, Direction, ExtraAction, IocPolicy, KeptRequest,
+            Latency, PendingRequest, RequestId, RequestLane, RequestResult, RequestedOperation,
+            USBRequest, UsbError,
         },
+        power::{ConfigPower, PowerDecision, USB2_PORT_BUDGET_MA, USB3_PORT_BUDGET_MA},
         standards::TopologyRoute,
     },
 };
@@ -51,18 +82,58 @@ where
     pub vendor_id: OnceCell<u16>,
     pub product_id: OnceCell<u16>,
     pub descriptor: OnceCell<Arc<TopologyDeviceDesc>>,
+    ///raw device descriptor, read by controller while addressing
+    pub(crate) device_desc_raw: OnceCell<Vec<u8>>,
+    ///names of driver modules bound to this device
+    pub(crate) bound_drivers: RwLock<Vec<String>>,
+    ///interfaces whose endpoints are enabled, by interface number
+    enabled_functions: RwLock<BTreeMap<u8, Arc<USBInterface>>>,
+    pub(crate) config_power: RwLock<Vec<ConfigPower>>,
+    ///superspeed endpoint companions of each configuration, empty below superspeed
+    pub(crate) ss_companions: RwLock<Vec<ConfigCompanions>>,
+    ///class specific descriptors of each configuration, set along with `descriptor`
+    pub(crate) class_descriptors: OnceCell<Vec<ConfigClassDescriptors>>,
+    ///as seen on its port, set by controller on attach
+    pub(crate) speed: DeviceSpeed,
+    ///periodic bandwidth of the bus device is on, shared with every device of its controller
+    pub(crate) bandwidth: Arc<BandwidthLedger>,
     pub topology_path: TopologyRoute,
+    ///None for devices on root ports
+    pub(crate) attachment: Option<HubAttachment>,
+    ///coordination between driver instances bound to this device
+    pub shared: SharedClaims,
+    ///see [`USBDevice::manufacturer`], [`USBDevice::product`] and [`USBDevice::serial_number`]
+    strings: DeviceStrings,
+    extensions: Extensions,
+    timing: EnumerationTiming,
     decoder_ref: OnceCell<Arc<RwLock<DescriptorDecoder>>>,
     configure_sem: Arc<Semaphore>,
-    request_channel: RwLock<ArcAsyncRingBufPord<USBRequest, RING_BUFFER_SIZE>>,
-    pub current_config: u8,
+    ///failure controller left on the operation holding `configure_sem`
+    configure_outcome: Arc<Mutex<Option<UsbError>>>,
+    ///backpressure of each endpoint, see [`USBDevice::endpoint_flow`]
+    flow: FlowControl,
+    ///one per [`RequestLane`], in its order
+    request_channels: Vec<RwLock<ArcAsyncRingBufPord<USBRequest, RING_BUFFER_SIZE>>>,
+    ///see [`USBDevice::current_config`] and [`USBDevice::set_configuration`]
+    current_config: AtomicU8,
+    ///see [`USBDevice::suspend`]
+    suspended: AtomicBool,
+    ///set by controller once device is unplugged, see [`USBDevice::is_gone`]
+    gone: OnceCell<()>,
 }
 
 pub enum DeviceState {
     Probed,
     Assigned,
     Configured,
-    PreDrop, //for hot plug,  how to design drop all working functions mechanism?
+    ///refused by device filter or an init hook, slot already released or never made
+    Rejected,
+    ///current configuration exceeds bus power budget, no driver would be bound
+    PowerRefused,
+    ///unplugged, slot released, every further request is refused
+    PreDrop,
+    ///enumeration failed, no driver would be bound and further requests are refused
+    Failed(UsbError),
 }
 
 pub type ArcAsyncRingBufPord<T, const N: usize> = async_ringbuf::wrap::AsyncWrap<
@@ -76,18 +147,27 @@ pub type ArcAsyncRingBufCons<T, const N: usize> = async_ringbuf::wrap::AsyncWrap
     true,
 >;
 
-#[derive(Debug)]
-pub struct ConfigureSemaphore(SemaphoreGuardArc);
-
 impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
 where
     O: PlatformAbstractions,
 {
+    ///receiving ends of its request channels are returned, one per [`RequestLane`] in its order
     pub fn new(
         cfg: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
-        sender: ArcAsyncRingBufPord<USBRequest, RING_BUFFER_SIZE>,
-    ) -> (Self, Arc<OnceCell<u8>>) {
+    ) -> (
+        Self,
+        Arc<OnceCell<u8>>,
+        Vec<ArcAsyncRingBufCons<USBRequest, RING_BUFFER_SIZE>>,
+    ) {
         let once_cell = Arc::new(OnceCell::new());
+        let (senders, receivers): (Vec<_>, Vec<_>) = RequestLane::ALL
+            .iter()
+            .map(|_| {
+                let (sender, receiver) =
+                    AsyncStaticRb::<USBRequest, RING_BUFFER_SIZE>::default().split();
+                (RwLock::new(sender), receiver)
+            })
+            .unzip();
 
         (
             USBDevice {
@@ -95,32 +175,213 @@ where
                 vendor_id: OnceCell::new(),
                 product_id: OnceCell::new(),
                 descriptor: OnceCell::new(),
-                request_channel: sender.into(),
+                device_desc_raw: OnceCell::new(),
+                bound_drivers: RwLock::new(Vec::new()),
+                enabled_functions: RwLock::new(BTreeMap::new()),
+                config_power: RwLock::new(Vec::new()),
+                ss_companions: RwLock::new(Vec::new()),
+                class_descriptors: OnceCell::new(),
+                speed: DeviceSpeed::High,
+                bandwidth: Arc::new(BandwidthLedger::default()),
+                request_channels: senders,
+                flow: FlowControl::new(RING_BUFFER_SIZE),
                 configure_sem: Semaphore::new(1).into(),
+                configure_outcome: Arc::new(Mutex::new(None)),
                 topology_path: TopologyRoute::new(),
+                attachment: None,
+                shared: SharedClaims::new(),
+                strings: DeviceStrings::default(),
+                extensions: Extensions::new(),
+                timing: EnumerationTiming::default(),
                 slot_id: once_cell.clone(),
                 config: cfg,
                 decoder_ref: OnceCell::new(),
-                current_config: 1,
+                current_config: AtomicU8::new(1),
+                suspended: AtomicBool::new(false),
+                gone: OnceCell::new(),
             },
             once_cell,
+            receivers,
         )
     }
 
+    ///state of OS integration layers, dropped together with device
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    ///when enumeration milestones were passed, see [`Self::enumeration_latency`]
+    pub fn enumeration_timing(&self) -> &EnumerationTiming {
+        &self.timing
+    }
+
+    ///port connect to driver ready, by phase. complete once drivers were offered the device
+    pub fn enumeration_latency(&self) -> EnumerationLatency {
+        self.timing.latency()
+    }
+
+    pub(crate) fn mark_milestone(&self, milestone: EnumerationMilestone) {
+        self.mark_milestone_at(milestone, self.config.os.now())
+    }
+
+    ///for milestones passed before device existed, like port reset
+    pub(crate) fn mark_milestone_at(&self, milestone: EnumerationMilestone, at: Option<Duration>) {
+        self.timing.mark(milestone, at)
+    }
+
+    ///unplugged, whatever is still waiting on it gets aborted
+    pub fn is_gone(&self) -> bool {
+        self.gone.is_initialized()
+    }
+
+    ///before its slot is released, so nothing waits on a reply that never comes
+    pub(crate) async fn mark_gone(&self) {
+        let _ = self.gone.set(()).await;
+    }
+
+    ///`fut` unless device gets unplugged first, it's dropped then together with whatever it holds
+    async fn unless_gone<T>(&self, fut: impl Future<Output = T>) -> Result<T, UsbError> {
+        //checked first, a gone device must not get any further request posted
+        match select(pin!(self.gone.wait()), pin!(fut)).await {
+            Either::Left(_) => Err(UsbError::DeviceGone),
+            Either::Right((output, _)) => Ok(output),
+        }
+    }
+
+    pub async fn is_rejected(&self) -> bool {
+        matches!(
+            *self.state.read().await,
+            DeviceState::Rejected | DeviceState::PowerRefused | DeviceState::Failed(_)
+        )
+    }
+
+    ///power requirement of each configuration, filled while assigning
+    pub async fn power_requirements(&self) -> Vec<ConfigPower> {
+        self.config_power.read().await.clone()
+    }
+
+    ///class and vendor specific descriptors of an alternate setting of current configuration
+    pub fn class_descriptors(&self, interface: u8, alternate: u8) -> InterfaceClassDescriptors {
+        self.class_descriptors
+            .get()
+            .and_then(|configs| {
+                configs
+                    .iter()
+                    .find(|config| config.config_value == self.current_config())
+            })
+            .map(|config| config.of(interface, alternate))
+            .unwrap_or_default()
+    }
+
+    ///companion of endpoint `address` in an alternate setting of configuration `config`
+    pub(crate) async fn ss_companion(
+        &self,
+        config: u8,
+        interface: u8,
+        alternate: u8,
+        address: u8,
+    ) -> Option<SsEndpointCompanion> {
+        self.ss_companions
+            .read()
+            .await
+            .iter()
+            .find(|companions| companions.config_value == config)?
+            .get(interface, alternate, address)
+    }
+
+    ///by speed of its port, usb3 devices on a usb2 port run at high speed whatever bcdUSB says
+    pub(crate) fn is_superspeed(&self) -> bool {
+        self.speed == DeviceSpeed::Super
+    }
+
+    ///check current configuration against budget of its root port
+    pub async fn power_decision(&self) -> Option<(ConfigPower, PowerDecision)> {
+        let available =
+            self.config
+                .power_policy
+                .root_port_budget_ma
+                .unwrap_or(if self.is_superspeed() {
+                    USB3_PORT_BUDGET_MA
+                } else {
+                    USB2_PORT_BUDGET_MA
+                });
+        self.config_power
+            .read()
+            .await
+            .iter()
+            .find(|power| power.config_value == self.current_config())
+            .map(|power| (*power, PowerDecision::evaluate(power, available)))
+    }
+
     pub async fn add_decoder(&self, decoder: Arc<RwLock<DescriptorDecoder>>) {
         self.decoder_ref.set(decoder).await;
     }
 
     pub fn acquire_cfg_sem(&self) -> Option<ConfigureSemaphore> {
-        self.configure_sem.try_acquire_arc().map(ConfigureSemaphore)
+        self.configure_sem
+            .try_acquire_arc()
+            .map(|guard| ConfigureSemaphore {
+                guard,
+                outcome: self.configure_outcome.clone(),
+            })
     }
 
     pub async fn async_acquire_cfg_sem(&self) -> ConfigureSemaphore {
-        ConfigureSemaphore(self.configure_sem.acquire_arc().await)
+        self.acquire_configure().await.0
+    }
+
+    ///wait for semaphore, together with failure controller left on the operation that held it
+    async fn acquire_configure(&self) -> (ConfigureSemaphore, Option<UsbError>) {
+        let guard = self.configure_sem.acquire_arc().await;
+        let error = self.configure_outcome.lock().await.take();
+        (
+            ConfigureSemaphore {
+                guard,
+                outcome: self.configure_outcome.clone(),
+            },
+            error,
+        )
     }
 
-    async fn post_usb_request(&self, request: USBRequest) {
-        self.request_channel
+    async fn fail_enumeration(&self, error: UsbError) -> Result<(), UsbError> {
+        warn!(
+            "device at {} failed enumeration: {}",
+            self.topology_path, error
+        );
+        *self.state.write().await = DeviceState::Failed(error);
+        Err(error)
+    }
+
+    ///occupancy of endpoint `endpoint_id`, producers pace submissions on
+    ///[`EndpointFlow::writable`] instead of waiting on a full ring or channel
+    pub fn endpoint_flow(&self, endpoint_id: usize) -> &Arc<EndpointFlow> {
+        self.flow.endpoint(endpoint_id)
+    }
+
+    ///controller published how full transfer ring of `endpoint_id` is
+    pub(crate) fn publish_ring_occupancy(&self, endpoint_id: usize, used: usize, capacity: usize) {
+        self.flow.endpoint(endpoint_id).publish_ring(used, capacity)
+    }
+
+    ///controller got a completion on `endpoint_id`, see [`EndpointFlow::stats`]
+    pub(crate) fn record_completion(&self, endpoint_id: usize, code: Result<RequestResult, u8>) {
+        self.flow.endpoint(endpoint_id).record_completion(code)
+    }
+
+    ///successful completion on `endpoint_id` moved `bytes`
+    pub(crate) fn record_bytes(&self, endpoint_id: usize, bytes: usize) {
+        self.flow.endpoint(endpoint_id).record_bytes(bytes)
+    }
+
+    async fn post_usb_request(&self, mut request: USBRequest) {
+        trace!("{} posted on device {}", request.id, self.topology_path);
+        //kept-filling ones never leave the endpoint, they are not a producer's backlog
+        if let (Some(endpoint_id), ExtraAction::NOOP) =
+            (request.endpoint_id(), &request.extra_action)
+        {
+            request.flow = Some(FlowTicket::issue(self.flow.endpoint(endpoint_id)));
+        }
+        self.request_channels[request.operation.lane() as usize]
             .write()
             .await
             .push(request)
@@ -135,134 +396,544 @@ where
     }
 
     pub async fn request_no_response(&self, request: RequestedOperation) {
-        self.check_self_status().await;
-        self.post_usb_request(USBRequest {
-            operation: request,
-            extra_action: ExtraAction::default(),
-            complete_action: CompleteAction::default(),
-        })
+        if !self.check_self_status().await {
+            return;
+        }
+        self.post_usb_request(USBRequest::once(request).no_response())
+            .await;
+    }
+
+    ///see [`USBRequest::keep_bulk`] and [`USBRequest::keep_interrupt`]
+    pub async fn keep_no_response(&self, request: KeptRequest) {
+        if !self.check_self_status().await {
+            return;
+        }
+        self.post_usb_request(request.into()).await;
+    }
+
+    ///keep filling an interrupt endpoint, buffer of later fills could be swapped via returned handle
+    pub async fn keep_interrupt(
+        &self,
+        endpoint_id: usize,
+        buffer_addr_len: (usize, usize),
+    ) -> Arc<PeriodicStream>
+    where
+        O: 'static,
+    {
+        let os = self.config.os.clone();
+        let stream = PeriodicStream::new(buffer_addr_len, Arc::new(move || os.now()));
+        self.submit_interrupt_refill(endpoint_id, buffer_addr_len, &stream)
+            .await;
+        stream
+    }
+
+    ///[`Self::keep_interrupt`], with every completion queued for [`PeriodicStream::next_report`].
+    ///
+    ///up to `depth` reports are kept for a driver falling behind, cancel through the handle
+    pub async fn keep_interrupt_reports(
+        &self,
+        endpoint_id: usize,
+        buffer_addr_len: (usize, usize),
+        depth: usize,
+    ) -> Arc<PeriodicStream>
+    where
+        O: 'static,
+    {
+        let os = self.config.os.clone();
+        let stream =
+            PeriodicStream::reporting(buffer_addr_len, Arc::new(move || os.now()), depth.max(1));
+        self.submit_interrupt_refill(endpoint_id, buffer_addr_len, &stream)
+            .await;
+        stream
+    }
+
+    ///keep filling an interrupt endpoint over `buffers` in turn, a filled one is not touched
+    ///again before [`Self::release_interrupt_buffer`]. refused without any buffer
+    pub async fn keep_interrupt_rotating(
+        &self,
+        endpoint_id: usize,
+        buffers: Vec<(usize, usize)>,
+    ) -> Result<Arc<PeriodicStream>, UsbError>
+    where
+        O: 'static,
+    {
+        let os = self.config.os.clone();
+        let Some(&buffer_addr_len) = buffers.first() else {
+            warn!(
+                "interrupt endpoint {} of {} given no buffers to rotate",
+                endpoint_id, self.topology_path
+            );
+            return Err(UsbError::InvalidArgument);
+        };
+        let stream = PeriodicStream::rotating(buffers, Arc::new(move || os.now()))
+            .ok_or(UsbError::InvalidArgument)?;
+        self.submit_interrupt_refill(endpoint_id, buffer_addr_len, &stream)
+            .await;
+        Ok(stream)
+    }
+
+    ///give taken buffer back, resubmit if the endpoint stalled for lack of buffers
+    pub async fn release_interrupt_buffer(
+        &self,
+        endpoint_id: usize,
+        stream: &Arc<PeriodicStream>,
+        buffer_idx: usize,
+    ) {
+        if stream.release(buffer_idx)
+            && let Some(buffer_addr_len) = stream.take_free()
+        {
+            self.submit_interrupt_refill(endpoint_id, buffer_addr_len, stream)
+                .await;
+        }
+    }
+
+    async fn submit_interrupt_refill(
+        &self,
+        endpoint_id: usize,
+        buffer_addr_len: (usize, usize),
+        stream: &Arc<PeriodicStream>,
+    ) {
+        self.keep_no_response(USBRequest::keep_interrupt(InterruptTransfer {
+            endpoint_id,
+            buffer_addr_len,
+            scatter: Vec::new(),
+            short_packet_ok: true,
+            refill: Some(stream.clone()),
+        }))
         .await;
     }
 
-    pub async fn keep_no_response(&self, request: RequestedOperation, channel_number: u16) {
-        self.check_self_status().await;
-        self.post_usb_request(USBRequest {
-            operation: request,
-            extra_action: ExtraAction::KeepFill,
-            complete_action: CompleteAction::default(),
-        })
+    ///keep `depth` TDs in flight on a bulk IN endpoint, rotating over `buffers`
+    pub async fn keep_bulk_in(
+        &self,
+        endpoint_id: usize,
+        buffers: Vec<(usize, usize)>,
+        depth: usize,
+    ) -> Arc<BulkInStream> {
+        let stream = BulkInStream::new(buffers);
+        for _ in 0..depth {
+            if !self.submit_bulk_in(endpoint_id, &stream).await {
+                break;
+            }
+        }
+        stream
+    }
+
+    ///give consumed buffer back, resubmit if the stream stalled for lack of buffers
+    pub async fn recycle_bulk_in(
+        &self,
+        endpoint_id: usize,
+        stream: &Arc<BulkInStream>,
+        buffer_idx: usize,
+    ) {
+        if stream.recycle(buffer_idx).await {
+            self.submit_bulk_in(endpoint_id, stream).await;
+        }
+    }
+
+    async fn submit_bulk_in(&self, endpoint_id: usize, stream: &Arc<BulkInStream>) -> bool {
+        let Some(buffer_idx) = stream.take_free().await else {
+            return false;
+        };
+        self.keep_no_response(USBRequest::keep_bulk(
+            BulkTransfer {
+                endpoint_id,
+                buffer_addr_len: stream.buffer(buffer_idx),
+                scatter: Vec::new(),
+                ioc_policy: IocPolicy::default(),
+                stream_id: None,
+                refill: None,
+            },
+            BulkRefill {
+                stream: stream.clone(),
+                buffer_idx,
+            },
+        ))
         .await;
+        true
     }
 
     ///I must lost my mind...
     pub async fn request_once(&self, request: RequestedOperation) -> Result<RequestResult, u8> {
-        self.check_self_status().await;
+        self.request_with_length(request)
+            .await
+            .map(|(result, _)| result)
+    }
+
+    ///like [`Self::request_once`], plus bytes actually transferred, e.g. length of a descriptor read
+    pub async fn request_with_length(
+        &self,
+        request: RequestedOperation,
+    ) -> Result<(RequestResult, usize), u8> {
+        self.submit_with_length(request).await.wait().await
+    }
+
+    ///post `request` without waiting for it, requests on one endpoint complete in submit order.
+    ///
+    ///for keeping several transfers in flight, see [`crate::driver::bulk_out::BulkOutPipe`]
+    pub async fn submit_with_length(&self, request: RequestedOperation) -> PendingRequest {
+        self.submit_with_latency(request, Latency::Normal).await
+    }
+
+    ///like [`Self::submit_with_length`], picked up by controller as urgently as `latency` asks
+    pub async fn submit_with_latency(
+        &self,
+        request: RequestedOperation,
+        latency: Latency,
+    ) -> PendingRequest {
+        let id = RequestId::next();
+        if !self.check_self_status().await {
+            return PendingRequest { id, receiver: None };
+        }
         let (sender, receiver) = oneshot::channel();
-        self.post_usb_request(USBRequest {
-            extra_action: ExtraAction::NOOP,
-            operation: request,
-            complete_action: CompleteAction::SimpleResponse(sender),
-        })
+        self.post_usb_request(
+            USBRequest::once(request)
+                .with_id(id)
+                .with_latency(latency)
+                .length_response(sender),
+        )
         .await;
+        PendingRequest {
+            id,
+            receiver: Some(receiver),
+        }
+    }
 
-        receiver.await.unwrap().to_owned()
-    }
-
-    // pub async fn keep_request(
-    //     &self,
-    //     request: RequestedOperation,
-    //     channel_number: ChannelNumber,
-    // ) -> Sink<Result<RequestResult, u8>> {
-    //     self.check_self_status().await;
-    //     let channel = construct_keep_callback_listener();
-    //     self.post_usb_request(USBRequest {
-    //         extra_action: ExtraAction::KeepFill(channel_number),
-    //         operation: request,
-    //         complete_action: CompleteAction::KeepResponse(channel.0),
-    //     })
-    //     .await;
-    //     channel.1
-    // }
-
-    async fn check_self_status(&self) -> &Self {
+    ///false once device is gone, requests are refused then. resumes suspended device
+    async fn check_self_status(&self) -> bool {
         match *self.state.read().await {
             DeviceState::Probed => {
-                self.request_assign().await;
+                if self.request_assign().await.is_err() {
+                    return false;
+                }
+            }
+            DeviceState::Failed(error) => {
+                debug!(
+                    "device at {} failed with {}, request refused",
+                    self.topology_path, error
+                );
+                return false;
+            }
+            DeviceState::PreDrop => {
+                debug!(
+                    "device at {} is unplugged, request refused",
+                    self.topology_path
+                );
+                return false;
             }
-            DeviceState::PreDrop => todo!(),
             _ => (),
         };
 
-        self
-    }
+        //requests would go nowhere while suspended
+        if self.is_suspended() && !matches!(self.resume().await, Ok(RequestResult::Success)) {
+            return false;
+        }
 
-    pub async fn enable_function(&self, interface: Arc<USBInterface>) {
-        let sem = self.configure_sem.acquire_arc().await;
-        self.post_usb_request(USBRequest {
-            operation: crate::usb::operations::RequestedOperation::EnableFunction(
-                self.current_config,
-                interface,
-            ),
-            extra_action: ExtraAction::default(),
-            complete_action: CompleteAction::DropSem(ConfigureSemaphore(sem)),
-        })
-        .await;
+        true
+    }
 
-        trace!("enable interface success!");
-        *self.state.write().await = DeviceState::Configured;
+    ///tell controller this device is a hub, before any child is attached
+    pub async fn configure_hub(
+        &self,
+        configuration: HubConfiguration,
+    ) -> Result<RequestResult, u8> {
+        self.request_once(RequestedOperation::ConfigureHub(configuration))
+            .await
     }
 
-    pub async fn request_assign(&self) {
-        info!("device request assign!");
-        let sem = self.configure_sem.acquire_arc().await;
-        self.post_usb_request(USBRequest {
-            operation: RequestedOperation::InitializeDevice(self.topology_path.clone()),
-            extra_action: ExtraAction::default(),
-            complete_action: CompleteAction::DropSem(ConfigureSemaphore(sem)),
-        })
-        .await;
+    pub async fn attach_child(&self, attach: HubPortAttach) -> Result<RequestResult, u8> {
+        self.request_once(RequestedOperation::AttachChild(attach))
+            .await
+    }
 
-        let sem = self.configure_sem.acquire_arc().await;
-        *self.state.write().await = DeviceState::Assigned;
-        trace!("switch device state into assigned!");
-        trace!("device initialize complete, now request device desc...");
+    pub async fn detach_child(&self, port: u8) -> Result<RequestResult, u8> {
+        self.request_once(RequestedOperation::DetachChild(port))
+            .await
+    }
 
-        let device = {
-            let buffer_device: DMA<[u8], O> =
-                DMA::new_vec(0u8, O::PAGE_SIZE, O::PAGE_SIZE, self.config.os.dma_alloc());
+    ///recover endpoint after a transfer completed with [`RequestResult::StallError`],
+    ///kept-filling interrupt endpoint resumes filling afterwards
+    pub async fn clear_stall(&self, endpoint_id: usize) -> Result<RequestResult, u8> {
+        self.request_once(RequestedOperation::ClearStall(endpoint_id))
+            .await
+    }
 
+    ///returns alternate setting actually enabled. if bus can't take periodic bandwidth of
+    ///`interface`, a cheaper alternate setting of it is tried instead, so drivers must
+    ///SET_INTERFACE with the returned one
+    pub async fn enable_function(
+        &self,
+        interface: Arc<USBInterface>,
+    ) -> Result<Arc<USBInterface>, UsbError> {
+        let mut last_error = None;
+        for (candidate, cost) in self.bandwidth_candidates(&interface).await {
+            let number = candidate.interface.interface_number;
+            //behind a TT, its budget is checked by controller
+            let tracked = cost > 0 && !self.behind_tt();
+            if tracked
+                && let Err(error) =
+                    self.bandwidth
+                        .claim(&self.topology_path, number, self.speed, cost)
             {
-                self.post_usb_request(USBRequest {
-                    operation: RequestedOperation::Control(ControlTransfer {
-                        request_type: bmRequestType::new(
-                            Direction::In,
-                            DataTransferType::Standard,
-                            Recipient::Device,
+                debug!(
+                    "alternate setting {} of interface {} on {} does not fit: {}",
+                    candidate.interface.alternate_setting, number, self.topology_path, error
+                );
+                last_error = Some(error);
+                continue;
+            }
+
+            let (sem, _) = self.acquire_configure().await;
+            self.post_usb_request(
+                USBRequest::once(crate::usb::operations::RequestedOperation::EnableFunction(
+                    self.current_config(),
+                    candidate.clone(),
+                ))
+                .release_on_complete(sem),
+            )
+            .await;
+
+            match self.acquire_configure().await {
+                (_, None) => {
+                    if candidate.interface.alternate_setting
+                        != interface.interface.alternate_setting
+                    {
+                        info!(
+                            "interface {} on {} enabled with alternate setting {} instead of {}, short on bandwidth",
+                            number,
+                            self.topology_path,
+                            candidate.interface.alternate_setting,
+                            interface.interface.alternate_setting
+                        );
+                    }
+                    trace!("enable interface success!");
+                    self.enabled_functions
+                        .write()
+                        .await
+                        .insert(number, candidate.clone());
+                    *self.state.write().await = DeviceState::Configured;
+                    return Ok(candidate);
+                }
+                //controller knows better than the estimate, try next one
+                (
+                    _,
+                    Some(
+                        error @ UsbError::Completion(
+                            RequestResult::BandwidthError | RequestResult::SecondaryBandwidthError,
                         ),
-                        request: bRequest::Standard(bRequestStandard::GetDescriptor),
-                        index: 0,
-                        value: construct_control_transfer_type(
-                            USBStandardDescriptorTypes::Device as u8,
-                            0,
-                        )
-                        .bits(),
-                        data: Some(buffer_device.phys_addr_len_tuple().into()),
-                        response: false,
-                    }),
-                    extra_action: ExtraAction::default(),
-                    complete_action: CompleteAction::DropSem(ConfigureSemaphore(sem)),
-                })
-                .await;
-                let sem = self.configure_sem.acquire().await;
-                drop(sem);
+                    ),
+                ) => {
+                    if tracked {
+                        self.bandwidth.release(&self.topology_path, number);
+                    }
+                    debug!(
+                        "controller refused alternate setting {} of interface {} on {}: {}",
+                        candidate.interface.alternate_setting, number, self.topology_path, error
+                    );
+                    last_error = Some(error);
+                }
+                (_, Some(error)) => {
+                    if tracked {
+                        self.bandwidth.release(&self.topology_path, number);
+                    }
+                    warn!(
+                        "enable interface on {} failed: {}",
+                        self.topology_path, error
+                    );
+                    return Err(error);
+                }
             }
+        }
+
+        let error = last_error.unwrap_or(UsbError::BadDescriptor);
+        warn!(
+            "enable interface on {} failed: {}",
+            self.topology_path, error
+        );
+        Err(error)
+    }
 
-            DescriptorDecoder::peek_device_desc(buffer_device.to_vec()).unwrap()
+    ///`interface` first, then its cheaper alternate settings, most expensive first.
+    ///alternate settings without periodic endpoints are useless to a periodic driver, so skipped
+    async fn bandwidth_candidates(
+        &self,
+        interface: &Arc<USBInterface>,
+    ) -> Vec<(Arc<USBInterface>, u32)> {
+        let config = self.current_config();
+        let companions = self
+            .ss_companions
+            .read()
+            .await
+            .iter()
+            .find(|companions| companions.config_value == config)
+            .cloned()
+            .unwrap_or_default();
+        let number = interface.interface.interface_number;
+        let cost_of = |candidate: &USBInterface| {
+            interface_cost(self.speed, candidate, |address| {
+                companions.get(number, candidate.interface.alternate_setting, address)
+            })
+        };
+
+        let requested = cost_of(interface);
+        let mut cheaper: Vec<_> = self
+            .configurations()
+            .await
+            .into_iter()
+            .filter(|info| info.value == config)
+            .flat_map(|info| info.interfaces)
+            .filter(|candidate| {
+                candidate.interface.interface_number == number
+                    && candidate.interface.alternate_setting
+                        != interface.interface.alternate_setting
+            })
+            .map(|candidate| {
+                let cost = cost_of(&candidate);
+                (candidate, cost)
+            })
+            .filter(|(_, cost)| *cost > 0 && *cost < requested)
+            .collect();
+        cheaper.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        let mut candidates = alloc::vec![(interface.clone(), requested)];
+        candidates.extend(cheaper);
+        candidates
+    }
+
+    fn behind_tt(&self) -> bool {
+        self.attachment
+            .is_some_and(|attachment| attachment.tt.is_some() && attachment.speed.needs_tt())
+    }
+
+    ///drop endpoints of interface, so it could be claimed again by other driver.
+    ///
+    ///device stays configured while any other interface is still enabled
+    pub async fn release_function(&self, interface: Arc<USBInterface>) -> Result<(), UsbError> {
+        self.disable_function(interface, false).await
+    }
+
+    ///interfaces enabled on `interface_number`, every one for None. taken out, so
+    ///[`Self::release_orphaned`] knows whether somebody enabled them again meanwhile
+    pub(crate) async fn take_functions(
+        &self,
+        interface_number: Option<u8>,
+    ) -> Vec<Arc<USBInterface>> {
+        let mut enabled = self.enabled_functions.write().await;
+        let (taken, kept): (BTreeMap<_, _>, BTreeMap<_, _>) = mem::take(&mut *enabled)
+            .into_iter()
+            .partition(|(number, _)| interface_number.is_none_or(|wanted| wanted == *number));
+        *enabled = kept;
+        taken.into_values().collect()
+    }
+
+    ///drop endpoints an unbound driver left behind. interface enabled again meanwhile
+    ///belongs to whoever enabled it, so it is kept
+    pub(crate) async fn release_orphaned(&self, interfaces: Vec<Arc<USBInterface>>) {
+        for interface in interfaces {
+            if let Err(error) = self.disable_function(interface, true).await {
+                warn!(
+                    "dropping endpoints left on {} failed: {}",
+                    self.topology_path, error
+                );
+            }
+        }
+    }
+
+    async fn disable_function(
+        &self,
+        interface: Arc<USBInterface>,
+        orphaned: bool,
+    ) -> Result<(), UsbError> {
+        let number = interface.interface.interface_number;
+        let (sem, _) = self.acquire_configure().await;
+        if orphaned && self.enabled_functions.read().await.contains_key(&number) {
+            return Ok(());
+        }
+        self.bandwidth.release(&self.topology_path, number);
+        self.post_usb_request(
+            USBRequest::once(RequestedOperation::DisableFunction(interface))
+                .release_on_complete(sem),
+        )
+        .await;
+
+        if let (_, Some(error)) = self.acquire_configure().await {
+            warn!(
+                "release interface on {} failed: {}",
+                self.topology_path, error
+            );
+            return Err(error);
+        }
+        trace!("release interface success!");
+        let mut enabled = self.enabled_functions.write().await;
+        enabled.remove(&number);
+        let mut state = self.state.write().await;
+        if enabled.is_empty() && matches!(*state, DeviceState::Configured) {
+            *state = DeviceState::Assigned;
+        }
+        Ok(())
+    }
+
+    ///rejection by filter or power policy is not an error, see [`Self::is_rejected`]
+    pub async fn request_assign(&self) -> Result<(), UsbError> {
+        info!("device request assign!");
+        match self.unless_gone(self.assign()).await {
+            Ok(result) => result,
+            Err(error) => {
+                warn!(
+                    "device at {} unplugged while enumerating, aborted",
+                    self.topology_path
+                );
+                //controller may not have got to it yet
+                let mut state = self.state.write().await;
+                if !matches!(*state, DeviceState::PreDrop) {
+                    *state = DeviceState::Failed(error);
+                }
+                Err(error)
+            }
+        }
+    }
+
+    async fn assign(&self) -> Result<(), UsbError> {
+        let (sem, _) = self.acquire_configure().await;
+        self.post_usb_request(
+            USBRequest::once(RequestedOperation::InitializeDevice(
+                self.topology_path.clone(),
+            ))
+            .release_on_complete(sem),
+        )
+        .await;
+
+        let (mut sem, error) = self.acquire_configure().await;
+        if let Some(error) = error {
+            return self.fail_enumeration(error).await;
+        }
+        if self.is_rejected().await {
+            info!(
+                "device at {} rejected, stop enumeration",
+                self.topology_path
+            );
+            return Ok(());
+        }
+        *self.state.write().await = DeviceState::Assigned;
+        self.mark_milestone(EnumerationMilestone::Addressed);
+        trace!("switch device state into assigned!");
+        trace!("device initialize complete, now parse device desc...");
+
+        let Some(device) = self
+            .device_desc_raw
+            .get()
+            .and_then(|raw| DescriptorDecoder::peek_device_desc(raw.clone()).ok())
+        else {
+            return self.fail_enumeration(UsbError::BadDescriptor).await;
         };
         trace!("peeked device! {:#?}", device);
+        if let Some(info) = self.info() {
+            let _ = self.vendor_id.set(info.vendor_id).await;
+            let _ = self.product_id.set(info.product_id).await;
+        }
 
         let mut cfgs = Vec::new();
-        let mut sem = self.configure_sem.acquire_arc().await;
+        let mut class_descriptors = Vec::new();
 
         trace!("fetching decoder ref!");
         let parser = self.decoder_ref.wait().await.read().await;
@@ -270,37 +941,59 @@ where
 
         for index in 0..device.num_configurations {
             trace!("now at cfg index {index}");
-            let buffer: DMA<[u8], O> =
-                DMA::new_vec(0u8, O::PAGE_SIZE, O::PAGE_SIZE, self.config.os.dma_alloc());
-            self.post_usb_request(USBRequest {
-                operation: RequestedOperation::Control(ControlTransfer {
-                    request_type: bmRequestType::new(
-                        Direction::In,
-                        DataTransferType::Standard,
+            let Ok(buffer) = DMA::<[u8], O>::try_new_vec(
+                0u8,
+                O::PAGE_SIZE,
+                O::PAGE_SIZE,
+                self.config.os.dma_alloc(),
+            ) else {
+                return self.fail_enumeration(UsbError::OutOfMemory).await;
+            };
+            self.post_usb_request(
+                USBRequest::once(RequestedOperation::Control(
+                    ControlTransfer::get_descriptor(
                         Recipient::Device,
-                    ),
-                    request: bRequest::Standard(bRequestStandard::GetDescriptor),
-                    index: 0,
-                    value: construct_control_transfer_type(
                         USBStandardDescriptorTypes::Configuration as u8,
                         index as _,
-                    )
-                    .bits(),
-                    data: Some(buffer.phys_addr_len_tuple().into()),
-                    response: false,
-                }),
-                extra_action: ExtraAction::default(),
-                complete_action: CompleteAction::DropSem(ConfigureSemaphore(sem)),
-            })
+                        0,
+                        buffer.phys_addr_len_tuple().into(),
+                    ),
+                ))
+                .release_on_complete(sem),
+            )
             .await;
-            sem = self.configure_sem.acquire_arc().await;
-            if let Ok(cfg) = parser.parse_config(&buffer.to_vec()) {
-                cfgs.push(cfg.0.into());
-            };
+            let error;
+            (sem, error) = self.acquire_configure().await;
+            if let Some(error) = error {
+                return self.fail_enumeration(error).await;
+            }
+            if let Some(power) = ConfigPower::from_config_desc(&buffer, self.is_superspeed()) {
+                self.config_power.write().await.push(power);
+            }
+            if self.is_superspeed()
+                && let Some(companions) = ConfigCompanions::from_config_desc(&buffer)
+            {
+                self.ss_companions.write().await.push(companions);
+            }
+            if let Some(descriptors) = ConfigClassDescriptors::from_config_desc(&buffer) {
+                class_descriptors.push(descriptors);
+            }
+            match parser.parse_config(&buffer.to_vec()) {
+                Ok(cfg) => cfgs.push(cfg.0.into()),
+                Err(_) => {
+                    if let Err(error) = self.config.spec_policy.check(format_args!(
+                        "device at {} configuration {} unparsable, skipped",
+                        self.topology_path, index
+                    )) {
+                        return self.fail_enumeration(error).await;
+                    }
+                }
+            }
         }
 
         trace!("desc decode complete!");
 
+        let _ = self.class_descriptors.set(class_descriptors).await;
         let _ = self
             .descriptor
             .set(Arc::new(TopologyDeviceDesc {
@@ -308,6 +1001,20 @@ where
                 configs: cfgs,
             }))
             .await;
-        debug!("parsed device desc: {:#?}", self.descriptor)
+        debug!("parsed device desc: {:#?}", self.descriptor);
+        self.mark_milestone(EnumerationMilestone::Described);
+
+        if let Some((power, decision)) = self.power_decision().await
+            && decision.is_over()
+        {
+            warn!(
+                "device at {} config {} over power budget: {:?}",
+                self.topology_path, power.config_value, decision
+            );
+            if self.config.power_policy.enforce {
+                *self.state.write().await = DeviceState::PowerRefused;
+            }
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,86 @@
+use core::any::Any;
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, string::String};
+use async_lock::{Mutex, MutexGuard};
+
+///coordination storage shared by every driver instance bound to one device,
+///e.g. CDC control interface publishing what it negotiated for data interface
+#[derive(Default)]
+pub struct SharedClaims {
+    entries: Mutex<BTreeMap<String, Box<dyn Any + Send + Sync>>>,
+}
+
+///holds the claims mutex, so read-modify-write across several keys is atomic
+pub struct SharedClaimsGuard<'a> {
+    entries: MutexGuard<'a, BTreeMap<String, Box<dyn Any + Send + Sync>>>,
+}
+
+impl SharedClaims {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn lock(&self) -> SharedClaimsGuard<'_> {
+        SharedClaimsGuard {
+            entries: self.entries.lock().await,
+        }
+    }
+
+    ///store `value` under `key`, returns previous value if it had same type
+    pub async fn put<T: Any + Send + Sync>(&self, key: &str, value: T) -> Option<T> {
+        self.lock().await.insert(key, value)
+    }
+
+    ///copy of value under `key`, None if absent or of another type
+    pub async fn get<T: Any + Send + Sync + Clone>(&self, key: &str) -> Option<T> {
+        self.lock().await.get::<T>(key).cloned()
+    }
+
+    pub async fn take<T: Any + Send + Sync>(&self, key: &str) -> Option<T> {
+        self.lock().await.remove(key)
+    }
+}
+
+impl SharedClaimsGuard<'_> {
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self, key: &str) -> Option<&T> {
+        self.entries.get(key)?.downcast_ref()
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self, key: &str) -> Option<&mut T> {
+        self.entries.get_mut(key)?.downcast_mut()
+    }
+
+    pub fn insert<T: Any + Send + Sync>(&mut self, key: &str, value: T) -> Option<T> {
+        self.entries
+            .insert(key.into(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    ///removes entry under `key` only if it holds a `T`
+    pub fn remove<T: Any + Send + Sync>(&mut self, key: &str) -> Option<T> {
+        if !self.entries.get(key)?.is::<T>() {
+            return None;
+        }
+        self.entries
+            .remove(key)
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    ///entry under `key`, inserted from `init` when absent or of another type
+    pub fn get_or_insert_with<T: Any + Send + Sync>(
+        &mut self,
+        key: &str,
+        init: impl FnOnce() -> T,
+    ) -> &mut T {
+        if self.get::<T>(key).is_none() {
+            self.entries.insert(key.into(), Box::new(init()));
+        }
+        self.get_mut(key).unwrap()
+    }
+}
@@ -0,0 +1,142 @@
+use alloc::{string::String, vec, vec::Vec};
+use async_lock::OnceCell;
+use log::{debug, warn};
+
+use crate::{
+    abstractions::PlatformAbstractions,
+    usb::operations::{
+        control::{ControlRequestBuilder, DescType},
+        UsbError,
+    },
+};
+
+use super::USBDevice;
+
+///refer usb2 spec 9.6.7
+const STRING_DESC_TYPE: u8 = 3;
+///string descriptors are at most this long, bLength is a byte
+const STRING_DESC_MAX_LEN: usize = 255;
+///asked for if device lists no language at all
+const LANG_ID_EN_US: u16 = 0x0409;
+///iManufacturer, iProduct and iSerialNumber in device descriptor
+const MANUFACTURER_INDEX_OFFSET: usize = 14;
+const PRODUCT_INDEX_OFFSET: usize = 15;
+const SERIAL_NUMBER_INDEX_OFFSET: usize = 16;
+
+///strings a device names itself with, read on first use and kept.
+///failed reads are not kept, next call asks device again
+#[derive(Default)]
+pub struct DeviceStrings {
+    lang_id: OnceCell<u16>,
+    manufacturer: OnceCell<Option<String>>,
+    product: OnceCell<Option<String>>,
+    serial_number: OnceCell<Option<String>>,
+}
+
+impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+{
+    ///string descriptor `index` in language `lang_id`, UTF-16LE decoded.
+    ///invalid code units turn into U+FFFD
+    pub async fn get_string_descriptor(&self, index: u8, lang_id: u16) -> Result<String, UsbError> {
+        let raw = self.read_string_descriptor(index, lang_id).await?;
+        let units = raw
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        Ok(char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect())
+    }
+
+    ///language ids device has strings in, from string descriptor 0
+    pub async fn string_languages(&self) -> Result<Vec<u16>, UsbError> {
+        let raw = self.read_string_descriptor(0, 0).await?;
+        Ok(raw
+            .chunks_exact(2)
+            .map(|lang| u16::from_le_bytes([lang[0], lang[1]]))
+            .collect())
+    }
+
+    pub async fn manufacturer(&self) -> Option<&str> {
+        self.cached_string(&self.strings.manufacturer, MANUFACTURER_INDEX_OFFSET)
+            .await
+    }
+
+    pub async fn product(&self) -> Option<&str> {
+        self.cached_string(&self.strings.product, PRODUCT_INDEX_OFFSET)
+            .await
+    }
+
+    pub async fn serial_number(&self) -> Option<&str> {
+        self.cached_string(&self.strings.serial_number, SERIAL_NUMBER_INDEX_OFFSET)
+            .await
+    }
+
+    ///first language device lists, en-US if it lists none or can't tell
+    async fn default_lang_id(&self) -> u16 {
+        *self
+            .strings
+            .lang_id
+            .get_or_init(|| async {
+                match self.string_languages().await {
+                    Ok(langs) => langs.first().copied().unwrap_or(LANG_ID_EN_US),
+                    Err(err) => {
+                        debug!(
+                            "device at {} has no language table: {}",
+                            self.topology_path, err
+                        );
+                        LANG_ID_EN_US
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn cached_string<'s>(
+        &'s self,
+        cell: &'s OnceCell<Option<String>>,
+        index_offset: usize,
+    ) -> Option<&'s str> {
+        cell.get_or_try_init(|| async {
+            let Some(index) = self
+                .device_desc_raw
+                .get()
+                .and_then(|raw| raw.get(index_offset).copied())
+                .filter(|index| *index != 0)
+            else {
+                return Ok(None);
+            };
+            let lang_id = self.default_lang_id().await;
+            self.get_string_descriptor(index, lang_id).await.map(Some)
+        })
+        .await
+        .inspect_err(|err| {
+            warn!(
+                "string at offset {} of device at {} unreadable: {}",
+                index_offset, self.topology_path, err
+            )
+        })
+        .ok()?
+        .as_deref()
+    }
+
+    ///payload of string descriptor, header stripped
+    async fn read_string_descriptor(&self, index: u8, lang_id: u16) -> Result<Vec<u8>, UsbError> {
+        let mut buffer = vec![0u8; STRING_DESC_MAX_LEN];
+        let length = self
+            .control_in_place(
+                ControlRequestBuilder::get_descriptor(DescType::String, index).index(lang_id),
+                &mut buffer,
+            )
+            .await?;
+
+        let length = length.min(buffer[0] as usize);
+        if length < 2 || buffer[1] != STRING_DESC_TYPE {
+            return Err(UsbError::BadDescriptor);
+        }
+        buffer.truncate(length);
+        buffer.drain(..2);
+        Ok(buffer)
+    }
+}
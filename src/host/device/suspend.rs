@@ -0,0 +1,88 @@
+use core::sync::atomic::Ordering;
+
+use futures::channel::oneshot;
+use log::{info, warn};
+
+use crate::{
+    abstractions::PlatformAbstractions,
+    usb::operations::{RequestResult, RequestedOperation, USBRequest},
+};
+
+use super::{DeviceState, USBDevice};
+
+impl<O, const RING_BUFFER_SIZE: usize> USBDevice<O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions,
+{
+    ///see [`Self::suspend`]
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.load(Ordering::Acquire)
+    }
+
+    ///selectively suspend device through its root port, refer usb2 spec 7.1.7.6.
+    ///
+    ///any later request resumes it first, drivers need not care.
+    ///devices behind hubs are refused with [`RequestResult::Invalid`], their ports belong to hub driver
+    pub async fn suspend(&self) -> Result<RequestResult, u8> {
+        if self.is_suspended() {
+            return Ok(RequestResult::Success);
+        }
+        if !self.check_self_status().await {
+            return Ok(RequestResult::SlotNotEnabledError);
+        }
+        let result = self.post_power(RequestedOperation::Suspend).await;
+        if let Ok(RequestResult::Success) = result {
+            self.suspended.store(true, Ordering::Release);
+            info!("device at {} suspended", self.topology_path);
+        }
+        result
+    }
+
+    ///wake device up from [`Self::suspend`]
+    pub async fn resume(&self) -> Result<RequestResult, u8> {
+        if !self.is_suspended() {
+            return Ok(RequestResult::Success);
+        }
+        let result = self.post_power(RequestedOperation::Resume).await;
+        match result {
+            Ok(RequestResult::Success) => {
+                self.suspended.store(false, Ordering::Release);
+                info!("device at {} resumed", self.topology_path);
+            }
+            _ => warn!(
+                "device at {} failed to resume: {:?}",
+                self.topology_path, result
+            ),
+        }
+        result
+    }
+
+    ///controller finished a resume the device asked for, see [`crate::event::RemoteWakeup`]
+    pub(crate) fn mark_resumed(&self) {
+        if self.suspended.swap(false, Ordering::AcqRel) {
+            info!("device at {} resumed", self.topology_path);
+        }
+    }
+
+    ///enumerated, on a root port, awake, and no driver is bound to it
+    pub(crate) async fn autosuspend_candidate(&self) -> bool {
+        self.attachment.is_none()
+            && !self.is_suspended()
+            && matches!(
+                *self.state.read().await,
+                DeviceState::Assigned | DeviceState::Configured
+            )
+            && self.bound_drivers.read().await.is_empty()
+    }
+
+    ///bypasses [`Self::check_self_status`], which would resume device on its own
+    async fn post_power(&self, operation: RequestedOperation) -> Result<RequestResult, u8> {
+        let (sender, receiver) = oneshot::channel();
+        self.post_usb_request(USBRequest::once(operation).simple_response(sender))
+            .await;
+        //callback dropped unanswered, device got unplugged meanwhile
+        receiver
+            .await
+            .unwrap_or(Ok(RequestResult::SlotNotEnabledError))
+    }
+}
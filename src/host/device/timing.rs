@@ -0,0 +1,93 @@
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+///points enumeration of a device passes, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EnumerationMilestone {
+    ///connect status settled, or device found at init
+    Connected,
+    PortReset,
+    ///address assigned and device descriptor read
+    Addressed,
+    ///every configuration descriptor read
+    Described,
+    ///configuration selected and power budget checked
+    Configured,
+    ///every driver module was offered the device
+    DriversStarted,
+}
+
+const MILESTONES: usize = EnumerationMilestone::DriversStarted as usize + 1;
+
+///when each milestone was passed, by [`crate::abstractions::PlatformAbstractions::now`].
+///stays empty on platforms without a clock source
+#[derive(Default)]
+pub struct EnumerationTiming {
+    ///nanoseconds plus one, 0 while not passed
+    marks: [AtomicU64; MILESTONES],
+}
+
+///time spent in each phase of enumeration, None for phases not measured.
+///a phase not measured is counted into the next measured one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EnumerationLatency {
+    pub reset: Option<Duration>,
+    pub address: Option<Duration>,
+    pub descriptors: Option<Duration>,
+    pub configure: Option<Duration>,
+    pub driver_start: Option<Duration>,
+    ///port connect to drivers started
+    pub total: Option<Duration>,
+}
+
+impl EnumerationTiming {
+    pub(crate) fn mark(&self, milestone: EnumerationMilestone, at: Option<Duration>) {
+        if let Some(at) = at {
+            self.marks[milestone as usize].store(at.as_nanos() as u64 + 1, Ordering::Release);
+        }
+    }
+
+    pub fn at(&self, milestone: EnumerationMilestone) -> Option<Duration> {
+        match self.marks[milestone as usize].load(Ordering::Acquire) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos - 1)),
+        }
+    }
+
+    ///all milestones passed so far
+    pub fn is_complete(&self) -> bool {
+        self.at(EnumerationMilestone::DriversStarted).is_some()
+    }
+
+    pub fn latency(&self) -> EnumerationLatency {
+        use EnumerationMilestone::*;
+        let marks = [
+            Connected,
+            PortReset,
+            Addressed,
+            Described,
+            Configured,
+            DriversStarted,
+        ]
+        .map(|milestone| self.at(milestone));
+        //from last milestone passed before, a phase needs both ends
+        let phase = |end: usize| {
+            let to = marks[end]?;
+            let from = marks[..end].iter().rev().find_map(|mark| *mark)?;
+            Some(to.saturating_sub(from))
+        };
+        EnumerationLatency {
+            reset: phase(PortReset as usize),
+            address: phase(Addressed as usize),
+            descriptors: phase(Described as usize),
+            configure: phase(Configured as usize),
+            driver_start: phase(DriversStarted as usize),
+            total: marks[DriversStarted as usize]
+                .zip(marks[Connected as usize])
+                .map(|(to, from)| to.saturating_sub(from)),
+        }
+    }
+}
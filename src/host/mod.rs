@@ -1,2 +1,4 @@
+pub(crate) mod bandwidth;
+#[cfg(feature = "host-controller")]
 pub(crate) mod controllers;
 pub(crate) mod device;
@@ -0,0 +1,593 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use async_lock::{Mutex, OnceCell, RwLock};
+use embassy_futures::{block_on, select::select, yield_now};
+use futures::{
+    future::{join, join3, join_all},
+    join,
+    task::AtomicWaker,
+};
+use log::{info, trace, warn};
+use usb_descriptor_decoder::DescriptorDecoder;
+
+use crate::{
+    abstractions::{dma_tracker, filter::DeviceFilter, PlatformAbstractions, USBSystemConfig},
+    driver::{
+        device_node::DeviceNode,
+        driverapi::{OutputStream, USBSystemDriverModule},
+    },
+    event::{EventBus, PowerOverBudget},
+    host::{
+        controllers::{Controller, InitError, Outstanding, Statistics},
+        device::{DeviceState, EnumerationMilestone, USBDevice},
+    },
+    usb::{
+        capabilities::{ApiVersion, Capabilities, API_VERSION},
+        claims::BindError,
+        configuration::ConfigurationPolicy,
+        functional_interface::{USBLayer, UnclaimedInterface},
+        operations::RequestResult,
+        snapshot::{DeviceSnapshot, TopologySnapshot},
+        standards::TopologyRoute,
+    },
+};
+
+pub struct USBSystem<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
+    ///in order of [`USBSystemConfig::controller_descs`]
+    controllers: Vec<Box<dyn Controller<'a, O, RING_BUFFER_SIZE>>>,
+    usb_layer: USBLayer<'a, O, RING_BUFFER_SIZE>,
+    event_bus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
+    desc_decoder: Arc<RwLock<DescriptorDecoder>>,
+    ///set once initial enumeration finished
+    ready: OnceCell<()>,
+    ///probed by controller, waiting for enumeration
+    attached: Mutex<VecDeque<Arc<USBDevice<O, RING_BUFFER_SIZE>>>>,
+    ///set by [`Self::stop`], ends [`Self::async_run`]
+    stopping: AtomicBool,
+    ///[`Self::async_run`] waiting for `stopping`
+    stop_waker: AtomicWaker,
+    ///controllers brought up and not shut down since, drop shuts them down otherwise
+    running: AtomicBool,
+}
+
+impl<'a, O, const RING_BUFFER_SIZE: usize> USBSystem<'a, O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    pub fn new(config: USBSystemConfig<O, RING_BUFFER_SIZE>) -> Self {
+        let config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>> = config.into();
+        let event_bus = Arc::new(EventBus::new());
+        let controllers = config
+            .controller_descs()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, desc)| {
+                //each controller sees its own registers and interrupt line
+                let controller_config = Arc::new(USBSystemConfig {
+                    base_addr: desc.base_addr,
+                    wake_method: desc.wake_method,
+                    extra_controllers: Vec::new(),
+                    ..(*config).clone()
+                });
+                let controller = crate::host::controllers::initialize_controller(
+                    desc.kind,
+                    index as _,
+                    controller_config,
+                    event_bus.clone(),
+                );
+                if controller.is_none() {
+                    warn!(
+                        "controller {} is {:?}, backend not compiled in, skipped",
+                        index, desc.kind
+                    );
+                }
+                controller
+            })
+            .collect();
+        let usb_layer = USBLayer::new(config.clone(), event_bus.clone());
+
+        let usbsystem = USBSystem {
+            config,
+            controllers,
+            usb_layer,
+            event_bus,
+            desc_decoder: Arc::new(RwLock::new(DescriptorDecoder::new())),
+            ready: OnceCell::new(),
+            attached: Mutex::new(VecDeque::new()),
+            stopping: AtomicBool::new(false),
+            stop_waker: AtomicWaker::new(),
+            running: AtomicBool::new(false),
+        };
+
+        #[cfg(feature = "packed-drivers")]
+        {
+            usbsystem.plug_driver_module(
+                "hid-mouse".to_string(),
+                Box::new(crate::driver::implemented_drivers::hid_mouse::HIDMouseModule {}),
+            );
+            usbsystem.plug_driver_module(
+                "hub".to_string(),
+                Box::new(crate::driver::implemented_drivers::hub::HubModule {}),
+            );
+        }
+
+        usbsystem
+    }
+
+    ///could be called while running, see [`USBLayer::plug_driver_module`]
+    pub fn plug_driver_module(
+        &self,
+        name: String,
+        mut module: Box<dyn USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>>,
+    ) -> &Self {
+        if !API_VERSION.satisfies(module.api_version()) {
+            warn!(
+                "driver module {} wants api {}, but this is {}, refused",
+                name,
+                module.api_version(),
+                API_VERSION
+            );
+            return self;
+        }
+        let required = module.required_capabilities();
+        let capabilities = self.capabilities();
+        if !capabilities.contains(required) {
+            warn!(
+                "driver module {} requires {:?}, missing {:?}, refused",
+                name,
+                required,
+                required.difference(capabilities)
+            );
+            return self;
+        }
+
+        module.as_mut().preload_module(); //add some hooks?
+        self.usb_layer.plug_driver_module(name, module);
+
+        self
+    }
+
+    ///instances of module plugged as `name` are torn down, false if there is none
+    pub fn unplug_driver_module(&self, name: &str) -> bool {
+        self.usb_layer.unplug_driver_module(name)
+    }
+
+    ///interfaces no driver took, candidates for [`Self::bind_interface`]
+    pub async fn unclaimed_interfaces(&self) -> Vec<UnclaimedInterface<O, RING_BUFFER_SIZE>> {
+        self.usb_layer.unclaimed_interfaces().await
+    }
+
+    ///see [`USBLayer::bind_interface`]
+    pub async fn bind_interface(
+        &self,
+        name: &str,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        interface_number: u8,
+    ) -> Result<(), BindError> {
+        self.usb_layer
+            .bind_interface(name, device, interface_number)
+            .await
+    }
+
+    ///which configuration devices enumerated from now on run with, see [`ConfigurationPolicy`]
+    pub fn set_configuration_policy(&self, policy: Option<Arc<dyn ConfigurationPolicy>>) -> &Self {
+        self.usb_layer.set_configuration_policy(policy);
+        self
+    }
+
+    ///panics if controller can't be brought up, see [`Self::try_stage_1_start_controller`]
+    pub fn stage_1_start_controller(&'a self) -> &Self {
+        self.try_stage_1_start_controller()
+            .unwrap_or_else(|err| panic!("controller init failed: {}", err))
+    }
+
+    pub fn try_stage_1_start_controller(&'a self) -> Result<&Self, InitError> {
+        self.event_bus.pre_initialize_device.subscribe(|dev| {
+            trace!("adding decoder ref to device!");
+            block_on(dev.add_decoder(self.desc_decoder.clone()));
+            block_on(self.attached.lock()).push_back(dev.clone());
+            squeak::Response::StaySubscribed
+        });
+        self.init_controllers()?;
+        info!("controller init complete!");
+        Ok(self)
+    }
+
+    pub fn stage_2_initialize_usb_layer(&'a self) -> &'a Self {
+        self.event_bus.post_initialized_device.subscribe(|dev| {
+            self.usb_layer.new_device_initialized(dev.clone());
+            squeak::Response::StaySubscribed
+        });
+        self.event_bus.device_removed.subscribe(|dev| {
+            self.usb_layer.device_removed(dev.clone());
+            dev.bandwidth.release_device(&dev.topology_path);
+            squeak::Response::StaySubscribed
+        });
+
+        info!("usb layer init complete!");
+        self
+    }
+
+    ///on failure, controllers brought up before the failing one are shut down again
+    fn init_controllers(&self) -> Result<(), InitError> {
+        for (index, controller) in self.controllers.iter().enumerate() {
+            if let Err(err) = controller.init() {
+                warn!("controller {} init failed: {}", index, err);
+                self.controllers[..index]
+                    .iter()
+                    .for_each(|controller| controller.shutdown());
+                return Err(err);
+            }
+        }
+        self.running.store(true, Ordering::Release);
+        //controllers reserved what they need, nothing is allocated anew from here on
+        if self.config.bounded_memory.is_some() {
+            self.config.os.seal_dma();
+            info!("DMA memory sealed");
+        }
+        Ok(())
+    }
+
+    async fn inner_stage_3_initial_controller_polling_and_deivces(&self) {
+        self.controllers
+            .iter()
+            .for_each(|controller| controller.rescan());
+        let initial: Vec<_> = self.attached.lock().await.drain(..).collect();
+        join_all(
+            initial
+                .iter()
+                .map(|device| self.enumerate_device(device))
+                .collect::<Vec<_>>(),
+        )
+        .await;
+
+        info!("controller poll and initial device init complete!");
+        let _ = self.ready.set(()).await;
+
+        join(self.hotplug_loop(), self.autosuspend_loop()).await;
+    }
+
+    ///suspends devices left without drivers, see [`USBSystemConfig::autosuspend`]
+    async fn autosuspend_loop(&self) {
+        let Some(idle) = self.config.autosuspend else {
+            return;
+        };
+        let mut driverless_since: BTreeMap<TopologyRoute, Duration> = BTreeMap::new();
+        loop {
+            if let Some(now) = self.config.os.now() {
+                let mut still_driverless = BTreeMap::new();
+                for controller in &self.controllers {
+                    for device in controller.device_accesses() {
+                        if !device.autosuspend_candidate().await {
+                            continue;
+                        }
+                        let route = device.topology_path.clone();
+                        let since = driverless_since.get(&route).copied().unwrap_or(now);
+                        if now.saturating_sub(since) < idle {
+                            still_driverless.insert(route, since);
+                        } else if !matches!(device.suspend().await, Ok(RequestResult::Success)) {
+                            //not retried before another idle period passed
+                            still_driverless.insert(route, now);
+                        }
+                    }
+                }
+                driverless_since = still_driverless;
+            }
+            yield_now().await
+        }
+    }
+
+    async fn enumerate_device(&self, device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        if let Some(hook) = self.event_bus.init_hooks.run(device).await {
+            info!(
+                "device at {} vetoed by init hook {}",
+                device.topology_path, hook
+            );
+            *device.state.write().await = DeviceState::Rejected;
+            return;
+        }
+        //unplugged again before its turn came, or while hooks held it back
+        if matches!(*device.state.read().await, DeviceState::PreDrop) {
+            return;
+        }
+        //failure is logged and recorded in device state, other devices carry on
+        if device.request_assign().await.is_err() {
+            return;
+        }
+        //before power is judged, that depends on configuration
+        self.usb_layer.apply_configuration_policy(device).await;
+        if let Some((config, decision)) = device.power_decision().await
+            && decision.is_over()
+        {
+            self.event_bus.power_over_budget.broadcast(PowerOverBudget {
+                device: device.clone(),
+                config,
+                decision,
+                refused: self.config.power_policy.enforce,
+            });
+        }
+        device.mark_milestone(EnumerationMilestone::Configured);
+        if !device.is_rejected().await {
+            self.event_bus
+                .post_initialized_device
+                .broadcast(device.clone());
+        }
+    }
+
+    ///devices attached after initial enumeration go through the same steps, one at a time
+    async fn hotplug_loop(&self) {
+        loop {
+            let next = self.attached.lock().await.pop_front();
+            match next {
+                Some(device) => {
+                    info!("hot plugged device at {}", device.topology_path);
+                    self.enumerate_device(&device).await
+                }
+                None => yield_now().await,
+            }
+        }
+    }
+
+    ///resolves once controller is running and initial enumeration finished
+    pub async fn ready(&self) {
+        self.ready.wait().await;
+    }
+
+    ///like [`Self::ready`], but gives up after `timeout`, returns whether system got ready.
+    ///
+    ///platforms without clock source never time out.
+    pub async fn ready_within(&self, timeout: Duration) -> bool {
+        let start = self.config.os.now();
+        loop {
+            if self.ready.is_initialized() {
+                return true;
+            }
+            if let (Some(start), Some(now)) = (start, self.config.os.now())
+                && now.saturating_sub(start) >= timeout
+            {
+                return false;
+            }
+            yield_now().await
+        }
+    }
+
+    ///block new submissions and resolve once all outstanding transfers completed.
+    ///
+    ///pending interrupt IN polls only complete when device sends data,
+    ///so pair it with a timeout if such drivers are running.
+    pub async fn quiesce(&'a self) {
+        join_all(
+            self.controllers
+                .iter()
+                .map(|controller| controller.quiesce()),
+        )
+        .await;
+    }
+
+    ///release submissions held back by [`Self::quiesce`]
+    pub async fn resume(&'a self) {
+        join_all(
+            self.controllers
+                .iter()
+                .map(|controller| controller.resume()),
+        )
+        .await;
+    }
+
+    pub async fn async_run(&'a self) {
+        //TODO structure run logic
+        // join(self.controller.workaround(), self.usb_layer.workaround()).await
+        info!("usb system workaround...");
+        select(
+            join3(
+                self.inner_stage_3_initial_controller_polling_and_deivces(),
+                join_all(
+                    self.controllers
+                        .iter()
+                        .map(|controller| controller.workaround()),
+                ),
+                self.usb_layer.functional_interface_workaround(),
+            ),
+            poll_fn(|cx| {
+                self.stop_waker.register(cx.waker());
+                if self.stopping.load(Ordering::Acquire) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }),
+        )
+        .await;
+        info!("usb system stopped");
+    }
+
+    ///event handling of every controller, for kernels driving completions from their own reactor.
+    ///
+    ///controller side of [`Self::async_run`] together with [`Self::poll_scheduler`],
+    ///Ready once no controller is running
+    pub fn poll_events(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_controllers(|controller| controller.poll_events(cx))
+    }
+
+    ///request submission and completion delivery of every controller, see [`Self::poll_events`]
+    pub fn poll_scheduler(&'a self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_controllers(|controller| controller.poll_scheduler(cx))
+    }
+
+    ///every controller is polled, even if an earlier one is pending
+    fn poll_controllers(
+        &'a self,
+        mut poll: impl FnMut(&'a dyn Controller<'a, O, RING_BUFFER_SIZE>) -> Poll<()>,
+    ) -> Poll<()> {
+        self.controllers
+            .iter()
+            .fold(Poll::Ready(()), |all, controller| {
+                match (all, poll(controller.as_ref())) {
+                    (Poll::Ready(()), Poll::Ready(())) => Poll::Ready(()),
+                    _ => Poll::Pending,
+                }
+            })
+    }
+
+    ///make [`Self::async_run`] (and so [`Self::block_run`]) return, dropping every future it polls.
+    ///
+    ///could be called from another task or interrupt handler, follow it with [`Self::shutdown`]
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::Release);
+        self.stop_waker.wake();
+    }
+
+    ///what compiled features and running backend support together
+    pub fn capabilities(&self) -> Capabilities {
+        self.controllers
+            .iter()
+            .fold(Capabilities::compiled(), |caps, controller| {
+                caps | controller.capabilities()
+            })
+    }
+
+    pub fn api_version(&self) -> ApiVersion {
+        API_VERSION
+    }
+
+    pub fn device_filter(&self) -> &DeviceFilter {
+        &self.config.device_filter
+    }
+
+    pub async fn topology_snapshot(&self) -> TopologySnapshot {
+        let mut devices = Vec::new();
+        for controller in &self.controllers {
+            for device in controller.device_accesses() {
+                devices.push(DeviceSnapshot::capture(&device).await);
+            }
+        }
+        TopologySnapshot { devices }
+    }
+
+    ///subscribe output of every instance of driver module `driver` bound so far, e.g. `hid_mouse`.
+    ///
+    ///instances producing something else than `T` are skipped
+    pub async fn subscribe_output<T: Send + 'static>(
+        &self,
+        driver: &str,
+    ) -> Vec<(TopologyRoute, OutputStream<T>)> {
+        self.usb_layer.subscribe_output(driver).await
+    }
+
+    ///every bound driver instance with its topology derived name, see [`DeviceNode`]
+    pub async fn device_nodes(&self) -> Vec<DeviceNode> {
+        self.usb_layer.device_nodes().await
+    }
+
+    pub fn block_run(&'a self) {
+        block_on(self.async_run())
+    }
+
+    ///tear down whole stack once [`Self::async_run`] returned, returns count of DMA objects still alive (debug builds only).
+    ///
+    ///driver instances get `pre_drop`, then controller is halted and memory of it and its devices is released.
+    ///bring it up again with [`Self::restart`]
+    pub fn shutdown(&self) -> usize {
+        self.running.store(false, Ordering::Release);
+        self.usb_layer.shutdown();
+        self.controllers
+            .iter()
+            .for_each(|controller| controller.shutdown());
+        dma_tracker::report()
+    }
+
+    ///work each controller has not finished yet, in order of [`USBSystemConfig::controller_descs`]
+    pub fn outstanding(&self) -> Vec<Outstanding> {
+        self.controllers
+            .iter()
+            .map(|controller| controller.outstanding())
+            .collect()
+    }
+
+    ///counters of each controller, in order of [`USBSystemConfig::controller_descs`]
+    pub fn stats(&self) -> Vec<Statistics> {
+        self.controllers
+            .iter()
+            .map(|controller| controller.stats())
+            .collect()
+    }
+
+    ///start counting over, e.g. before a measurement run
+    pub fn reset_stats(&self) {
+        self.controllers
+            .iter()
+            .for_each(|controller| controller.reset_stats());
+    }
+
+    ///recent TRBs of each controller, in order of [`USBSystemConfig::controller_descs`].
+    ///`Display` of it is meant to be pasted into bug reports
+    #[cfg(feature = "trace_trb_ring")]
+    pub fn trb_trace(&self) -> Vec<crate::host::controllers::TrbTrace> {
+        self.controllers
+            .iter()
+            .map(|controller| controller.trb_trace())
+            .collect()
+    }
+
+    ///summary of what is left, to tell why a shutdown hangs or what it cut short
+    fn log_outstanding(&self) {
+        for (index, outstanding) in self.outstanding().into_iter().enumerate() {
+            if !outstanding.is_idle() {
+                warn!("controller {} left with {}", index, outstanding);
+            }
+        }
+        let nodes = block_on(self.usb_layer.device_nodes());
+        if !nodes.is_empty() {
+            warn!("{} driver instances still bound:", nodes.len());
+            nodes
+                .iter()
+                .for_each(|node| warn!("  {} ({})", node.name, node.driver));
+        }
+    }
+
+    ///init controller again after [`Self::shutdown`], next [`Self::async_run`] enumerates devices anew.
+    ///
+    ///event subscriptions of earlier stages are kept, [`Self::ready`] stays resolved
+    pub fn restart(&self) -> Result<&Self, InitError> {
+        self.stopping.store(false, Ordering::Release);
+        self.init_controllers()?;
+        info!("controller restarted!");
+        Ok(self)
+    }
+}
+
+///hardware left running would keep writing into memory freed right after,
+///so controllers not shut down yet are shut down here
+impl<'a, O, const RING_BUFFER_SIZE: usize> Drop for USBSystem<'a, O, RING_BUFFER_SIZE>
+where
+    O: PlatformAbstractions + 'static,
+    'a: 'static,
+{
+    fn drop(&mut self) {
+        self.log_outstanding();
+        if self.running.load(Ordering::Acquire) {
+            warn!("usb system dropped while running, shutting down");
+            self.stop();
+            //leaks are reported by shutdown itself
+            self.shutdown();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use core::fmt::Display;
+
+use bitflags::bitflags;
+
+///version of driver module api, major bumps on incompatible changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ApiVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+pub const API_VERSION: ApiVersion = ApiVersion { major: 0, minor: 1 };
+
+impl ApiVersion {
+    ///a module built against `required` works with this one
+    pub const fn satisfies(&self, required: ApiVersion) -> bool {
+        self.major == required.major && self.minor >= required.minor
+    }
+}
+
+impl Display for ApiVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+bitflags! {
+    ///what host stack could do, out-of-tree driver modules check it before loading
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize))]
+    pub struct Capabilities: u32 {
+        const CONTROL = 1 << 0;
+        const INTERRUPT = 1 << 1;
+        const BULK = 1 << 2;
+        const ISOCH = 1 << 3;
+        ///keep-filling interrupt and bulk IN transfers
+        const KEEP_FILL = 1 << 4;
+        ///bulk streams, usb3 only, see [`crate::usb::operations::bulk::BulkTransfer::stream_id`]
+        const BULK_STREAMS = 1 << 5;
+        ///class/vendor/raw control requests, see [`crate::usb::operations::control::bRequest`]
+        const RAW_REQUESTS = 1 << 6;
+        const LINK_POWER_MANAGEMENT = 1 << 7;
+        const REMOTE_WAKEUP = 1 << 8;
+        const HOT_PLUG = 1 << 9;
+        ///devices behind external hubs, full/low speed ones included only if backend does split transactions
+        const HUBS = 1 << 10;
+        const PACKED_DRIVERS = 1 << 16;
+        const SERDE = 1 << 17;
+        const BACKEND_XHCI = 1 << 24;
+        const BACKEND_EHCI = 1 << 25;
+    }
+}
+
+impl Capabilities {
+    ///what every backend got, plus what the crate was compiled with
+    pub const fn compiled() -> Self {
+        let mut caps = Self::CONTROL
+            .union(Self::INTERRUPT)
+            .union(Self::BULK)
+            .union(Self::KEEP_FILL)
+            .union(Self::RAW_REQUESTS);
+        if cfg!(feature = "packed-drivers") {
+            caps = caps.union(Self::PACKED_DRIVERS);
+        }
+        if cfg!(feature = "serde") {
+            caps = caps.union(Self::SERDE);
+        }
+        caps
+    }
+}
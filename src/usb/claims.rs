@@ -0,0 +1,84 @@
+use core::fmt::Display;
+
+use alloc::{collections::btree_map::BTreeMap, string::String};
+
+use super::standards::TopologyRoute;
+
+///interface a driver instance drives, None for instances driving device as a whole
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InterfaceKey {
+    pub route: TopologyRoute,
+    pub interface: Option<u8>,
+    pub alternate: u8,
+}
+
+impl InterfaceKey {
+    ///alternate settings of one interface exclude each other, whole device claims excludes everything
+    fn conflicts(&self, other: &Self) -> bool {
+        self.route == other.route
+            && (self.interface.is_none()
+                || other.interface.is_none()
+                || self.interface == other.interface)
+    }
+}
+
+///why an interface could not be bound on request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindError {
+    NoSuchModule,
+    ///not in current configuration of device
+    NoSuchInterface,
+    ///driven by this module already
+    Claimed(String),
+    ///module's bind_matched returned None
+    Refused,
+}
+
+impl Display for BindError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BindError::NoSuchModule => write!(f, "no module plugged under that name"),
+            BindError::NoSuchInterface => write!(f, "no such interface in current configuration"),
+            BindError::Claimed(holder) => write!(f, "interface is driven by {}", holder),
+            BindError::Refused => write!(f, "module refused the interface"),
+        }
+    }
+}
+
+///which driver module drives which interface, one module per interface at a time.
+///
+///modules are offered devices in priority order, so the first claim is the one that stays
+#[derive(Debug, Default)]
+pub struct InterfaceClaims {
+    claims: BTreeMap<InterfaceKey, String>,
+}
+
+impl InterfaceClaims {
+    ///module holding an interface `key` conflicts with
+    pub fn holder(&self, key: &InterfaceKey) -> Option<&str> {
+        self.claims
+            .iter()
+            .find(|(claimed, _)| claimed.conflicts(key))
+            .map(|(_, driver)| driver.as_str())
+    }
+
+    ///Err with current holder if interface is taken
+    pub fn claim(&mut self, key: InterfaceKey, driver: &str) -> Result<(), String> {
+        if let Some(holder) = self.holder(&key) {
+            return Err(holder.into());
+        }
+        self.claims.insert(key, driver.into());
+        Ok(())
+    }
+
+    pub fn release(&mut self, key: &InterfaceKey) {
+        self.claims.remove(key);
+    }
+
+    ///every claim, e.g. for debugging why a module didn't bind
+    pub fn iter(&self) -> impl Iterator<Item = (&InterfaceKey, &str)> {
+        self.claims
+            .iter()
+            .map(|(key, driver)| (key, driver.as_str()))
+    }
+}
@@ -0,0 +1,116 @@
+//! class and vendor specific descriptors interleaved in configuration data, kept raw for drivers
+//! to parse by themselves, e.g. UVC or UAC class headers.
+
+use alloc::vec::Vec;
+
+const CONFIG_DESC_TYPE: u8 = 2;
+const INTERFACE_DESC_TYPE: u8 = 4;
+const ENDPOINT_DESC_TYPE: u8 = 5;
+const INTERFACE_ASSOCIATION_DESC_TYPE: u8 = 0x0b;
+const SS_COMPANION_DESC_TYPE: u8 = 0x30;
+const SSP_ISOCH_COMPANION_DESC_TYPE: u8 = 0x31;
+const CONFIG_DESC_LEN: usize = 9;
+
+///one descriptor standard parsing skipped, bLength and bDescriptorType included
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassDescriptor {
+    raw: Vec<u8>,
+}
+
+impl ClassDescriptor {
+    pub fn descriptor_type(&self) -> u8 {
+        self.raw[1]
+    }
+
+    ///bDescriptorSubtype, class specific descriptors of most classes carry one
+    pub fn subtype(&self) -> Option<u8> {
+        self.raw.get(2).copied()
+    }
+
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+///class specific descriptors of one alternate setting, see [`ConfigClassDescriptors::of`]
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceClassDescriptors {
+    ///following interface descriptor, before its first endpoint
+    pub interface: Vec<ClassDescriptor>,
+    ///(bEndpointAddress, descriptor) following each endpoint descriptor
+    pub endpoints: Vec<(u8, ClassDescriptor)>,
+}
+
+impl InterfaceClassDescriptors {
+    pub fn endpoint(&self, address: u8) -> impl Iterator<Item = &ClassDescriptor> {
+        self.endpoints
+            .iter()
+            .filter(move |(owner, _)| *owner == address)
+            .map(|(_, desc)| desc)
+    }
+}
+
+///class specific descriptors of every alternate setting of one configuration
+#[derive(Debug, Clone, Default)]
+pub struct ConfigClassDescriptors {
+    pub config_value: u8,
+    ///(bInterfaceNumber, bAlternateSetting)
+    entries: Vec<((u8, u8), InterfaceClassDescriptors)>,
+}
+
+impl ConfigClassDescriptors {
+    ///walks raw configuration descriptor, what follows an interface or endpoint belongs to it
+    pub fn from_config_desc(raw: &[u8]) -> Option<Self> {
+        if raw.len() < CONFIG_DESC_LEN {
+            return None;
+        }
+        let total = (u16::from_le_bytes([raw[2], raw[3]]) as usize).min(raw.len());
+        let mut descriptors = Self {
+            config_value: raw[5],
+            entries: Vec::new(),
+        };
+
+        let mut endpoint = None;
+        let mut offset = raw[0] as usize;
+        while offset + 2 <= total {
+            let len = raw[offset] as usize;
+            if len < 2 || offset + len > total {
+                break;
+            }
+            let desc = &raw[offset..offset + len];
+            match desc[1] {
+                INTERFACE_DESC_TYPE if len >= 4 => {
+                    descriptors
+                        .entries
+                        .push(((desc[2], desc[3]), InterfaceClassDescriptors::default()));
+                    endpoint = None;
+                }
+                ENDPOINT_DESC_TYPE if len >= 3 => endpoint = Some(desc[2]),
+                //association groups interfaces, it belongs to none of them
+                INTERFACE_ASSOCIATION_DESC_TYPE => endpoint = None,
+                CONFIG_DESC_TYPE | SS_COMPANION_DESC_TYPE | SSP_ISOCH_COMPANION_DESC_TYPE => {}
+                _ => {
+                    //anything before first interface has no owner
+                    if let Some((_, owner)) = descriptors.entries.last_mut() {
+                        let desc = ClassDescriptor { raw: desc.to_vec() };
+                        match endpoint {
+                            Some(address) => owner.endpoints.push((address, desc)),
+                            None => owner.interface.push(desc),
+                        }
+                    }
+                }
+            }
+            offset += len;
+        }
+        Some(descriptors)
+    }
+
+    ///empty if alternate setting has none, or does not exist
+    pub fn of(&self, interface: u8, alternate: u8) -> InterfaceClassDescriptors {
+        self.entries
+            .iter()
+            .find(|(key, _)| *key == (interface, alternate))
+            .map(|(_, descriptors)| descriptors.clone())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,96 @@
+use alloc::vec::Vec;
+
+const INTERFACE_DESC_TYPE: u8 = 4;
+const ENDPOINT_DESC_TYPE: u8 = 5;
+const SS_COMPANION_DESC_TYPE: u8 = 0x30;
+const CONFIG_DESC_LEN: usize = 9;
+const SS_COMPANION_DESC_LEN: usize = 6;
+///MaxStreams field of bulk endpoints, refer usb3 spec table 9-27
+const BULK_MAX_STREAMS_MASK: u8 = 0x1f;
+///Mult field of isoch endpoints
+const ISOCH_MULT_MASK: u8 = 0x3;
+
+///superspeed endpoint companion descriptor, refer usb3 spec 9.6.7
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SsEndpointCompanion {
+    ///packets per burst minus one
+    pub max_burst: u8,
+    attributes: u8,
+    pub bytes_per_interval: u16,
+}
+
+impl SsEndpointCompanion {
+    ///streams a bulk endpoint supports, 0 if none
+    pub fn max_streams(&self) -> u32 {
+        match self.attributes & BULK_MAX_STREAMS_MASK {
+            0 => 0,
+            exp => 1 << exp,
+        }
+    }
+
+    ///bursts per service interval minus one, isoch only
+    pub fn mult(&self) -> u8 {
+        self.attributes & ISOCH_MULT_MASK
+    }
+}
+
+///companions of every endpoint of one configuration
+#[derive(Debug, Clone, Default)]
+pub struct ConfigCompanions {
+    pub config_value: u8,
+    ///(bInterfaceNumber, bAlternateSetting, bEndpointAddress)
+    entries: Vec<((u8, u8, u8), SsEndpointCompanion)>,
+}
+
+impl ConfigCompanions {
+    ///walks raw configuration descriptor, a companion directly follows its endpoint descriptor
+    pub fn from_config_desc(raw: &[u8]) -> Option<Self> {
+        if raw.len() < CONFIG_DESC_LEN {
+            return None;
+        }
+        let total = (u16::from_le_bytes([raw[2], raw[3]]) as usize).min(raw.len());
+        let mut companions = Self {
+            config_value: raw[5],
+            entries: Vec::new(),
+        };
+
+        let (mut interface, mut endpoint) = (None, None);
+        let mut offset = raw[0] as usize;
+        while offset + 2 <= total {
+            let len = raw[offset] as usize;
+            if len < 2 || offset + len > total {
+                break;
+            }
+            let desc = &raw[offset..offset + len];
+            match desc[1] {
+                INTERFACE_DESC_TYPE if len >= 4 => {
+                    interface = Some((desc[2], desc[3]));
+                    endpoint = None;
+                }
+                ENDPOINT_DESC_TYPE if len >= 3 => endpoint = Some(desc[2]),
+                SS_COMPANION_DESC_TYPE if len >= SS_COMPANION_DESC_LEN => {
+                    if let (Some((number, alt)), Some(address)) = (interface, endpoint.take()) {
+                        companions.entries.push((
+                            (number, alt, address),
+                            SsEndpointCompanion {
+                                max_burst: desc[2],
+                                attributes: desc[3],
+                                bytes_per_interval: u16::from_le_bytes([desc[4], desc[5]]),
+                            },
+                        ));
+                    }
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+        Some(companions)
+    }
+
+    pub fn get(&self, interface: u8, alternate: u8, address: u8) -> Option<SsEndpointCompanion> {
+        self.entries
+            .iter()
+            .find(|(key, _)| *key == (interface, alternate, address))
+            .map(|(_, companion)| *companion)
+    }
+}
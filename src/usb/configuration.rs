@@ -0,0 +1,30 @@
+use crate::{abstractions::filter::DeviceIdentity, host::device::ConfigurationInfo};
+
+///picks which configuration a device runs with, asked once per device before drivers bind.
+///
+///set it with [`crate::USBSystem::set_configuration_policy`], without one devices stay on their first configuration
+pub trait ConfigurationPolicy: Send + Sync {
+    ///value of configuration to use, None keeps the current one
+    fn select(
+        &self,
+        identity: Option<DeviceIdentity>,
+        configurations: &[ConfigurationInfo],
+    ) -> Option<u8>;
+}
+
+///first configuration having an interface of given class, e.g. HID over a vendor protocol
+#[derive(Debug, Clone, Copy)]
+pub struct PreferInterfaceClass(pub u8);
+
+impl ConfigurationPolicy for PreferInterfaceClass {
+    fn select(
+        &self,
+        _identity: Option<DeviceIdentity>,
+        configurations: &[ConfigurationInfo],
+    ) -> Option<u8> {
+        configurations
+            .iter()
+            .find(|config| config.has_interface_class(self.0))
+            .map(|config| config.value)
+    }
+}
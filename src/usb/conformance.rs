@@ -0,0 +1,113 @@
+//! what devices declare, checked against usb spec.
+//!
+//! [`SpecPolicy`] decides whether a violation refuses the device or gets clamped into range
+
+use core::fmt::Display;
+
+use usb_descriptor_decoder::descriptors::desc_endpoint::{Endpoint, EndpointType};
+
+use crate::{
+    abstractions::SpecPolicy,
+    usb::operations::{hub::DeviceSpeed, UsbError},
+};
+
+const PACKET_SIZE_MASK: u16 = 0x7ff;
+///additional transactions per microframe, refer usb2 spec 9.6.6
+const ADDITIONAL_TRANSACTIONS_SHIFT: u16 = 11;
+const ADDITIONAL_TRANSACTIONS_MASK: u16 = 0x3;
+const MAX_ADDITIONAL_TRANSACTIONS: u16 = 2;
+
+///largest wMaxPacketSize an endpoint may declare, refer usb2 spec 5.5 to 5.8 and usb3 spec 9.6.6
+fn packet_limit(speed: DeviceSpeed, endpoint_type: EndpointType) -> u16 {
+    let isoch = matches!(
+        endpoint_type,
+        EndpointType::IsochIn | EndpointType::IsochOut
+    );
+    let bulk = matches!(endpoint_type, EndpointType::BulkIn | EndpointType::BulkOut);
+    match speed {
+        DeviceSpeed::Low => 8,
+        DeviceSpeed::Full if isoch => 1023,
+        DeviceSpeed::Full => 64,
+        DeviceSpeed::High if bulk => 512,
+        DeviceSpeed::High if endpoint_type == EndpointType::Control => 64,
+        DeviceSpeed::High => 1024,
+        DeviceSpeed::Super if endpoint_type == EndpointType::Control => 512,
+        DeviceSpeed::Super => 1024,
+    }
+}
+
+///endpoint values as they get programmed, within spec unless strict policy refused them
+#[derive(Debug, Clone, Copy)]
+pub struct CheckedEndpoint {
+    ///in frames for low/full speed interrupt endpoints, an exponent otherwise
+    pub interval: u8,
+    ///additional transactions are kept in bits 12:11 on high speed periodic endpoints
+    pub max_packet_size: u16,
+}
+
+impl CheckedEndpoint {
+    ///`device` names it in reports
+    pub fn check(
+        policy: SpecPolicy,
+        device: &impl Display,
+        speed: DeviceSpeed,
+        endpoint: &Endpoint,
+    ) -> Result<Self, UsbError> {
+        let endpoint_type = endpoint.endpoint_type();
+        let dci = endpoint.doorbell_value_aka_dci();
+        let periodic = matches!(
+            endpoint_type,
+            EndpointType::InterruptIn
+                | EndpointType::InterruptOut
+                | EndpointType::IsochIn
+                | EndpointType::IsochOut
+        );
+
+        let mut interval = endpoint.interval;
+        if periodic {
+            let frames = speed.needs_tt()
+                && matches!(
+                    endpoint_type,
+                    EndpointType::InterruptIn | EndpointType::InterruptOut
+                );
+            let range = if frames { 1..=255 } else { 1..=16 };
+            if !range.contains(&interval) {
+                policy.check(format_args!(
+                    "{} dci {} bInterval {} out of {:?}",
+                    device, dci, interval, range
+                ))?;
+                interval = interval.clamp(*range.start(), *range.end());
+            }
+        }
+
+        let mut size = endpoint.max_packet_size & PACKET_SIZE_MASK;
+        let limit = packet_limit(speed, endpoint_type);
+        if size > limit {
+            policy.check(format_args!(
+                "{} dci {} wMaxPacketSize {} over {} at {:?} speed",
+                device, dci, size, limit, speed
+            ))?;
+            size = limit;
+        }
+
+        let mut additional = (endpoint.max_packet_size >> ADDITIONAL_TRANSACTIONS_SHIFT)
+            & ADDITIONAL_TRANSACTIONS_MASK;
+        let allowed = if periodic && speed == DeviceSpeed::High {
+            MAX_ADDITIONAL_TRANSACTIONS
+        } else {
+            0
+        };
+        if additional > allowed {
+            policy.check(format_args!(
+                "{} dci {} asks {} additional transactions, {} allowed",
+                device, dci, additional, allowed
+            ))?;
+            additional = allowed;
+        }
+
+        Ok(Self {
+            interval,
+            max_packet_size: size | additional << ADDITIONAL_TRANSACTIONS_SHIFT,
+        })
+    }
+}
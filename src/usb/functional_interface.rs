@@ -1,52 +1,93 @@
 use core::{cell::UnsafeCell, future::Future};
 
-use alloc::{boxed::Box, collections::btree_map::BTreeMap, string::String, sync::Arc, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
 use async_lock::{Mutex, OnceCell, RwLock};
 use dynamic_join_array::DynamicJoinArray;
 use embassy_futures::join::JoinArray;
 use futures::{
-    future::{BoxFuture, SelectOk},
+    future::{abortable, AbortHandle, BoxFuture, SelectOk},
     task::Spawn,
+    FutureExt,
 };
-use log::{info, trace};
-use usb_descriptor_decoder::descriptors::desc_device::Device;
+use log::{info, trace, warn};
+use usb_descriptor_decoder::descriptors::{desc_device::Device, desc_interface::USBInterface};
 
 use crate::{
-    abstractions::{PlatformAbstractions, USBSystemConfig},
+    abstractions::{filter::DeviceIdentity, PlatformAbstractions, USBSystemConfig},
     driver::{
         self,
-        driverapi::{USBSystemDriverModule, USBSystemDriverModuleInstanceFunctionalInterface},
+        device_node::DeviceNode,
+        driverapi::{
+            DriverMatchRule, MatchedInterface, OutputStream, USBSystemDriverModule,
+            USBSystemDriverModuleInstanceFunctionalInterface,
+        },
+        log_context::DriverContext,
+    },
+    event::{DeviceReady, EventBus},
+    host::device::{EnumerationMilestone, USBDevice},
+    usb::{
+        claims::{BindError, InterfaceClaims, InterfaceKey},
+        configuration::ConfigurationPolicy,
+        standards::TopologyRoute,
     },
-    event::EventBus,
-    host::device::USBDevice,
 };
 
+///interface nothing drives, see [`USBLayer::unclaimed_interfaces`]
+pub struct UnclaimedInterface<O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    pub interface_number: u8,
+    ///every alternate setting of it
+    pub alternates: Vec<Arc<USBInterface>>,
+}
+
+///driver instance bound to a device, together with what is needed to tear it down
+pub struct BoundInstance<'a, O, const RING_BUFFER_SIZE: usize>
+where
+    O: PlatformAbstractions,
+{
+    pub instance: Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>,
+    pub device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ///prefixed to log lines of this instance, see [`crate::driver::log_context::ContextLogger`]
+    pub context: DriverContext,
+    ///what [`USBSystemConfig::device_node_hook`] was told on bind
+    pub node: DeviceNode,
+    ///index in [`USBLayer::dynamic_join_array`]
+    pub idx: usize,
+    ///released once instance is unbound
+    pub claim: InterfaceKey,
+    ///aborted future completes on next poll, that's how it leaves the join array
+    abort: AbortHandle,
+}
+
 pub struct USBLayer<'a, O, const RING_BUFFER_SIZE: usize>
 where
     O: PlatformAbstractions,
 {
     config: Arc<USBSystemConfig<O, RING_BUFFER_SIZE>>,
     eventbus: Arc<EventBus<'a, O, RING_BUFFER_SIZE>>,
-    pub driver_modules: BTreeMap<
-        String,
-        Box<dyn driver::driverapi::USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>>,
-    >,
-    pub functional_interfaces: RwLock<
-        BTreeMap<
-            &'a str,
-            Vec<(
-                Arc<
-                    RwLock<
-                        dyn driver::driverapi::USBSystemDriverModuleInstanceFunctionalInterface<
-                            'a,
-                            O,
-                        >,
-                    >,
-                >,
-                usize,
-            )>,
-        >,
+    ///kept sorted in binding order, see [`USBLayer::plug_driver_module`]
+    pub driver_modules: RwLock<
+        Vec<(
+            String,
+            Box<dyn driver::driverapi::USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>>,
+        )>,
     >,
+    ///initialized and not removed yet, offered to modules plugged later
+    devices: RwLock<Vec<Arc<USBDevice<O, RING_BUFFER_SIZE>>>>,
+    configuration_policy: RwLock<Option<Arc<dyn ConfigurationPolicy>>>,
+    ///keeps a lower priority module off interfaces already driven
+    claims: RwLock<InterfaceClaims>,
+    pub functional_interfaces:
+        RwLock<BTreeMap<&'a str, Vec<BoundInstance<'a, O, RING_BUFFER_SIZE>>>>,
     pub dynamic_join_array: Arc<DynamicJoinArray>,
 }
 
@@ -61,7 +102,10 @@ where
     ) -> Self {
         let usblayer = Self {
             config,
-            driver_modules: BTreeMap::new(),
+            driver_modules: Vec::new().into(),
+            devices: Vec::new().into(),
+            configuration_policy: None.into(),
+            claims: InterfaceClaims::default().into(),
             functional_interfaces: BTreeMap::new().into(),
             eventbus: evt_bus,
             dynamic_join_array: Arc::new(DynamicJoinArray::new().into()),
@@ -69,41 +113,420 @@ where
         usblayer
     }
 
+    ///modules bind in descending priority, then in plug order.
+    ///plugging a name that already exists replaces the old module, unbinding its instances.
+    ///
+    ///devices already initialized are offered to the new module right away
+    pub fn plug_driver_module(
+        &self,
+        name: String,
+        module: Box<dyn USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>>,
+    ) {
+        let mut driver_modules = embassy_futures::block_on(self.driver_modules.write());
+        if let Some(pos) = driver_modules.iter().position(|(exist, _)| *exist == name) {
+            let (_, replaced) = driver_modules.remove(pos);
+            self.unbind_module(replaced.name());
+        }
+        let priority = module.priority();
+        let pos = driver_modules
+            .iter()
+            .position(|(_, m)| m.priority() < priority)
+            .unwrap_or(driver_modules.len());
+        trace!(
+            "plug driver module {} at {} with priority {}",
+            name,
+            pos,
+            priority
+        );
+        driver_modules.insert(pos, (name, module));
+
+        let module = driver_modules[pos].1.as_ref();
+        for device in embassy_futures::block_on(self.devices.read()).iter() {
+            self.bind(module, device.clone());
+        }
+    }
+
+    ///remove module plugged as `name`, every instance it bound gets `pre_drop` and is stopped.
+    ///
+    ///false if no module was plugged under that name
+    pub fn unplug_driver_module(&self, name: &str) -> bool {
+        let module = {
+            let mut driver_modules = embassy_futures::block_on(self.driver_modules.write());
+            let Some(pos) = driver_modules.iter().position(|(exist, _)| exist == name) else {
+                return false;
+            };
+            driver_modules.remove(pos).1
+        };
+        self.unbind_module(module.name());
+        info!("driver module {} unplugged!", name);
+        true
+    }
+
+    ///applies to devices enumerated from now on, None keeps first configuration
+    pub fn set_configuration_policy(&self, policy: Option<Arc<dyn ConfigurationPolicy>>) {
+        *embassy_futures::block_on(self.configuration_policy.write()) = policy;
+    }
+
+    ///switch device to configuration policy picked, before anything binds to it.
+    ///on failure device keeps the configuration it had
+    pub async fn apply_configuration_policy(&self, device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        let Some(policy) = self.configuration_policy.read().await.clone() else {
+            return;
+        };
+        let configurations = device.configurations().await;
+        let identity = device
+            .device_desc_raw
+            .get()
+            .map(|raw| DeviceIdentity::from_device_desc(raw));
+        let Some(value) = policy.select(identity, &configurations) else {
+            return;
+        };
+        if value == device.current_config() {
+            return;
+        }
+        if let Err(err) = device.set_configuration(value).await {
+            warn!(
+                "switching device at {} to configuration {} failed: {}",
+                device.topology_path, value, err
+            );
+        }
+    }
+
     pub fn new_device_initialized(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
-        self.driver_modules
-            .values()
-            .filter_map(
-                |module: &Box<dyn USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>>| {
-                    module
-                        .should_active(device.clone(), &self.config)
-                        .map(|a| (a, module.name()))
-                },
-            )
-            .for_each(|(function, name)| {
-                //safety: feature holded ref would drop while module drop or device drop
-                let future = unsafe {
-                    (*(function.as_ref()
-                        as *const RwLock<
-                            dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>,
-                        >
-                        as *mut RwLock<
-                            dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>,
-                        >))
-                        .get_mut()
-                        .run()
+        embassy_futures::block_on(self.devices.write()).push(device.clone());
+        embassy_futures::block_on(self.driver_modules.read())
+            .iter()
+            .for_each(|(_, module)| self.bind(module.as_ref(), device.clone()));
+
+        device.mark_milestone(EnumerationMilestone::DriversStarted);
+        let latency = device.enumeration_latency();
+        info!(
+            "initialized new device at {}, ready after {:?}",
+            device.topology_path, latency.total
+        );
+        self.eventbus
+            .device_ready
+            .broadcast(DeviceReady { device, latency });
+    }
+
+    ///offer `device` to `module`, run the instance if it takes it
+    fn bind(
+        &self,
+        module: &dyn USBSystemDriverModule<'a, O, RING_BUFFER_SIZE>,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ) {
+        let Some(function) = module.should_active(device.clone(), &self.config) else {
+            return;
+        };
+        let _ = self.start_instance(module.name(), device, function);
+    }
+
+    ///bind module plugged as `name` to interface `interface_number` of `device` and nothing else,
+    ///e.g. a raw passthrough module beside drivers of other functions of a combo device.
+    ///
+    ///module gets the interface through [`USBSystemDriverModule::bind_matched`], its rules are not checked
+    pub async fn bind_interface(
+        &self,
+        name: &str,
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        interface_number: u8,
+    ) -> Result<(), BindError> {
+        let driver_modules = self.driver_modules.read().await;
+        let module = driver_modules
+            .iter()
+            .find(|(exist, _)| exist == name)
+            .map(|(_, module)| module.as_ref())
+            .ok_or(BindError::NoSuchModule)?;
+        let alternates = Self::interface_alternates(device, interface_number)
+            .await
+            .ok_or(BindError::NoSuchInterface)?;
+        let claim = InterfaceKey {
+            route: device.topology_path.clone(),
+            interface: Some(interface_number),
+            alternate: 0,
+        };
+        if let Some(holder) = self.claims.read().await.holder(&claim) {
+            return Err(BindError::Claimed(holder.into()));
+        }
+
+        let interface = alternates[0].clone();
+        let matched = MatchedInterface {
+            rule: DriverMatchRule::default(),
+            class_descriptors: device
+                .class_descriptors(interface_number, interface.interface.alternate_setting),
+            interface,
+            alternates,
+        };
+        let function = module
+            .bind_matched(device.clone(), &self.config, matched)
+            .ok_or(BindError::Refused)?;
+        self.start_instance(module.name(), device.clone(), function)
+            .map_err(BindError::Claimed)
+    }
+
+    ///interfaces of current configuration no instance drives, on every device still attached
+    pub async fn unclaimed_interfaces(&self) -> Vec<UnclaimedInterface<O, RING_BUFFER_SIZE>> {
+        let devices = self.devices.read().await.clone();
+        let claims = self.claims.read().await;
+        let mut unclaimed = Vec::new();
+        for device in devices {
+            let Some(config) = device
+                .configurations()
+                .await
+                .into_iter()
+                .find(|config| config.value == device.current_config())
+            else {
+                continue;
+            };
+            let numbers: BTreeSet<u8> = config
+                .interfaces
+                .iter()
+                .map(|interface| interface.interface.interface_number)
+                .collect();
+            for interface_number in numbers {
+                let claim = InterfaceKey {
+                    route: device.topology_path.clone(),
+                    interface: Some(interface_number),
+                    alternate: 0,
                 };
+                if claims.holder(&claim).is_some() {
+                    continue;
+                }
+                unclaimed.push(UnclaimedInterface {
+                    device: device.clone(),
+                    interface_number,
+                    alternates: config
+                        .interfaces
+                        .iter()
+                        .filter(|interface| {
+                            interface.interface.interface_number == interface_number
+                        })
+                        .cloned()
+                        .collect(),
+                });
+            }
+        }
+        unclaimed
+    }
 
-                trace!("setteled driver instance future!");
-                let idx = embassy_futures::block_on(self.dynamic_join_array.add(future));
-                trace!("setteled driver instance future!");
-                embassy_futures::block_on(self.functional_interfaces.write())
-                    .entry(name)
-                    .or_insert(Vec::new())
-                    .push((function, idx));
-                trace!("placed instance into array!");
+    ///every alternate setting of interface in current configuration, alternate 0 first
+    async fn interface_alternates(
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+        interface_number: u8,
+    ) -> Option<Vec<Arc<USBInterface>>> {
+        let mut alternates: Vec<_> = device
+            .configurations()
+            .await
+            .into_iter()
+            .find(|config| config.value == device.current_config())?
+            .interfaces
+            .into_iter()
+            .filter(|interface| interface.interface.interface_number == interface_number)
+            .collect();
+        alternates.sort_by_key(|interface| interface.interface.alternate_setting);
+        (!alternates.is_empty()).then_some(alternates)
+    }
+
+    ///claim interface of `function` and run it, Err with current holder if it's driven already
+    fn start_instance(
+        &self,
+        name: &'a str,
+        device: Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+        function: Arc<RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>>,
+    ) -> Result<(), String> {
+        let (interface, alternate) = {
+            let instance = embassy_futures::block_on(function.read());
+            (instance.interface_number(), instance.alternate_setting())
+        };
+        let claim = InterfaceKey {
+            route: device.topology_path.clone(),
+            interface,
+            alternate,
+        };
+        if let Err(holder) =
+            embassy_futures::block_on(self.claims.write()).claim(claim.clone(), name)
+        {
+            //instance never ran, dropping it is all that's needed
+            info!(
+                "interface {:?} of device at {} is driven by {} already, {} not bound",
+                interface, device.topology_path, holder, name
+            );
+            return Err(holder);
+        }
+        let context = DriverContext::new(name, &device.topology_path);
+        let node = self.device_node(name, &device, interface);
+        //safety: feature holded ref would drop while module drop or device drop
+        let future = unsafe {
+            (*(function.as_ref()
+                as *const RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>
+                as *mut RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>))
+                .get_mut()
+                .run()
+        };
+
+        let (future, abort) = abortable(context.clone().wrap(future));
+        //instance must outlive its future, even after being unbound
+        let keep_alive = function.clone();
+        let future = future.map(move |_| drop(keep_alive)).boxed();
+        let idx = embassy_futures::block_on(self.dynamic_join_array.add(future));
+        trace!("setteled driver instance future!");
+        embassy_futures::block_on(self.functional_interfaces.write())
+            .entry(name)
+            .or_insert(Vec::new())
+            .push(BoundInstance {
+                instance: function,
+                device: device.clone(),
+                context,
+                node: node.clone(),
+                idx,
+                claim,
+                abort,
             });
+        trace!("placed instance into array!");
+        if let Some(hook) = &self.config.device_node_hook {
+            hook.bound(&node);
+        }
+        embassy_futures::block_on(device.bound_drivers.write()).push(name.to_string());
+        Ok(())
+    }
+
+    ///unbind every instance of driver module `driver`, devices stay enumerated
+    fn unbind_module(&self, driver: &str) {
+        let removed = embassy_futures::block_on(self.functional_interfaces.write())
+            .remove(driver)
+            .unwrap_or_default();
+        for bound in removed {
+            let mut bound_drivers = embassy_futures::block_on(bound.device.bound_drivers.write());
+            if let Some(pos) = bound_drivers.iter().position(|exist| exist == driver) {
+                bound_drivers.remove(pos);
+            }
+            drop(bound_drivers);
+            self.unbind(driver, bound);
+        }
+    }
+
+    ///device got unplugged, controller already released its slot and transfer rings.
+    ///
+    ///every instance bound to it gets `pre_drop`, then its future is stopped.
+    pub fn device_removed(&self, device: Arc<USBDevice<O, RING_BUFFER_SIZE>>) {
+        embassy_futures::block_on(self.devices.write())
+            .retain(|exist| !Arc::ptr_eq(exist, &device));
+        let mut functional_interfaces =
+            embassy_futures::block_on(self.functional_interfaces.write());
+        for (name, instances) in functional_interfaces.iter_mut() {
+            let (removed, kept): (Vec<_>, Vec<_>) = core::mem::take(instances)
+                .into_iter()
+                .partition(|bound| Arc::ptr_eq(&bound.device, &device));
+            *instances = kept;
+            removed
+                .into_iter()
+                .for_each(|bound| self.unbind(name, bound));
+        }
+        functional_interfaces.retain(|_, instances| !instances.is_empty());
+
+        info!("device at {} removed!", device.topology_path);
+    }
+
+    ///every instance gets `pre_drop` and its future is stopped, devices stay bound to controller
+    pub fn shutdown(&self) {
+        embassy_futures::block_on(self.devices.write()).clear();
+        let functional_interfaces = core::mem::take(&mut *embassy_futures::block_on(
+            self.functional_interfaces.write(),
+        ));
+        for (name, instances) in functional_interfaces {
+            instances
+                .into_iter()
+                .for_each(|bound| self.unbind(name, bound));
+        }
+        info!("all driver instances stopped!");
+    }
+
+    fn device_node(
+        &self,
+        driver: &str,
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+        interface: Option<u8>,
+    ) -> DeviceNode {
+        let route = device.topology_path.clone();
+        DeviceNode {
+            name: match interface {
+                Some(interface) => route.function_name(device.current_config(), interface),
+                None => route.device_name(),
+            },
+            driver: driver.to_string(),
+            route,
+            vendor_id: device.vendor_id.get().copied(),
+            product_id: device.product_id.get().copied(),
+            config: device.current_config(),
+            interface,
+        }
+    }
+
+    ///bound device nodes, e.g. for populating devfs after hook was attached late
+    pub async fn device_nodes(&self) -> Vec<DeviceNode> {
+        self.functional_interfaces
+            .read()
+            .await
+            .values()
+            .flatten()
+            .map(|bound| bound.node.clone())
+            .collect()
+    }
+
+    ///interface could be bound again, by whichever module is offered it next
+    fn release_interface(&self, claim: &InterfaceKey) {
+        embassy_futures::block_on(self.claims.write()).release(claim);
+    }
 
-        info!("initialized new device!");
+    fn unbind(&self, name: &str, bound: BoundInstance<'a, O, RING_BUFFER_SIZE>) {
+        //safety: same as run, instance lives as long as its future
+        bound.context.scope(|| unsafe {
+            (*(bound.instance.as_ref()
+                as *const RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>
+                as *mut RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'a, O>>))
+                .get_mut()
+                .pre_drop()
+        });
+        bound.abort.abort();
+        //a gone device took its endpoints along, otherwise they are dropped once controller
+        //gets to it. interface can be claimed right away, see `USBDevice::release_orphaned`
+        if !bound.device.is_gone() {
+            let orphaned =
+                embassy_futures::block_on(bound.device.take_functions(bound.claim.interface));
+            if !orphaned.is_empty() {
+                let device = bound.device.clone();
+                embassy_futures::block_on(
+                    self.dynamic_join_array
+                        .add(async move { device.release_orphaned(orphaned).await }.boxed()),
+                );
+            }
+        }
+        self.release_interface(&bound.claim);
+        if let Some(hook) = &self.config.device_node_hook {
+            hook.unbound(&bound.node);
+        }
+        trace!("driver {} instance {} dropped", name, bound.idx);
+    }
+
+    ///subscribe every bound instance of driver module `driver` whose output is `T`
+    pub async fn subscribe_output<T: Send + 'static>(
+        &self,
+        driver: &str,
+    ) -> Vec<(TopologyRoute, OutputStream<T>)> {
+        let functional_interfaces = self.functional_interfaces.read().await;
+        let Some(instances) = functional_interfaces.get(driver) else {
+            return Vec::new();
+        };
+        let mut streams = Vec::new();
+        for bound in instances {
+            let Some(output) = bound.instance.read().await.subscribe_output() else {
+                continue;
+            };
+            if let Ok(stream) = output.downcast::<OutputStream<T>>() {
+                streams.push((bound.device.topology_path.clone(), *stream));
+            }
+        }
+        streams
     }
 
     pub async fn functional_interface_workaround(&self) {
@@ -111,3 +534,87 @@ where
         self.dynamic_join_array.work().await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{boxed::Box, string::ToString, sync::Arc, vec, vec::Vec};
+    use async_lock::RwLock;
+
+    use super::USBLayer;
+    use crate::{
+        abstractions::{
+            spin::SpinCell,
+            testing::{test_config, TestPlatform, TEST_RING_BUFFER_SIZE},
+            USBSystemConfig,
+        },
+        driver::driverapi::{
+            USBSystemDriverModule, USBSystemDriverModuleInstanceFunctionalInterface,
+        },
+        event::EventBus,
+        host::device::USBDevice,
+    };
+
+    const N: usize = TEST_RING_BUFFER_SIZE;
+
+    ///refuses every device, noting down when it was offered one
+    struct Recorder {
+        name: &'static str,
+        priority: i32,
+        offered: Arc<SpinCell<Vec<&'static str>>>,
+    }
+
+    impl USBSystemDriverModule<'static, TestPlatform, N> for Recorder {
+        fn should_active(
+            &self,
+            _device: Arc<USBDevice<TestPlatform, N>>,
+            _config: &Arc<USBSystemConfig<TestPlatform, N>>,
+        ) -> Option<
+            Arc<
+                RwLock<dyn USBSystemDriverModuleInstanceFunctionalInterface<'static, TestPlatform>>,
+            >,
+        > {
+            self.offered.with(|offered| offered.push(self.name));
+            None
+        }
+
+        fn preload_module(&self) {}
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+    }
+
+    #[test]
+    fn modules_are_offered_by_priority_then_plug_order() {
+        let layer = USBLayer::new(test_config(), Arc::new(EventBus::new()));
+        let offered = Arc::new(SpinCell::new(Vec::new()));
+        let plug = |name: &'static str, priority| {
+            layer.plug_driver_module(
+                name.to_string(),
+                Box::new(Recorder {
+                    name,
+                    priority,
+                    offered: offered.clone(),
+                }),
+            )
+        };
+        plug("low", -1);
+        plug("first", 0);
+        plug("high", 10);
+        plug("second", 0);
+        plug("highest", 20);
+        //replaced module takes its place by its new priority, as if plugged last
+        plug("high", 0);
+
+        let (device, _, _) = USBDevice::new(test_config());
+        layer.new_device_initialized(Arc::new(device));
+        assert_eq!(
+            offered.with(|offered| offered.clone()),
+            vec!["highest", "first", "second", "high", "low"]
+        );
+    }
+}
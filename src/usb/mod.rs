@@ -1,3 +1,15 @@
+pub mod capabilities;
+#[cfg(feature = "drivers")]
+pub mod claims;
+pub mod class_descriptors;
+pub mod companion;
+#[cfg(feature = "drivers")]
+pub mod configuration;
+pub mod conformance;
+#[cfg(feature = "drivers")]
 pub mod functional_interface;
 pub mod operations;
+pub mod power;
+#[cfg(feature = "drivers")]
+pub mod snapshot;
 pub mod standards;
@@ -1,5 +1,115 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+use async_lock::{Mutex, Semaphore};
+
+use super::IocPolicy;
+
 #[derive(Debug, Clone)]
 pub struct BulkTransfer {
     pub endpoint_id: usize,
+    ///with a scatter list, first address and total length of it
     pub buffer_addr_len: (usize, usize),
+    ///segments one TD is gathered from, empty for a single contiguous `buffer_addr_len`
+    pub scatter: Vec<(usize, usize)>,
+    pub ioc_policy: IocPolicy,
+    ///stream this TD goes on, required on usb3 bulk endpoints whose companion asks for streams
+    ///and refused everywhere else. ids run from 1 to what the companion offers
+    pub stream_id: Option<u16>,
+    ///only meaningful with KeepFill, which buffer of stream this TD fills
+    pub refill: Option<BulkRefill>,
+}
+
+impl BulkTransfer {
+    ///one TD over several buffers, e.g. a >64KB read without a giant contiguous DMA allocation.
+    ///
+    ///every segment but the last should be a multiple of max packet size,
+    ///EHCI reads a segment boundary mid packet as a short packet
+    pub fn scattered(endpoint_id: usize, segments: &[(usize, usize)]) -> Self {
+        Self {
+            endpoint_id,
+            buffer_addr_len: super::scatter_span(segments),
+            scatter: segments.to_vec(),
+            ioc_policy: IocPolicy::default(),
+            stream_id: None,
+            refill: None,
+        }
+    }
+
+    ///buffers of this TD in order
+    pub fn segments(&self) -> &[(usize, usize)] {
+        super::segments_of(&self.scatter, &self.buffer_addr_len)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BulkRefill {
+    pub stream: Arc<BulkInStream>,
+    pub buffer_idx: usize,
+}
+
+///rotating buffers of a kept-filling bulk IN endpoint.
+///
+///controller fills free buffers one after another, driver takes filled ones
+///and recycles them once consumed.
+#[derive(Debug)]
+pub struct BulkInStream {
+    buffers: Vec<(usize, usize)>,
+    free: Mutex<VecDeque<usize>>,
+    filled: Mutex<VecDeque<(usize, usize)>>,
+    filled_count: Semaphore,
+    ///refills that found no free buffer, each left a TD slot idle until a buffer comes back
+    starved: AtomicUsize,
+}
+
+impl BulkInStream {
+    pub fn new(buffers: Vec<(usize, usize)>) -> Arc<Self> {
+        Arc::new(Self {
+            free: Mutex::new((0..buffers.len()).collect()),
+            buffers,
+            filled: Mutex::new(VecDeque::new()),
+            filled_count: Semaphore::new(0),
+            starved: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn buffer(&self, idx: usize) -> (usize, usize) {
+        self.buffers[idx]
+    }
+
+    pub(crate) async fn take_free(&self) -> Option<usize> {
+        let mut free = self.free.lock().await;
+        let idx = free.pop_front();
+        if idx.is_none() {
+            self.starved.fetch_add(1, Ordering::AcqRel);
+        }
+        idx
+    }
+
+    pub(crate) async fn complete(&self, idx: usize, length: usize) {
+        self.filled.lock().await.push_back((idx, length));
+        self.filled_count.add_permits(1);
+    }
+
+    ///wait for next filled buffer, returns its index and transferred length
+    pub async fn next_filled(&self) -> (usize, usize) {
+        //permit is consumed, not returned
+        core::mem::forget(self.filled_count.acquire().await);
+        self.filled
+            .lock()
+            .await
+            .pop_front()
+            .expect("filled count and queue out of sync")
+    }
+
+    ///give consumed buffer back, returns true if a starved refill is owed a resubmit with it
+    pub async fn recycle(&self, idx: usize) -> bool {
+        let mut free = self.free.lock().await;
+        free.push_back(idx);
+        self.starved
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |starved| {
+                starved.checked_sub(1)
+            })
+            .is_ok()
+    }
 }
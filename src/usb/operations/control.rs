@@ -1,4 +1,5 @@
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive as _;
 
 use super::{
     configurations::{AltnativeNumber, ConfigurationID, InterfaceNumber},
@@ -20,14 +21,42 @@ pub struct ControlTransfer {
 #[derive(Debug, Clone)]
 pub enum bRequest {
     Standard(bRequestStandard),
+    ///request code defined by class spec, meaning depends on interface class
+    Class(u8),
+    Vendor(u8),
+    ///raw value, escape hatch for anything not modeled above
     Spec(u8),
 }
 
+impl bRequest {
+    pub const fn class(request: u8) -> Self {
+        Self::Class(request)
+    }
+
+    pub const fn vendor(request: u8) -> Self {
+        Self::Vendor(request)
+    }
+
+    pub const fn raw(request: u8) -> Self {
+        Self::Spec(request)
+    }
+
+    ///request space this request belongs to, raw requests could be in any of them
+    pub const fn transfer_type(&self) -> Option<DataTransferType> {
+        match self {
+            bRequest::Standard(_) => Some(DataTransferType::Standard),
+            bRequest::Class(_) => Some(DataTransferType::Class),
+            bRequest::Vendor(_) => Some(DataTransferType::Vendor),
+            bRequest::Spec(_) => None,
+        }
+    }
+}
+
 impl From<bRequest> for u8 {
     fn from(value: bRequest) -> Self {
         match value {
             bRequest::Standard(b_request_standard) => b_request_standard as u8,
-            bRequest::Spec(a) => a,
+            bRequest::Class(a) | bRequest::Vendor(a) | bRequest::Spec(a) => a,
         }
     }
 }
@@ -94,6 +123,36 @@ impl bmRequestType {
     }
 }
 
+impl bmRequestType {
+    pub const fn standard(direction: Direction, recipient: Recipient) -> Self {
+        Self::new(direction, DataTransferType::Standard, recipient)
+    }
+
+    pub const fn class(direction: Direction, recipient: Recipient) -> Self {
+        Self::new(direction, DataTransferType::Class, recipient)
+    }
+
+    pub const fn vendor(direction: Direction, recipient: Recipient) -> Self {
+        Self::new(direction, DataTransferType::Vendor, recipient)
+    }
+}
+
+///fails on reserved recipient values
+impl TryFrom<u8> for bmRequestType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let direction = if value & 0x80 != 0 {
+            Direction::In
+        } else {
+            Direction::Out
+        };
+        let transfer_type = DataTransferType::from_u8((value >> 5) & 0x3).ok_or(value)?;
+        let recipient = Recipient::from_u8(value & 0x1f).ok_or(value)?;
+        Ok(Self::new(direction, transfer_type, recipient))
+    }
+}
+
 impl From<bmRequestType> for u8 {
     fn from(value: bmRequestType) -> Self {
         ((value.direction as u8) << 7) | ((value.transfer_type as u8) << 5) | value.recipient as u8
@@ -119,6 +178,97 @@ pub enum Recipient {
 }
 
 impl ControlTransfer {
+    ///request type is derived from `request`, raw requests are sent as vendor ones
+    pub fn new(
+        direction: Direction,
+        recipient: Recipient,
+        request: bRequest,
+        value: u16,
+        index: u16,
+        data: Option<(usize, usize)>,
+    ) -> Self {
+        let transfer_type = request.transfer_type().unwrap_or(DataTransferType::Vendor);
+        Self {
+            request_type: bmRequestType::new(direction, transfer_type, recipient),
+            request,
+            index,
+            value,
+            //status stage goes the opposite way of data stage, or IN if there's none
+            response: !(matches!(direction, Direction::In) && data.is_some()),
+            data,
+        }
+    }
+
+    #[inline]
+    pub fn class(
+        direction: Direction,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: Option<(usize, usize)>,
+    ) -> Self {
+        Self::new(
+            direction,
+            recipient,
+            bRequest::class(request),
+            value,
+            index,
+            data,
+        )
+    }
+
+    #[inline]
+    pub fn vendor(
+        direction: Direction,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: Option<(usize, usize)>,
+    ) -> Self {
+        Self::new(
+            direction,
+            recipient,
+            bRequest::vendor(request),
+            value,
+            index,
+            data,
+        )
+    }
+
+    #[inline]
+    pub fn get_descriptor(
+        recipient: Recipient,
+        desc_type: u8,
+        desc_index: u8,
+        index: u16,
+        data: (usize, usize),
+    ) -> Self {
+        Self {
+            request_type: bmRequestType::new(Direction::In, DataTransferType::Standard, recipient),
+            request: bRequestStandard::GetDescriptor.into(),
+            index,
+            value: construct_control_transfer_type(desc_type, desc_index).bits(),
+            data: Some(data),
+            response: false,
+        }
+    }
+
+    ///CLEAR_FEATURE(ENDPOINT_HALT) on endpoint of given DCI, refer usb2 spec 9.4.1
+    #[inline]
+    pub fn clear_endpoint_halt(dci: u8) -> Self {
+        let endpoint_address = (dci / 2) | if dci % 2 == 1 { 0x80 } else { 0 };
+        Self::new(
+            Direction::Out,
+            Recipient::Endpoint,
+            bRequestStandard::ClearFeature.into(),
+            0,
+            endpoint_address as _,
+            None,
+        )
+    }
+
     #[inline]
     pub(super) fn set_configuration(c: ConfigurationID, i: InterfaceNumber) -> Self {
         Self {
@@ -152,6 +302,303 @@ impl ControlTransfer {
     }
 }
 
+///descriptor types drivers ask for, refer usb2 spec table 9-5 and class specs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescType {
+    Device,
+    Configuration,
+    String,
+    Interface,
+    Endpoint,
+    Bos,
+    Hid,
+    HidReport,
+    Hub,
+    SuperSpeedHub,
+    ///anything not listed
+    Raw(u8),
+}
+
+impl DescType {
+    pub const fn code(self) -> u8 {
+        match self {
+            DescType::Device => 1,
+            DescType::Configuration => 2,
+            DescType::String => 3,
+            DescType::Interface => 4,
+            DescType::Endpoint => 5,
+            DescType::Bos => 0x0f,
+            DescType::Hid => 0x21,
+            DescType::HidReport => 0x22,
+            DescType::Hub => 0x29,
+            DescType::SuperSpeedHub => 0x2a,
+            DescType::Raw(code) => code,
+        }
+    }
+}
+
+///HID class requests, refer hid 1.11 spec 7.2
+#[cfg(feature = "hid")]
+mod hid_request {
+    pub const GET_REPORT: u8 = 0x01;
+    pub const GET_IDLE: u8 = 0x02;
+    pub const SET_REPORT: u8 = 0x09;
+    pub const SET_IDLE: u8 = 0x0a;
+    pub const SET_PROTOCOL: u8 = 0x0b;
+}
+///CDC ACM class requests, refer usbcdc 1.2 pstn 6.3
+const CDC_SET_LINE_CODING: u8 = 0x20;
+const CDC_GET_LINE_CODING: u8 = 0x21;
+const CDC_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+///report type in high byte of wValue of GET_REPORT/SET_REPORT
+#[cfg(feature = "hid")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HidReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+///data stage of CDC SET_LINE_CODING/GET_LINE_CODING
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    ///0: 1 stop bit, 1: 1.5, 2: 2
+    pub stop_bits: u8,
+    ///0: none, 1: odd, 2: even, 3: mark, 4: space
+    pub parity: u8,
+    pub data_bits: u8,
+}
+
+impl LineCoding {
+    pub const LEN: usize = 7;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let baud = self.baud_rate.to_le_bytes();
+        [
+            baud[0],
+            baud[1],
+            baud[2],
+            baud[3],
+            self.stop_bits,
+            self.parity,
+            self.data_bits,
+        ]
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Option<Self> {
+        let raw: &[u8; Self::LEN] = raw.get(..Self::LEN)?.try_into().ok()?;
+        Some(Self {
+            baud_rate: u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]),
+            stop_bits: raw[4],
+            parity: raw[5],
+            data_bits: raw[6],
+        })
+    }
+}
+
+///builds a [`ControlTransfer`] from what request means, bmRequestType and field layout are derived.
+///
+///recipient is device until [`Self::interface`], [`Self::endpoint`] or [`Self::other`] picks another,
+///those also put the target into wIndex
+#[derive(Debug, Clone)]
+pub struct ControlRequestBuilder {
+    direction: Direction,
+    recipient: Recipient,
+    request: bRequest,
+    value: u16,
+    index: u16,
+    data: Option<(usize, usize)>,
+}
+
+impl ControlRequestBuilder {
+    pub const fn new(direction: Direction, request: bRequest) -> Self {
+        Self {
+            direction,
+            recipient: Recipient::Device,
+            request,
+            value: 0,
+            index: 0,
+            data: None,
+        }
+    }
+
+    pub const fn class(direction: Direction, request: u8) -> Self {
+        Self::new(direction, bRequest::Class(request))
+    }
+
+    pub const fn vendor(direction: Direction, request: u8) -> Self {
+        Self::new(direction, bRequest::Vendor(request))
+    }
+
+    ///set buffer with [`Self::data`]
+    pub const fn get_descriptor(desc: DescType, desc_index: u8) -> Self {
+        Self::new(
+            Direction::In,
+            bRequest::Standard(bRequestStandard::GetDescriptor),
+        )
+        .value(construct_control_transfer_type(desc.code(), desc_index).bits())
+    }
+
+    pub const fn get_status() -> Self {
+        Self::new(
+            Direction::In,
+            bRequest::Standard(bRequestStandard::GetStatus),
+        )
+    }
+
+    pub const fn set_feature(feature: u16) -> Self {
+        Self::new(
+            Direction::Out,
+            bRequest::Standard(bRequestStandard::SetFeature),
+        )
+        .value(feature)
+    }
+
+    pub const fn clear_feature(feature: u16) -> Self {
+        Self::new(
+            Direction::Out,
+            bRequest::Standard(bRequestStandard::ClearFeature),
+        )
+        .value(feature)
+    }
+
+    pub const fn set_configuration(config_value: u8) -> Self {
+        Self::new(
+            Direction::Out,
+            bRequest::Standard(bRequestStandard::SetConfiguration),
+        )
+        .value(config_value as _)
+    }
+
+    pub const fn set_interface(interface: u8, alternate: u8) -> Self {
+        Self::new(
+            Direction::Out,
+            bRequest::Standard(bRequestStandard::SetInterface),
+        )
+        .value(alternate as _)
+        .interface(interface)
+    }
+
+    ///data stage is a [`LineCoding::to_bytes`] buffer
+    pub const fn cdc_set_line_coding(interface: u8) -> Self {
+        Self::class(Direction::Out, CDC_SET_LINE_CODING).interface(interface)
+    }
+
+    ///data stage is [`LineCoding::LEN`] bytes, see [`LineCoding::from_bytes`]
+    pub const fn cdc_get_line_coding(interface: u8) -> Self {
+        Self::class(Direction::In, CDC_GET_LINE_CODING).interface(interface)
+    }
+
+    pub const fn cdc_set_control_line_state(interface: u8, dtr: bool, rts: bool) -> Self {
+        Self::class(Direction::Out, CDC_SET_CONTROL_LINE_STATE)
+            .value((dtr as u16) | ((rts as u16) << 1))
+            .interface(interface)
+    }
+
+    pub const fn device(mut self) -> Self {
+        self.recipient = Recipient::Device;
+        self
+    }
+
+    ///interface number goes into low byte of wIndex
+    pub const fn interface(mut self, interface: u8) -> Self {
+        self.recipient = Recipient::Interface;
+        self.index = (self.index & 0xff00) | interface as u16;
+        self
+    }
+
+    ///`address` as in endpoint descriptor, direction bit included
+    pub const fn endpoint(mut self, address: u8) -> Self {
+        self.recipient = Recipient::Endpoint;
+        self.index = (self.index & 0xff00) | address as u16;
+        self
+    }
+
+    ///hub port requests
+    pub const fn other(mut self, port: u8) -> Self {
+        self.recipient = Recipient::Other;
+        self.index = port as _;
+        self
+    }
+
+    pub const fn value(mut self, value: u16) -> Self {
+        self.value = value;
+        self
+    }
+
+    ///whole wIndex, e.g. language id of string descriptors
+    pub const fn index(mut self, index: u16) -> Self {
+        self.index = index;
+        self
+    }
+
+    ///buffer of data stage, direction is the request's
+    pub const fn data(mut self, data: (usize, usize)) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub const fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn build(self) -> ControlTransfer {
+        ControlTransfer::new(
+            self.direction,
+            self.recipient,
+            self.request,
+            self.value,
+            self.index,
+            self.data,
+        )
+    }
+}
+
+#[cfg(feature = "hid")]
+impl ControlRequestBuilder {
+    ///GET_REPORT of given type and id, 0 if device uses no report ids
+    pub const fn hid_get_report(interface: u8, report_type: HidReportType, report_id: u8) -> Self {
+        Self::class(Direction::In, hid_request::GET_REPORT)
+            .value(((report_type as u16) << 8) | report_id as u16)
+            .interface(interface)
+    }
+
+    pub const fn hid_set_report(interface: u8, report_type: HidReportType, report_id: u8) -> Self {
+        Self::class(Direction::Out, hid_request::SET_REPORT)
+            .value(((report_type as u16) << 8) | report_id as u16)
+            .interface(interface)
+    }
+
+    ///`duration_4ms` 0 reports only on change, applies to every report if `report_id` is 0
+    pub const fn hid_set_idle(interface: u8, duration_4ms: u8, report_id: u8) -> Self {
+        Self::class(Direction::Out, hid_request::SET_IDLE)
+            .value(((duration_4ms as u16) << 8) | report_id as u16)
+            .interface(interface)
+    }
+
+    ///data stage is a single byte, duration in 4ms units
+    pub const fn hid_get_idle(interface: u8, report_id: u8) -> Self {
+        Self::class(Direction::In, hid_request::GET_IDLE)
+            .value(report_id as _)
+            .interface(interface)
+    }
+
+    pub const fn hid_set_protocol(interface: u8, boot: bool) -> Self {
+        Self::class(Direction::Out, hid_request::SET_PROTOCOL)
+            .value(if boot { 0 } else { 1 })
+            .interface(interface)
+    }
+}
+
+impl From<ControlRequestBuilder> for ControlTransfer {
+    fn from(builder: ControlRequestBuilder) -> Self {
+        builder.build()
+    }
+}
+
 pub(crate) const fn construct_control_transfer_type(
     desc: u8,
     index: u8,
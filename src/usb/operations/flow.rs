@@ -0,0 +1,195 @@
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Poll, Waker},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::abstractions::spin::SpinCell;
+
+use super::RequestResult;
+
+///endpoint stops being writable once its transfer ring is this full, in percent
+pub const RING_HIGH_WATER_PERCENT: usize = 75;
+///DCI 1..=31, index 0 is unused
+const ENDPOINTS: usize = 32;
+
+///backpressure of one endpoint, for producers that would otherwise outrun controller.
+///
+///two things count: requests posted but not picked up by controller yet,
+///and TRBs on its transfer ring controller has not finished. backends without transfer rings
+///only report the former
+pub struct EndpointFlow {
+    queued: AtomicUsize,
+    queue_limit: usize,
+    ///TRBs in flight and TRBs ring could hold at most, 0 until controller published any
+    ring_used: AtomicUsize,
+    ring_capacity: AtomicUsize,
+    ///see [`EndpointFlow::stats`]
+    completed: AtomicUsize,
+    errors: AtomicUsize,
+    bytes: AtomicUsize,
+    waiters: SpinCell<Vec<Waker>>,
+}
+
+///what went over one endpoint since its device got attached, see [`EndpointFlow::stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EndpointStats {
+    ///completions reported by controller, failed ones included
+    pub completed: usize,
+    ///failed completions, stops asked for by us are none
+    pub errors: usize,
+    ///bytes moved by successful completions
+    pub bytes: usize,
+}
+
+impl EndpointFlow {
+    fn new(queue_limit: usize) -> Self {
+        Self {
+            queued: AtomicUsize::new(0),
+            queue_limit,
+            ring_used: AtomicUsize::new(0),
+            ring_capacity: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            waiters: SpinCell::new(Vec::new()),
+        }
+    }
+
+    ///requests waiting in device channel
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    ///TRBs in flight and capacity of transfer ring, as last published by controller
+    pub fn ring_occupancy(&self) -> (usize, usize) {
+        (
+            self.ring_used.load(Ordering::Acquire),
+            self.ring_capacity.load(Ordering::Acquire),
+        )
+    }
+
+    ///completions and bytes counted so far, kept running transfers count once per buffer
+    pub fn stats(&self) -> EndpointStats {
+        EndpointStats {
+            completed: self.completed.load(Ordering::Acquire),
+            errors: self.errors.load(Ordering::Acquire),
+            bytes: self.bytes.load(Ordering::Acquire),
+        }
+    }
+
+    pub fn is_writable(&self) -> bool {
+        let (used, capacity) = self.ring_occupancy();
+        let ring_ok = capacity == 0 || used * 100 < capacity * RING_HIGH_WATER_PERCENT;
+        self.queued() < self.queue_limit && ring_ok
+    }
+
+    ///resolves once another submission would neither wait for ring room nor pile up in channel
+    pub async fn writable(&self) {
+        poll_fn(|cx| {
+            if self.is_writable() {
+                return Poll::Ready(());
+            }
+            self.waiters.with(|waiters| {
+                if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    waiters.push(cx.waker().clone());
+                }
+            });
+            //published in between, waker may have been missed
+            if self.is_writable() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    ///called by controller whenever TRBs were enqueued or finished
+    pub(crate) fn publish_ring(&self, used: usize, capacity: usize) {
+        self.ring_used.store(used, Ordering::Release);
+        self.ring_capacity.store(capacity, Ordering::Release);
+        self.wake_if_writable();
+    }
+
+    ///called by controller for every completion on endpoint, stale ones excluded
+    pub(crate) fn record_completion(&self, code: Result<RequestResult, u8>) {
+        self.completed.fetch_add(1, Ordering::AcqRel);
+        if !matches!(
+            code,
+            Ok(RequestResult::Success
+                | RequestResult::ShortPacket
+                | RequestResult::Stopped
+                | RequestResult::StoppedLengthInvalid
+                | RequestResult::StoppedShortPacket)
+        ) {
+            self.errors.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    ///called by controller once a successful completion told how much it moved
+    pub(crate) fn record_bytes(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    fn wake_if_writable(&self) {
+        if self.is_writable() {
+            self.waiters
+                .with(core::mem::take)
+                .into_iter()
+                .for_each(Waker::wake);
+        }
+    }
+}
+
+///[`EndpointFlow`] of every endpoint of a device
+pub struct FlowControl {
+    endpoints: Vec<Arc<EndpointFlow>>,
+}
+
+impl FlowControl {
+    ///`queue_limit` is depth of device request channel
+    pub fn new(queue_limit: usize) -> Self {
+        Self {
+            endpoints: (0..ENDPOINTS)
+                .map(|_| Arc::new(EndpointFlow::new(queue_limit)))
+                .collect(),
+        }
+    }
+
+    ///`endpoint_id` is DCI, as in transfers
+    pub fn endpoint(&self, endpoint_id: usize) -> &Arc<EndpointFlow> {
+        &self.endpoints[endpoint_id.min(ENDPOINTS - 1)]
+    }
+}
+
+///counts a posted request as queued on its endpoint, until controller picks it up
+#[derive(Debug)]
+pub struct FlowTicket(Arc<EndpointFlow>);
+
+impl FlowTicket {
+    pub(crate) fn issue(flow: &Arc<EndpointFlow>) -> Self {
+        flow.queued.fetch_add(1, Ordering::AcqRel);
+        Self(flow.clone())
+    }
+}
+
+impl Drop for FlowTicket {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::AcqRel);
+        self.0.wake_if_writable();
+    }
+}
+
+impl core::fmt::Debug for EndpointFlow {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EndpointFlow")
+            .field("queued", &self.queued())
+            .field("ring_occupancy", &self.ring_occupancy())
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
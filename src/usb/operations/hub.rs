@@ -0,0 +1,257 @@
+use core::time::Duration;
+
+use alloc::collections::btree_map::BTreeMap;
+
+///speed of a device, as seen on the port it is attached to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceSpeed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl DeviceSpeed {
+    ///from wPortStatus of usb2 hub, refer usb2 spec 11.24.2.7.1
+    pub fn from_hub_port_status(status: u16, superspeed_hub: bool) -> Self {
+        if superspeed_hub {
+            Self::Super
+        } else if status & (1 << 9) != 0 {
+            Self::Low
+        } else if status & (1 << 10) != 0 {
+            Self::High
+        } else {
+            Self::Full
+        }
+    }
+
+    ///low/full speed devices behind a high speed hub are reached through its transaction translator
+    pub fn needs_tt(&self) -> bool {
+        matches!(self, Self::Low | Self::Full)
+    }
+
+    ///default protocol speed ids, refer xhci spec 7.2.2.1.1
+    pub fn xhci_speed_id(&self) -> u8 {
+        match self {
+            Self::Full => 1,
+            Self::Low => 2,
+            Self::High => 3,
+            Self::Super => 4,
+        }
+    }
+
+    pub fn from_xhci_speed_id(id: u8) -> Self {
+        match id {
+            1 => Self::Full,
+            2 => Self::Low,
+            3 => Self::High,
+            _ => Self::Super,
+        }
+    }
+
+    ///ep0 max packet size to talk with before device descriptor is read
+    pub fn default_ep0_packet_size(&self) -> u16 {
+        match self {
+            Self::Low => 8,
+            Self::Full | Self::High => 64,
+            Self::Super => 512,
+        }
+    }
+
+    ///bMaxPacketSize0 spec allows, refer usb2 spec 5.5.3 and usb3 spec 9.6.1
+    pub fn is_valid_ep0_packet_size(&self, b_max_packet_size0: u8) -> bool {
+        match self {
+            Self::Low => b_max_packet_size0 == 8,
+            Self::Full => matches!(b_max_packet_size0, 8 | 16 | 32 | 64),
+            Self::High => b_max_packet_size0 == 64,
+            Self::Super => b_max_packet_size0 == 9,
+        }
+    }
+
+    ///bMaxPacketSize0 in bytes, on superspeed it's an exponent of 2, refer usb3 spec 9.6.1.
+    ///None if it can't be decoded at all
+    pub fn declared_ep0_packet_size(&self, b_max_packet_size0: u8) -> Option<u16> {
+        match (self, b_max_packet_size0) {
+            (Self::Super, exp @ 0..=15) => Some(1 << exp),
+            (Self::Super, _) | (_, 0) => None,
+            (_, size) => Some(size as u16),
+        }
+    }
+
+    ///ep0 max packet size to run with once bMaxPacketSize0 is read.
+    ///superspeed ep0 is 512 whatever the device declares, so it never changes from default
+    pub fn ep0_packet_size(&self, b_max_packet_size0: u8) -> u16 {
+        match self {
+            Self::Low | Self::Super => self.default_ep0_packet_size(),
+            _ => self
+                .declared_ep0_packet_size(b_max_packet_size0)
+                .unwrap_or(8),
+        }
+    }
+}
+
+///what hub driver learned from hub descriptor, controller needs it to schedule split transactions
+#[derive(Debug, Clone, Copy)]
+pub struct HubConfiguration {
+    pub ports: u8,
+    pub multi_tt: bool,
+    ///TT think time, in units of 8 FS bit times minus one, refer usb2 spec 11.23.2.1
+    pub tt_think_time: u8,
+}
+
+///device showed up on downstream port of hub
+#[derive(Debug, Clone, Copy)]
+pub struct HubPortAttach {
+    ///1 based
+    pub port: u8,
+    pub speed: DeviceSpeed,
+    ///when hub reported connection and when port reset finished, for enumeration latency
+    pub connected_at: Option<Duration>,
+    pub reset_at: Option<Duration>,
+}
+
+///transaction translator a low/full speed device talks through
+#[derive(Debug, Clone, Copy)]
+pub struct TransactionTranslator {
+    ///slot id on xhci, address on ehci
+    pub hub_slot: u8,
+    pub port: u8,
+    pub multi_tt: bool,
+}
+
+impl TransactionTranslator {
+    ///TT is per port on multi TT hubs, shared by every port otherwise
+    pub fn key(&self) -> (u8, u8) {
+        (self.hub_slot, if self.multi_tt { self.port } else { 0 })
+    }
+}
+
+///periodic budget of one TT per frame in FS bit times, 90% of a frame, refer usb2 spec 5.7.4
+pub const TT_PERIODIC_BUDGET: u32 = 12000 * 9 / 10;
+
+///periodic bandwidth handed out by every TT, so low/full speed interrupt and isoch endpoints
+///behind a hub don't oversubscribe it.
+///
+///costs are averaged over the endpoint interval instead of scheduled per microframe,
+///coarse, but never lets a TT run over its budget on average
+#[derive(Debug, Default)]
+pub struct TtBandwidth {
+    ///TT key -> (slot, dci) -> FS bit times per frame
+    claims: BTreeMap<(u8, u8), BTreeMap<(u8, u8), u32>>,
+}
+
+impl TtBandwidth {
+    ///FS bit times a periodic endpoint takes per frame, interval in frames.
+    ///refer usb2 spec 5.11.3, token, handshake and gaps rounded up into 13 bytes
+    pub fn cost(speed: DeviceSpeed, max_packet_size: u16, interval_frames: u32) -> u32 {
+        //bit stuffing worst case is one extra bit every six
+        let bits = (max_packet_size as u32 + 13) * 8 * 7 / 6;
+        //low speed bits are 8 times as long
+        let bits = if speed == DeviceSpeed::Low {
+            bits * 8
+        } else {
+            bits
+        };
+        bits.div_ceil(interval_frames.max(1))
+    }
+
+    pub fn used(&self, tt: &TransactionTranslator) -> u32 {
+        self.claims
+            .get(&tt.key())
+            .map(|claims| claims.values().sum())
+            .unwrap_or(0)
+    }
+
+    ///false if `tt` can't take it, nothing is claimed then. claiming an endpoint again replaces its old claim
+    pub fn claim(&mut self, tt: &TransactionTranslator, endpoint: (u8, u8), cost: u32) -> bool {
+        let claims = self.claims.entry(tt.key()).or_default();
+        let used: u32 = claims
+            .iter()
+            .filter(|(claimed, _)| **claimed != endpoint)
+            .map(|(_, cost)| cost)
+            .sum();
+        if used + cost > TT_PERIODIC_BUDGET {
+            return false;
+        }
+        claims.insert(endpoint, cost);
+        true
+    }
+
+    pub fn release(&mut self, endpoint: (u8, u8)) {
+        self.release_where(|claimed| *claimed == endpoint)
+    }
+
+    ///every endpoint of `slot`, e.g. once it got disabled
+    pub fn release_slot(&mut self, slot: u8) {
+        self.release_where(|(claimed_slot, _)| *claimed_slot == slot)
+    }
+
+    pub fn clear(&mut self) {
+        self.claims.clear()
+    }
+
+    fn release_where(&mut self, released: impl Fn(&(u8, u8)) -> bool) {
+        self.claims.values_mut().for_each(|claims| {
+            claims.retain(|endpoint, _| !released(endpoint));
+        });
+        self.claims.retain(|_, claims| !claims.is_empty());
+    }
+}
+
+///how a device behind an external hub is attached, filled by controller when the hub reports it
+#[derive(Debug, Clone, Copy)]
+pub struct HubAttachment {
+    pub speed: DeviceSpeed,
+    pub tt: Option<TransactionTranslator>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceSpeed;
+
+    #[test]
+    fn full_speed_takes_declared_size() {
+        let speed = DeviceSpeed::Full;
+        assert_eq!(speed.default_ep0_packet_size(), 64);
+        for size in [8, 16, 32, 64] {
+            assert!(speed.is_valid_ep0_packet_size(size));
+            assert_eq!(speed.ep0_packet_size(size), size as u16);
+        }
+        assert!(!speed.is_valid_ep0_packet_size(9));
+        assert_eq!(speed.declared_ep0_packet_size(0), None);
+        assert_eq!(speed.ep0_packet_size(0), 8);
+    }
+
+    #[test]
+    fn high_speed_allows_64_only() {
+        let speed = DeviceSpeed::High;
+        assert_eq!(speed.default_ep0_packet_size(), 64);
+        assert!(speed.is_valid_ep0_packet_size(64));
+        assert!(!speed.is_valid_ep0_packet_size(8));
+        assert_eq!(speed.declared_ep0_packet_size(64), Some(64));
+        assert_eq!(speed.ep0_packet_size(64), 64);
+    }
+
+    #[test]
+    fn low_speed_stays_at_8() {
+        let speed = DeviceSpeed::Low;
+        assert_eq!(speed.default_ep0_packet_size(), 8);
+        assert!(speed.is_valid_ep0_packet_size(8));
+        assert!(!speed.is_valid_ep0_packet_size(64));
+        assert_eq!(speed.ep0_packet_size(64), 8);
+    }
+
+    #[test]
+    fn superspeed_size_is_an_exponent() {
+        let speed = DeviceSpeed::Super;
+        assert_eq!(speed.default_ep0_packet_size(), 512);
+        assert!(speed.is_valid_ep0_packet_size(9));
+        //a usb2 style 512 would not even fit, 64 is no valid exponent
+        assert!(!speed.is_valid_ep0_packet_size(64));
+        assert_eq!(speed.declared_ep0_packet_size(9), Some(512));
+        assert_eq!(speed.declared_ep0_packet_size(0), Some(1));
+        assert_eq!(speed.declared_ep0_packet_size(16), None);
+        assert_eq!(speed.ep0_packet_size(9), 512);
+        assert_eq!(speed.ep0_packet_size(6), 512);
+    }
+}
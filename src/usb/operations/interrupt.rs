@@ -1,6 +1,446 @@
+use core::{
+    fmt,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    task::Poll,
+    time::Duration,
+};
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
+use futures::{task::AtomicWaker, Stream};
+
+use crate::abstractions::spin::SpinCell;
+
+use super::RequestResult;
+
 #[derive(Debug, Clone)]
 pub struct InterruptTransfer {
     pub endpoint_id: usize,
+    ///with a scatter list, first address and total length of it
     pub buffer_addr_len: (usize, usize),
+    ///segments one TD is gathered from, empty for a single contiguous `buffer_addr_len`.
+    ///not honored by KeepFill, refills always use one buffer
+    pub scatter: Vec<(usize, usize)>,
     pub short_packet_ok: bool,
+    ///only meaningful with KeepFill, refills take buffer from here instead of `buffer_addr_len`
+    pub refill: Option<Arc<PeriodicStream>>,
+}
+
+impl InterruptTransfer {
+    ///one TD over several buffers, see [`super::bulk::BulkTransfer::scattered`]
+    pub fn scattered(endpoint_id: usize, segments: &[(usize, usize)]) -> Self {
+        Self {
+            endpoint_id,
+            buffer_addr_len: super::scatter_span(segments),
+            scatter: segments.to_vec(),
+            short_packet_ok: true,
+            refill: None,
+        }
+    }
+
+    ///buffers of this TD in order
+    pub fn segments(&self) -> &[(usize, usize)] {
+        super::segments_of(&self.scatter, &self.buffer_addr_len)
+    }
+}
+
+///monotonic clock of platform, see [`crate::abstractions::PlatformAbstractions::now`]
+pub type Clock = Arc<dyn Fn() -> Option<Duration> + Send + Sync>;
+
+///how late a realtime driver may take a completed fill, e.g. 2ms for gaming mice.
+///
+///latency is measured from controller handling the completion to driver taking it,
+///so it reflects scheduling and wake method of the platform, not the bus itself
+#[derive(Debug, Clone, Copy)]
+pub struct CompletionDeadline {
+    pub deadline: Duration,
+    ///misses accumulated before a warning is raised, counting restarts after each warning
+    pub miss_threshold: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeadlineStats {
+    ///fills taken while clock was available
+    pub completions: u64,
+    pub misses: u64,
+    pub worst: Duration,
+}
+
+#[derive(Default)]
+struct DeadlineMonitor {
+    ///in us, 0 means no deadline declared
+    deadline: AtomicU64,
+    miss_threshold: AtomicU32,
+    ///in us, 0 means unknown
+    completed_at: AtomicU64,
+    completions: AtomicU64,
+    misses: AtomicU64,
+    worst: AtomicU64,
+    unreported: AtomicU32,
+}
+
+impl DeadlineMonitor {
+    fn observe(&self, now: Option<Duration>) {
+        let completed_at = self.completed_at.load(Ordering::Acquire);
+        let Some(now) = now.map(|now| now.as_micros() as u64) else {
+            return;
+        };
+        if completed_at == 0 {
+            return;
+        }
+        let latency = now.saturating_sub(completed_at);
+        self.completions.fetch_add(1, Ordering::Relaxed);
+        self.worst.fetch_max(latency, Ordering::Relaxed);
+        let deadline = self.deadline.load(Ordering::Relaxed);
+        if deadline != 0 && latency > deadline {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.unreported.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    fn stats(&self) -> DeadlineStats {
+        DeadlineStats {
+            completions: self.completions.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            worst: Duration::from_micros(self.worst.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+///one completion of a kept-filling endpoint, see [`PeriodicStream::next_report`]
+#[derive(Debug, Clone)]
+pub struct FillReport {
+    pub result: Result<RequestResult, u8>,
+    ///bytes transferred, 0 for failed fills
+    pub length: usize,
+    ///copy of what was received, empty for OUT endpoints and failed fills
+    pub data: Vec<u8>,
+}
+
+///buffers a kept-filling endpoint rotates over, see [`PeriodicStream::rotating`]
+struct BufferRotation {
+    buffers: Vec<(usize, usize)>,
+    free: SpinCell<VecDeque<usize>>,
+    ///(index, length) in completion order, not yet taken by driver
+    filled: SpinCell<VecDeque<(usize, usize)>>,
+    filled_waker: AtomicWaker,
+    ///refills that found no free buffer, each left the endpoint without a TD in flight
+    starved: AtomicUsize,
+}
+
+///handle of a kept-filling interrupt endpoint.
+///
+///buffer could be swapped at any time, the swap takes effect on next refill.
+///
+///[`Self::next_fill`] only tells about the latest fill, streams made with [`Self::reporting`]
+///also queue every completion for [`Self::next_report`].
+///streams made with [`Self::rotating`] never refill a buffer before driver released it
+pub struct PeriodicStream {
+    buffer_addr_len: SpinCell<(usize, usize)>,
+    last_length: AtomicUsize,
+    ///buffer the latest completed fill went into
+    last_buffer: AtomicUsize,
+    fresh: AtomicBool,
+    waker: AtomicWaker,
+    clock: Clock,
+    monitor: DeadlineMonitor,
+    ///0 if completions are not queued
+    report_depth: usize,
+    reports: SpinCell<VecDeque<FillReport>>,
+    ///dropped because driver did not take them in time
+    lost_reports: AtomicU64,
+    report_waker: AtomicWaker,
+    ///driver asked to stop, controller drops endpoint out of refilling on next completion
+    cancelled: AtomicBool,
+    ///controller stopped refilling for good, buffer is the driver's again
+    closed: AtomicBool,
+    rotation: Option<BufferRotation>,
+}
+
+impl fmt::Debug for PeriodicStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeriodicStream")
+            .field("last_length", &self.last_length)
+            .field("last_buffer", &self.last_buffer)
+            .field("fresh", &self.fresh)
+            .field("deadline", &self.deadline())
+            .finish_non_exhaustive()
+    }
+}
+
+impl PeriodicStream {
+    pub fn new(buffer_addr_len: (usize, usize), clock: Clock) -> Arc<Self> {
+        Self::reporting(buffer_addr_len, clock, 0)
+    }
+
+    ///also queue up to `depth` completions, oldest ones are dropped once driver falls behind
+    pub fn reporting(buffer_addr_len: (usize, usize), clock: Clock, depth: usize) -> Arc<Self> {
+        Arc::new(Self::build(buffer_addr_len, clock, depth))
+    }
+
+    fn build(buffer_addr_len: (usize, usize), clock: Clock, depth: usize) -> Self {
+        Self {
+            buffer_addr_len: SpinCell::new(buffer_addr_len),
+            last_length: AtomicUsize::new(0),
+            last_buffer: AtomicUsize::new(buffer_addr_len.0),
+            fresh: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            clock,
+            monitor: DeadlineMonitor::default(),
+            report_depth: depth,
+            reports: SpinCell::new(VecDeque::with_capacity(depth)),
+            lost_reports: AtomicU64::new(0),
+            report_waker: AtomicWaker::new(),
+            cancelled: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            rotation: None,
+        }
+    }
+
+    ///fill `buffers` one after another, each taken by [`Self::next_filled`] is left alone
+    ///until handed back through [`Self::release`]. first buffer is filled first, None without any
+    pub fn rotating(buffers: Vec<(usize, usize)>, clock: Clock) -> Option<Arc<Self>> {
+        let mut stream = Self::build(*buffers.first()?, clock, 0);
+        stream.rotation = Some(BufferRotation {
+            free: SpinCell::new((1..buffers.len()).collect()),
+            filled: SpinCell::new(VecDeque::with_capacity(buffers.len())),
+            filled_waker: AtomicWaker::new(),
+            starved: AtomicUsize::new(0),
+            buffers,
+        });
+        Some(Arc::new(stream))
+    }
+
+    ///buffer at `idx` of a rotating stream
+    pub fn buffer(&self, idx: usize) -> Option<(usize, usize)> {
+        self.rotation.as_ref()?.buffers.get(idx).copied()
+    }
+
+    ///wait for the next filled buffer of a rotating stream, returns its index and length.
+    ///
+    ///every fill is delivered in order. None once controller stopped refilling and
+    ///every filled buffer was taken, or if stream does not rotate
+    pub async fn next_filled(&self) -> Option<(usize, usize)> {
+        let rotation = self.rotation.as_ref()?;
+        poll_fn(|cx| {
+            rotation.filled_waker.register(cx.waker());
+            if let Some(filled) = rotation.filled.with(|filled| filled.pop_front()) {
+                self.monitor.observe((self.clock)());
+                Poll::Ready(Some(filled))
+            } else if self.is_closed() {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    ///hand buffer taken from [`Self::next_filled`] back for refilling.
+    ///returns true if endpoint starved meanwhile, it has to be resubmitted then,
+    ///see [`crate::host::device::USBDevice::release_interrupt_buffer`]
+    pub fn release(&self, idx: usize) -> bool {
+        let Some(rotation) = &self.rotation else {
+            return false;
+        };
+        rotation.free.with(|free| {
+            free.push_back(idx);
+            rotation
+                .starved
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |starved| {
+                    starved.checked_sub(1)
+                })
+                .is_ok()
+        })
+    }
+
+    ///buffer next refill goes into, None if a rotating stream has none free.
+    ///starved endpoint is resumed by whoever releases a buffer next
+    pub(crate) fn take_free(&self) -> Option<(usize, usize)> {
+        let Some(rotation) = &self.rotation else {
+            return Some(self.buffer_addr_len());
+        };
+        let idx = rotation.free.with(|free| {
+            let idx = free.pop_front();
+            if idx.is_none() {
+                rotation.starved.fetch_add(1, Ordering::AcqRel);
+            }
+            idx
+        });
+        idx.map(|idx| rotation.buffers[idx])
+    }
+
+    ///start monitoring how late fills are taken, misses beyond threshold raise a warning event
+    pub fn set_deadline(&self, deadline: CompletionDeadline) {
+        let monitor = &self.monitor;
+        monitor
+            .miss_threshold
+            .store(deadline.miss_threshold.max(1), Ordering::Relaxed);
+        monitor.deadline.store(
+            (deadline.deadline.as_micros() as u64).max(1),
+            Ordering::Relaxed,
+        );
+        monitor.unreported.store(0, Ordering::Release);
+    }
+
+    pub fn deadline(&self) -> Option<CompletionDeadline> {
+        match self.monitor.deadline.load(Ordering::Relaxed) {
+            0 => None,
+            deadline => Some(CompletionDeadline {
+                deadline: Duration::from_micros(deadline),
+                miss_threshold: self.monitor.miss_threshold.load(Ordering::Relaxed),
+            }),
+        }
+    }
+
+    ///latency statistics of taken fills, collected even without a deadline
+    pub fn deadline_stats(&self) -> DeadlineStats {
+        self.monitor.stats()
+    }
+
+    ///Some once misses since last report reached threshold, called by controller on completion
+    pub(crate) fn take_deadline_report(&self) -> Option<(CompletionDeadline, DeadlineStats)> {
+        let deadline = self.deadline()?;
+        self.monitor
+            .unreported
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |unreported| {
+                (unreported >= deadline.miss_threshold).then_some(0)
+            })
+            .ok()
+            .map(|_| (deadline, self.monitor.stats()))
+    }
+
+    ///returns the old buffer, which is still owned by controller until current fill completes.
+    ///rotating streams ignore it, they always refill from their own buffers
+    pub fn swap_buffer(&self, buffer_addr_len: (usize, usize)) -> (usize, usize) {
+        self.buffer_addr_len
+            .with(|current| core::mem::replace(current, buffer_addr_len))
+    }
+
+    pub fn buffer_addr_len(&self) -> (usize, usize) {
+        self.buffer_addr_len.with(|current| *current)
+    }
+
+    ///bytes transferred by the latest completed fill
+    pub fn last_length(&self) -> usize {
+        self.last_length.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn complete(&self, buffer_addr: usize, length: usize) {
+        let now = (self.clock)().map_or(0, |now| (now.as_micros() as u64).max(1));
+        self.monitor.completed_at.store(now, Ordering::Release);
+        self.last_buffer.store(buffer_addr, Ordering::Release);
+        self.last_length.store(length, Ordering::Release);
+        self.fresh.store(true, Ordering::Release);
+        self.waker.wake();
+        if let Some(rotation) = &self.rotation
+            && let Some(idx) = rotation
+                .buffers
+                .iter()
+                .position(|(addr, _)| *addr == buffer_addr)
+        {
+            rotation
+                .filled
+                .with(|filled| filled.push_back((idx, length)));
+            rotation.filled_waker.wake();
+        }
+    }
+
+    ///wait for a fill completed after previous call, returns its buffer address and length.
+    ///
+    ///fills completed in between are coalesced, only the latest one is reported
+    pub async fn next_fill(&self) -> (usize, usize) {
+        poll_fn(|cx| {
+            self.waker.register(cx.waker());
+            if self.fresh.swap(false, Ordering::AcqRel) {
+                self.monitor.observe((self.clock)());
+                Poll::Ready((self.last_buffer.load(Ordering::Acquire), self.last_length()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    ///queue a completion, `data` is what controller wrote into the buffer.
+    ///called by controller for every fill, stalls and failures included
+    pub(crate) fn report(&self, result: Result<RequestResult, u8>, length: usize, data: &[u8]) {
+        if self.report_depth == 0 {
+            return;
+        }
+        let report = FillReport {
+            result,
+            length,
+            data: data.to_vec(),
+        };
+        self.reports.with(|reports| {
+            if reports.len() >= self.report_depth {
+                reports.pop_front();
+                self.lost_reports.fetch_add(1, Ordering::Relaxed);
+            }
+            reports.push_back(report);
+        });
+        self.report_waker.wake();
+    }
+
+    ///next queued completion in order, None once controller stopped refilling and queue ran dry.
+    ///
+    ///only one task should wait on it
+    pub async fn next_report(&self) -> Option<FillReport> {
+        poll_fn(|cx| {
+            self.report_waker.register(cx.waker());
+            if let Some(report) = self.reports.with(|reports| reports.pop_front()) {
+                self.monitor.observe((self.clock)());
+                Poll::Ready(Some(report))
+            } else if self.is_closed() {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    ///[`Self::next_report`] as a stream
+    pub fn reports(self: &Arc<Self>) -> impl Stream<Item = FillReport> {
+        futures::stream::unfold(self.clone(), |stream| async move {
+            let report = stream.next_report().await?;
+            Some((report, stream))
+        })
+    }
+
+    ///completions dropped since driver did not take them in time
+    pub fn lost_reports(&self) -> u64 {
+        self.lost_reports.load(Ordering::Relaxed)
+    }
+
+    ///stop refilling. the TD in flight still completes, or is dropped together with endpoint,
+    ///buffer must be kept until [`Self::is_closed`]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    ///controller will not touch buffer again
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    ///called by controller once endpoint left refilling, for whatever reason
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.report_waker.wake();
+        self.waker.wake();
+        if let Some(rotation) = &self.rotation {
+            rotation.filled_waker.wake();
+        }
+    }
+
+    ///valid part of `buffer`, never longer than buffer itself even if it shrinks after fill
+    pub fn filled<'b>(&self, buffer: &'b [u8]) -> &'b [u8] {
+        &buffer[..self.last_length().min(buffer.len())]
+    }
 }
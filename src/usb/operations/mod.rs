@@ -1,35 +1,140 @@
 pub mod configurations;
 pub mod isoch;
-use core::fmt::Debug;
+use core::{
+    fmt::{Debug, Display},
+    num::NonZeroUsize,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use alloc::{sync::Arc, vec::Vec};
-use bulk::BulkTransfer;
+use async_lock::{Mutex, SemaphoreGuardArc};
+use bulk::{BulkRefill, BulkTransfer};
 use control::ControlTransfer;
-use futures::channel::oneshot::Sender;
+use flow::FlowTicket;
+use futures::channel::oneshot::{self, Sender};
+use hub::{HubConfiguration, HubPortAttach};
 use interrupt::InterruptTransfer;
 use isoch::IsochTransfer;
-use nosy::{Listen, Listener, Sink};
+use log::trace;
 use num_derive::FromPrimitive;
 use usb_descriptor_decoder::descriptors::{
     desc_configuration::Configuration, desc_endpoint::Endpoint, desc_interface::USBInterface,
 };
 
-use crate::host::device::ConfigureSemaphore;
-
 use super::standards::TopologyRoute;
 
 pub mod bulk;
 pub mod control;
+pub mod flow;
+pub mod hub;
 pub mod interrupt;
 
+///first address and total length of a scatter list
+fn scatter_span(segments: &[(usize, usize)]) -> (usize, usize) {
+    (
+        segments.first().map_or(0, |(addr, _)| *addr),
+        segments.iter().map(|(_, len)| len).sum(),
+    )
+}
+
+fn segments_of<'a>(
+    scatter: &'a [(usize, usize)],
+    buffer_addr_len: &'a (usize, usize),
+) -> &'a [(usize, usize)] {
+    if scatter.is_empty() {
+        core::slice::from_ref(buffer_addr_len)
+    } else {
+        scatter
+    }
+}
+
+///correlates one request across device, controller ring, event and callback in logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+impl RequestId {
+    pub fn next() -> Self {
+        Self(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+///every request got fresh id, even those built from Default
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::next()
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "req#{}", self.0)
+    }
+}
+
+///built through [`USBRequest::once`], [`USBRequest::keep_bulk`] or [`USBRequest::keep_interrupt`],
+///which only put together actions the controller knows how to carry out
 #[derive(Default)]
 pub struct USBRequest {
-    pub extra_action: ExtraAction,
-    pub operation: RequestedOperation,
-    pub complete_action: CompleteAction,
+    pub(crate) id: RequestId,
+    pub(crate) extra_action: ExtraAction,
+    pub(crate) operation: RequestedOperation,
+    pub(crate) complete_action: CompleteAction,
+    pub(crate) latency: Latency,
+    ///held until controller picks request up, see [`flow::EndpointFlow`]
+    pub(crate) flow: Option<FlowTicket>,
 }
 
 impl USBRequest {
+    ///submitted once, finish it with how completion gets reported
+    pub fn once(operation: RequestedOperation) -> OnceRequest {
+        OnceRequest {
+            id: RequestId::next(),
+            operation,
+            latency: Latency::default(),
+        }
+    }
+
+    ///bulk TD controller submits again after every completion, filling buffers of `refill.stream`
+    ///one after another. completions are reported through the stream, there is no callback
+    pub fn keep_bulk(mut transfer: BulkTransfer, refill: BulkRefill) -> KeptRequest {
+        transfer.refill = Some(refill);
+        KeptRequest::new(RequestedOperation::Bulk(transfer))
+    }
+
+    ///interrupt TD controller submits again after every completion, reported through
+    ///[`InterruptTransfer::refill`] if there is one
+    pub fn keep_interrupt(transfer: InterruptTransfer) -> KeptRequest {
+        KeptRequest::new(RequestedOperation::Interrupt(transfer))
+    }
+
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    pub fn operation(&self) -> &RequestedOperation {
+        &self.operation
+    }
+
+    pub fn latency(&self) -> Latency {
+        self.latency
+    }
+
+    ///endpoint a data transfer goes to, None for everything else
+    pub fn endpoint_id(&self) -> Option<usize> {
+        match &self.operation {
+            RequestedOperation::Bulk(transfer) => Some(transfer.endpoint_id),
+            RequestedOperation::Interrupt(transfer) => Some(transfer.endpoint_id),
+            RequestedOperation::Isoch(transfer) => Some(transfer.endpoint_id),
+            _ => None,
+        }
+    }
+
     pub fn is_control(&self) -> bool {
         match self.operation {
             RequestedOperation::Control(_) => true,
@@ -41,11 +146,98 @@ impl USBRequest {
 impl Debug for USBRequest {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("USBRequest")
+            .field("id", &self.id)
             .field("operation", &self.operation)
+            .field("latency", &self.latency)
             .finish()
     }
 }
 
+///see [`USBRequest::once`]
+pub struct OnceRequest {
+    id: RequestId,
+    operation: RequestedOperation,
+    latency: Latency,
+}
+
+impl OnceRequest {
+    ///keep id of a request this one continues, fresh one otherwise
+    pub fn with_id(mut self, id: RequestId) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    fn finish(self, complete_action: CompleteAction) -> USBRequest {
+        USBRequest {
+            id: self.id,
+            extra_action: ExtraAction::NOOP,
+            operation: self.operation,
+            complete_action,
+            latency: self.latency,
+            flow: None,
+        }
+    }
+
+    pub fn no_response(self) -> USBRequest {
+        self.finish(CompleteAction::NOOP)
+    }
+
+    pub fn simple_response(self, sender: CallbackValue) -> USBRequest {
+        self.finish(CompleteAction::SimpleResponse(sender))
+    }
+
+    pub fn length_response(self, sender: LengthCallbackValue) -> USBRequest {
+        self.finish(CompleteAction::LengthResponse(sender))
+    }
+
+    ///semaphore goes back once controller is done, failure is left on it
+    pub fn release_on_complete(self, sem: ConfigureSemaphore) -> USBRequest {
+        self.finish(CompleteAction::DropSem(sem))
+    }
+}
+
+///kept-filling request, see [`USBRequest::keep_bulk`] and [`USBRequest::keep_interrupt`]
+pub struct KeptRequest(USBRequest);
+
+impl KeptRequest {
+    fn new(operation: RequestedOperation) -> Self {
+        Self(USBRequest {
+            id: RequestId::next(),
+            extra_action: ExtraAction::KeepFill,
+            operation,
+            complete_action: CompleteAction::NOOP,
+            latency: Latency::default(),
+            flow: None,
+        })
+    }
+
+    pub fn with_id(mut self, id: RequestId) -> Self {
+        self.0.id = id;
+        self
+    }
+
+    ///only the first submission is affected, controller refills on its own
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.0.latency = latency;
+        self
+    }
+
+    pub fn id(&self) -> RequestId {
+        self.0.id
+    }
+}
+
+impl From<KeptRequest> for USBRequest {
+    fn from(request: KeptRequest) -> Self {
+        request.0
+    }
+}
+
 pub type ChannelNumber = u16;
 
 #[derive(Debug, Default)]
@@ -55,32 +247,194 @@ pub enum ExtraAction {
     KeepFill,
 }
 
+///how urgently controller picks a request up from device channels.
+///
+///requests already waiting are dispatched realtime first, e.g. isoch feedback or HID reports
+///overtake bulk work queued by other devices. order within one device never changes,
+///so requests queued before a realtime one in its [`RequestLane`] inherit its latency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Latency {
+    #[default]
+    Normal,
+    Realtime,
+}
+
+///which TRBs of a multi-TRB TD should raise an interrupt, the TD end always does
+#[derive(Debug, Clone, Copy, Default)]
+pub enum IocPolicy {
+    #[default]
+    LastOnly,
+    EveryTrb,
+    EveryNth(NonZeroUsize),
+}
+
+impl IocPolicy {
+    pub fn should_interrupt(&self, trb_idx: usize) -> bool {
+        match self {
+            IocPolicy::LastOnly => false,
+            IocPolicy::EveryTrb => true,
+            IocPolicy::EveryNth(n) => (trb_idx + 1) % n.get() == 0,
+        }
+    }
+}
+
 type ValueResult = Result<RequestResult, u8>;
+pub type LengthResult = Result<(RequestResult, usize), u8>;
+///like [`CallbackValue`], plus bytes actually transferred, which is less than requested on short packets
+pub type LengthCallbackValue = Sender<LengthResult>;
 pub type CallbackValue = Sender<ValueResult>; //todo: change this into a oneshot channel
-                                              //                                               pub type KeepCallbackValue = <ValueResult>;
-
-// pub fn construct_keep_callback_listener() -> (
-//     nosy::Notifier<
-//         Result<RequestResult, u8>,
-//         Arc<dyn Listener<Result<RequestResult, u8>> + Send + Sync>,
-//     >,
-//     Sink<Result<RequestResult, u8>>,
-// ) {
-//     let notifier = KeepCallbackValue::new();
-//     let sink = Sink::new();
-//     notifier.listen(sink.listener());
-//     (notifier, sink)
-// }
+
+///request posted by [`crate::host::device::USBDevice::submit_with_length`], not yet completed
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub(crate) id: RequestId,
+    ///None if device was not usable at submit time
+    pub(crate) receiver: Option<oneshot::Receiver<LengthResult>>,
+}
+
+impl PendingRequest {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    ///result and bytes transferred, SlotNotEnabledError if device went away meanwhile
+    pub async fn wait(self) -> LengthResult {
+        let Some(receiver) = self.receiver else {
+            return Ok((RequestResult::SlotNotEnabledError, 0));
+        };
+        //callback dropped unanswered, device got unplugged while request was pending
+        let result = receiver
+            .await
+            .unwrap_or(Ok((RequestResult::SlotNotEnabledError, 0)));
+        trace!("{} callback with {:?}", self.id, result);
+        result
+    }
+}
+
+///why an operation failed, surfaced to whoever waits on it instead of panicking controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    ///controller completed the operation with a failure code
+    Completion(RequestResult),
+    ///completion code this stack does not know
+    UnknownCode(u8),
+    ///controller ran out of slots or device addresses
+    NoSlot,
+    ///device vanished from controller before its operation got processed
+    DeviceGone,
+    ///device answered, but its descriptor could not be read or parsed
+    BadDescriptor,
+    ///gave up waiting, see [`crate::abstractions::TimeoutPolicy`]
+    Timeout,
+    ///DMA memory ran out, e.g. what [`crate::abstractions::bounded::BoundedMemory`] reserved
+    OutOfMemory,
+    ///device offers no configuration of this value
+    UnknownConfiguration(u8),
+    ///device breaks usb spec, refused by [`crate::abstractions::SpecPolicy::Strict`]
+    SpecViolation,
+    ///periodic endpoints would overrun bus, in bytes per microframe.
+    ///see `USBDevice::enable_function`
+    Bandwidth { required: u32, available: u32 },
+    ///backend can't carry this kind of operation, e.g. isochronous transfers
+    Unsupported,
+    ///caller asked for something that can't be set up, e.g. a pipe without buffers
+    InvalidArgument,
+}
+
+impl UsbError {
+    ///success and short packet pass, everything else is an error.
+    ///
+    ///endpoints are only stopped and commands only aborted when they time out, so those are timeouts
+    pub fn check(code: Result<RequestResult, u8>) -> Result<RequestResult, UsbError> {
+        match code {
+            Ok(result @ (RequestResult::Success | RequestResult::ShortPacket)) => Ok(result),
+            Ok(
+                RequestResult::Stopped
+                | RequestResult::StoppedLengthInvalid
+                | RequestResult::StoppedShortPacket
+                | RequestResult::CommandAborted,
+            ) => Err(UsbError::Timeout),
+            Ok(other) => Err(UsbError::Completion(other)),
+            Err(code) => Err(UsbError::UnknownCode(code)),
+        }
+    }
+}
+
+impl Display for UsbError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            UsbError::Completion(result) => write!(f, "completed with {:?}", result),
+            UsbError::UnknownCode(code) => write!(f, "unknown completion code {}", code),
+            UsbError::NoSlot => write!(f, "no free slot or address"),
+            UsbError::DeviceGone => write!(f, "device is gone"),
+            UsbError::BadDescriptor => write!(f, "descriptor unreadable"),
+            UsbError::Timeout => write!(f, "timed out"),
+            UsbError::OutOfMemory => write!(f, "out of DMA memory"),
+            UsbError::UnknownConfiguration(value) => write!(f, "no configuration {}", value),
+            UsbError::SpecViolation => write!(f, "spec violation refused"),
+            UsbError::Bandwidth {
+                required,
+                available,
+            } => write!(
+                f,
+                "periodic bandwidth exhausted, {} bytes/microframe asked, {} left",
+                required, available
+            ),
+            UsbError::Unsupported => write!(f, "operation not supported by controller"),
+            UsbError::InvalidArgument => write!(f, "invalid argument"),
+        }
+    }
+}
+
+///held while device is being (re)configured, released by controller once done
+#[derive(Debug)]
+pub struct ConfigureSemaphore {
+    pub(crate) guard: SemaphoreGuardArc,
+    ///where a failure is left for the device, read after it got the semaphore back
+    pub(crate) outcome: Arc<Mutex<Option<UsbError>>>,
+}
+
+impl ConfigureSemaphore {
+    ///release semaphore, leaving `error` for the waiting device
+    pub(crate) async fn fail(self, error: UsbError) {
+        *self.outcome.lock().await = Some(error);
+        drop(self.guard);
+    }
+}
 
 #[derive(Default, Debug)]
 pub enum CompleteAction {
     #[default]
     NOOP,
     SimpleResponse(CallbackValue),
-    // KeepResponse(KeepCallbackValue),
+    LengthResponse(LengthCallbackValue),
     DropSem(ConfigureSemaphore),
 }
 
+impl CompleteAction {
+    ///for operations controller finishes by itself, without a TRB/qTD completing
+    pub(crate) fn respond(self, result: RequestResult) {
+        match self {
+            CompleteAction::SimpleResponse(sender) => {
+                let _ = sender.send(Ok(result));
+            }
+            CompleteAction::LengthResponse(sender) => {
+                let _ = sender.send(Ok((result, 0)));
+            }
+            _ => {}
+        }
+    }
+
+    ///for operations controller refuses outright. callbacks only carry a completion code, so they
+    ///get `code`, while whoever holds the semaphore gets `error`
+    pub(crate) async fn fail(self, error: UsbError, code: RequestResult) {
+        match self {
+            CompleteAction::DropSem(sem) => sem.fail(error).await,
+            action => action.respond(code),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub enum RequestedOperation {
     Control(ControlTransfer),
@@ -89,12 +443,59 @@ pub enum RequestedOperation {
     Isoch(IsochTransfer),
     InitializeDevice(TopologyRoute),
     EnableFunction(u8, Arc<USBInterface>), //config value, interface //sus, should we split enable configuration and enable interface as two part?
+    DisableFunction(Arc<USBInterface>),
+    ///drop every endpoint but ep0, sent before SET_CONFIGURATION picks another configuration
+    Deconfigure,
+    ///mark device as hub, sent by hub driver once hub descriptor is read
+    ConfigureHub(HubConfiguration),
+    ///new device on downstream port of this hub, controller probes it like a root port one
+    AttachChild(HubPortAttach),
+    ///device on downstream port (1 based) of this hub is gone, together with anything behind it
+    DetachChild(u8),
+    ///recover endpoint of given DCI after it stalled, queued transfers behind the stall are dropped
+    ClearStall(usize),
+    ///selective suspend of device through its root port, see [`crate::host::device::USBDevice::suspend`]
+    Suspend,
+    Resume,
     #[default]
     NOOP,
 }
 
+impl RequestedOperation {
+    pub fn lane(&self) -> RequestLane {
+        match self {
+            RequestedOperation::Bulk(_) => RequestLane::Bulk,
+            RequestedOperation::Interrupt(_) | RequestedOperation::Isoch(_) => {
+                RequestLane::Periodic
+            }
+            _ => RequestLane::Control,
+        }
+    }
+}
+
+///queue of its device a request waits in. controller takes one request of every queue per round,
+///so control traffic never waits behind long bulk or interrupt queues.
+///
+///requests keep their order within a lane only
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestLane {
+    ///default pipe and everything changing device or endpoint state
+    Control,
+    Periodic,
+    Bulk,
+}
+
+impl RequestLane {
+    pub const ALL: [RequestLane; 3] = [
+        RequestLane::Control,
+        RequestLane::Periodic,
+        RequestLane::Bulk,
+    ];
+}
+
 /// The direction of the data transfer.
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Direction {
     /// Out (Write Data)
     Out = 0,
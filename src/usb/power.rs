@@ -0,0 +1,59 @@
+///bus power current a port could deliver by default, refer usb2 spec 7.2.1 and usb3 spec 11.4.5
+pub const USB2_PORT_BUDGET_MA: u16 = 500;
+pub const USB3_PORT_BUDGET_MA: u16 = 900;
+///what a port of bus-powered hub could deliver, for when hubs are supported
+pub const BUS_POWERED_HUB_PORT_BUDGET_MA: u16 = 100;
+
+const CONFIG_DESC_LEN: usize = 9;
+const CONFIG_ATTR_OFFSET: usize = 7;
+const CONFIG_MAX_POWER_OFFSET: usize = 8;
+const CONFIG_ATTR_SELF_POWERED: u8 = 1 << 6;
+
+///power requirement of one configuration, from its configuration descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConfigPower {
+    pub config_value: u8,
+    pub max_power_ma: u16,
+    pub self_powered: bool,
+}
+
+impl ConfigPower {
+    ///bMaxPower is in 2mA unit, or 8mA for superspeed devices
+    pub fn from_config_desc(raw: &[u8], superspeed: bool) -> Option<Self> {
+        if raw.len() < CONFIG_DESC_LEN {
+            return None;
+        }
+        let unit = if superspeed { 8 } else { 2 };
+        Some(Self {
+            config_value: raw[5],
+            max_power_ma: raw[CONFIG_MAX_POWER_OFFSET] as u16 * unit,
+            self_powered: raw[CONFIG_ATTR_OFFSET] & CONFIG_ATTR_SELF_POWERED != 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PowerDecision {
+    Within,
+    OverBudget { required_ma: u16, available_ma: u16 },
+}
+
+impl PowerDecision {
+    pub fn evaluate(config: &ConfigPower, available_ma: u16) -> Self {
+        //self powered device still draws from bus, bMaxPower already tells how much
+        if config.max_power_ma <= available_ma {
+            PowerDecision::Within
+        } else {
+            PowerDecision::OverBudget {
+                required_ma: config.max_power_ma,
+                available_ma,
+            }
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        matches!(self, PowerDecision::OverBudget { .. })
+    }
+}
@@ -0,0 +1,209 @@
+use alloc::{string::String, sync::Arc, vec::Vec};
+use usb_descriptor_decoder::descriptors::{
+    desc_endpoint::{Endpoint, EndpointType},
+    desc_interface::{TopologyUSBFunction, USBInterface},
+};
+
+use crate::{
+    abstractions::PlatformAbstractions,
+    host::device::{DeviceState, EnumerationLatency, USBDevice},
+};
+
+use super::{
+    operations::{flow::EndpointStats, Direction},
+    power::ConfigPower,
+};
+
+///DCI of default control endpoint
+const CONTROL_DCI: usize = 1;
+
+///parse-only view of device tree, could be shipped to external tooling.
+///with `serde` feature on, it's serializable into any compact format the OS likes.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TopologySnapshot {
+    pub devices: Vec<DeviceSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeviceSnapshot {
+    pub route: u32,
+    pub root_port: u8,
+    pub slot_id: Option<u8>,
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub device_class: Option<(u8, u8, u8)>,
+    pub current_config: u8,
+    pub configs: Vec<ConfigSnapshot>,
+    pub drivers: Vec<String>,
+    pub power_refused: bool,
+    pub enumeration: EnumerationLatency,
+    ///default control endpoint, no interface lists it
+    pub control: EndpointStats,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConfigSnapshot {
+    pub config_value: u8,
+    pub power: Option<ConfigPower>,
+    pub interfaces: Vec<InterfaceSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct InterfaceSnapshot {
+    pub interface_number: u8,
+    pub alternate_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EndpointSnapshot {
+    pub dci: u8,
+    pub kind: EndpointKind,
+    pub direction: Direction,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    ///requests waiting in device channel, see [`crate::usb::operations::flow::EndpointFlow`]
+    pub queued: usize,
+    ///TRBs in flight and ring capacity, both 0 on backends without transfer rings
+    pub ring_occupancy: (usize, usize),
+    ///counted since device got attached, alternate settings sharing an endpoint share these
+    pub stats: EndpointStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum EndpointKind {
+    Control,
+    Isoch,
+    Bulk,
+    Interrupt,
+}
+
+impl EndpointKind {
+    ///control endpoints are bidirectional, reported as OUT
+    pub fn of(endpoint_type: EndpointType) -> (Self, Direction) {
+        match endpoint_type {
+            EndpointType::Control | EndpointType::NotValid => (Self::Control, Direction::Out),
+            EndpointType::IsochOut => (Self::Isoch, Direction::Out),
+            EndpointType::IsochIn => (Self::Isoch, Direction::In),
+            EndpointType::BulkOut => (Self::Bulk, Direction::Out),
+            EndpointType::BulkIn => (Self::Bulk, Direction::In),
+            EndpointType::InterruptOut => (Self::Interrupt, Direction::Out),
+            EndpointType::InterruptIn => (Self::Interrupt, Direction::In),
+        }
+    }
+}
+
+impl DeviceSnapshot {
+    pub async fn capture<O, const RING_BUFFER_SIZE: usize>(
+        device: &Arc<USBDevice<O, RING_BUFFER_SIZE>>,
+    ) -> Self
+    where
+        O: PlatformAbstractions,
+    {
+        let raw = device.device_desc_raw.get();
+        let power = device.power_requirements().await;
+        let read_u16 = |offset: usize| {
+            raw.filter(|raw| raw.len() > offset + 1)
+                .map(|raw| u16::from_le_bytes([raw[offset], raw[offset + 1]]))
+        };
+
+        Self {
+            route: device.topology_path.route_string(),
+            root_port: device.topology_path.port_number(),
+            slot_id: device.slot_id.get().copied(),
+            vendor_id: device.vendor_id.get().copied().or(read_u16(8)),
+            product_id: device.product_id.get().copied().or(read_u16(10)),
+            device_class: raw
+                .filter(|raw| raw.len() > 6)
+                .map(|raw| (raw[4], raw[5], raw[6])),
+            current_config: device.current_config(),
+            configs: device
+                .descriptor
+                .get()
+                .map(|desc| {
+                    desc.configs
+                        .iter()
+                        .map(|config| ConfigSnapshot {
+                            config_value: config.desc.config_val(),
+                            power: power
+                                .iter()
+                                .find(|p| p.config_value == config.desc.config_val())
+                                .copied(),
+                            interfaces: config
+                                .functions
+                                .iter()
+                                .flat_map(|function| match function.as_ref() {
+                                    TopologyUSBFunction::Interface(alts) => alts
+                                        .iter()
+                                        .map(|alt| InterfaceSnapshot::from_interface(alt, device))
+                                        .collect(),
+                                    _ => Vec::new(),
+                                })
+                                .collect(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            drivers: device.bound_drivers.read().await.clone(),
+            power_refused: matches!(*device.state.read().await, DeviceState::PowerRefused),
+            enumeration: device.enumeration_latency(),
+            control: device.endpoint_flow(CONTROL_DCI).stats(),
+        }
+    }
+}
+
+impl InterfaceSnapshot {
+    fn from_interface<O, const RING_BUFFER_SIZE: usize>(
+        interface: &Arc<USBInterface>,
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+    ) -> Self
+    where
+        O: PlatformAbstractions,
+    {
+        Self {
+            interface_number: interface.interface.interface_number,
+            alternate_setting: interface.interface.alternate_setting,
+            class: interface.interface.interface_class,
+            subclass: interface.interface.interface_subclass,
+            protocol: interface.interface.interface_protocol,
+            endpoints: interface
+                .endpoints
+                .iter()
+                .map(|endpoint| EndpointSnapshot::from_endpoint(endpoint, device))
+                .collect(),
+        }
+    }
+}
+
+impl EndpointSnapshot {
+    fn from_endpoint<O, const RING_BUFFER_SIZE: usize>(
+        endpoint: &Arc<Endpoint>,
+        device: &USBDevice<O, RING_BUFFER_SIZE>,
+    ) -> Self
+    where
+        O: PlatformAbstractions,
+    {
+        let (kind, direction) = EndpointKind::of(endpoint.endpoint_type());
+        let dci = endpoint.doorbell_value_aka_dci() as usize;
+        let flow = device.endpoint_flow(dci);
+        Self {
+            dci: dci as _,
+            kind,
+            direction,
+            max_packet_size: endpoint.max_packet_size,
+            interval: endpoint.interval,
+            queued: flow.queued(),
+            ring_occupancy: flow.ring_occupancy(),
+            stats: flow.stats(),
+        }
+    }
+}
@@ -1,18 +1,54 @@
-use core::fmt::Display;
+use core::{fmt::Display, ops::Range};
 
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use bit_field::BitField;
 ///utils according to USB standard
 
+///hubs allowed between root port and a device, refer usb2 spec 4.1.1 and usb3 spec 8.9
+pub const MAX_HUB_DEPTH: usize = 5;
+///route string only got 4 bits per hub
+pub const MAX_HUB_PORT: u8 = 15;
+///root port plus a tier per hub
+const MAX_TIERS: usize = MAX_HUB_DEPTH + 1;
+const ROUTE_STRING_BITS: Range<usize> = 0..20;
+///xhci allows up to 255 root ports, they don't fit in a tier
+const ROOT_PORT_BITS: Range<usize> = 20..28;
+const CONTROLLER_BITS: Range<usize> = 32..40;
+
 /// The Route String is a 20-bit field in downstream directed packets that the hub uses to route
 /// each packet to the designated downstream port.  It is composed of a concatenation of the
 /// downstream port numbers (4 bits per hub) for each hub traversed to reach a device.  The
 /// hub uses a Hub Depth value multiplied by four as an offset into the Route String to locate the
 /// bits it uses to determine the downstream port number.  The Hub Depth value is determined
 /// and assigned to every hub during the enumeration process.  
-
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// route string is kept as is in low 20 bits, tier 1 being the port of first hub.
+/// tier 0 here is the root port number, held apart with index of the controller it belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(C)]
-pub struct TopologyRoute(u32);
+pub struct TopologyRoute(u64);
+
+///why a route could not be extended, see [`TopologyRoute::checked_child`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    ///hub is at [`MAX_HUB_DEPTH`] already, one more tier does not fit
+    TooDeep,
+    ///port 0 or above [`MAX_HUB_PORT`]
+    PortOutOfRange(u8),
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RouteError::TooDeep => write!(f, "more than {} hubs in a row", MAX_HUB_DEPTH),
+            RouteError::PortOutOfRange(port) => write!(f, "port {} is not routable", port),
+        }
+    }
+}
 
 impl Default for TopologyRoute {
     fn default() -> Self {
@@ -25,17 +61,25 @@ impl TopologyRoute {
         Self(0)
     }
 
+    ///panics if route is full or port does not fit, see [`Self::try_append_port_number`]
     pub fn append_port_number(&mut self, port_number: u8) {
-        for i in 0..5 {
-            if self.get_hub_index_at_tier(i) == 0 {
-                self.write_hub_tier(i as _, port_number);
-                return;
-            }
+        self.try_append_port_number(port_number)
+            .unwrap_or_else(|err| panic!("can't extend {}: {}", self, err))
+    }
+
+    pub fn try_append_port_number(&mut self, port_number: u8) -> Result<(), RouteError> {
+        let tier = (0..MAX_TIERS)
+            .find(|tier| self.get_hub_index_at_tier(*tier) == 0)
+            .ok_or(RouteError::TooDeep)?;
+        if port_number == 0 || (tier > 0 && port_number > MAX_HUB_PORT) {
+            return Err(RouteError::PortOutOfRange(port_number));
         }
+        self.write_hub_tier(tier, port_number);
+        Ok(())
     }
 
     pub fn get_hub_index_at_tier(&self, i: usize) -> u8 {
-        assert!(i <= 4, "for tier > 4, it's un implemented");
+        assert!(i < MAX_TIERS, "usb allows at most 5 hubs in a row");
         // let ret = match i {
         //     0 => self.0.read(RouteString_REG::HUB_TIER0),
         //     1 => self.0.read(RouteString_REG::HUB_TIER1),
@@ -45,16 +89,42 @@ impl TopologyRoute {
         //     _ => unimplemented!("reserved"),
         // };
 
-        let from = i * 4;
-        let to = i * 4 + 4;
-        self.0.get_bits(from..to) as _
+        self.0.get_bits(Self::tier_bits(i)) as _
     }
 
     pub fn write_hub_tier(&mut self, i: usize, u: u8) {
-        assert!(i <= 4, "for tier > 4, it's un implemented");
-        let from = i * 4;
-        let to = i * 4 + 4;
-        self.0.set_bits(from..to, u as _);
+        assert!(i < MAX_TIERS, "usb allows at most 5 hubs in a row");
+        assert!(
+            i == 0 || u <= MAX_HUB_PORT,
+            "port {} does not fit in route string",
+            u
+        );
+        self.0.set_bits(Self::tier_bits(i), u as _);
+    }
+
+    fn tier_bits(i: usize) -> Range<usize> {
+        match i {
+            0 => ROOT_PORT_BITS,
+            _ => (i - 1) * 4..i * 4,
+        }
+    }
+
+    ///route of device directly on root port
+    pub fn from_port_idx(port_idx: usize) -> Self {
+        let mut route = Self::new();
+        route.append_port_number((port_idx + 1) as _);
+        route
+    }
+
+    ///same route, on controller of given index
+    pub fn on_controller(mut self, controller: u8) -> Self {
+        self.0.set_bits(CONTROLLER_BITS, controller as _);
+        self
+    }
+
+    ///index of controller, 0 with single controller
+    pub fn controller(&self) -> u8 {
+        self.0.get_bits(CONTROLLER_BITS) as _
     }
 
     pub fn port_idx(&self) -> u8 {
@@ -65,20 +135,167 @@ impl TopologyRoute {
         self.get_hub_index_at_tier(0)
     }
 
-    //TODO: Thid method is Broken!
+    ///downstream port numbers of every hub on the way, root port excluded, 0 for root devices
     pub fn route_string(&self) -> u32 {
-        let mut r = self.clone();
-        for idx in 0..4 {
-            let get_hub_index_at_tier = r.get_hub_index_at_tier(idx);
-            if get_hub_index_at_tier != 0 {
-                r.write_hub_tier(idx, get_hub_index_at_tier);
-            }
-        }
-        r.0
+        self.0.get_bits(ROUTE_STRING_BITS) as _
+    }
+
+    ///hubs between root port and device
+    pub fn depth(&self) -> usize {
+        (1..MAX_TIERS)
+            .take_while(|tier| self.get_hub_index_at_tier(*tier) != 0)
+            .count()
+    }
+
+    ///downstream port of parent hub, or root port for root devices
+    pub fn last_port(&self) -> u8 {
+        self.get_hub_index_at_tier(self.depth())
+    }
+
+    ///route of hub this device hangs on, None for root devices
+    pub fn parent(&self) -> Option<Self> {
+        let depth = self.depth();
+        (depth > 0).then(|| {
+            let mut parent = self.clone();
+            parent.write_hub_tier(depth, 0);
+            parent
+        })
+    }
+
+    pub fn child(&self, port_number: u8) -> Self {
+        let mut child = self.clone();
+        child.append_port_number(port_number);
+        child
+    }
+
+    ///route of device on `port_number` of hub at `self`, if route string could hold it
+    pub fn checked_child(&self, port_number: u8) -> Result<Self, RouteError> {
+        let mut child = self.clone();
+        child.try_append_port_number(port_number)?;
+        Ok(child)
+    }
+
+    ///root port, then downstream port of every hub on the way
+    pub fn ports(&self) -> Vec<u8> {
+        (0..=self.depth())
+            .map(|tier| self.get_hub_index_at_tier(tier))
+            .collect()
+    }
+
+    ///stable name like "usb1-2.3": controller counted from 1, then root port and hub ports on the way.
+    ///stays the same as long as device sits on the same physical port
+    pub fn device_name(&self) -> String {
+        let ports = self
+            .ports()
+            .iter()
+            .map(u8::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+        format!("usb{}-{}", self.controller() as u16 + 1, ports)
+    }
+
+    ///name of one function of device, like "usb1-2.3:1.0" for interface 0 in configuration 1
+    pub fn function_name(&self, config: u8, interface: u8) -> String {
+        format!("{}:{}.{}", self.device_name(), config, interface)
+    }
+
+    ///`self` is somewhere below hub at `hub`
+    pub fn is_behind(&self, hub: &Self) -> bool {
+        let depth = hub.depth();
+        self.controller() == hub.controller()
+            && self.depth() > depth
+            && (0..=depth)
+                .all(|tier| self.get_hub_index_at_tier(tier) == hub.get_hub_index_at_tier(tier))
     }
 }
 impl Display for TopologyRoute {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "topology: {:x}", self.0)
+        write!(
+            f,
+            "topology: {}.{:05x}@{}",
+            self.port_number(),
+            self.route_string(),
+            self.controller()
+        )
+    }
+}
+
+///link power management abilities advertised in BOS device capabilities
+#[derive(Clone, Debug, Default)]
+pub struct LinkPowerCapabilities {
+    pub usb2_lpm: bool,
+    pub besl: bool,
+    pub baseline_besl: Option<u8>,
+    pub deep_besl: Option<u8>,
+    ///in microseconds
+    pub u1_exit_latency: Option<u8>,
+    ///in microseconds
+    pub u2_exit_latency: Option<u16>,
+}
+
+impl LinkPowerCapabilities {
+    const DEVICE_CAPABILITY: u8 = 0x10;
+    const USB2_EXTENSION: u8 = 0x02;
+    const SUPERSPEED_USB: u8 = 0x03;
+
+    pub fn parse_bos(bos: &[u8]) -> Self {
+        let mut caps = Self::default();
+        let mut offset = bos.first().copied().unwrap_or(0) as usize;
+
+        while offset + 3 <= bos.len() {
+            let len = bos[offset] as usize;
+            if len < 3 || offset + len > bos.len() {
+                break;
+            }
+            let cap = &bos[offset..offset + len];
+            offset += len;
+
+            if cap[1] != Self::DEVICE_CAPABILITY {
+                continue;
+            }
+            match cap[2] {
+                Self::USB2_EXTENSION if len >= 7 => {
+                    let attributes = u32::from_le_bytes([cap[3], cap[4], cap[5], cap[6]]);
+                    caps.usb2_lpm = attributes.get_bit(1);
+                    caps.besl = attributes.get_bit(2);
+                    caps.baseline_besl = attributes
+                        .get_bit(3)
+                        .then(|| attributes.get_bits(8..12) as u8);
+                    caps.deep_besl = attributes
+                        .get_bit(4)
+                        .then(|| attributes.get_bits(12..16) as u8);
+                }
+                Self::SUPERSPEED_USB if len >= 10 => {
+                    caps.u1_exit_latency = Some(cap[7]);
+                    caps.u2_exit_latency = Some(u16::from_le_bytes([cap[8], cap[9]]));
+                }
+                _ => {}
+            }
+        }
+        caps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TopologyRoute;
+
+    #[test]
+    fn device_behind_hub_is_not_on_root_port() {
+        let root = TopologyRoute::from_port_idx(2);
+        let behind = root.checked_child(1).unwrap();
+        assert_eq!(behind.port_number(), root.port_number());
+        assert_ne!(behind, root);
+        assert_eq!(behind.route_string(), 1);
+        assert!(behind.is_behind(&root));
+        assert!(!root.is_behind(&behind));
+    }
+
+    #[test]
+    fn same_port_on_another_controller_differs() {
+        let first = TopologyRoute::from_port_idx(0);
+        let second = first.clone().on_controller(1);
+        assert_ne!(first, second);
+        assert!(!second.checked_child(1).unwrap().is_behind(&first));
     }
 }